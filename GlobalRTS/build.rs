@@ -0,0 +1,51 @@
+//! Build-time facts for `GET /api/version`: the git commit and build time.
+//!
+//! Both are optional. A build from a source tarball without git still
+//! works; the commit is simply unknown. Set GLOBALRTS_GIT_COMMIT to supply
+//! it yourself, and SOURCE_DATE_EPOCH for a reproducible build time.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-env-changed=GLOBALRTS_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let commit = std::env::var("GLOBALRTS_GIT_COMMIT").ok().or_else(git_commit);
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=GLOBALRTS_GIT_COMMIT={}", commit);
+    }
+
+    let build_time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=GLOBALRTS_BUILD_TIME={}", build_time);
+}
+
+/// The short hash of HEAD, if this is a git checkout and git is installed.
+/// Also asks to rebuild when HEAD moves, so the hash stays current.
+fn git_commit() -> Option<String> {
+    let git_dir = git(&["rev-parse", "--git-dir"])?;
+    println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+    if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        println!("cargo:rerun-if-changed={}/{}", git_dir, head_ref);
+    }
+    git(&["rev-parse", "--short", "HEAD"])
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
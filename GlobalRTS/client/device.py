@@ -11,6 +11,7 @@ USAGE:
     python device.py --id my-robot      # Set device ID
     python device.py --name "My Robot"  # Set device name
     python device.py --type robot       # Set device type
+    python device.py --signed --reset   # Pair as a signing device (HMAC per message)
 
 WORKFLOW:
     1. Script requests to pair with server
@@ -27,6 +28,7 @@ import json
 import socket
 import ssl
 import hashlib
+import hmac
 import base64
 import struct
 import os
@@ -322,9 +324,10 @@ class DeviceState:
 # PAIRING FLOW
 # ============================================================================
 
-def pair_device(server, device_id, name, device_type):
+def pair_device(server, device_id, name, device_type, signed=False):
     """
     Pair device with server using 6-digit code flow.
+    With signed=True the server will only accept signed telemetry and acks.
     Returns auth token on success.
     """
     base_url = f"http://{server}"
@@ -337,7 +340,8 @@ def pair_device(server, device_id, name, device_type):
         body={
             "device_id": device_id,
             "name": name,
-            "device_type": device_type
+            "device_type": device_type,
+            "signed": signed
         }
     )
     
@@ -396,13 +400,24 @@ def load_token():
     if os.path.exists(TOKEN_FILE):
         with open(TOKEN_FILE, "r") as f:
             data = json.load(f)
-            return data.get("token"), data.get("device_id")
-    return None, None
+            return data.get("token"), data.get("device_id"), data.get("signed", False)
+    return None, None, False
 
-def save_token(token, device_id):
+def save_token(token, device_id, signed=False):
     """Save token to file."""
     with open(TOKEN_FILE, "w") as f:
-        json.dump({"token": token, "device_id": device_id}, f)
+        json.dump({"token": token, "device_id": device_id, "signed": signed}, f)
+
+def envelope(msg_type, data, key=None):
+    """
+    Message text. With a key, adds sig = HMAC-SHA1(key, type + "\n" + data)
+    over the exact data text sent.
+    """
+    data_text = json.dumps(data)
+    if not key:
+        return json.dumps({"type": msg_type, "data": data})
+    sig = hmac.new(key.encode(), f"{msg_type}\n{data_text}".encode(), hashlib.sha1).hexdigest()
+    return f'{{"type": {json.dumps(msg_type)}, "data": {data_text}, "sig": "{sig}"}}'
 
 # ============================================================================
 # COMMAND HANDLING
@@ -444,6 +459,7 @@ def main():
     parser.add_argument("--name", default="Python Device", help="Device name")
     parser.add_argument("--type", default="robot", help="Device type")
     parser.add_argument("--reset", action="store_true", help="Clear saved token and re-pair")
+    parser.add_argument("--signed", action="store_true", help="When pairing, require signed messages from this device")
     args = parser.parse_args()
     
     # Generate device ID if not provided
@@ -460,15 +476,16 @@ def main():
     print("="*50 + "\n")
     
     # Check for saved token
-    token, saved_id = load_token()
+    token, saved_id, signed = load_token()
     
     if args.reset or not token:
         # Need to pair
-        token = pair_device(args.server, device_id, args.name, args.type)
+        signed = args.signed
+        token = pair_device(args.server, device_id, args.name, args.type, signed)
         if not token:
             print("\n❌ Could not pair device. Exiting.")
             sys.exit(1)
-        save_token(token, device_id)
+        save_token(token, device_id, signed)
         print(f"✓ Token saved to {TOKEN_FILE}")
     else:
         device_id = saved_id or device_id
@@ -521,6 +538,8 @@ def main():
     # Initialize state
     state = DeviceState()
     tick = 0
+    last_seq = 0  # Highest command seq handled: a re-delivered command is skipped
+    signing_key = token if signed else None
     
     # Main loop
     try:
@@ -535,17 +554,26 @@ def main():
                         command_id = data.get("commandId", "")
                         command_type = data.get("type", "")
                         payload = data.get("payload", {})
+                        seq = data.get("seq", 0)
                         
-                        status = handle_command(state, command_type, payload)
+                        repeat = bool(seq) and seq <= last_seq
+                        last_seq = max(last_seq, seq)
                         
-                        # Send ack
-                        ws.send({
-                            "type": "command:ack",
-                            "data": {
+                        if repeat:
+                            # Re-delivered: it ran (or was skipped) the first time
+                            print(f"\n📥 Command: {command_type} (seq {seq} already handled, skipping)")
+                        elif data.get("dryRun"):
+                            # Dry run: log what we would do, change nothing, report nothing
+                            print(f"\n📥 Command: {command_type} (dry run)")
+                            print(f"   🧪 Would run {command_type} with {json.dumps(payload)}")
+                        else:
+                            status = handle_command(state, command_type, payload)
+                            
+                            # Send ack
+                            ws.send(envelope("command:ack", {
                                 "commandId": command_id,
                                 "status": status
-                            }
-                        })
+                            }, signing_key))
                 except json.JSONDecodeError:
                     pass
             
@@ -553,10 +581,7 @@ def main():
             state.update()
             
             # Send telemetry
-            ws.send({
-                "type": "telemetry",
-                "data": state.to_telemetry()
-            })
+            ws.send(envelope("telemetry", state.to_telemetry(), signing_key))
             
             # Log status periodically
             tick += 1
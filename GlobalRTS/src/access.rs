@@ -0,0 +1,266 @@
+//! # Connection Access Control
+//!
+//! CIDR allowlist/denylist checked against a peer's IP before any HTTP or
+//! WebSocket work. A cheap perimeter control, independent of token auth.
+//!
+//! RULES:
+//! - A peer matching any deny entry is refused, even if it is also allowed.
+//! - With an empty allowlist, everyone not denied gets in.
+//! - With a non-empty allowlist, only matching peers get in.
+//!
+//! Configure with comma-separated CIDRs (IPv4 or IPv6; a bare address is a
+//! single host) in GLOBALRTS_ALLOW_CIDRS and GLOBALRTS_DENY_CIDRS.
+//!
+//! BEHIND A PROXY:
+//! Every connection then comes from the proxy. List it in
+//! GLOBALRTS_TRUSTED_PROXIES and its X-Forwarded-For (or X-Real-IP) names
+//! the client instead. Headers from any other peer are ignored: anyone can
+//! send them.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// An address block: `10.0.0.0/8`, `fd00::/8`, or a bare address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse `addr/prefix` or a bare address. An IPv4-mapped block
+    /// (`::ffff:10.0.0.0/104`) is kept as the IPv4 one it means, since
+    /// mapped peers are matched as IPv4.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid address in {:?}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix in {:?}", s))?,
+            None => max,
+        };
+        if let IpAddr::V6(v6) = addr {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                if prefix < 96 {
+                    return Err(format!("an IPv4-mapped block needs a prefix of at least 96 in {:?}", s));
+                }
+                return Ok(Self { addr: IpAddr::V4(v4), prefix: prefix - 96 });
+            }
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    /// Whether `ip` falls in this block. IPv4-mapped IPv6 peers
+    /// (`::ffff:1.2.3.4`) match as their IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Allow and deny lists for incoming connections.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    /// Peers whose forwarding headers are believed.
+    trusted_proxies: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Build from comma-separated CIDR lists. Empty strings mean empty lists.
+    pub fn new(allow: &str, deny: &str) -> Result<Self, String> {
+        Ok(Self {
+            allow: parse_list(allow)?,
+            deny: parse_list(deny)?,
+            trusted_proxies: Vec::new(),
+        })
+    }
+
+    /// Believe forwarding headers from peers in these comma-separated CIDRs.
+    pub fn with_trusted_proxies(mut self, proxies: &str) -> Result<Self, String> {
+        self.trusted_proxies = parse_list(proxies)?;
+        Ok(self)
+    }
+
+    /// Whether a peer at `ip` may connect. Deny wins over allow.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    /// True when neither list has entries (every peer is permitted).
+    pub fn is_open(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether `peer` is a proxy whose forwarding headers are believed.
+    pub fn trusts_proxy(&self, peer: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|c| c.contains(peer))
+    }
+
+    /// The allow, deny and trusted proxy lists, comma-separated, as `new`
+    /// and `with_trusted_proxies` take them.
+    pub fn lists(&self) -> [String; 3] {
+        [&self.allow, &self.deny, &self.trusted_proxies].map(|list| join_list(list))
+    }
+
+    /// Number of trusted proxy entries.
+    pub fn trusted_proxy_count(&self) -> usize {
+        self.trusted_proxies.len()
+    }
+
+    /// The address of the client behind a connection from `peer`, given the
+    /// request's X-Forwarded-For (all of them, comma-joined) and X-Real-IP.
+    /// Only a trusted proxy's headers count; otherwise it's the peer itself.
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>, real_ip: Option<&str>) -> IpAddr {
+        if !self.trusts_proxy(peer) {
+            return peer;
+        }
+        if let Some(chain) = forwarded_for {
+            // Each proxy appends the address it heard from, so read from the
+            // right: the first hop that isn't one of ours is the client.
+            // Entries left of it came from the client and prove nothing.
+            let mut client = peer;
+            for hop in chain.rsplit(',') {
+                match parse_forwarded(hop) {
+                    Some(ip) if self.trusts_proxy(ip) => client = ip,
+                    Some(ip) => return ip,
+                    None => break,
+                }
+            }
+            return client;
+        }
+        real_ip.and_then(parse_forwarded).unwrap_or(peer)
+    }
+}
+
+/// An address as proxies write it: `1.2.3.4`, `2001:db8::1`, `[2001:db8::1]`,
+/// or any of those with a port.
+fn parse_forwarded(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value.parse::<IpAddr>().ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| value.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+fn join_list(list: &[Cidr]) -> String {
+    list.iter().map(Cidr::to_string).collect::<Vec<_>>().join(",")
+}
+
+fn parse_list(list: &str) -> Result<Vec<Cidr>, String> {
+    list.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(Cidr::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn allowlisted_ips_get_in_and_others_do_not() {
+        let access = AccessList::new("192.168.1.0/24, 2001:db8::/32", "").unwrap();
+        assert!(access.permits(ip("192.168.1.77")));
+        assert!(access.permits(ip("2001:db8:0:1::5")));
+        assert!(access.permits(ip("::ffff:192.168.1.9")));
+        assert!(!access.permits(ip("192.168.2.1")));
+        assert!(!access.permits(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn denylisted_ips_are_refused() {
+        let access = AccessList::new("", "10.0.0.0/8,fe80::1").unwrap();
+        assert!(!access.permits(ip("10.200.3.4")));
+        assert!(!access.permits(ip("fe80::1")));
+        assert!(access.permits(ip("fe80::2")));
+        assert!(access.permits(ip("8.8.8.8")));
+        assert!(AccessList::default().permits(ip("10.200.3.4")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access = AccessList::new("10.0.0.0/8", "10.0.0.5").unwrap();
+        assert!(access.permits(ip("10.0.0.4")));
+        assert!(!access.permits(ip("10.0.0.5")));
+        // Even a more specific allow doesn't override a deny
+        let access = AccessList::new("10.0.0.5/32", "10.0.0.0/8").unwrap();
+        assert!(!access.permits(ip("10.0.0.5")));
+    }
+
+    #[test]
+    fn forwarded_ip_is_believed_only_from_trusted_proxies() {
+        let access = AccessList::default().with_trusted_proxies("10.0.0.0/8").unwrap();
+        let proxy = ip("10.1.2.3");
+        assert_eq!(access.client_ip(proxy, Some("203.0.113.7"), None), ip("203.0.113.7"));
+        assert_eq!(access.client_ip(proxy, Some("[2001:db8::1]:4711"), None), ip("2001:db8::1"));
+        assert_eq!(access.client_ip(proxy, None, Some("198.51.100.4")), ip("198.51.100.4"));
+        assert_eq!(access.client_ip(proxy, None, None), proxy);
+
+        // Anyone else's headers are ignored
+        let stranger = ip("192.0.2.50");
+        assert_eq!(access.client_ip(stranger, Some("203.0.113.7"), Some("203.0.113.8")), stranger);
+        assert_eq!(AccessList::default().client_ip(proxy, Some("203.0.113.7"), None), proxy);
+    }
+
+    #[test]
+    fn forwarded_chains_are_read_from_the_nearest_hop() {
+        let access = AccessList::default().with_trusted_proxies("10.0.0.0/8").unwrap();
+        let proxy = ip("10.1.2.3");
+        // The client claimed 1.1.1.1 itself; our proxies saw 203.0.113.7
+        assert_eq!(access.client_ip(proxy, Some("1.1.1.1, 203.0.113.7, 10.9.9.9"), None), ip("203.0.113.7"));
+        // Only trusted hops: the furthest one is as close as we get
+        assert_eq!(access.client_ip(proxy, Some("10.5.5.5,10.9.9.9"), None), ip("10.5.5.5"));
+        // Garbage stops the walk at what was known good
+        assert_eq!(access.client_ip(proxy, Some("nonsense, 10.9.9.9"), None), ip("10.9.9.9"));
+        assert_eq!(access.client_ip(proxy, Some("nonsense"), Some("203.0.113.7")), proxy);
+    }
+
+    #[test]
+    fn prefix_edges_and_bad_entries() {
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("2001:db8::1")));
+        assert!(!Cidr::parse("::/0").unwrap().contains(ip("203.0.113.9")));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(AccessList::new("10.0.0.0/8,nope", "").is_err());
+    }
+
+    #[test]
+    fn ipv4_mapped_entries_match_as_ipv4() {
+        let mapped = Cidr::parse("::ffff:10.0.0.0/104").unwrap();
+        assert_eq!(mapped, Cidr::parse("10.0.0.0/8").unwrap());
+        assert!(mapped.contains(ip("10.20.30.40")));
+        assert!(mapped.contains(ip("::ffff:10.20.30.40")));
+        assert!(!mapped.contains(ip("11.0.0.1")));
+        assert_eq!(Cidr::parse("::ffff:192.0.2.7").unwrap(), Cidr::parse("192.0.2.7").unwrap());
+        assert!(Cidr::parse("::ffff:10.0.0.0/80").is_err());
+    }
+}
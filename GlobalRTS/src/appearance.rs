@@ -0,0 +1,89 @@
+//! # Device Appearance
+//!
+//! How a device looks on the map: a color and an icon. Operators may pick
+//! them (`PATCH /api/devices/{id}/appearance`); until they do, the color
+//! comes from a hash of the device id and the icon from the device type.
+//! Either way every UI, in every session, draws a device the same.
+
+/// Longest icon, in bytes. Room for any emoji sequence, not for prose.
+pub const MAX_ICON_BYTES: usize = 32;
+
+/// Icon for a device type nobody chose one for.
+pub fn default_icon(device_type: &str) -> &'static str {
+    match device_type {
+        "robot" => "🤖",
+        "phone" => "📱",
+        "iot" => "📡",
+        "drone" => "🚁",
+        "vehicle" => "🚗",
+        _ => "📍",
+    }
+}
+
+/// `#rrggbb` for a device nobody chose a color for. The hue comes from
+/// the id's hash; saturation and lightness are fixed so every default
+/// reads well on the dark map.
+pub fn default_color(device_id: &str) -> String {
+    let hue = fnv1a(device_id.as_bytes()) % 360;
+    hsl_to_hex(hue as f64, 0.7, 0.55)
+}
+
+/// Whether `color` is `#rrggbb`.
+pub fn is_valid_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether `icon` is something to draw: short and without control characters.
+pub fn is_valid_icon(icon: &str) -> bool {
+    !icon.trim().is_empty() && icon.len() <= MAX_ICON_BYTES && !icon.chars().any(char::is_control)
+}
+
+/// 32-bit FNV-1a: tiny, and the same on every platform and release.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x01000193))
+}
+
+fn hsl_to_hex(hue: f64, saturation: f64, lightness: f64) -> String {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |v: f64| ((v + m) * 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(r), channel(g), channel(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_color_is_stable_per_id() {
+        assert_eq!(default_color("robot-01"), default_color("robot-01"));
+        // Pinned: a change here repaints every device in every UI
+        assert_eq!(default_color("robot-01"), "#3cddaa");
+        assert_eq!(hsl_to_hex(0.0, 1.0, 0.5), "#ff0000");
+        assert_eq!(hsl_to_hex(240.0, 1.0, 0.5), "#0000ff");
+
+        let colors: std::collections::HashSet<String> = (0..20).map(|i| default_color(&format!("robot-{:02}", i))).collect();
+        assert!(colors.len() > 10, "{:?}", colors);
+    }
+
+    #[test]
+    fn colors_and_icons_are_checked() {
+        assert!(is_valid_color("#FF8800"));
+        assert!(!is_valid_color("ff8800"));
+        assert!(!is_valid_color("#ff880"));
+        assert!(!is_valid_color("#gg8800"));
+        assert!(is_valid_icon("🚜"));
+        assert!(!is_valid_icon(" "));
+        assert!(!is_valid_icon("a\nb"));
+        assert!(!is_valid_icon(&"x".repeat(MAX_ICON_BYTES + 1)));
+    }
+}
@@ -0,0 +1,557 @@
+//! # Device Client
+//!
+//! What a device written in Rust needs to talk to a server, so it doesn't
+//! have to hand-roll WebSocket framing: pairing, keeping its token, then
+//! registering, reporting telemetry and answering commands, reconnecting
+//! whenever the connection drops.
+//!
+//! ```no_run
+//! use globalrts::client::DeviceClient;
+//! use globalrts::protocol::TelemetryMessage;
+//!
+//! let mut client = DeviceClient::new("127.0.0.1", 3000, "robot-01", "robot", "Robot Alpha")
+//!     .with_token_file("robot-01.token");
+//! if !client.is_paired() {
+//!     client.request_pairing().unwrap();
+//!     // The operator reads the code off GlobalUI
+//!     client.confirm_pairing("A1B2C3").unwrap();
+//! }
+//! client.connect(34.05, -118.24).unwrap();
+//! loop {
+//!     client.poll(|command| {
+//!         if command.cancel {
+//!             println!("stop {}", command.id);
+//!             return Vec::new();
+//!         }
+//!         println!("{} {}", command.command_type, command.payload);
+//!         vec![command.ack(), command.complete("completed")]
+//!     }).ok();
+//!     let telemetry = TelemetryMessage {
+//!         latitude: 34.05, longitude: -118.24, altitude: 0.0, heading: 0.0, speed: 0.0,
+//!         battery: 90.0, sensors: serde_json::Value::Null, ack: false,
+//!     };
+//!     client.send_telemetry(&telemetry).ok();
+//!     std::thread::sleep(std::time::Duration::from_secs(1));
+//! }
+//! ```
+//!
+//! Plain `ws://` and `http://` only. The WebSocket client underneath,
+//! `WsClient`, is usable on its own by a device that already has a token.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+
+use crate::protocol::{RegisterMessage, TelemetryMessage};
+
+/// How long `connect` waits for the server to answer `register`.
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First wait before reconnecting; it doubles up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// ============================================================================
+// WEBSOCKET CLIENT (minimal implementation)
+// ============================================================================
+
+pub struct WsClient {
+    stream: TcpStream,
+    /// Bytes read but not yet a whole frame.
+    buf: Vec<u8>,
+    /// A fragmented message so far.
+    partial: Vec<u8>,
+}
+
+impl WsClient {
+    pub fn connect(host: &str, port: u16) -> Result<Self, String> {
+        let mut stream = TcpStream::connect((host, port)).map_err(|e| e.to_string())?;
+        
+        // Generate random key
+        let key = base64::engine::general_purpose::STANDARD.encode(rand_bytes());
+        
+        // Send upgrade request
+        let request = format!(
+            "GET / HTTP/1.1\r\n\
+             Host: {}:{}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            host, port, key
+        );
+        stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+        
+        // Read the response head; anything after it is already frames
+        stream.set_read_timeout(Some(REGISTER_TIMEOUT)).map_err(|e| e.to_string())?;
+        let mut buf = Vec::new();
+        let head_end = loop {
+            if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            let mut chunk = [0u8; 1024];
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err("connection closed during upgrade".to_string()),
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(e) => return Err(e.to_string()),
+            }
+        };
+        
+        let response = String::from_utf8_lossy(&buf[..head_end]);
+        if response.split_whitespace().nth(1) != Some("101") {
+            return Err(format!("WebSocket upgrade failed: {}", response.lines().next().unwrap_or("")));
+        }
+        
+        stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        
+        Ok(Self { stream, buf: buf.split_off(head_end), partial: Vec::new() })
+    }
+    
+    pub fn send(&mut self, msg: &str) -> Result<(), String> {
+        self.send_frame(0x1, msg.as_bytes())
+    }
+    
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), String> {
+        let len = payload.len();
+        
+        let mut frame = Vec::with_capacity(len + 14);
+        
+        // Header: FIN + opcode
+        frame.push(0x80 | opcode);
+        
+        // Length + mask bit
+        if len < 126 {
+            frame.push(0x80 | len as u8);
+        } else if len <= u16::MAX as usize {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        
+        // Masking key
+        let mask = rand_bytes();
+        frame.extend_from_slice(&mask);
+        
+        // Masked payload
+        for (i, byte) in payload.iter().enumerate() {
+            frame.push(byte ^ mask[i % 4]);
+        }
+        
+        // The socket is non-blocking: wait out a full send buffer
+        let mut written = 0;
+        while written < frame.len() {
+            match self.stream.write(&frame[written..]) {
+                Ok(0) => return Err("connection closed".to_string()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+    
+    /// The next message, if one has arrived. Never blocks.
+    pub fn recv(&mut self) -> Option<String> {
+        self.try_recv().ok().flatten()
+    }
+    
+    /// The next message if one has arrived, None if not yet, or an error
+    /// once the connection is gone. Never blocks. Pings are answered.
+    pub fn try_recv(&mut self) -> Result<Option<String>, String> {
+        loop {
+            if let Some((fin, opcode, payload)) = self.take_frame() {
+                match opcode {
+                    // Text, binary, continuation
+                    0x0..=0x2 => {
+                        self.partial.extend_from_slice(&payload);
+                        if fin {
+                            let message = String::from_utf8_lossy(&self.partial).to_string();
+                            self.partial.clear();
+                            return Ok(Some(message));
+                        }
+                    }
+                    0x8 => return Err("connection closed by server".to_string()),
+                    0x9 => self.send_frame(0xA, &payload)?,
+                    _ => {}
+                }
+                continue;
+            }
+            
+            let mut chunk = [0u8; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err("connection closed".to_string()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => return Ok(None),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+    
+    /// Take one whole (unmasked) frame off the buffer: FIN, opcode and
+    /// payload.
+    fn take_frame(&mut self) -> Option<(bool, u8, Vec<u8>)> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return None;
+        }
+        let (len, header) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+            127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().ok()?) as usize, 10),
+            126 | 127 => return None,
+            len => (len as usize, 2),
+        };
+        if buf.len() < header + len {
+            return None;
+        }
+        let (fin, opcode) = (buf[0] & 0x80 != 0, buf[0] & 0x0F);
+        let payload = buf[header..header + len].to_vec();
+        self.buf.drain(..header + len);
+        Some((fin, opcode, payload))
+    }
+}
+
+fn rand_bytes() -> [u8; 4] {
+    let t = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    [
+        (t >> 24) as u8,
+        (t >> 16) as u8,
+        (t >> 8) as u8,
+        t as u8,
+    ]
+}
+
+// ============================================================================
+// COMMANDS
+// ============================================================================
+
+/// A command from the server, as handed to `poll`'s callback.
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub id: String,
+    pub command_type: String,
+    pub payload: serde_json::Value,
+    /// Its place in the device's command order; 0 if it has none.
+    pub seq: i64,
+    /// To be logged, not carried out, nor answered.
+    pub dry_run: bool,
+    /// Not a command but a `command:cancel` for command `id`, which the
+    /// server has marked cancelled: stop it if it's under way. Nothing is
+    /// answered.
+    pub cancel: bool,
+    /// The whole `data` of the `command` message.
+    pub data: serde_json::Value,
+}
+
+impl Command {
+    fn parse(data: serde_json::Value) -> Self {
+        let text = |key: &str| data.get(key).and_then(|v| v.as_str()).unwrap_or("").to_string();
+        Self {
+            id: text("commandId"),
+            command_type: text("type"),
+            payload: data.get("payload").cloned().unwrap_or_default(),
+            seq: data.get("seq").and_then(|v| v.as_i64()).unwrap_or(0),
+            dry_run: data.get("dryRun").and_then(|v| v.as_bool()).unwrap_or(false),
+            cancel: false,
+            data,
+        }
+    }
+    
+    /// The `command:ack` saying it arrived.
+    pub fn ack(&self) -> String {
+        serde_json::json!({
+            "type": "command:ack",
+            "data": { "commandId": self.id, "status": "received" }
+        }).to_string()
+    }
+    
+    /// The `command:complete` saying how it ended: `completed`, `failed`, ...
+    pub fn complete(&self, status: &str) -> String {
+        serde_json::json!({
+            "type": "command:complete",
+            "data": { "commandId": self.id, "status": status }
+        }).to_string()
+    }
+}
+
+// ============================================================================
+// DEVICE CLIENT
+// ============================================================================
+
+/// Where a pairing request stands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pairing {
+    /// Waiting for the operator's code: `confirm_pairing` with it.
+    Pending,
+    /// Approved without a code; the token is kept already.
+    Paired,
+}
+
+/// One device's connection to a server. See the module docs.
+pub struct DeviceClient {
+    host: String,
+    port: u16,
+    device_id: String,
+    device_type: String,
+    name: String,
+    token: Option<String>,
+    /// Where the token is kept between runs, if anywhere.
+    token_file: Option<PathBuf>,
+    ws: Option<WsClient>,
+    /// Messages that came in while `connect` waited for its answer.
+    inbox: VecDeque<String>,
+    /// Last position reported, to register with again after a reconnect.
+    position: (f64, f64),
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl DeviceClient {
+    pub fn new(host: &str, port: u16, device_id: &str, device_type: &str, name: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            device_id: device_id.to_string(),
+            device_type: device_type.to_string(),
+            name: name.to_string(),
+            token: None,
+            token_file: None,
+            ws: None,
+            inbox: VecDeque::new(),
+            position: (0.0, 0.0),
+            backoff: MIN_BACKOFF,
+            retry_at: None,
+        }
+    }
+    
+    /// Use a token from pairing done elsewhere, e.g. a bulk import.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+    
+    /// Keep the token in `path`: read now if it's there, written once paired.
+    pub fn with_token_file(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        if let Ok(token) = fs::read_to_string(&path) {
+            let token = token.trim();
+            if !token.is_empty() {
+                self.token = Some(token.to_string());
+            }
+        }
+        self.token_file = Some(path);
+        self
+    }
+    
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+    
+    pub fn is_paired(&self) -> bool {
+        self.token.is_some()
+    }
+    
+    pub fn is_connected(&self) -> bool {
+        self.ws.is_some()
+    }
+    
+    /// Ask to join. Usually the answer is `Pending` until the operator's
+    /// code is confirmed; an id the server trusts is `Paired` at once.
+    pub fn request_pairing(&mut self) -> Result<Pairing, String> {
+        let reply = self.post("/api/pair/request", &serde_json::json!({
+            "device_id": self.device_id,
+            "name": self.name,
+            "device_type": self.device_type,
+        }))?;
+        match reply.get("token").and_then(|t| t.as_str()) {
+            Some(token) => {
+                self.keep_token(token)?;
+                Ok(Pairing::Paired)
+            }
+            None => Ok(Pairing::Pending),
+        }
+    }
+    
+    /// Confirm pairing with the code GlobalUI shows, and keep the token.
+    pub fn confirm_pairing(&mut self, code: &str) -> Result<(), String> {
+        let reply = self.post("/api/pair/confirm", &serde_json::json!({
+            "device_id": self.device_id,
+            "code": code.trim(),
+        }))?;
+        let token = reply.get("token").and_then(|t| t.as_str()).ok_or("no token in the reply")?;
+        self.keep_token(token)
+    }
+    
+    /// Remember `token`, and write it to the token file, readable only by
+    /// its owner, if there is one.
+    fn keep_token(&mut self, token: &str) -> Result<(), String> {
+        if let Some(path) = &self.token_file {
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options.open(path)
+                .and_then(|mut file| file.write_all(token.as_bytes()))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        self.token = Some(token.to_string());
+        Ok(())
+    }
+    
+    /// Open the WebSocket and register at `latitude`, `longitude`, waiting
+    /// for the server to accept or refuse it.
+    pub fn connect(&mut self, latitude: f64, longitude: f64) -> Result<(), String> {
+        let token = self.token.clone().ok_or("not paired")?;
+        self.position = (latitude, longitude);
+        self.ws = None;
+        let mut ws = WsClient::connect(&self.host, self.port)?;
+        let register = RegisterMessage {
+            token: Some(token),
+            device_id: self.device_id.clone(),
+            device_type: self.device_type.clone(),
+            name: self.name.clone(),
+            latitude,
+            longitude,
+            altitude: 0.0,
+            capabilities: Vec::new(),
+        };
+        ws.send(&envelope("register", &register))?;
+        
+        let deadline = Instant::now() + REGISTER_TIMEOUT;
+        while Instant::now() < deadline {
+            let Some(message) = ws.try_recv()? else {
+                thread::sleep(Duration::from_millis(5));
+                continue;
+            };
+            let reply: serde_json::Value = serde_json::from_str(&message).unwrap_or_default();
+            match reply["type"].as_str() {
+                Some("registered") => {
+                    self.ws = Some(ws);
+                    self.backoff = MIN_BACKOFF;
+                    self.retry_at = None;
+                    return Ok(());
+                }
+                Some("error") => {
+                    let message = reply["data"]["message"].as_str().unwrap_or("registration failed");
+                    return Err(message.to_string());
+                }
+                _ => self.inbox.push_back(message),
+            }
+        }
+        Err("no answer to register".to_string())
+    }
+    
+    /// Send one telemetry record. A failed send drops the connection for
+    /// `poll` to restore.
+    pub fn send_telemetry(&mut self, telemetry: &TelemetryMessage) -> Result<(), String> {
+        self.position = (telemetry.latitude, telemetry.longitude);
+        self.send(&envelope("telemetry", telemetry))
+    }
+    
+    /// Send any message. A failed send drops the connection for `poll` to
+    /// restore.
+    pub fn send(&mut self, message: &str) -> Result<(), String> {
+        let ws = self.ws.as_mut().ok_or("not connected")?;
+        let sent = ws.send(message);
+        if sent.is_err() {
+            self.disconnected();
+        }
+        sent
+    }
+    
+    /// Handle what has arrived without waiting: each command, and each
+    /// cancel of one, goes to `on_command`, and the messages it returns are
+    /// sent back. Disconnected,
+    /// it first tries to reconnect, backing off between tries. Returns how
+    /// many commands were handled.
+    pub fn poll(&mut self, mut on_command: impl FnMut(&Command) -> Vec<String>) -> Result<usize, String> {
+        if self.ws.is_none() {
+            if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                return Err("not connected".to_string());
+            }
+            let (latitude, longitude) = self.position;
+            if let Err(e) = self.connect(latitude, longitude) {
+                self.disconnected();
+                return Err(e);
+            }
+        }
+        
+        let mut handled = 0;
+        loop {
+            let message = match self.inbox.pop_front() {
+                Some(message) => message,
+                None => match self.ws.as_mut().map(WsClient::try_recv) {
+                    Some(Ok(Some(message))) => message,
+                    Some(Ok(None)) | None => break,
+                    Some(Err(e)) => {
+                        self.disconnected();
+                        return Err(e);
+                    }
+                },
+            };
+            let Ok(mut envelope) = serde_json::from_str::<serde_json::Value>(&message) else { continue };
+            let cancel = envelope["type"] == "command:cancel";
+            if envelope["type"] != "command" && !cancel {
+                continue;
+            }
+            let command = Command { cancel, ..Command::parse(envelope["data"].take()) };
+            for reply in on_command(&command) {
+                self.send(&reply)?;
+            }
+            handled += 1;
+        }
+        Ok(handled)
+    }
+    
+    /// Drop the connection, and wait a little longer each time before the
+    /// next try.
+    fn disconnected(&mut self) {
+        self.ws = None;
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+    
+    /// POST JSON to the server's HTTP API; the JSON answer, or its error.
+    fn post(&self, path: &str, body: &serde_json::Value) -> Result<serde_json::Value, String> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(REGISTER_TIMEOUT)).map_err(|e| e.to_string())?;
+        let body = body.to_string();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path, self.host, self.port, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).map_err(|e| e.to_string())?;
+        
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response.split_once("\r\n\r\n").ok_or("malformed response")?;
+        let status: u16 = head.split_whitespace().nth(1).and_then(|s| s.parse().ok()).ok_or("malformed response")?;
+        let json: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+        if status != 200 {
+            let error = json.get("error").and_then(|e| e.as_str()).unwrap_or("request failed");
+            return Err(format!("{} ({})", error, status));
+        }
+        Ok(json)
+    }
+}
+
+fn envelope(msg_type: &str, data: &impl serde::Serialize) -> String {
+    serde_json::json!({"type": msg_type, "data": data}).to_string()
+}
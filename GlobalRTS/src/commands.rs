@@ -0,0 +1,284 @@
+//! # Command Validation
+//!
+//! Payload checks for commands before they are saved or dispatched.
+//!
+//! A malformed command (e.g. `navigate` without coordinates) is rejected
+//! here with a readable error instead of being silently ignored by the device.
+//!
+//! Validators are keyed by command type. The built-in types are checked by
+//! reading their payloads as `protocol::CommandPayload`. Unknown types pass
+//! through so devices can define their own commands; register a validator
+//! to check them.
+//!
+//! A command may also carry a precondition on the device's state, e.g.
+//! `battery > 30 AND status == online`, checked just before dispatch.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::protocol::{CommandPayload, DeviceInfo, DeviceStatus};
+
+/// Longest precondition, in bytes.
+pub const MAX_PRECONDITION: usize = 256;
+
+/// Checks a command payload. Returns a human-readable error on failure.
+pub type Validator = Box<dyn Fn(&Value) -> Result<(), String> + Send>;
+
+/// Validator table keyed by command type.
+pub struct CommandValidators {
+    validators: HashMap<String, Validator>,
+}
+
+impl CommandValidators {
+    /// Create a table with the built-in command types registered.
+    pub fn new() -> Self {
+        let mut table = Self { validators: HashMap::new() };
+        for command_type in CommandPayload::BUILT_IN {
+            table.register(command_type, Box::new(move |payload| {
+                CommandPayload::parse(command_type, payload)
+                    .map(|_| ())
+                    .map_err(|e| format!("invalid {} payload: {}", command_type, e))
+            }));
+        }
+        table
+    }
+
+    /// Add or replace the validator for a command type.
+    pub fn register(&mut self, command_type: &str, validator: Validator) {
+        self.validators.insert(command_type.to_string(), validator);
+    }
+
+    /// Validate a payload. Command types without a validator are accepted.
+    pub fn validate(&self, command_type: &str, payload: &Value) -> Result<(), String> {
+        if command_type.is_empty() {
+            return Err("command_type required".to_string());
+        }
+        match self.validators.get(command_type) {
+            Some(validator) => validator(payload),
+            None => Ok(()),
+        }
+    }
+}
+
+// ============================================================================
+// PRECONDITIONS
+// ============================================================================
+
+/// Device fields a precondition can test.
+const NUMERIC_FIELDS: [&str; 6] = ["battery", "speed", "altitude", "heading", "latitude", "longitude"];
+const TEXT_FIELDS: [&str; 1] = ["status"];
+
+/// Comparisons, longest first so `>=` isn't read as `>`.
+const OPERATORS: [&str; 6] = [">=", "<=", "==", "!=", ">", "<"];
+
+/// What a device must look like for a command to go out: `field op value`
+/// clauses joined by AND, e.g. `battery > 30 AND status == online`.
+/// Numeric fields take any comparison; `status` only `==` and `!=`.
+#[derive(Debug)]
+pub struct Precondition {
+    clauses: Vec<Clause>,
+}
+
+#[derive(Debug)]
+struct Clause {
+    field: &'static str,
+    op: &'static str,
+    value: String,
+}
+
+impl Precondition {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        if expr.len() > MAX_PRECONDITION {
+            return Err(format!("precondition is longer than {} bytes", MAX_PRECONDITION));
+        }
+        let mut clauses = Vec::new();
+        let mut words: Vec<&str> = Vec::new();
+        for word in expr.split_whitespace().chain(std::iter::once("AND")) {
+            if word.eq_ignore_ascii_case("and") || word == "&&" {
+                clauses.push(Clause::parse(&words.concat())?);
+                words.clear();
+            } else {
+                words.push(word);
+            }
+        }
+        Ok(Self { clauses })
+    }
+
+    /// Why `device` doesn't meet this; None if it does.
+    pub fn unmet(&self, device: &DeviceInfo) -> Option<String> {
+        self.clauses.iter().find(|c| !c.holds(device)).map(|c| {
+            format!("{} is {}, needs {} {}", c.field, c.actual(device), c.op, c.value)
+        })
+    }
+}
+
+impl Clause {
+    fn parse(clause: &str) -> Result<Self, String> {
+        if clause.is_empty() {
+            return Err("empty clause in precondition".to_string());
+        }
+        let (at, op) = OPERATORS.iter()
+            .filter_map(|op| clause.find(op).map(|at| (at, *op)))
+            .min_by_key(|(at, op)| (*at, std::cmp::Reverse(op.len())))
+            .ok_or_else(|| format!("{} has no comparison (==, !=, <, <=, >, >=)", clause))?;
+        let (field, value) = (&clause[..at], clause[at + op.len()..].trim_matches(|c| c == '"' || c == '\''));
+        if value.is_empty() {
+            return Err(format!("{} has no value", clause));
+        }
+        if let Some(field) = NUMERIC_FIELDS.iter().find(|f| **f == field) {
+            if !value.parse::<f64>().is_ok_and(f64::is_finite) {
+                return Err(format!("{} needs a number, not {}", field, value));
+            }
+            Ok(Self { field, op, value: value.to_string() })
+        } else if let Some(field) = TEXT_FIELDS.iter().find(|f| **f == field) {
+            if op != "==" && op != "!=" {
+                return Err(format!("{} can only be compared with == or !=", field));
+            }
+            // A typo would never match, and skip the command for good
+            value.parse::<DeviceStatus>()?;
+            Ok(Self { field, op, value: value.to_string() })
+        } else {
+            Err(format!("unknown field {:?} (known: {}, {})", field, NUMERIC_FIELDS.join(", "), TEXT_FIELDS.join(", ")))
+        }
+    }
+
+    fn actual(&self, device: &DeviceInfo) -> String {
+        match self.field {
+            "status" => device.status.as_str().to_string(),
+            field => number(device, field).to_string(),
+        }
+    }
+
+    fn holds(&self, device: &DeviceInfo) -> bool {
+        if self.field == "status" {
+            return (device.status.as_str() == self.value) == (self.op == "==");
+        }
+        let (actual, wanted) = (number(device, self.field), self.value.parse::<f64>().unwrap_or(f64::NAN));
+        match self.op {
+            ">=" => actual >= wanted,
+            "<=" => actual <= wanted,
+            "==" => actual == wanted,
+            "!=" => actual != wanted,
+            ">" => actual > wanted,
+            _ => actual < wanted,
+        }
+    }
+}
+
+fn number(device: &DeviceInfo, field: &str) -> f64 {
+    match field {
+        "battery" => device.battery,
+        "speed" => device.speed,
+        "altitude" => device.altitude,
+        "heading" => device.heading,
+        "latitude" => device.latitude,
+        _ => device.longitude,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn navigate_with_coordinates_in_range_passes() {
+        let validators = CommandValidators::new();
+        assert!(validators.validate("navigate", &json!({"latitude": 34.05, "longitude": -118.24})).is_ok());
+        assert!(validators.validate("navigate", &json!({"latitude": -90, "longitude": 180, "altitude": 120.0})).is_ok());
+    }
+
+    #[test]
+    fn navigate_without_or_outside_coordinates_fails() {
+        let validators = CommandValidators::new();
+        assert!(validators.validate("navigate", &json!({"latitude": 34.05})).is_err());
+        assert!(validators.validate("navigate", &json!({"latitude": "north", "longitude": 0})).is_err());
+        let err = validators.validate("navigate", &json!({"latitude": 91.0, "longitude": 0.0})).unwrap_err();
+        assert!(err.contains("latitude 91"), "{}", err);
+        let err = validators.validate("navigate", &json!({"latitude": 0.0, "longitude": -180.5})).unwrap_err();
+        assert!(err.contains("longitude -180.5"), "{}", err);
+    }
+
+    #[test]
+    fn built_in_payloads_read_as_their_types() {
+        use crate::protocol::{EmptyPayload, NavigatePayload};
+        let navigate = CommandPayload::parse("navigate", &json!({"latitude": 34.05, "longitude": -118.24, "altitude": 12.5})).unwrap();
+        assert_eq!(navigate, CommandPayload::Navigate(NavigatePayload { latitude: 34.05, longitude: -118.24, altitude: Some(12.5) }));
+        for (command_type, payload) in [("stop", json!({})), ("ring", Value::Null), ("photo", json!({})), ("poll", Value::Null)] {
+            let parsed = CommandPayload::parse(command_type, &payload).unwrap();
+            let expected = match command_type {
+                "stop" => CommandPayload::Stop(EmptyPayload {}),
+                "ring" => CommandPayload::Ring(EmptyPayload {}),
+                "photo" => CommandPayload::Photo(EmptyPayload {}),
+                _ => CommandPayload::Poll(EmptyPayload {}),
+            };
+            assert_eq!(parsed, expected, "{}", command_type);
+        }
+        let dance = json!({"moves": ["spin"]});
+        assert_eq!(CommandPayload::parse("dance", &dance).unwrap(), CommandPayload::Other(dance));
+    }
+
+    #[test]
+    fn malformed_built_in_payloads_are_refused() {
+        let validators = CommandValidators::new();
+        let err = validators.validate("navigate", &json!({"lattitude": 34.05, "latitude": 34.05, "longitude": 0})).unwrap_err();
+        assert!(err.contains("unknown field `lattitude`"), "{}", err);
+        let err = validators.validate("navigate", &json!({"latitude": 34.05, "longitude": 0, "altitude": "high"})).unwrap_err();
+        assert!(err.starts_with("invalid navigate payload"), "{}", err);
+        assert!(validators.validate("navigate", &json!([34.05, -118.24])).is_err());
+        assert!(validators.validate("stop", &json!({"now": true})).is_err());
+        assert!(validators.validate("ring", &json!("loud")).is_err());
+    }
+
+    #[test]
+    fn power_commands_must_be_confirmed() {
+        let validators = CommandValidators::new();
+        for command_type in CommandPayload::POWER {
+            for payload in [Value::Null, json!({}), json!({"confirm": false})] {
+                let err = validators.validate(command_type, &payload).unwrap_err();
+                assert!(err.contains(r#""confirm": true"#), "{}", err);
+            }
+            assert!(validators.validate(command_type, &json!({"confirm": "yes"})).is_err());
+            assert!(validators.validate(command_type, &json!({"confirm": true})).is_ok());
+        }
+    }
+
+    #[test]
+    fn unknown_types_pass_and_registered_ones_are_checked() {
+        let mut validators = CommandValidators::new();
+        assert!(validators.validate("dance", &json!({"moves": 3})).is_ok());
+        assert!(validators.validate("", &json!({})).is_err());
+        validators.register("dance", Box::new(|p| p.get("moves").map(|_| ()).ok_or_else(|| "moves required".to_string())));
+        assert!(validators.validate("dance", &json!({})).is_err());
+    }
+
+    fn device(battery: f64, status: &str) -> DeviceInfo {
+        serde_json::from_value(json!({
+            "id": "robot-01", "name": "Robot", "device_type": "robot", "status": status,
+            "latitude": 0.0, "longitude": 0.0, "altitude": 0.0, "heading": 0.0, "speed": 0.0,
+            "battery": battery, "last_seen": 0,
+        })).unwrap()
+    }
+
+    #[test]
+    fn preconditions_are_and_combined() {
+        let precondition = Precondition::parse("battery > 30 AND status == online").unwrap();
+        assert_eq!(precondition.unmet(&device(31.0, "online")), None);
+        assert_eq!(precondition.unmet(&device(30.0, "online")).unwrap(), "battery is 30, needs > 30");
+        assert_eq!(precondition.unmet(&device(80.0, "offline")).unwrap(), "status is offline, needs == online");
+
+        // Spacing and case are loose
+        let precondition = Precondition::parse("battery>=30 and status!='revoked'").unwrap();
+        assert_eq!(precondition.unmet(&device(30.0, "offline")), None);
+        assert!(precondition.unmet(&device(30.0, "revoked")).is_some());
+    }
+
+    #[test]
+    fn malformed_preconditions_are_refused() {
+        for expr in ["", "battery", "battery > lots", "fuel > 3", "status > online", "status == onlin", "battery > 30 AND", "battery >"] {
+            assert!(Precondition::parse(expr).is_err(), "{}", expr);
+        }
+        assert!(Precondition::parse(&format!("battery > {}", "9".repeat(MAX_PRECONDITION))).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! # Demo Mode
+//!
+//! Simulated devices that run inside the server, so an evaluation or a
+//! UI session has a fleet on the map without a simulator process per
+//! device.
+//!
+//! Each is provisioned like an imported device (`demo-01`, `demo-02`, ...,
+//! with a token and no pairing code) and then treated like any other: it
+//! connects to the server's own port, registers, streams telemetry every
+//! second and answers commands, all through the WebSocket path a real
+//! device takes. When nobody has sent it anywhere it wanders about.
+//! Restarting reuses the devices and tokens from last time.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::client::WsClient;
+use crate::sim::{self, DeviceState};
+use crate::state::{DeviceImport, StateDb};
+use crate::trace::log;
+
+/// Most demo devices one server runs: each is a thread and a connection.
+pub const MAX_DEVICES: u64 = 1000;
+
+/// How often each demo device moves and reports.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Demo device types, taken in turn.
+const TYPES: [&str; 3] = ["robot", "phone", "drone"];
+
+/// One demo device, ready to connect.
+struct DemoDevice {
+    id: String,
+    device_type: &'static str,
+    name: String,
+    token: String,
+}
+
+/// Provision `count` demo devices and start each on its own thread,
+/// connecting to the server at `addr`, a loopback address. They stop once
+/// `running` is cleared; join the returned threads to wait for them.
+pub fn start(db: &StateDb, addr: SocketAddr, count: u64, running: Arc<AtomicBool>) -> Result<Vec<thread::JoinHandle<()>>, String> {
+    if count > MAX_DEVICES {
+        return Err(format!("at most {} demo devices", MAX_DEVICES));
+    }
+    let devices = provision(db, count)?;
+    Ok(devices.into_iter().map(|device| {
+        let running = Arc::clone(&running);
+        thread::spawn(move || run_device(addr, device, &running))
+    }).collect())
+}
+
+/// The demo devices' registry rows and tokens: the ones already there
+/// from an earlier run, and new ones imported for the rest.
+fn provision(db: &StateDb, count: u64) -> Result<Vec<DemoDevice>, String> {
+    let existing = db.export_devices()?;
+    let mut devices = Vec::new();
+    let mut missing = Vec::new();
+    for n in 1..=count {
+        let device_type = TYPES[(n as usize - 1) % TYPES.len()];
+        let id = format!("demo-{:02}", n);
+        let name = format!("Demo {}{} {}", device_type[..1].to_uppercase(), &device_type[1..], n);
+        match existing.iter().find(|record| record.id == id) {
+            Some(record) => {
+                let token = record.token.clone().ok_or_else(|| format!("{} has no token", id))?;
+                devices.push(DemoDevice { id, device_type, name, token });
+            }
+            None => missing.push(DemoDevice { id, device_type, name, token: String::new() }),
+        }
+    }
+    
+    let imports: Vec<DeviceImport> = missing.iter()
+        .map(|device| DeviceImport {
+            id: device.id.clone(),
+            name: device.name.clone(),
+            device_type: device.device_type.to_string(),
+            token: None,
+            signed: false,
+        })
+        .collect();
+    let tokens = db.import_devices(&imports)?;
+    for (mut device, token) in missing.into_iter().zip(tokens) {
+        device.token = token.map_err(|e| format!("{}: {}", device.id, e))?;
+        devices.push(device);
+    }
+    devices.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(devices)
+}
+
+/// Connect, register and report every tick until the server stops.
+fn run_device(addr: SocketAddr, device: DemoDevice, running: &AtomicBool) {
+    let mut ws = match WsClient::connect(&addr.ip().to_string(), addr.port()) {
+        Ok(ws) => ws,
+        Err(e) => {
+            log!("✗ Demo device {} couldn't connect: {}", device.id, e);
+            return;
+        }
+    };
+    let mut state = DeviceState::new();
+    state.quiet = true;
+    let register = sim::register_message(&state, &device.id, device.device_type, &device.name, Some(&device.token));
+    if ws.send(&register).is_err() {
+        return;
+    }
+    
+    while running.load(Ordering::SeqCst) {
+        while let Some(msg) = ws.recv() {
+            for reply in sim::handle_message(&mut state, &msg) {
+                let _ = ws.send(&reply);
+            }
+        }
+        if state.status == "off" {
+            return;
+        }
+        state.wander();
+        state.update();
+        if ws.send(&sim::telemetry_message(&state)).is_err() {
+            return;
+        }
+        thread::sleep(TICK);
+    }
+}
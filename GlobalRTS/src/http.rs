@@ -4,12 +4,51 @@
 //! 
 //! ENDPOINTS:
 //! - GET  /                         → GlobalUI (static HTML)
+//! - GET  /favicon.ico              → Bundled icon (unless public/ has one)
 //! - GET  /api/pair/requests        → List pending pairing requests
 //! - POST /api/pair/request         → Device requests to join
 //! - POST /api/pair/confirm         → Device confirms with 6-digit code
 //! - DELETE /api/pair/{id}          → Dismiss/reject pairing request
-//! - GET  /api/devices              → List all paired devices
+//! - GET  /api/devices              → List all paired devices (?sensor=&op=&value=), ETag'd
+//! - GET  /api/devices.csv          → The registry as a spreadsheet (CSV download)
+//! - GET  /api/devices/connected    → Devices with a live WebSocket right now
+//! - GET  /api/devices/near         → Devices within a radius, nearest first (?lat=&lon=&radius=)
 //! - DELETE /api/devices/{id}       → Revoke device
+//! - POST /api/devices/import       → Provision devices with tokens (admin)
+//! - GET  /api/devices/export       → The whole device registry, tokens included (admin)
+//! - POST /api/devices/restore      → Put an exported registry back (?conflict=merge|replace) (admin)
+//! - POST /api/commands             → Send one command to several devices (admin)
+//! - POST /api/commands/{id}/cancel → Call off a command that hasn't ended (admin)
+//! - GET  /api/devices/{id}/stats   → Telemetry summary (?start=&end=)
+//! - GET  /api/devices/{id}/commands/stream → Live command statuses for one device (SSE)
+//! - PATCH /api/devices/{id}/appearance → Choose a device's color and icon
+//! - PATCH /api/devices/{id}/retention → Keep a device's telemetry longer or shorter (admin)
+//! - POST /api/devices/{id}/lease   → Take or extend the command lease on a device
+//! - DELETE /api/devices/{id}/lease → Give the lease up (the admin token ends anyone's)
+//! - POST /api/devices/{id}/tokens  → Issue a read or telemetry token (device's own token)
+//! - DELETE /api/devices/{id}/tokens → Revoke a device's scoped tokens (device or admin)
+//! - GET  /api/leases               → Leases in force
+//! - GET  /api/telemetry           → Several devices' records at once (?device_ids=&start=&end=&limit=&fields=)
+//! - GET  /api/telemetry/{id}.ndjson.gz → Gzipped telemetry download (?fields=)
+//! - GET  /api/telemetry/{id}/recent → A device's last N records (?n=, ?fields=)
+//! - GET  /api/telemetry/{id}/files → A device's day files, with sizes and line counts
+//! - POST /api/telemetry/{id}       → Upload a device's records (device or telemetry token)
+//! - POST /api/telemetry/{id}/replay → Play telemetry back to UIs (admin)
+//! - DELETE /api/telemetry/{id}/replay → Cancel a replay (admin)
+//! - GET  /api/maintenance          → Where command dispatch is paused
+//! - POST /api/maintenance          → Pause or resume dispatch, fleet or device (admin)
+//! - GET  /api/version              → Build and protocol version
+//! - GET  /api/time                 → The server's clock, seconds and milliseconds
+//! - GET  /api/whoami               → The caller's role: admin, viewer or operator
+//! - GET  /api/connections          → Live WebSocket connections and frame stats (admin)
+//! - GET  /api/stats                → Fleet summary counts, ETag'd
+//! - GET  /api/metrics              → Command queue depth gauges (Prometheus text)
+//! - GET  /api/heatmap              → Visit counts per lat/lon cell (?start=&end=&cell=)
+//! - GET  /api/audit                → Audit log (?action=&since=&limit=) (admin)
+//! - GET  /api/alerts               → Alert history (?device_id=&since=&unacknowledged=&limit=)
+//! - POST /api/alerts/{id}/ack      → Acknowledge an alert (admin)
+//! - GET  /api/prefs                → Get UI layout preferences
+//! - PUT  /api/prefs                → Store UI layout preferences
 //! - GET  /api/oura/*               → Proxy to Oura Ring API (any path)
 //! 
 //! WHY FROM SCRATCH:
@@ -17,13 +56,106 @@
 //! - Static file serving + simple REST is trivial
 //! - No dependency that can break
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::{Read, Write, BufRead, BufReader};
-use std::net::TcpStream;
+use std::io::{Read, Write};
 use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::state::StateDb;
+use sha1::{Sha1, Digest};
+
+use crate::appearance;
+use crate::gzip::GzipEncoder;
+use crate::protocol::{DeviceInfo, SendCommand, TelemetryMessage};
+use crate::replay;
+use crate::server::{self, Cancelled, Server};
+use crate::state::{self, Alert, DeviceImport, DeviceRecord, Lease, RestoreConflict, ScopedToken, SensorOp, StateDb, TokenScope};
+use crate::signing;
+use crate::telemetry::{self, TelemetryReader, TelemetryRecord, TelemetryStats};
+use crate::tls::Stream;
+use crate::trace::{self, log};
+use crate::version;
+
+/// Maximum size of a stored UI preferences blob.
+const MAX_PREFS_BYTES: usize = 16 * 1024;
+
+/// Most devices one `GET /api/telemetry` may ask for.
+const MAX_QUERY_DEVICES: usize = 100;
+
+/// Records per device `GET /api/telemetry` gives without a `limit`.
+const DEFAULT_QUERY_LIMIT: usize = 1000;
+
+/// Most records one telemetry upload may carry.
+const MAX_UPLOAD_RECORDS: usize = 10_000;
+
+/// Maximum size of a device import body. Imports opt out of the server's
+/// general body cap for this one: a fleet's worth of rows is big. A
+/// registry restore gets the same allowance.
+const MAX_IMPORT_BYTES: usize = 16 * 1024 * 1024;
+
+/// Layout of `GET /api/devices/export`. A restore refuses any other.
+const REGISTRY_EXPORT_VERSION: u64 = 1;
+
+/// Default /api/heatmap cell, in degrees: about 110 m of latitude.
+const DEFAULT_HEATMAP_CELL: f64 = 0.001;
+
+/// Coarsest /api/heatmap cell, in degrees.
+const MAX_HEATMAP_CELL: f64 = 10.0;
+
+/// How often an idle command stream sends a comment, so proxies and the
+/// client's own timeouts don't take it for dead.
+const STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// How long a write to a command stream may block before the reader is
+/// given up on.
+const STREAM_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a command lease lasts when the request doesn't say.
+const DEFAULT_LEASE_SECS: i64 = 300;
+
+/// Longest command lease, in seconds. Holding on means asking again.
+const MAX_LEASE_SECS: i64 = 3600;
+
+/// Audit entries /api/audit returns when the request doesn't say.
+const DEFAULT_AUDIT_ENTRIES: usize = 100;
+
+/// Most audit entries one /api/audit call returns.
+const MAX_AUDIT_ENTRIES: usize = 1000;
+
+/// Alerts /api/alerts returns when the request doesn't say.
+const DEFAULT_ALERTS: usize = 100;
+
+/// Most alerts one /api/alerts call returns.
+const MAX_ALERTS: usize = 1000;
+
+/// Records /api/telemetry/{id}/recent returns when the request doesn't say.
+const DEFAULT_RECENT: usize = 100;
+
+/// Most records one /api/telemetry/{id}/recent call returns.
+const MAX_RECENT: usize = 10_000;
+
+/// Longest telemetry retention a device may be given, in days: a century.
+const MAX_RETENTION_DAYS: u64 = 36_500;
+
+/// Longest name an alert may be acknowledged by.
+const MAX_ACKNOWLEDGED_BY: usize = 64;
+
+/// Most devices one group command may target.
+const MAX_GROUP_DEVICES: usize = 1000;
+
+/// On every response: any origin may read what the server serves.
+const CORS_ALLOW_ORIGIN: &str = "Access-Control-Allow-Origin: *\r\n";
+
+/// Methods the API answers, for preflights and API responses.
+const API_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// Request headers a preflight allows when it doesn't name any.
+const ALLOWED_HEADERS: &str = "Content-Type, Authorization, X-Request-Id";
+
+/// Served for /favicon.ico when the public dir doesn't have one.
+const FAVICON: &[u8] = include_bytes!("../assets/favicon.ico");
 
 /// Oura API token - can be overridden via OURA_TOKEN env var
 fn get_oura_token() -> String {
@@ -79,14 +211,289 @@ fn urlencoded_decode(s: &str) -> String {
     result
 }
 
-/// Read HTTP request body
-fn read_body(stream: &mut TcpStream, headers: &str) -> Option<String> {
-    let content_length: usize = headers
+/// Find a header value (case-insensitive name).
+pub fn header_value<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim().eq_ignore_ascii_case(name) { Some(value.trim()) } else { None }
+        })
+}
+
+/// Every X-Forwarded-For header, joined in order: proxies may add a line
+/// each rather than extend one.
+pub fn forwarded_for(request: &str) -> Option<String> {
+    let hops: Vec<&str> = request
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case("x-forwarded-for").then(|| value.trim())
+        })
+        .collect();
+    (!hops.is_empty()).then(|| hops.join(","))
+}
+
+/// Identify the UI operator by a hash of their bearer token.
+/// Requests without a token share the "anonymous" identity.
+fn ui_identity(request: &str) -> String {
+    identity_of(header_value(request, "authorization").and_then(|v| v.strip_prefix("Bearer ")))
+}
+
+/// The operator behind a WebSocket upgrade, as `ui_identity` would have
+/// it. As with `ws_role`, the token may come as `?token=` instead.
+pub(crate) fn ws_identity(request: &str) -> String {
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let query = path.split_once('?').map(|(_, q)| q).unwrap_or("");
+    let from_query = parse_query_string(query).remove("token");
+    let bearer = header_value(request, "authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .filter(|t| !t.trim().is_empty());
+    identity_of(bearer.or(from_query.as_deref()))
+}
+
+/// `ui_identity`, for what's kept per operator: without a token there's no
+/// telling operators apart, so the request is refused.
+fn operator_identity(request: &str) -> Result<String, (u16, &'static str)> {
+    match ui_identity(request) {
+        identity if identity == "anonymous" => Err((401, "A bearer token is required to tell operators apart")),
+        identity => Ok(identity),
+    }
+}
+
+/// A token's identity: the hex SHA-1 of it, or "anonymous" for none.
+fn identity_of(token: Option<&str>) -> String {
+    match token.map(str::trim) {
+        Some(token) if !token.is_empty() => {
+            let hash = Sha1::digest(token.as_bytes());
+            hash.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+        _ => "anonymous".to_string(),
+    }
+}
+
+/// Whether the request carries the admin token (GLOBALRTS_ADMIN_TOKEN).
+/// Err with status and message if not, or if no admin token is configured.
+fn check_admin(request: &str, server: &Arc<Mutex<Server>>) -> Result<(), (u16, &'static str)> {
+    let Some(admin) = server::role_tokens(server).0 else {
+        return Err((403, "Admin endpoints are disabled. Set GLOBALRTS_ADMIN_TOKEN to enable them."));
+    };
+    match header_value(request, "authorization").and_then(|v| v.strip_prefix("Bearer ")) {
+        Some(token) if signing::constant_time_eq(token.trim().as_bytes(), admin.as_bytes()) => Ok(()),
+        _ => Err((401, "Admin token required")),
+    }
+}
+
+/// What a caller may do, by the token it presents.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Role {
+    /// The admin token (GLOBALRTS_ADMIN_TOKEN): everything.
+    Admin,
+    /// The viewer token (GLOBALRTS_VIEWER_TOKEN): reads and watches only.
+    /// So is any other token, or none, while there is a viewer token, or
+    /// leaving it off or making one up would get round it.
+    Viewer,
+    /// Any token but the admin one, or none, while there's no viewer token:
+    /// what the UI has always been allowed.
+    Operator,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+        }
+    }
+
+    /// The role of `token`. An unset admin token matches nothing.
+    fn of(token: Option<&str>, server: &Arc<Mutex<Server>>) -> Role {
+        let token = token.map(str::trim).unwrap_or_default();
+        let (admin, viewer) = server::role_tokens(server);
+        let is_admin = !token.is_empty()
+            && admin.as_deref().is_some_and(|admin| signing::constant_time_eq(admin.as_bytes(), token.as_bytes()));
+        if is_admin {
+            Role::Admin
+        } else if viewer.is_some() {
+            Role::Viewer
+        } else {
+            Role::Operator
+        }
+    }
+}
+
+/// The request's bearer token, if it has one.
+fn bearer_token(request: &str) -> Option<&str> {
+    header_value(request, "authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+}
+
+/// Whether a device's scoped token may make this request. Whatever its
+/// scope, it only reaches its own device's routes; beyond those it may
+/// ask the version, the time and who it is.
+fn scope_allows(scoped: &ScopedToken, method: &str, path: &str) -> bool {
+    if method == "GET" && matches!(path, "/api/version" | "/api/time" | "/api/whoami") {
+        return true;
+    }
+    let device_id = scoped.device_id.as_str();
+    match scoped.scope {
+        TokenScope::Read => method == "GET" && (
+            device_route(path, "/api/devices/", device_id).is_some_and(|rest| matches!(rest, "/stats" | "/commands/stream"))
+                || device_route(path, "/api/telemetry/", device_id).is_some_and(|rest| matches!(rest, "/recent" | "/files" | ".ndjson.gz"))
+        ),
+        TokenScope::Telemetry => method == "POST" && device_route(path, "/api/telemetry/", device_id) == Some(""),
+    }
+}
+
+/// What follows `prefix` and then `device_id` in `path`, if it names that
+/// device. `robot-1` doesn't name `robot-10`: the rest would be `0...`.
+fn device_route<'a>(path: &'a str, prefix: &str, device_id: &str) -> Option<&'a str> {
+    path.strip_prefix(prefix)?.strip_prefix(device_id)
+}
+
+/// Whether the request's bearer token is `device_id`'s registration token.
+fn is_device_token(db: &StateDb, request: &str, device_id: &str) -> Result<bool, String> {
+    match bearer_token(request) {
+        Some(token) => Ok(db.validate_token(token)?.as_deref() == Some(device_id)),
+        None => Ok(false),
+    }
+}
+
+/// Whether the request's bearer token is some device's, registration or
+/// scoped.
+fn is_any_device_token(db: &StateDb, request: &str) -> Result<bool, String> {
+    match bearer_token(request) {
+        Some(token) => Ok(db.validate_token(token)?.is_some() || db.scoped_token(token)?.is_some()),
+        None => Ok(false),
+    }
+}
+
+/// The role of an HTTP request's bearer token.
+fn request_role(request: &str, server: &Arc<Mutex<Server>>) -> Role {
+    Role::of(header_value(request, "authorization").and_then(|v| v.strip_prefix("Bearer ")), server)
+}
+
+/// The role of a WebSocket upgrade. A browser can't set headers on a
+/// WebSocket, so the token may come as `?token=` instead.
+pub(crate) fn ws_role(request: &str, server: &Arc<Mutex<Server>>) -> Role {
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let query = path.split_once('?').map(|(_, q)| q).unwrap_or("");
+    let token = bearer_token(request).map(str::to_string)
+        .or_else(|| parse_query_string(query).remove("token"));
+    Role::of(token.as_deref(), server)
+}
+
+/// Write each event from `events` to `stream` as a server-sent event until
+/// either end goes away, with a comment every `STREAM_KEEPALIVE` between.
+fn stream_command_events(stream: &mut Stream, events: &Receiver<String>) {
+    let _ = stream.set_write_timeout(Some(STREAM_WRITE_TIMEOUT));
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n{}Connection: close\r\n\r\n: command statuses\n\n",
+        response_headers()
+    );
+    if stream.write_all(head.as_bytes()).is_err() {
+        return;
+    }
+    loop {
+        let chunk = match events.recv_timeout(STREAM_KEEPALIVE) {
+            Ok(data) => format!("event: command:status\ndata: {}\n\n", data),
+            Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if stream.write_all(chunk.as_bytes()).and_then(|_| stream.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/// A lease as the HTTP API shows it.
+fn lease_json(lease: &Lease) -> serde_json::Value {
+    serde_json::json!({
+        "device_id": lease.device_id,
+        "holder": lease.holder,
+        "acquired_at": lease.acquired_at,
+        "expires_at": lease.expires_at,
+    })
+}
+
+/// An alert as the HTTP API shows it.
+fn alert_json(alert: &Alert) -> serde_json::Value {
+    serde_json::json!({
+        "id": alert.id,
+        "device_id": alert.device_id,
+        "severity": alert.severity,
+        "code": alert.code,
+        "message": alert.message,
+        "created_at": alert.created_at,
+        "acknowledged_at": alert.acknowledged_at,
+        "acknowledged_by": alert.acknowledged_by
+    })
+}
+
+/// The response of a bulk endpoint: one result per item, in request order,
+/// and how many went each way. An item that succeeded is `"ok"` plus the
+/// fields of its `Ok` object; one that failed is `"error"` with the reason.
+fn bulk_json(items: Vec<(String, Result<serde_json::Value, String>)>) -> serde_json::Value {
+    let failed = items.iter().filter(|(_, result)| result.is_err()).count();
+    let ok = items.len() - failed;
+    let results: Vec<serde_json::Value> = items.into_iter().map(|(id, result)| {
+        let mut item = serde_json::json!({"id": id});
+        match result {
+            Ok(serde_json::Value::Object(fields)) => {
+                item["status"] = serde_json::json!("ok");
+                item.as_object_mut().unwrap().extend(fields);
+            }
+            Ok(_) => item["status"] = serde_json::json!("ok"),
+            Err(e) => {
+                item["status"] = serde_json::json!("error");
+                item["error"] = serde_json::json!(e);
+            }
+        }
+        item
+    }).collect();
+    serde_json::json!({"results": results, "ok": ok, "failed": failed})
+}
+
+/// One row of an import body. Rows that aren't objects get an empty id,
+/// which the import refuses like any other bad id.
+fn parse_import_row(row: &serde_json::Value) -> DeviceImport {
+    let field = |names: &[&str]| names.iter().find_map(|n| row.get(*n).and_then(|v| v.as_str()));
+    let id = field(&["id", "device_id"]).unwrap_or("").to_string();
+    DeviceImport {
+        name: field(&["name"]).unwrap_or(&id).to_string(),
+        device_type: field(&["type", "device_type"]).unwrap_or("unknown").to_string(),
+        token: field(&["token"]).map(str::to_string),
+        signed: row.get("signed").and_then(|v| v.as_bool()).unwrap_or(false),
+        id,
+    }
+}
+
+/// The request's declared Content-Length (0 when absent).
+fn content_length(headers: &str) -> u64 {
+    headers
         .lines()
         .find(|line| line.to_lowercase().starts_with("content-length:"))
         .and_then(|line| line.split(':').nth(1))
         .and_then(|len| len.trim().parse().ok())
-        .unwrap_or(0);
+        .unwrap_or(0)
+}
+
+/// Largest body a request to `path` may declare, bytes. 0 = no cap.
+fn body_limit(path: &str, max_body: u64) -> u64 {
+    match path {
+        "/api/devices/import" | "/api/devices/restore" => MAX_IMPORT_BYTES as u64,
+        _ => max_body,
+    }
+}
+
+/// Read HTTP request body
+fn read_body(stream: &mut Stream, headers: &str) -> Option<String> {
+    let content_length = content_length(headers) as usize;
     
     if content_length == 0 {
         return None;
@@ -117,9 +524,10 @@ fn read_body(stream: &mut TcpStream, headers: &str) -> Option<String> {
     }
 }
 
-/// Handle an HTTP request.
+/// Handle an HTTP request. Static files are looked up in `static_dirs` in
+/// order; the first that has the file serves it.
 /// Returns true if handled, false if WebSocket upgrade needed.
-pub fn handle_request(stream: &mut TcpStream, request: &str, public_dir: &str) -> bool {
+pub fn handle_request(stream: &mut Stream, request: &str, static_dirs: &[String], server: &Arc<Mutex<Server>>) -> bool {
     if request.contains("Upgrade: websocket") || request.contains("upgrade: websocket") {
         return false;
     }
@@ -135,18 +543,23 @@ pub fn handle_request(stream: &mut TcpStream, request: &str, public_dir: &str) -
     let method = parts[0];
     let full_path = parts[1];
     let (path, query) = full_path.split_once('?').unwrap_or((full_path, ""));
-    let query_params = parse_query_string(query);
+    
+    // Preflights get the same answer on every route, API or static
+    if method == "OPTIONS" {
+        send_cors_preflight(stream, path, request);
+        return true;
+    }
     
     // Route API calls
     if path.starts_with("/api/") {
-        let db = match StateDb::open("data/state.db") {
+        let db = match server::state_db(server) {
             Ok(db) => db,
             Err(e) => {
                 send_json_error(stream, 500, &format!("Database error: {}", e));
                 return true;
             }
         };
-        handle_api(stream, method, path, query, &query_params, request, &db);
+        handle_api(stream, method, path, query, request, &db, server);
         return true;
     }
     
@@ -157,45 +570,121 @@ pub fn handle_request(stream: &mut TcpStream, request: &str, public_dir: &str) -
     
     let path = if path == "/" { "/globalui.html" } else { path };
     let path = path.replace("..", "");
-    let file_path = format!("{}{}", public_dir, path);
-    let file_path = Path::new(&file_path);
-    
-    if !file_path.starts_with(public_dir) {
-        send_error(stream, 403, "Forbidden");
-        return true;
-    }
     
-    match fs::read(&file_path) {
-        Ok(content) => {
-            let mime = mime_type(&path);
-            let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
-                mime, content.len()
-            );
-            let _ = stream.write_all(response.as_bytes());
-            let _ = stream.write_all(&content);
-        }
-        Err(_) => send_error(stream, 404, "Not Found"),
+    match read_static(static_dirs, &path, accepts_gzip(request)) {
+        Ok(Some(file)) => {
+            let headers = match (file.gzipped, file.varies) {
+                (true, _) => "Content-Encoding: gzip\r\nVary: Accept-Encoding\r\n",
+                (false, true) => "Vary: Accept-Encoding\r\n",
+                (false, false) => "",
+            };
+            send_file_with_headers(stream, mime_type(&path), &file.content, headers);
+        }
+        Ok(None) if path == "/favicon.ico" => send_file(stream, mime_type(&path), FAVICON),
+        Ok(None) => send_not_found(stream),
+        Err(()) => send_error(stream, 403, "Forbidden"),
     }
     
     true
 }
 
+/// A static file as read for one request.
+struct StaticFile {
+    content: Vec<u8>,
+    /// Its `.gz` sidecar was read in its place, to go out as Content-Encoding: gzip.
+    gzipped: bool,
+    /// It has a sidecar, so what's sent depends on Accept-Encoding.
+    varies: bool,
+}
+
+/// Read `path` from the first static dir that has it. Where the build left
+/// a `.gz` sidecar beside it, at least as new, and the client takes gzip,
+/// the sidecar is read instead: nothing is compressed per request. Err if
+/// the path escapes a dir (checked for every dir searched).
+fn read_static(static_dirs: &[String], path: &str, gzip: bool) -> Result<Option<StaticFile>, ()> {
+    for dir in static_dirs {
+        let file_path = format!("{}{}", dir, path);
+        let file_path = Path::new(&file_path);
+        
+        if !file_path.starts_with(dir) {
+            return Err(());
+        }
+        
+        let Ok(modified) = fs::metadata(file_path).and_then(|m| m.modified()) else {
+            continue;
+        };
+        // An older sidecar is left over from a previous build
+        let sidecar = format!("{}{}.gz", dir, path);
+        let varies = fs::metadata(&sidecar).and_then(|m| m.modified()).is_ok_and(|gz| gz >= modified);
+        if gzip && varies {
+            if let Ok(content) = fs::read(&sidecar) {
+                return Ok(Some(StaticFile { content, gzipped: true, varies }));
+            }
+        }
+        if let Ok(content) = fs::read(file_path) {
+            return Ok(Some(StaticFile { content, gzipped: false, varies }));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the request's Accept-Encoding takes gzip: named, or `*`, and
+/// not with `q=0`.
+fn accepts_gzip(request: &str) -> bool {
+    let Some(accepted) = header_value(request, "Accept-Encoding") else {
+        return false;
+    };
+    accepted.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f64>().ok()) == Some(0.0));
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
 /// Handle API requests
 fn handle_api(
-    stream: &mut TcpStream, 
+    stream: &mut Stream, 
     method: &str, 
     path: &str, 
     query: &str,
-    query_params: &HashMap<String, String>,
     request: &str,
     db: &StateDb,
+    server: &Arc<Mutex<Server>>,
 ) {
-    if method == "OPTIONS" {
-        send_cors_preflight(stream);
+    // Refused on the declared length, before any of it is read
+    let limit = body_limit(path, server::max_body(server));
+    if limit > 0 && content_length(request) > limit {
+        send_json_error(stream, 413, &format!("Body exceeds {} bytes", limit));
         return;
     }
     
+    // A viewer reads. Its own layout aside, anything that changes state is
+    // refused; a device pairing has no token yet, so that goes through, and
+    // a device's own token is checked by the route it calls.
+    let viewer_may = method == "GET" || matches!(path, "/api/prefs" | "/api/pair/request" | "/api/pair/confirm");
+    if request_role(request, server) == Role::Viewer && !viewer_may {
+        match is_any_device_token(db, request) {
+            Ok(true) => {}
+            Ok(false) => { send_json_error(stream, 403, "Viewer token is read-only"); return; }
+            Err(e) => { send_json_error(stream, 500, &e); return; }
+        }
+    }
+    
+    // A device's scoped token is held to its scope
+    if let Some(token) = bearer_token(request) {
+        match db.scoped_token(token) {
+            Ok(Some(scoped)) if !scope_allows(&scoped, method, path) => {
+                send_json_error(stream, 403, &format!("Token is scoped to {} for {}", scoped.scope.as_str(), scoped.device_id));
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => { send_json_error(stream, 500, &e); return; }
+        }
+    }
+    
+    let query_params = parse_query_string(query);
+    
     match (method, path) {
         // Pairing requests list
         ("GET", "/api/pair/requests") => {
@@ -208,7 +697,8 @@ fn handle_api(
                             "device_type": r.device_type,
                             "code": r.code,
                             "expires_at": r.expires_at,
-                            "created_at": r.created_at
+                            "created_at": r.created_at,
+                            "seconds_remaining": r.seconds_remaining
                         })
                     }).collect();
                     send_json(stream, 200, &serde_json::json!({"requests": json}));
@@ -232,15 +722,57 @@ fn handle_api(
             let device_id = data.get("device_id").and_then(|v| v.as_str()).unwrap_or("");
             let name = data.get("name").and_then(|v| v.as_str()).unwrap_or("Unknown Device");
             let device_type = data.get("device_type").and_then(|v| v.as_str()).unwrap_or("unknown");
+            let signed = data.get("signed").and_then(|v| v.as_bool()).unwrap_or(false);
             
             if device_id.is_empty() {
                 send_json_error(stream, 400, "device_id required");
                 return;
             }
             
-            match db.create_pairing_request(device_id, name, device_type) {
+            let cooldown = server::pair_cooldown(server) as i64;
+            if cooldown > 0 {
+                if let Ok(Some(age)) = db.pairing_request_age(device_id) {
+                    let wait = cooldown - age;
+                    if wait > 0 {
+                        send_json_retry_after(stream, 429, &format!("Pairing was requested {}s ago; wait {}s", age, wait), wait as u64);
+                        return;
+                    }
+                }
+            }
+            
+            // A trusted id pairs at once, with the code nobody has to type.
+            // Only new devices: re-pairing issues a new token, which must
+            // still take an operator.
+            let auto_approve = server::auto_approve_pattern(server, device_id)
+                .filter(|_| matches!(db.get_device(device_id), Ok(None)));
+            if let Some(pattern) = auto_approve {
+                let paired = db.create_pairing_request(device_id, name, device_type, signed)
+                    .and_then(|code| db.confirm_pairing(device_id, &code));
+                match paired {
+                    Ok(token) => {
+                        log!("✓ Device auto-approved: {} ({}) - matches {}", name, device_id, pattern);
+                        if let Err(e) = db.audit("server", "pair.auto_approve", device_id, &format!("matched {}", pattern)) {
+                            log!("Audit log write failed: {}", e);
+                        }
+                        if let Ok(Some(device)) = db.get_device(device_id) {
+                            server::devices_added(server, &[device], false);
+                        }
+                        send_json(stream, 200, &serde_json::json!({
+                            "status": "paired",
+                            "token": token,
+                            "device_id": device_id,
+                            "auto_approved": true
+                        }));
+                    }
+                    Err(e) => send_json_error(stream, 500, &e),
+                }
+                return;
+            }
+            
+            match db.create_pairing_request(device_id, name, device_type, signed) {
                 Ok(code) => {
-                    println!("🔔 Pairing request: {} ({}) - Code: {}", name, device_id, code);
+                    log!("🔔 Pairing request: {} ({}) - Code: {}", name, device_id, code);
+                    server::pairing_changed(server);
                     send_json(stream, 200, &serde_json::json!({
                         "status": "pending",
                         "message": "Enter the 6-digit code shown in GlobalUI",
@@ -271,9 +803,14 @@ fn handle_api(
                 return;
             }
             
+            let repaired = matches!(db.get_device(device_id), Ok(Some(_)));
             match db.confirm_pairing(device_id, &code.to_uppercase()) {
                 Ok(token) => {
-                    println!("✓ Device paired: {}", device_id);
+                    log!("✓ Device paired: {}", device_id);
+                    server::pairing_changed(server);
+                    if let Ok(Some(device)) = db.get_device(device_id) {
+                        server::devices_added(server, &[device], repaired);
+                    }
                     send_json(stream, 200, &serde_json::json!({
                         "status": "paired",
                         "token": token,
@@ -284,9 +821,220 @@ fn handle_api(
             }
         }
         
+        // Bulk provisioning: devices go straight in with tokens, no pairing codes
+        ("POST", "/api/devices/import") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let body = match read_body(stream, request) {
+                Some(b) => b,
+                None => { send_json_error(stream, 400, "Missing body"); return; }
+            };
+            let rows: Vec<serde_json::Value> = match serde_json::from_str(&body) {
+                Ok(rows) => rows,
+                Err(_) => { send_json_error(stream, 400, "Expected a JSON array of devices"); return; }
+            };
+            
+            let devices: Vec<DeviceImport> = rows.iter().map(parse_import_row).collect();
+            match db.import_devices(&devices) {
+                Ok(results) => {
+                    let refused = results.iter().filter(|r| r.is_err()).count();
+                    let imported = refused == 0;
+                    let items = devices.iter().zip(results).map(|(device, result)| {
+                        let result = match result {
+                            Ok(token) if imported => Ok(serde_json::json!({"token": token})),
+                            // Fine on its own, but rolled back with the rest
+                            Ok(_) => Err("not imported: another row was refused".to_string()),
+                            Err(e) => Err(e),
+                        };
+                        (device.id.clone(), result)
+                    }).collect();
+                    let mut body = bulk_json(items);
+                    if imported {
+                        log!("✓ Imported {} devices", devices.len());
+                        let added: Vec<_> = devices.iter().filter_map(|d| db.get_device(&d.id).ok().flatten()).collect();
+                        server::devices_added(server, &added, false);
+                        send_json(stream, 200, &body);
+                    } else {
+                        body["error"] = serde_json::json!(format!("{} of {} rows refused; nothing imported", refused, devices.len()));
+                        send_json(stream, 400, &body);
+                    }
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // The registry in full, tokens and all, for a backup or a move to
+        // another server
+        ("GET", "/api/devices/export") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            match db.export_devices() {
+                Ok(devices) => {
+                    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+                    send_json(stream, 200, &serde_json::json!({
+                        "version": REGISTRY_EXPORT_VERSION,
+                        "exported_at": now,
+                        "devices": devices,
+                    }));
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // An export put back. Like an import, one refused row restores nothing.
+        ("POST", "/api/devices/restore") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let conflict = match query_params.get("conflict").map(String::as_str) {
+                None | Some("merge") => RestoreConflict::Merge,
+                Some("replace") => RestoreConflict::Replace,
+                Some(_) => { send_json_error(stream, 400, "conflict must be merge or replace"); return; }
+            };
+            let body = match read_body(stream, request) {
+                Some(b) => b,
+                None => { send_json_error(stream, 400, "Missing body"); return; }
+            };
+            let export: serde_json::Value = match serde_json::from_str(&body) {
+                Ok(export) => export,
+                Err(_) => { send_json_error(stream, 400, "Expected an export from /api/devices/export"); return; }
+            };
+            if export.get("version").and_then(|v| v.as_u64()) != Some(REGISTRY_EXPORT_VERSION) {
+                send_json_error(stream, 400, &format!("Only version {} exports can be restored", REGISTRY_EXPORT_VERSION));
+                return;
+            }
+            let devices: Vec<DeviceRecord> = match serde_json::from_value(export["devices"].clone()) {
+                Ok(devices) => devices,
+                Err(e) => { send_json_error(stream, 400, &format!("Bad devices: {}", e)); return; }
+            };
+            
+            match db.restore_devices(&devices, conflict) {
+                Ok(results) => {
+                    let refused = results.iter().filter(|r| r.is_err()).count();
+                    let restored = refused == 0;
+                    // New to this server, and paired rather than revoked
+                    let added: Vec<String> = devices.iter().zip(&results)
+                        .filter(|(device, result)| *result == &Ok("restored") && device.token.is_some())
+                        .map(|(device, _)| device.id.clone())
+                        .collect();
+                    let items = devices.iter().zip(results).map(|(device, result)| {
+                        let result = match result {
+                            Ok(outcome) if restored => Ok(serde_json::json!({"outcome": outcome})),
+                            // Fine on its own, but rolled back with the rest
+                            Ok(_) => Err("not restored: another row was refused".to_string()),
+                            Err(e) => Err(e),
+                        };
+                        (device.id.clone(), result)
+                    }).collect();
+                    let mut body = bulk_json(items);
+                    if restored {
+                        log!("✓ Restored {} devices", devices.len());
+                        let added: Vec<_> = added.iter().filter_map(|id| db.get_device(id).ok().flatten()).collect();
+                        server::devices_added(server, &added, false);
+                        send_json(stream, 200, &body);
+                    } else {
+                        body["error"] = serde_json::json!(format!("{} of {} rows refused; nothing restored", refused, devices.len()));
+                        send_json(stream, 400, &body);
+                    }
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // One command to many devices. Each device gets its own result, and
+        // one that can't take the command doesn't stop the rest.
+        ("POST", "/api/commands") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let body = match read_body(stream, request) {
+                Some(b) => b,
+                None => { send_json_error(stream, 400, "Missing body"); return; }
+            };
+            let mut data: serde_json::Value = match serde_json::from_str(&body) {
+                Ok(d @ serde_json::Value::Object(_)) => d,
+                _ => { send_json_error(stream, 400, "Invalid JSON"); return; }
+            };
+            let device_ids: Option<Vec<String>> = data.get("device_ids").and_then(|v| v.as_array())
+                .and_then(|ids| ids.iter().map(|id| id.as_str().map(str::to_string)).collect());
+            let device_ids = match device_ids {
+                Some(ids) if !ids.is_empty() && ids.len() <= MAX_GROUP_DEVICES => ids,
+                _ => {
+                    send_json_error(stream, 400, &format!("device_ids must be 1 to {} device ids", MAX_GROUP_DEVICES));
+                    return;
+                }
+            };
+            data["device_id"] = serde_json::json!("");
+            let cmd: SendCommand = match serde_json::from_value(data) {
+                Ok(cmd) => cmd,
+                Err(_) => { send_json_error(stream, 400, "Expected a command_type"); return; }
+            };
+            
+            if let Err(e) = server::check_payload_size(server, &cmd) {
+                send_json(stream, 413, &serde_json::json!({"error": e, "reason": server::PAYLOAD_TOO_LARGE}));
+                return;
+            }
+            match server::send_group_command(server, &device_ids, &cmd) {
+                Ok(results) => {
+                    let items = device_ids.into_iter().zip(results).map(|(device_id, result)| {
+                        let result = result.map(|dispatched| {
+                            let mut fields = serde_json::json!({
+                                "command_id": dispatched.command_id,
+                                "command_status": dispatched.status,
+                                "dispatched": dispatched.sent,
+                            });
+                            if let Some(reason) = dispatched.skipped {
+                                fields["reason"] = serde_json::json!(reason);
+                            }
+                            if dispatched.duplicate {
+                                fields["duplicate"] = serde_json::json!(true);
+                            }
+                            fields
+                        });
+                        (device_id, result)
+                    }).collect();
+                    send_json(stream, 200, &bulk_json(items));
+                }
+                Err(e) => send_json_error(stream, 400, &e),
+            }
+        }
+        
+        // Call off a command that hasn't ended; the device, if it has it,
+        // is told to stop
+        _ if method == "POST" && path.starts_with("/api/commands/") && path.ends_with("/cancel") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let command_id = path.trim_start_matches("/api/commands/").trim_end_matches("/cancel");
+            match server::cancel_command(server, command_id) {
+                Ok(Cancelled::Done { device_id, notified }) => send_json(stream, 200, &serde_json::json!({
+                    "command_id": command_id,
+                    "device_id": device_id,
+                    "status": "cancelled",
+                    "notified": notified,
+                })),
+                Ok(Cancelled::Finished(status)) => send_json_error(stream, 409, &format!("Command is already {}", status)),
+                Ok(Cancelled::Leased) => send_json_error(stream, 409, "Device is leased"),
+                Ok(Cancelled::Unknown) => send_json_error(stream, 404, "Command not found"),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
         // Devices list
         ("GET", "/api/devices") => {
-            match db.get_all_devices() {
+            let devices = match sensor_filter(&query_params) {
+                Ok(Some((sensor, op, value))) => db.devices_by_sensor(sensor, op, value),
+                Ok(None) => db.get_all_devices(),
+                Err(e) => { send_json_error(stream, 400, &e); return; }
+            };
+            match devices {
                 Ok(devices) => {
                     let json: Vec<serde_json::Value> = devices.iter().map(|d| {
                         serde_json::json!({
@@ -297,48 +1045,942 @@ fn handle_api(
                             "latitude": d.latitude,
                             "longitude": d.longitude,
                             "battery": d.battery,
-                            "last_seen": d.last_seen
+                            "last_seen": d.last_seen,
+                            "queued_commands": d.queued_commands,
+                            "pending_commands": d.pending_commands,
+                            "color": d.color,
+                            "icon": d.icon,
+                            "capabilities": d.capabilities,
+                            "firmware_version": d.firmware_version,
+                            "sensors": d.sensors,
+                            "wake_at": d.wake_at,
+                            "tags": d.tags
                         })
                     }).collect();
-                    send_json(stream, 200, &serde_json::json!({"devices": json}));
+                    send_json_tagged(stream, request, &serde_json::json!({"devices": json}));
                 }
                 Err(e) => send_json_error(stream, 500, &e),
             }
         }
         
-        // Oura API proxy - handles all /api/oura/* paths
-        _ if method == "GET" && path.starts_with("/api/oura/") => {
-            // Extract the Oura API path (everything after /api/oura)
-            let oura_path = path.trim_start_matches("/api/oura");
-            match fetch_oura_api(oura_path, query) {
-                Ok(data) => send_json(stream, 200, &data),
-                Err(e) => send_json_error(stream, 502, &e),
-            }
-        }
-        
-        // Delete pairing request or device
-        _ if method == "DELETE" && path.starts_with("/api/pair/") => {
-            let device_id = path.trim_start_matches("/api/pair/");
-            match db.delete_pairing_request(device_id) {
-                Ok(_) => send_json(stream, 200, &serde_json::json!({"status": "deleted"})),
+        // The registry for a spreadsheet rather than a map
+        ("GET", "/api/devices.csv") => {
+            match db.get_all_devices() {
+                Ok(devices) => send_attachment(stream, "text/csv; charset=utf-8", "devices.csv", devices_csv(&devices).as_bytes()),
                 Err(e) => send_json_error(stream, 500, &e),
             }
         }
         
-        _ if method == "DELETE" && path.starts_with("/api/devices/") => {
-            let device_id = path.trim_start_matches("/api/devices/");
-            match db.delete_device(device_id) {
-                Ok(_) => {
-                    println!("✗ Device revoked: {}", device_id);
-                    send_json(stream, 200, &serde_json::json!({"status": "deleted"}));
+        // Proximity: the devices within a radius of a point, nearest first
+        ("GET", "/api/devices/near") => {
+            let number = |name: &str, range: std::ops::RangeInclusive<f64>| {
+                query_params.get(name).and_then(|v| v.parse::<f64>().ok()).filter(|v| range.contains(v))
+            };
+            let (lat, lon, radius) = match (number("lat", -90.0..=90.0), number("lon", -180.0..=180.0), number("radius", 0.0..=f64::MAX)) {
+                (Some(lat), Some(lon), Some(radius)) => (lat, lon, radius),
+                _ => {
+                    send_json_error(stream, 400, "lat (-90 to 90), lon (-180 to 180) and radius (metres) are required");
+                    return;
+                }
+            };
+            match db.get_devices_near(lat, lon, radius) {
+                Ok(devices) => {
+                    let json: Vec<serde_json::Value> = devices.iter().map(|d| {
+                        serde_json::json!({
+                            "id": d.id,
+                            "name": d.name,
+                            "device_type": d.device_type,
+                            "status": d.status,
+                            "latitude": d.latitude,
+                            "longitude": d.longitude,
+                            "battery": d.battery,
+                            "distance_m": telemetry::haversine_m(lat, lon, d.latitude, d.longitude).round()
+                        })
+                    }).collect();
+                    send_json(stream, 200, &serde_json::json!({"count": json.len(), "devices": json}));
                 }
                 Err(e) => send_json_error(stream, 500, &e),
             }
         }
         
-        _ => send_json_error(stream, 404, "Not found"),
-    }
-}
+        // Connected, as opposed to registered: what the live connections say,
+        // whatever status the registry last recorded
+        ("GET", "/api/devices/connected") => {
+            match server::connected_devices(server) {
+                Ok(devices) => {
+                    let json: Vec<serde_json::Value> = devices.iter().map(|(d, secs)| {
+                        serde_json::json!({
+                            "id": d.id,
+                            "name": d.name,
+                            "device_type": d.device_type,
+                            "status": d.status,
+                            "connected_secs": secs
+                        })
+                    }).collect();
+                    send_json(stream, 200, &serde_json::json!({"count": json.len(), "devices": json}));
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Maintenance: commands are held, not sent, while it's on
+        ("GET", "/api/maintenance") => {
+            match db.maintenance() {
+                Ok(maintenance) => send_json(stream, 200, &server::maintenance_json(&maintenance)),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        ("POST", "/api/maintenance") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let body = match read_body(stream, request) {
+                Some(b) => b,
+                None => { send_json_error(stream, 400, "Missing body"); return; }
+            };
+            let data: serde_json::Value = match serde_json::from_str(&body) {
+                Ok(d) => d,
+                Err(_) => { send_json_error(stream, 400, "Invalid JSON"); return; }
+            };
+            let Some(active) = data.get("active").and_then(|v| v.as_bool()) else {
+                send_json_error(stream, 400, "active must be true or false");
+                return;
+            };
+            let device_id = data.get("device_id").and_then(|v| v.as_str());
+            if let Some(device_id) = device_id {
+                match db.get_device(device_id) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => { send_json_error(stream, 404, "Device not found"); return; }
+                    Err(e) => { send_json_error(stream, 500, &e); return; }
+                }
+            }
+            
+            match server::set_maintenance(server, device_id, active) {
+                Ok((maintenance, released)) => {
+                    let scope = device_id.unwrap_or("the fleet");
+                    match active {
+                        true => log!("⚠ Maintenance on for {}: commands are held", scope),
+                        false => log!("✓ Maintenance off for {}: {} held commands released", scope, released),
+                    }
+                    let mut body = server::maintenance_json(&maintenance);
+                    body["released"] = serde_json::json!(released);
+                    send_json(stream, 200, &body);
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // What's deployed
+        ("GET", "/api/version") => send_json(stream, 200, &version::info()),
+        
+        // The server's clock, for clients working out their offset from it
+        ("GET", "/api/time") => {
+            let millis = server::now_millis();
+            send_json(stream, 200, &serde_json::json!({"unix": millis / 1000, "millis": millis}));
+        }
+        
+        // Live WebSocket connections and their frame counts, for debugging
+        ("GET", "/api/connections") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            match server::connections(server) {
+                Ok(connections) => send_json(stream, 200, &serde_json::json!({
+                    "count": connections.len(),
+                    "connections": connections
+                })),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Who the caller's token says they are
+        ("GET", "/api/whoami") => send_json(stream, 200, &serde_json::json!({
+            "role": request_role(request, server).as_str(),
+            "identity": ui_identity(request)
+        })),
+        
+        ("GET", "/api/leases") => match db.leases() {
+            Ok(leases) => send_json(stream, 200, &serde_json::json!({
+                "leases": leases.iter().map(lease_json).collect::<Vec<_>>()
+            })),
+            Err(e) => send_json_error(stream, 500, &e),
+        },
+        
+        // Fleet summary: a few aggregate queries and today's telemetry files.
+        // "Today" is the UTC day, as telemetry files are.
+        ("GET", "/api/stats") => {
+            match server::telemetry_reader(server).and_then(|reader| server::fleet_stats(db, &reader)) {
+                Ok(stats) => send_json_tagged(stream, request, &stats),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Gauges for a scraper: commands piling up for devices that aren't
+        // taking them
+        ("GET", "/api/metrics") => {
+            let by_device = match db.pending_commands_by_device() {
+                Ok(counts) => counts,
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            };
+            send_file(stream, "text/plain; version=0.0.4; charset=utf-8", metrics_text(&by_device).as_bytes());
+        }
+        
+        // Where the fleet has been: every device's positions over the range,
+        // binned into cells and counted
+        ("GET", "/api/heatmap") => {
+            let start = query_params.get("start").and_then(|v| v.parse().ok()).unwrap_or(0);
+            let end = query_params.get("end").and_then(|v| v.parse().ok()).unwrap_or(i64::MAX);
+            let cell = match query_params.get("cell").map(|v| v.parse::<f64>()) {
+                None => DEFAULT_HEATMAP_CELL,
+                Some(Ok(cell)) if cell.is_finite() && (telemetry::MIN_HEATMAP_CELL..=MAX_HEATMAP_CELL).contains(&cell) => cell,
+                Some(_) => {
+                    let msg = format!("cell must be a number from {} to {} degrees", telemetry::MIN_HEATMAP_CELL, MAX_HEATMAP_CELL);
+                    send_json_error(stream, 400, &msg);
+                    return;
+                }
+            };
+            let devices = match db.get_all_devices() {
+                Ok(devices) => devices,
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            };
+            let reader = match server::telemetry_reader(server) {
+                Ok(reader) => reader,
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            };
+            let mut heatmap = telemetry::Heatmap::new(cell, telemetry::MAX_HEATMAP_CELLS);
+            let mut records = 0u64;
+            for device in devices.iter().filter(|d| telemetry::is_valid_device_id(&d.id)) {
+                for record in reader.records(&device.id, start, end) {
+                    heatmap.add(&record);
+                    records += 1;
+                }
+            }
+            send_json(stream, 200, &serde_json::json!({
+                "cell": heatmap.cell(),
+                "records": records,
+                "cells": heatmap.cells(),
+            }));
+        }
+        
+        // What was done and by whom, oldest first: the latest `limit` since `since`
+        ("GET", "/api/audit") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let action = query_params.get("action").map(String::as_str);
+            let since = query_params.get("since").and_then(|v| v.parse().ok()).unwrap_or(0);
+            let limit = query_params.get("limit").and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_AUDIT_ENTRIES).min(MAX_AUDIT_ENTRIES);
+            match db.audit_log(action, since, limit) {
+                Ok(entries) => {
+                    let json: Vec<serde_json::Value> = entries.iter().map(|entry| serde_json::json!({
+                        "id": entry.id,
+                        "at": entry.at,
+                        "actor": entry.actor,
+                        "action": entry.action,
+                        "target": entry.target,
+                        "detail": entry.detail,
+                    })).collect();
+                    send_json(stream, 200, &serde_json::json!({"entries": json}));
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Alert history, oldest first: the latest `limit` since `since`
+        ("GET", "/api/alerts") => {
+            let device_id = query_params.get("device_id").map(String::as_str);
+            let since = query_params.get("since").and_then(|v| v.parse().ok()).unwrap_or(0);
+            let unacknowledged = query_params.get("unacknowledged").is_some_and(|v| v == "1" || v == "true");
+            let limit = query_params.get("limit").and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_ALERTS).min(MAX_ALERTS);
+            match db.get_alerts(device_id, since, unacknowledged, limit) {
+                Ok(alerts) => {
+                    let json: Vec<serde_json::Value> = alerts.iter().map(alert_json).collect();
+                    send_json(stream, 200, &serde_json::json!({"alerts": json}));
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Acknowledge an alert; the first acknowledgment stands
+        _ if method == "POST" && path.starts_with("/api/alerts/") && path.ends_with("/ack") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let id: i64 = match path.trim_start_matches("/api/alerts/").trim_end_matches("/ack").parse() {
+                Ok(id) => id,
+                Err(_) => { send_json_error(stream, 400, "Invalid alert id"); return; }
+            };
+            
+            let data: serde_json::Value = match read_body(stream, request) {
+                Some(body) => match serde_json::from_str(&body) {
+                    Ok(d) => d,
+                    Err(_) => { send_json_error(stream, 400, "Invalid JSON"); return; }
+                },
+                None => serde_json::json!({}),
+            };
+            let by = match data.get("by") {
+                None | Some(serde_json::Value::Null) => "admin",
+                Some(v) => match v.as_str() {
+                    Some(by) if !by.trim().is_empty() && by.len() <= MAX_ACKNOWLEDGED_BY => by,
+                    _ => {
+                        send_json_error(stream, 400, &format!("by must be 1 to {} bytes of text", MAX_ACKNOWLEDGED_BY));
+                        return;
+                    }
+                },
+            };
+            
+            let acknowledged = match db.acknowledge_alert(id, by) {
+                Ok(acknowledged) => acknowledged,
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            };
+            match db.get_alert(id) {
+                Ok(Some(alert)) if acknowledged => {
+                    log!("✓ Alert {} acknowledged by {}", id, by);
+                    server::alert_acknowledged(server, &alert);
+                    send_json(stream, 200, &alert_json(&alert));
+                }
+                Ok(Some(_)) => send_json_error(stream, 409, "Alert already acknowledged"),
+                Ok(None) => send_json_error(stream, 404, "Alert not found"),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // UI preferences (opaque JSON blob per operator)
+        ("GET", "/api/prefs") => {
+            let identity = match operator_identity(request) {
+                Ok(identity) => identity,
+                Err((status, message)) => { send_json_error(stream, status, message); return; }
+            };
+            match db.get_prefs(&identity) {
+                Ok(Some(prefs)) => {
+                    let data = serde_json::from_str(&prefs).unwrap_or(serde_json::Value::Null);
+                    send_json(stream, 200, &serde_json::json!({"prefs": data}));
+                }
+                Ok(None) => send_json(stream, 200, &serde_json::json!({"prefs": null})),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        ("PUT", "/api/prefs") => {
+            let identity = match operator_identity(request) {
+                Ok(identity) => identity,
+                Err((status, message)) => { send_json_error(stream, status, message); return; }
+            };
+            // Refused on the declared length, before any of it is read
+            if content_length(request) > MAX_PREFS_BYTES as u64 {
+                send_json_error(stream, 413, &format!("Preferences exceed {} bytes", MAX_PREFS_BYTES));
+                return;
+            }
+            let body = match read_body(stream, request) {
+                Some(b) => b,
+                None => { send_json_error(stream, 400, "Missing body"); return; }
+            };
+            
+            if body.len() > MAX_PREFS_BYTES {
+                send_json_error(stream, 413, &format!("Preferences exceed {} bytes", MAX_PREFS_BYTES));
+                return;
+            }
+            
+            if serde_json::from_str::<serde_json::Value>(&body).is_err() {
+                send_json_error(stream, 400, "Invalid JSON");
+                return;
+            }
+            
+            match db.set_prefs(&identity, &body) {
+                Ok(_) => send_json(stream, 200, &serde_json::json!({"status": "saved"})),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Several devices' tracks in one request, each device read as
+        // /recent or the download would read it
+        ("GET", "/api/telemetry") => {
+            let mut device_ids: Vec<&str> = query_params.get("device_ids").map(String::as_str).unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .collect();
+            device_ids.sort_unstable();
+            device_ids.dedup();
+            if device_ids.is_empty() {
+                send_json_error(stream, 400, "device_ids required");
+                return;
+            }
+            if device_ids.len() > MAX_QUERY_DEVICES {
+                send_json_error(stream, 400, &format!("At most {} device_ids", MAX_QUERY_DEVICES));
+                return;
+            }
+            if let Some(bad) = device_ids.iter().find(|id| !telemetry::is_valid_device_id(id)) {
+                send_json_error(stream, 400, &format!("Invalid device id: {}", bad));
+                return;
+            }
+            let limit = match query_params.get("limit").map(|v| v.parse::<usize>()) {
+                None => DEFAULT_QUERY_LIMIT,
+                Some(Ok(n)) if n <= MAX_RECENT => n,
+                Some(_) => {
+                    send_json_error(stream, 400, &format!("limit must be 0 to {}", MAX_RECENT));
+                    return;
+                }
+            };
+            let fields = match query_fields(&query_params) {
+                Ok(fields) => fields,
+                Err(e) => { send_json_error(stream, 400, &e); return; }
+            };
+            let start = query_params.get("start").and_then(|v| v.parse().ok()).unwrap_or(0);
+            let end = query_params.get("end").and_then(|v| v.parse().ok()).unwrap_or(i64::MAX);
+            let reader = match server::telemetry_reader(server) {
+                Ok(reader) => reader,
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            };
+            
+            // The latest `limit` in the range: a track ends where the device is
+            let mut devices = serde_json::Map::new();
+            for device_id in device_ids {
+                let mut records = std::collections::VecDeque::with_capacity(limit.min(DEFAULT_QUERY_LIMIT));
+                for record in reader.records(device_id, start, end) {
+                    if records.len() == limit {
+                        records.pop_front();
+                    }
+                    if limit > 0 {
+                        records.push_back(record);
+                    }
+                }
+                let records: Vec<serde_json::Value> = match &fields {
+                    Some(fields) => records.iter().map(|record| record.project(fields)).collect(),
+                    None => records.iter().map(|record| serde_json::to_value(record).unwrap_or_default()).collect(),
+                };
+                devices.insert(device_id.to_string(), serde_json::Value::Array(records));
+            }
+            send_json(stream, 200, &serde_json::json!({"devices": devices}));
+        }
+        
+        // Telemetry download: gzipped NDJSON, streamed straight from the day files
+        _ if method == "GET" && path.starts_with("/api/telemetry/") && path.ends_with(".ndjson.gz") => {
+            let device_id = path
+                .trim_start_matches("/api/telemetry/")
+                .trim_end_matches(".ndjson.gz");
+            if !telemetry::is_valid_device_id(device_id) {
+                send_json_error(stream, 400, "Invalid device id");
+                return;
+            }
+            
+            let fields = match query_fields(&query_params) {
+                Ok(fields) => fields,
+                Err(e) => { send_json_error(stream, 400, &e); return; }
+            };
+            let start = query_params.get("start").and_then(|v| v.parse().ok()).unwrap_or(0);
+            let end = query_params.get("end").and_then(|v| v.parse().ok()).unwrap_or(i64::MAX);
+            match server::telemetry_reader(server) {
+                Ok(reader) => send_telemetry_gz(stream, &reader, device_id, start, end, fields.as_deref()),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // The last n records, however far back: a map trail of fixed length
+        _ if method == "GET" && path.starts_with("/api/telemetry/") && path.ends_with("/recent") => {
+            let device_id = path
+                .trim_start_matches("/api/telemetry/")
+                .trim_end_matches("/recent");
+            if !telemetry::is_valid_device_id(device_id) {
+                send_json_error(stream, 400, "Invalid device id");
+                return;
+            }
+            let n = match query_params.get("n").map(|v| v.parse::<usize>()) {
+                None => DEFAULT_RECENT,
+                Some(Ok(n)) if n <= MAX_RECENT => n,
+                Some(_) => {
+                    send_json_error(stream, 400, &format!("n must be 0 to {}", MAX_RECENT));
+                    return;
+                }
+            };
+            let fields = match query_fields(&query_params) {
+                Ok(fields) => fields,
+                Err(e) => { send_json_error(stream, 400, &e); return; }
+            };
+            
+            match server::telemetry_reader(server).and_then(|reader| reader.recent(device_id, n)) {
+                Ok(records) => {
+                    let records: Vec<serde_json::Value> = match &fields {
+                        Some(fields) => records.iter().map(|record| record.project(fields)).collect(),
+                        None => records.iter().map(|record| serde_json::to_value(record).unwrap_or_default()).collect(),
+                    };
+                    send_json(stream, 200, &serde_json::json!({
+                        "device_id": device_id,
+                        "count": records.len(),
+                        "records": records
+                    }))
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // What a device has stored, day by day: for capacity planning
+        _ if method == "GET" && path.starts_with("/api/telemetry/") && path.ends_with("/files") => {
+            let device_id = path
+                .trim_start_matches("/api/telemetry/")
+                .trim_end_matches("/files");
+            if !telemetry::is_valid_device_id(device_id) {
+                send_json_error(stream, 400, "Invalid device id");
+                return;
+            }
+            
+            match server::telemetry_reader(server).and_then(|reader| reader.files(device_id)) {
+                Ok(files) => send_json(stream, 200, &serde_json::json!({
+                    "device_id": device_id,
+                    "total_bytes": files.iter().map(|f| f.bytes).sum::<u64>(),
+                    "total_lines": files.iter().map(|f| f.lines).sum::<u64>(),
+                    "files": files
+                })),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Telemetry replay to UIs: live device state is left alone
+        _ if method == "POST" && path.starts_with("/api/telemetry/") && path.ends_with("/replay") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let device_id = path
+                .trim_start_matches("/api/telemetry/")
+                .trim_end_matches("/replay");
+            if !telemetry::is_valid_device_id(device_id) {
+                send_json_error(stream, 400, "Invalid device id");
+                return;
+            }
+            
+            let data: serde_json::Value = match read_body(stream, request) {
+                Some(body) => match serde_json::from_str(&body) {
+                    Ok(d) => d,
+                    Err(_) => { send_json_error(stream, 400, "Invalid JSON"); return; }
+                },
+                None => serde_json::json!({}),
+            };
+            let start = data.get("start").and_then(|v| v.as_i64()).unwrap_or(0);
+            let end = data.get("end").and_then(|v| v.as_i64()).unwrap_or(i64::MAX);
+            let speed = data.get("speed").and_then(|v| v.as_f64()).unwrap_or(1.0);
+            if !(speed > 0.0 && speed <= replay::MAX_SPEED) {
+                send_json_error(stream, 400, &format!("speed must be above 0 and at most {}", replay::MAX_SPEED));
+                return;
+            }
+            
+            match server::start_replay(server, device_id, start, end, speed) {
+                Ok(replay_id) => {
+                    log!("↻ Replaying {} at {}x", device_id, speed);
+                    send_json(stream, 200, &serde_json::json!({
+                        "status": "started",
+                        "replay_id": replay_id,
+                        "device_id": device_id,
+                        "speed": speed
+                    }));
+                }
+                Err(e) => send_json_error(stream, 409, &e),
+            }
+        }
+        
+        _ if method == "DELETE" && path.starts_with("/api/telemetry/") && path.ends_with("/replay") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let device_id = path
+                .trim_start_matches("/api/telemetry/")
+                .trim_end_matches("/replay");
+            match server::cancel_replay(server, device_id) {
+                Ok(true) => send_json(stream, 200, &serde_json::json!({"status": "cancelled"})),
+                Ok(false) => send_json_error(stream, 404, "No replay running for this device"),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Records a device kept while it couldn't stream, uploaded in one go
+        _ if method == "POST" && path.starts_with("/api/telemetry/") => {
+            let device_id = path.trim_start_matches("/api/telemetry/");
+            if !telemetry::is_valid_device_id(device_id) {
+                send_json_error(stream, 400, "Invalid device id");
+                return;
+            }
+            let scoped = match bearer_token(request).map(|token| db.scoped_token(token)).transpose() {
+                Ok(scoped) => scoped.flatten(),
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            };
+            let allowed = match scoped {
+                Some(scoped) => scoped.device_id == device_id && scoped.scope == TokenScope::Telemetry,
+                None => match is_device_token(db, request, device_id) {
+                    Ok(allowed) => allowed,
+                    Err(e) => { send_json_error(stream, 500, &e); return; }
+                },
+            };
+            if !allowed {
+                send_json_error(stream, 401, "The device's token or a telemetry token for it required");
+                return;
+            }
+            
+            #[derive(serde::Deserialize)]
+            struct Upload {
+                records: Vec<UploadedRecord>,
+            }
+            #[derive(serde::Deserialize)]
+            struct UploadedRecord {
+                timestamp: Option<i64>,
+                #[serde(flatten)]
+                telemetry: TelemetryMessage,
+            }
+            let body = read_body(stream, request).unwrap_or_default();
+            let upload: Upload = match serde_json::from_str(&body) {
+                Ok(upload) => upload,
+                Err(e) => { send_json_error(stream, 400, &format!("Invalid upload: {}", e)); return; }
+            };
+            if upload.records.len() > MAX_UPLOAD_RECORDS {
+                send_json_error(stream, 400, &format!("At most {} records per upload", MAX_UPLOAD_RECORDS));
+                return;
+            }
+            
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+            if upload.records.iter().any(|r| r.timestamp.is_some_and(|t| t > now)) {
+                send_json_error(stream, 400, "timestamp is in the future");
+                return;
+            }
+            let records: Vec<TelemetryRecord> = upload.records.into_iter().map(|r| TelemetryRecord {
+                timestamp: r.timestamp.unwrap_or(now),
+                device_id: device_id.to_string(),
+                latitude: r.telemetry.latitude,
+                longitude: r.telemetry.longitude,
+                altitude: r.telemetry.altitude,
+                heading: r.telemetry.heading,
+                speed: r.telemetry.speed,
+                battery: r.telemetry.battery,
+                sensors: r.telemetry.sensors,
+                out_of_order: false,
+            }).collect();
+            let received = records.len();
+            match server::import_telemetry(server, device_id, records) {
+                Ok(dropped) => send_json(stream, 200, &serde_json::json!({
+                    "device_id": device_id,
+                    "stored": received - dropped,
+                    "dropped": dropped
+                })),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Telemetry summary: one pass over the range, nothing held in memory
+        _ if method == "GET" && path.starts_with("/api/devices/") && path.ends_with("/stats") => {
+            let device_id = path
+                .trim_start_matches("/api/devices/")
+                .trim_end_matches("/stats");
+            if !telemetry::is_valid_device_id(device_id) {
+                send_json_error(stream, 400, "Invalid device id");
+                return;
+            }
+            
+            let start = query_params.get("start").and_then(|v| v.parse().ok()).unwrap_or(0);
+            let end = query_params.get("end").and_then(|v| v.parse().ok()).unwrap_or(i64::MAX);
+            let reader = match server::telemetry_reader(server) {
+                Ok(reader) => reader,
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            };
+            let mut records = reader.records(device_id, start, end);
+            let verify = query_params.get("verify").is_some_and(|v| v == "1" || v == "true");
+            if verify {
+                records = records.verify_checksums();
+            }
+            let stats = TelemetryStats::from_records(records.by_ref());
+            let mut body = serde_json::to_value(&stats).unwrap_or_default();
+            body["device_id"] = serde_json::json!(device_id);
+            if verify {
+                let corrupt: Vec<String> = records.corrupt_files().iter().map(|p| p.display().to_string()).collect();
+                body["corrupt_files"] = serde_json::json!(corrupt);
+            }
+            send_json(stream, 200, &body);
+        }
+        
+        // Server-sent events: the connection stays open and each status
+        // change of this device's commands is written as it happens
+        _ if method == "GET" && path.starts_with("/api/devices/") && path.ends_with("/commands/stream") => {
+            let device_id = path
+                .trim_start_matches("/api/devices/")
+                .trim_end_matches("/commands/stream");
+            match db.get_device(device_id) {
+                Ok(Some(_)) => {}
+                Ok(None) => { send_json_error(stream, 404, "Device not found"); return; }
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            }
+            let events = match server::subscribe_commands(server, device_id) {
+                Ok(events) => events,
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            };
+            stream_command_events(stream, &events);
+        }
+        
+        // A key that's absent stays as it is; null goes back to the default
+        _ if method == "PATCH" && path.starts_with("/api/devices/") && path.ends_with("/retention") => {
+            if let Err((status, message)) = check_admin(request, server) {
+                send_json_error(stream, status, message);
+                return;
+            }
+            let device_id = path
+                .trim_start_matches("/api/devices/")
+                .trim_end_matches("/retention");
+            let body = match read_body(stream, request) {
+                Some(b) => b,
+                None => { send_json_error(stream, 400, "Missing body"); return; }
+            };
+            let data: serde_json::Value = match serde_json::from_str(&body) {
+                Ok(d) => d,
+                Err(_) => { send_json_error(stream, 400, "Invalid JSON"); return; }
+            };
+            
+            // null goes back to the fleet default
+            let days = match data.get("retention_days") {
+                Some(serde_json::Value::Null) => None,
+                Some(v) => match v.as_u64() {
+                    Some(d) if d <= MAX_RETENTION_DAYS => Some(d),
+                    _ => {
+                        send_json_error(stream, 400, &format!("retention_days must be 0 to {} or null", MAX_RETENTION_DAYS));
+                        return;
+                    }
+                },
+                None => { send_json_error(stream, 400, "retention_days required"); return; }
+            };
+            
+            match db.set_retention(device_id, days) {
+                Ok(true) => send_json(stream, 200, &serde_json::json!({
+                    "device_id": device_id,
+                    "retention_days": days
+                })),
+                Ok(false) => send_json_error(stream, 404, "Device not found"),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        _ if method == "PATCH" && path.starts_with("/api/devices/") && path.ends_with("/appearance") => {
+            let device_id = path
+                .trim_start_matches("/api/devices/")
+                .trim_end_matches("/appearance");
+            let body = match read_body(stream, request) {
+                Some(b) => b,
+                None => { send_json_error(stream, 400, "Missing body"); return; }
+            };
+            let data: serde_json::Value = match serde_json::from_str(&body) {
+                Ok(d) => d,
+                Err(_) => { send_json_error(stream, 400, "Invalid JSON"); return; }
+            };
+            
+            let color = match data.get("color") {
+                None => None,
+                Some(serde_json::Value::Null) => Some(None),
+                Some(v) => match v.as_str() {
+                    Some(c) if appearance::is_valid_color(c) => Some(Some(c)),
+                    _ => { send_json_error(stream, 400, "color must be #rrggbb"); return; }
+                },
+            };
+            let icon = match data.get("icon") {
+                None => None,
+                Some(serde_json::Value::Null) => Some(None),
+                Some(v) => match v.as_str() {
+                    Some(i) if appearance::is_valid_icon(i) => Some(Some(i)),
+                    _ => {
+                        send_json_error(stream, 400, &format!("icon must be 1 to {} bytes of text", appearance::MAX_ICON_BYTES));
+                        return;
+                    }
+                },
+            };
+            
+            match db.set_appearance(device_id, color, icon) {
+                Ok(true) => match db.get_device(device_id) {
+                    Ok(Some(device)) => {
+                        server::devices_added(server, std::slice::from_ref(&device), true);
+                        send_json(stream, 200, &serde_json::json!({
+                            "device_id": device.id,
+                            "color": device.color,
+                            "icon": device.icon
+                        }));
+                    }
+                    Ok(None) => send_json_error(stream, 404, "Device not found"),
+                    Err(e) => send_json_error(stream, 500, &e),
+                },
+                Ok(false) => send_json_error(stream, 404, "Device not found"),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Only the holder may command the device until the lease runs out.
+        // Taking it again before then extends it.
+        _ if method == "POST" && path.starts_with("/api/devices/") && path.ends_with("/lease") => {
+            let identity = match operator_identity(request) {
+                Ok(identity) => identity,
+                Err((status, message)) => { send_json_error(stream, status, message); return; }
+            };
+            let device_id = path
+                .trim_start_matches("/api/devices/")
+                .trim_end_matches("/lease");
+            let body = read_body(stream, request).unwrap_or_default();
+            let data: serde_json::Value = if body.trim().is_empty() {
+                serde_json::json!({})
+            } else {
+                match serde_json::from_str(&body) {
+                    Ok(d) => d,
+                    Err(_) => { send_json_error(stream, 400, "Invalid JSON"); return; }
+                }
+            };
+            let ttl_secs = match data.get("ttl_secs") {
+                None | Some(serde_json::Value::Null) => DEFAULT_LEASE_SECS,
+                Some(v) => match v.as_i64() {
+                    Some(ttl) if (1..=MAX_LEASE_SECS).contains(&ttl) => ttl,
+                    _ => {
+                        send_json_error(stream, 400, &format!("ttl_secs must be 1 to {}", MAX_LEASE_SECS));
+                        return;
+                    }
+                },
+            };
+            match db.get_device(device_id) {
+                Ok(Some(_)) => {}
+                Ok(None) => { send_json_error(stream, 404, "Device not found"); return; }
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            }
+            
+            match db.acquire_lease(device_id, &identity, ttl_secs) {
+                Ok(lease) if lease.holder == identity => {
+                    server::lease_changed(server, device_id, Some(&lease));
+                    send_json(stream, 200, &lease_json(&lease));
+                }
+                Ok(lease) => {
+                    let mut body = lease_json(&lease);
+                    body["error"] = serde_json::json!("leased");
+                    send_json(stream, 409, &body);
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        _ if method == "DELETE" && path.starts_with("/api/devices/") && path.ends_with("/lease") => {
+            let identity = match operator_identity(request) {
+                Ok(identity) => identity,
+                Err((status, message)) => { send_json_error(stream, status, message); return; }
+            };
+            let device_id = path
+                .trim_start_matches("/api/devices/")
+                .trim_end_matches("/lease");
+            let holder = (request_role(request, server) != Role::Admin).then_some(identity.as_str());
+            match db.release_lease(device_id, holder) {
+                Ok(true) => {
+                    server::lease_changed(server, device_id, None);
+                    send_json(stream, 200, &serde_json::json!({"device_id": device_id, "released": true}));
+                }
+                Ok(false) => match db.lease(device_id) {
+                    Ok(Some(lease)) => {
+                        let mut body = lease_json(&lease);
+                        body["error"] = serde_json::json!("leased");
+                        send_json(stream, 409, &body);
+                    }
+                    Ok(None) => send_json_error(stream, 404, "No lease on this device"),
+                    Err(e) => send_json_error(stream, 500, &e),
+                },
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // A device issues itself a token for one scope, so a leaked copy
+        // can do less than its registration token could
+        _ if method == "POST" && path.starts_with("/api/devices/") && path.ends_with("/tokens") => {
+            let device_id = path
+                .trim_start_matches("/api/devices/")
+                .trim_end_matches("/tokens");
+            match is_device_token(db, request, device_id) {
+                Ok(true) => {}
+                Ok(false) => { send_json_error(stream, 401, "The device's own token required"); return; }
+                Err(e) => { send_json_error(stream, 500, &e); return; }
+            }
+            let body = read_body(stream, request).unwrap_or_default();
+            let data: serde_json::Value = match serde_json::from_str(&body) {
+                Ok(d) => d,
+                Err(_) => { send_json_error(stream, 400, "Invalid JSON"); return; }
+            };
+            let Some(scope) = data.get("scope").and_then(|v| v.as_str()).and_then(TokenScope::parse) else {
+                send_json_error(stream, 400, "scope must be read or telemetry");
+                return;
+            };
+            
+            match db.create_scoped_token(device_id, scope) {
+                Ok(scoped) => {
+                    if let Err(e) = db.audit(device_id, "token.create", device_id, scope.as_str()) {
+                        log!("Audit log write failed: {}", e);
+                    }
+                    log!("✓ Issued {} token: {}", scope.as_str(), device_id);
+                    send_json(stream, 200, &serde_json::json!({
+                        "device_id": device_id,
+                        "scope": scope.as_str(),
+                        "token": scoped.token,
+                        "created_at": scoped.created_at
+                    }));
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        _ if method == "DELETE" && path.starts_with("/api/devices/") && path.ends_with("/tokens") => {
+            let device_id = path
+                .trim_start_matches("/api/devices/")
+                .trim_end_matches("/tokens");
+            if request_role(request, server) != Role::Admin {
+                match is_device_token(db, request, device_id) {
+                    Ok(true) => {}
+                    Ok(false) => { send_json_error(stream, 401, "The device's own token or the admin token required"); return; }
+                    Err(e) => { send_json_error(stream, 500, &e); return; }
+                }
+            }
+            match db.delete_scoped_tokens(device_id) {
+                Ok(revoked) => send_json(stream, 200, &serde_json::json!({
+                    "device_id": device_id,
+                    "revoked": revoked
+                })),
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        // Oura API proxy - handles all /api/oura/* paths
+        _ if method == "GET" && path.starts_with("/api/oura/") => {
+            // Extract the Oura API path (everything after /api/oura)
+            let oura_path = path.trim_start_matches("/api/oura");
+            match fetch_oura_api(oura_path, query) {
+                Ok(data) => send_json(stream, 200, &data),
+                Err(e) => send_json_error(stream, 502, &e),
+            }
+        }
+        
+        // Delete pairing request or device
+        _ if method == "DELETE" && path.starts_with("/api/pair/") => {
+            let device_id = path.trim_start_matches("/api/pair/");
+            match db.delete_pairing_request(device_id) {
+                Ok(_) => {
+                    server::pairing_changed(server);
+                    send_json(stream, 200, &serde_json::json!({"status": "deleted"}));
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        _ if method == "DELETE" && path.starts_with("/api/devices/") => {
+            let device_id = path.trim_start_matches("/api/devices/");
+            match db.delete_device(device_id) {
+                Ok(_) => {
+                    log!("✗ Device revoked: {}", device_id);
+                    server::device_removed(server, device_id);
+                    send_json(stream, 200, &serde_json::json!({"status": "deleted"}));
+                }
+                Err(e) => send_json_error(stream, 500, &e),
+            }
+        }
+        
+        _ => send_json_error(stream, 404, "Not found"),
+    }
+}
 
 /// Fetch data from Oura API via HTTPS
 /// Uses rustls for TLS - pure Rust, no OpenSSL dependency
@@ -399,48 +2041,235 @@ fn fetch_oura_api(path: &str, query: &str) -> Result<serde_json::Value, String>
     }
 }
 
+/// The `?sensor=&op=&value=` filter on /api/devices, if one was asked for.
+/// Naming any of the three needs all of them.
+fn sensor_filter(query_params: &HashMap<String, String>) -> Result<Option<(&str, SensorOp, f64)>, String> {
+    let (sensor, op, value) = (query_params.get("sensor"), query_params.get("op"), query_params.get("value"));
+    if sensor.is_none() && op.is_none() && value.is_none() {
+        return Ok(None);
+    }
+    let (Some(sensor), Some(op), Some(value)) = (sensor, op, value) else {
+        return Err("sensor, op and value go together".to_string());
+    };
+    state::check_sensor_name(sensor)?;
+    let op = op.parse()?;
+    let value = value.parse::<f64>().ok().filter(|v| v.is_finite()).ok_or("value must be a number")?;
+    Ok(Some((sensor, op, value)))
+}
+
+/// Queue depth gauges in the Prometheus text format: one per device with
+/// commands waiting, and the fleet's total.
+fn metrics_text(pending_by_device: &BTreeMap<String, i64>) -> String {
+    let mut text = String::new();
+    text.push_str("# HELP globalrts_pending_commands Commands queued or held for a device, not yet delivered.\n");
+    text.push_str("# TYPE globalrts_pending_commands gauge\n");
+    for (device_id, count) in pending_by_device {
+        let label = device_id.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        text.push_str(&format!("globalrts_pending_commands{{device_id=\"{}\"}} {}\n", label, count));
+    }
+    text.push_str("# HELP globalrts_fleet_pending_commands Commands queued or held for the whole fleet.\n");
+    text.push_str("# TYPE globalrts_fleet_pending_commands gauge\n");
+    text.push_str(&format!("globalrts_fleet_pending_commands {}\n", pending_by_device.values().sum::<i64>()));
+    text
+}
+
+/// One CSV row per device, with a header row. Tags are joined with `;`.
+fn devices_csv(devices: &[DeviceInfo]) -> String {
+    let mut csv = String::from("id,name,device_type,status,latitude,longitude,battery,last_seen,tags\r\n");
+    for d in devices {
+        let row = [
+            d.id.clone(), d.name.clone(), d.device_type.clone(), d.status.as_str().to_string(),
+            d.latitude.to_string(), d.longitude.to_string(), d.battery.to_string(), d.last_seen.to_string(),
+            d.tags.join(";"),
+        ];
+        let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// A field quoted as RFC 4180 asks: only if it holds a comma, quote or line
+/// break, with its quotes doubled.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// The `?fields=` projection of a telemetry read, or `None` for whole records.
+fn query_fields(query_params: &HashMap<String, String>) -> Result<Option<Vec<&'static str>>, String> {
+    query_params.get("fields").map(|list| telemetry::parse_fields(list)).transpose()
+}
+
+/// Stream a device's telemetry as gzip-compressed NDJSON.
+/// No Content-Length: the body ends when the connection closes.
+fn send_telemetry_gz(stream: &mut Stream, reader: &TelemetryReader, device_id: &str, start: i64, end: i64, fields: Option<&[&str]>) {
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nContent-Encoding: gzip\r\nContent-Disposition: attachment; filename=\"{}.ndjson.gz\"\r\n{}Connection: close\r\n\r\n",
+        device_id, response_headers()
+    );
+    if stream.write_all(response.as_bytes()).is_err() {
+        return;
+    }
+    
+    let _ = write_telemetry_gz(&mut *stream, reader, device_id, start, end, fields);
+}
+
+/// The body of `send_telemetry_gz`: one gzip member of NDJSON records.
+fn write_telemetry_gz(out: impl Write, reader: &TelemetryReader, device_id: &str, start: i64, end: i64, fields: Option<&[&str]>) -> std::io::Result<()> {
+    let mut gz = GzipEncoder::new(out);
+    for item in reader.iter(device_id, start, end) {
+        // The status line is long gone: an unreadable day is logged and left out
+        let record = match item {
+            Ok(record) => record,
+            Err(e) => {
+                log!("⚠ Telemetry export of {} skipped a file: {}", device_id, e);
+                continue;
+            }
+        };
+        let mut line = match fields {
+            Some(fields) => record.project(fields).to_string(),
+            None => serde_json::to_string(&record).unwrap_or_default(),
+        };
+        line.push('\n');
+        gz.write_all(line.as_bytes())?;
+    }
+    gz.finish().map(|_| ())
+}
+
+/// Header lines every response carries: CORS, and the id of the request
+/// it answers.
+fn response_headers() -> String {
+    format!("{}{}", CORS_ALLOW_ORIGIN, trace::response_headers())
+}
+
 /// Send JSON response
-fn send_json(stream: &mut TcpStream, status: u16, data: &serde_json::Value) {
+fn send_json(stream: &mut Stream, status: u16, data: &serde_json::Value) {
+    send_json_with_headers(stream, status, data, "");
+}
+
+/// Send `data` with an ETag of its content, or just 304 Not Modified if
+/// the client's If-None-Match already names it. For what pollers fetch
+/// over and over and is often unchanged.
+fn send_json_tagged(stream: &mut Stream, request: &str, data: &serde_json::Value) {
+    let body = serde_json::to_string(data).unwrap_or_default();
+    let hash = Sha1::digest(body.as_bytes());
+    let etag = format!("\"{}\"", hash.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    let matched = header_value(request, "If-None-Match").is_some_and(|tags| {
+        tags.split(',').map(|tag| tag.trim().trim_start_matches("W/")).any(|tag| tag == etag || tag == "*")
+    });
+    if matched {
+        let response = format!(
+            "HTTP/1.1 304 Not Modified\r\nETag: {}\r\n{}Connection: close\r\n\r\n",
+            etag, response_headers()
+        );
+        let _ = stream.write_all(response.as_bytes());
+        return;
+    }
+    send_json_with_headers(stream, 200, data, &format!("ETag: {}\r\n", etag));
+}
+
+/// Send a JSON error telling the client to back off for `secs` seconds
+/// (429 or 503), so a well-behaved one knows when to try again.
+fn send_json_retry_after(stream: &mut Stream, status: u16, message: &str, secs: u64) {
+    let headers = format!("Retry-After: {}\r\n", secs.max(1));
+    send_json_with_headers(stream, status, &serde_json::json!({"error": message, "retry_after": secs.max(1)}), &headers);
+}
+
+/// `send_json` with extra header lines, each ending in CRLF.
+fn send_json_with_headers(stream: &mut Stream, status: u16, data: &serde_json::Value, headers: &str) {
     let body = serde_json::to_string(data).unwrap_or_default();
     let status_text = match status {
         200 => "OK",
         400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
         404 => "Not Found",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
         500 => "Internal Server Error",
         502 => "Bad Gateway",
+        503 => "Service Unavailable",
         _ => "Unknown",
     };
     
     let response = format!(
-        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, POST, DELETE, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\nConnection: close\r\n\r\n{}",
-        status, status_text, body.len(), body
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}{}Access-Control-Allow-Methods: {}\r\nAccess-Control-Allow-Headers: {}\r\nConnection: close\r\n\r\n{}",
+        status, status_text, body.len(), headers, response_headers(), API_METHODS, ALLOWED_HEADERS, body
     );
     let _ = stream.write_all(response.as_bytes());
 }
 
 /// Send JSON error response
-fn send_json_error(stream: &mut TcpStream, status: u16, message: &str) {
+fn send_json_error(stream: &mut Stream, status: u16, message: &str) {
     send_json(stream, status, &serde_json::json!({"error": message}));
 }
 
-/// Send CORS preflight response
-fn send_cors_preflight(stream: &mut TcpStream) {
-    let response = "HTTP/1.1 204 No Content\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, POST, DELETE, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\nAccess-Control-Max-Age: 86400\r\nConnection: close\r\n\r\n";
+/// Send CORS preflight response. The API allows its methods; static files
+/// are only read. Any origin, and whatever headers the browser asks to send:
+/// nothing here is guarded by the origin.
+fn send_cors_preflight(stream: &mut Stream, path: &str, request: &str) {
+    let methods = if path.starts_with("/api/") { API_METHODS } else { "GET, OPTIONS" };
+    let headers = header_value(request, "Access-Control-Request-Headers").unwrap_or(ALLOWED_HEADERS);
+    let response = format!(
+        "HTTP/1.1 204 No Content\r\n{}Access-Control-Allow-Methods: {}\r\nAccess-Control-Allow-Headers: {}\r\nAccess-Control-Max-Age: 86400\r\nConnection: close\r\n\r\n",
+        response_headers(), methods, headers
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+/// Send a static file's bytes.
+fn send_file(stream: &mut Stream, mime: &str, content: &[u8]) {
+    send_file_with_headers(stream, mime, content, "");
+}
+
+/// Send a file's bytes with extra headers (each ending in CRLF).
+fn send_file_with_headers(stream: &mut Stream, mime: &str, content: &[u8], headers: &str) {
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}{}Connection: close\r\n\r\n",
+        mime, content.len(), headers, response_headers()
+    );
+    let _ = stream.write_all(response.as_bytes());
+    let _ = stream.write_all(content);
+}
+
+/// Send `content` as a download to be saved as `filename`.
+fn send_attachment(stream: &mut Stream, mime: &str, filename: &str, content: &[u8]) {
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        mime, filename, content.len(), response_headers()
+    );
+    let _ = stream.write_all(response.as_bytes());
+    let _ = stream.write_all(content);
+}
+
+/// Send a static-file miss: plain text, since the client may have asked for
+/// an image or script rather than a page.
+fn send_not_found(stream: &mut Stream) {
+    let body = "Not Found";
+    let response = format!(
+        "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        body.len(), response_headers(), body
+    );
     let _ = stream.write_all(response.as_bytes());
 }
 
 /// Send an HTTP error response.
-fn send_error(stream: &mut TcpStream, code: u16, message: &str) {
+fn send_error(stream: &mut Stream, code: u16, message: &str) {
     let body = format!("<h1>{} {}</h1>", code, message);
     let response = format!(
-        "HTTP/1.1 {} {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
-        code, message, body.len(), body
+        "HTTP/1.1 {} {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        code, message, body.len(), response_headers(), body
     );
     let _ = stream.write_all(response.as_bytes());
 }
 
 /// Read HTTP request from stream (up to headers).
-pub fn read_request(stream: &mut TcpStream) -> Result<String, String> {
+pub fn read_request(stream: &mut Stream) -> Result<String, String> {
     let mut buffer = [0u8; 8192];
     let mut request = String::new();
     
@@ -463,3 +2292,94 @@ pub fn read_request(stream: &mut TcpStream) -> Result<String, String> {
     
     Ok(request)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::telemetry::{TelemetryRecord, TelemetryWriter};
+
+    /// A fresh directory under the system temp dir.
+    fn temp_dir(label: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("globalrts-http-{}-{}", label, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record(device_id: &str, timestamp: i64) -> TelemetryRecord {
+        TelemetryRecord {
+            timestamp,
+            device_id: device_id.to_string(),
+            latitude: 34.05 + timestamp as f64 * 1e-6,
+            longitude: -118.24,
+            altitude: 12.0,
+            heading: 45.0,
+            speed: 2.5,
+            battery: 77.0,
+            sensors: serde_json::json!({"temperature": 21.5}),
+            out_of_order: false,
+        }
+    }
+
+    /// Decompress with the crate's own decoder, which checks the CRC and
+    /// length a stock tool would.
+    fn gunzip(gz: &[u8]) -> String {
+        let mut text = String::new();
+        crate::gzip::GzipDecoder::new(gz).read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn first_static_dir_with_the_file_wins() {
+        let overlay = temp_dir("static-overlay");
+        let bundled = temp_dir("static-bundled");
+        fs::write(overlay.join("globalui.html"), "themed").unwrap();
+        fs::write(bundled.join("globalui.html"), "stock").unwrap();
+        fs::write(bundled.join("app.js"), "stock js").unwrap();
+        let dirs = vec![overlay.to_str().unwrap().to_string(), bundled.to_str().unwrap().to_string()];
+
+        let read = |dirs: &[String], path| read_static(dirs, path, false).map(|file| file.map(|f| f.content));
+
+        assert_eq!(read(&dirs, "/globalui.html"), Ok(Some(b"themed".to_vec())));
+        assert_eq!(read(&dirs, "/app.js"), Ok(Some(b"stock js".to_vec())));
+        assert_eq!(read(&dirs, "/missing.css"), Ok(None));
+        assert_eq!(read(&dirs[1..], "/globalui.html"), Ok(Some(b"stock".to_vec())));
+        let _ = fs::remove_dir_all(&overlay);
+        let _ = fs::remove_dir_all(&bundled);
+    }
+
+    #[test]
+    fn gzip_is_accepted_when_named_or_wildcarded_but_not_at_q_0() {
+        let accepts = |value: &str| accepts_gzip(&format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", value));
+        assert!(accepts("gzip, deflate, br"));
+        assert!(accepts("br;q=1.0, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("br, identity"));
+        assert!(!accepts_gzip("GET / HTTP/1.1\r\n\r\n"));
+    }
+
+    #[test]
+    fn gzip_download_matches_stored_records() {
+        let dir = temp_dir("gz-download");
+        let base = dir.to_str().unwrap();
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64;
+        let writer = TelemetryWriter::new(base);
+        for t in 0..50 {
+            writer.write(&record("robot-01", now - 100 + t)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let mut gz = Vec::new();
+        write_telemetry_gz(&mut gz, &TelemetryReader::new(base), "robot-01", now - 200, now + 1, None).unwrap();
+        let ndjson = gunzip(&gz);
+
+        let stored: Vec<String> = telemetry::read_range(&dir, "robot-01", now - 200, now + 1)
+            .map(|r| serde_json::to_string(&r).unwrap())
+            .collect();
+        assert_eq!(stored.len(), 50);
+        assert_eq!(ndjson.lines().collect::<Vec<_>>(), stored);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,74 @@
+//! # GlobalRTS Server
+//! 
+//! Command center for robot fleets.
+//! 
+//! Single binary. No runtime dependencies. 1000-year-proof.
+//! 
+//! ## Architecture
+//! 
+//! ```text
+//! Browser (GlobalUI)                    Devices (Robots/Phones)
+//!        │                                      │
+//!        └──────────── WebSocket ───────────────┘
+//!                          │
+//!                    ┌─────┴─────┐
+//!                    │  Server   │
+//!                    │           │
+//!                    │ ┌───────┐ │
+//!                    │ │ State │ │ ← SQLite (device registry, pairing)
+//!                    │ └───────┘ │
+//!                    │ ┌───────┐ │
+//!                    │ │ Telem │ │ ← Files (time-series data)
+//!                    │ └───────┘ │
+//!                    └───────────┘
+//! ```
+//!
+//! ## Device Connection Flow
+//! 
+//! 1. Device POSTs to /api/pair/request → Gets "pending" status
+//! 2. Server generates 6-digit code, broadcasts to GlobalUI
+//! 3. User tells device operator the code
+//! 4. Device POSTs to /api/pair/confirm with code → Gets auth token
+//! 5. Device connects via WebSocket with token → Fully connected
+
+//!
+//! ## Embedding
+//!
+//! The `globalrts` binary is a thin wrapper over this library. Another
+//! program can run the same server and stop it again:
+//!
+//! ```no_run
+//! use globalrts::{Config, Server};
+//!
+//! let handle = Server::run(Config { port: 8080, ..Config::default() }).unwrap();
+//! println!("serving on {}", handle.local_addr());
+//! handle.shutdown().unwrap();
+//! ```
+//!
+//! Data lives in `data/` under the working directory, as for the binary.
+
+pub mod protocol;
+pub mod websocket;
+pub mod state;
+pub mod telemetry;
+pub mod access;
+pub mod policy;
+pub mod version;
+pub mod sim;
+pub mod client;
+mod server;
+mod http;
+mod gzip;
+mod sha256;
+mod signing;
+mod commands;
+mod replay;
+mod appearance;
+mod webhook;
+mod preflight;
+mod tls;
+mod demo;
+mod trace;
+mod logfile;
+
+pub use server::{Config, Reloader, Server, ServerHandle};
@@ -0,0 +1,128 @@
+//! # Log File
+//!
+//! Where the server's log goes when GLOBALRTS_LOG_FILE names a file rather
+//! than leaving it on stdout. Once the file reaches its size limit it is
+//! rotated: `server.log` becomes `server.log.1`, `.1` becomes `.2`, and so
+//! on, and whatever would go past the number of files kept is deleted.
+//! Stdout is never rotated: in a container, whatever collects it does that.
+//!
+//! ```text
+//! server.log      ← being written
+//! server.log.1    ← the one before
+//! server.log.2
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A log file rotated by size.
+pub struct RotatingLog {
+    path: PathBuf,
+    /// Size past which the file is rotated, bytes. 0 = never.
+    max_bytes: u64,
+    /// Rotated files kept besides the one being written.
+    keep: u64,
+    file: File,
+    /// Bytes in `file`, counting what was there when it was opened.
+    written: u64,
+}
+
+impl RotatingLog {
+    /// Append to `path`, creating it (and its directory) if need be.
+    pub fn open(path: impl AsRef<Path>, max_bytes: u64, keep: u64) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        let file = append(&path)?;
+        let written = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(Self { path, max_bytes, keep, file, written })
+    }
+
+    /// Write one line, rotating first if it would take the file past the
+    /// limit. A line longer than the limit still goes in a file of its own.
+    pub fn write_line(&mut self, line: &str) -> Result<(), String> {
+        let len = line.len() as u64 + 1;
+        if self.max_bytes > 0 && self.written > 0 && self.written + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line).map_err(|e| e.to_string())?;
+        self.written += len;
+        Ok(())
+    }
+
+    /// Shift every kept file up one, drop the oldest, and start afresh.
+    fn rotate(&mut self) -> Result<(), String> {
+        // Anything past `keep`, including from a run that kept more
+        let mut n = self.keep + 1;
+        while self.numbered(n).exists() {
+            fs::remove_file(self.numbered(n)).map_err(|e| e.to_string())?;
+            n += 1;
+        }
+        if self.keep == 0 {
+            fs::remove_file(&self.path).map_err(|e| e.to_string())?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.numbered(n);
+                if from.exists() {
+                    fs::rename(&from, self.numbered(n + 1)).map_err(|e| e.to_string())?;
+                }
+            }
+            fs::rename(&self.path, self.numbered(1)).map_err(|e| e.to_string())?;
+        }
+        self.file = append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// `path` with `.n` on the end.
+    fn numbered(&self, n: u64) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+fn append(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writing_past_the_limit_rotates_and_prunes() {
+        let dir = std::env::temp_dir().join(format!("globalrts-logfile-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("server.log");
+        // A leftover from a run that kept more
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("server.log.3"), "old\n").unwrap();
+
+        // 10-byte lines, three to a file
+        let mut log = RotatingLog::open(&path, 30, 2).unwrap();
+        for n in 0..10 {
+            log.write_line(&format!("line {:03}", n)).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("server.log"), "line 009\n");
+        assert_eq!(read("server.log.1"), "line 006\nline 007\nline 008\n");
+        assert_eq!(read("server.log.2"), "line 003\nline 004\nline 005\n");
+        assert!(!dir.join("server.log.3").exists());
+
+        // Reopened, it counts what's there already
+        let mut log = RotatingLog::open(&path, 30, 2).unwrap();
+        log.write_line("line 010").unwrap();
+        log.write_line("line 011").unwrap();
+        log.write_line("line 012").unwrap();
+        assert_eq!(read("server.log"), "line 012\n");
+        assert_eq!(read("server.log.1"), "line 009\nline 010\nline 011\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
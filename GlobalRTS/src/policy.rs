@@ -0,0 +1,277 @@
+//! # Registration Policy
+//!
+//! Onboarding rules checked each time a device registers, before it is
+//! stored or comes online. A registration breaking one is refused with
+//! the reason; one that passes may pick up tags for its type.
+//!
+//! RULES:
+//! - With a list of allowed types, a device of any other type is refused.
+//! - With a name pattern, a device whose whole name doesn't match it is
+//!   refused.
+//! - Tags listed for the device's type are added to any it has already.
+//!
+//! Configure with GLOBALRTS_REGISTER_TYPES (comma-separated),
+//! GLOBALRTS_REGISTER_NAME_PATTERN and GLOBALRTS_REGISTER_TAGS
+//! (`drone=aerial,fleet-a;sensor=fixed`).
+//!
+//! PATTERNS:
+//! A small subset of regular expressions, matched against the whole name:
+//! literals, `.`, classes (`[a-z0-9_-]`, `[^ ]`), `\d` `\w` `\s`, and the
+//! quantifiers `*` `+` `?` `{n}` `{n,}` `{n,m}`. A leading `^` and a
+//! trailing `$` are allowed and change nothing. Groups and alternation
+//! are refused rather than half-supported.
+
+use std::collections::BTreeMap;
+
+/// What a registration is checked against. The default allows anything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RegistrationPolicy {
+    /// Device types allowed to register; empty = any.
+    types: Vec<String>,
+    /// Pattern every device name must match, if any.
+    name_pattern: Option<NamePattern>,
+    /// Tags added to devices of each type.
+    tags: BTreeMap<String, Vec<String>>,
+}
+
+impl RegistrationPolicy {
+    pub fn new(types: Vec<String>, name_pattern: Option<&str>, tags: BTreeMap<String, Vec<String>>) -> Result<Self, String> {
+        let name_pattern = name_pattern.map(NamePattern::parse).transpose()?;
+        Ok(Self { types, name_pattern, tags })
+    }
+
+    /// Device types allowed to register; empty = any.
+    pub fn types(&self) -> &[String] {
+        &self.types
+    }
+
+    /// The name pattern, as written.
+    pub fn name_pattern(&self) -> Option<&str> {
+        self.name_pattern.as_ref().map(|p| p.source.as_str())
+    }
+
+    /// Tags added to devices of each type.
+    pub fn tags(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.tags
+    }
+
+    /// Check a registering device. The tags it gets, or why it is refused.
+    pub fn check(&self, device_type: &str, name: &str) -> Result<Vec<String>, String> {
+        if !self.types.is_empty() && !self.types.iter().any(|t| t == device_type) {
+            return Err(format!("device type {} is not allowed here", device_type));
+        }
+        if let Some(pattern) = self.name_pattern.as_ref().filter(|p| !p.matches(name)) {
+            return Err(format!("name {:?} doesn't match {}", name, pattern.source));
+        }
+        Ok(self.tags.get(device_type).cloned().unwrap_or_default())
+    }
+}
+
+// ============================================================================
+// NAME PATTERNS
+// ============================================================================
+
+/// A compiled name pattern; see the module docs for what it supports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamePattern {
+    source: String,
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Atom {
+    Char(char),
+    Any,
+    /// Inclusive ranges, and whether the class is negated.
+    Class(Vec<(char, char)>, bool),
+}
+
+impl Atom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Atom::Char(expected) => c == *expected,
+            Atom::Any => true,
+            Atom::Class(ranges, negated) => ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated,
+        }
+    }
+}
+
+/// An atom repeated `min` to `max` times (None = unbounded).
+#[derive(Debug, Clone, PartialEq)]
+struct Piece {
+    atom: Atom,
+    min: usize,
+    max: Option<usize>,
+}
+
+impl NamePattern {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let body = source.strip_prefix('^').unwrap_or(source);
+        let body = match body.strip_suffix('$') {
+            Some(rest) if !rest.ends_with('\\') => rest,
+            _ => body,
+        };
+        let mut chars = body.chars().peekable();
+        let mut pieces = Vec::new();
+        while let Some(c) = chars.next() {
+            let atom = match c {
+                '.' => Atom::Any,
+                '\\' => escape(chars.next().ok_or("trailing \\")?),
+                '[' => {
+                    let negated = chars.next_if_eq(&'^').is_some();
+                    let mut ranges = Vec::new();
+                    loop {
+                        let lo = match chars.next().ok_or("unclosed [")? {
+                            ']' if !ranges.is_empty() => break,
+                            '\\' => {
+                                let c = chars.next().ok_or("trailing \\")?;
+                                if let Some(more) = class_escape(c) {
+                                    ranges.extend(more);
+                                    continue;
+                                }
+                                c
+                            }
+                            c => c,
+                        };
+                        // A `-` last in the class is itself
+                        let mut ahead = chars.clone();
+                        let hi = match (ahead.next(), ahead.next()) {
+                            (Some('-'), Some(hi)) if hi != ']' => {
+                                chars.nth(1);
+                                hi
+                            }
+                            _ => lo,
+                        };
+                        if hi < lo {
+                            return Err(format!("range {}-{} is backwards", lo, hi));
+                        }
+                        ranges.push((lo, hi));
+                    }
+                    Atom::Class(ranges, negated)
+                }
+                '(' | ')' | '|' => return Err("groups and alternation aren't supported".to_string()),
+                '*' | '+' | '?' | '{' => return Err(format!("{} has nothing to repeat", c)),
+                c => Atom::Char(c),
+            };
+            let (min, max) = match chars.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    chars.next();
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next().ok_or("unclosed {")? {
+                            '}' => break,
+                            c => spec.push(c),
+                        }
+                    }
+                    let count = |s: &str| s.trim().parse::<usize>().map_err(|_| format!("bad repeat {{{}}}", spec));
+                    let (min, max) = match spec.split_once(',') {
+                        None => (count(&spec)?, Some(count(&spec)?)),
+                        Some((min, "")) => (count(min)?, None),
+                        Some((min, max)) => (count(min)?, Some(count(max)?)),
+                    };
+                    if max.is_some_and(|max| max < min) {
+                        return Err(format!("bad repeat {{{}}}", spec));
+                    }
+                    pieces.push(Piece { atom, min, max });
+                    continue;
+                }
+                _ => (1, Some(1)),
+            };
+            if (min, max) != (1, Some(1)) {
+                chars.next();
+            }
+            pieces.push(Piece { atom, min, max });
+        }
+        Ok(Self { source: source.to_string(), pieces })
+    }
+
+    /// Whether the pattern matches all of `text`.
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        // Positions from which the rest of the pattern is known to fail,
+        // so backtracking visits each (piece, position) once
+        let mut failed = vec![false; (self.pieces.len() + 1) * (text.len() + 1)];
+        self.match_from(0, 0, &text, &mut failed)
+    }
+
+    fn match_from(&self, piece: usize, pos: usize, text: &[char], failed: &mut [bool]) -> bool {
+        let Some(p) = self.pieces.get(piece) else {
+            return pos == text.len();
+        };
+        let state = piece * (text.len() + 1) + pos;
+        if failed[state] {
+            return false;
+        }
+        // How far this piece can reach, then try the longest first
+        let mut reach = 0;
+        while p.max.is_none_or(|max| reach < max) && text.get(pos + reach).is_some_and(|c| p.atom.matches(*c)) {
+            reach += 1;
+        }
+        let found = (p.min..=reach).rev().any(|n| self.match_from(piece + 1, pos + n, text, failed));
+        if !found {
+            failed[state] = true;
+        }
+        found
+    }
+}
+
+/// What `\c` stands for: a class for `d`, `w` and `s`, else `c` itself.
+fn escape(c: char) -> Atom {
+    class_escape(c).map(|ranges| Atom::Class(ranges, false)).unwrap_or(Atom::Char(c))
+}
+
+/// The ranges `\d`, `\w` and `\s` stand for.
+fn class_escape(c: char) -> Option<Vec<(char, char)>> {
+    match c {
+        'd' => Some(vec![('0', '9')]),
+        'w' => Some(vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]),
+        's' => Some(vec![(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r')]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        NamePattern::parse(pattern).unwrap().matches(text)
+    }
+
+    #[test]
+    fn patterns_match_whole_names() {
+        assert!(matches("^robot-\\d{2}$", "robot-07"));
+        assert!(!matches("^robot-\\d{2}$", "robot-7"));
+        assert!(!matches("robot-\\d{2}", "my-robot-07"));
+        assert!(matches("[a-z]+-[0-9]{1,3}", "drone-123"));
+        assert!(!matches("[a-z]+-[0-9]{1,3}", "Drone-123"));
+        assert!(matches("[^ ]+", "no-spaces"));
+        assert!(!matches("[^ ]+", "has spaces"));
+        assert!(matches("[\\w-]+\\.v?\\d*", "cam_01.v2"));
+        assert!(matches("a.*b", "a-anything-b"));
+        assert!(matches("x{2,}", "xxxx"));
+        assert!(matches("[a-]", "-"));
+        assert!(!matches("a*a*a*a*a*a*a*a*a*a*b", &"a".repeat(60)));
+    }
+
+    #[test]
+    fn unsupported_or_broken_patterns_are_refused() {
+        for pattern in ["(robot|drone)-\\d+", "robot|drone", "*x", "[abc", "x{3,1}", "x{a}", "[z-a]", "x\\"] {
+            assert!(NamePattern::parse(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn a_policy_refuses_types_and_names_and_tags_the_rest() {
+        let tags = BTreeMap::from([("drone".to_string(), vec!["aerial".to_string()])]);
+        let policy = RegistrationPolicy::new(vec!["drone".to_string(), "robot".to_string()], Some("[a-z]+-\\d+"), tags).unwrap();
+        assert_eq!(policy.check("drone", "drone-1").unwrap(), ["aerial"]);
+        assert!(policy.check("robot", "robot-2").unwrap().is_empty());
+        assert!(policy.check("sensor", "sensor-1").unwrap_err().contains("sensor is not allowed"));
+        assert!(policy.check("robot", "Robot Two").unwrap_err().contains("doesn't match"));
+        assert_eq!(RegistrationPolicy::default().check("anything", "Any Name!").unwrap(), Vec::<String>::new());
+    }
+}
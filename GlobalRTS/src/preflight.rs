@@ -0,0 +1,179 @@
+//! # Startup Self-Check
+//!
+//! Checks run before the server binds, so a bad deployment is diagnosed up
+//! front instead of surfacing as a cryptic error on the first write.
+//!
+//! A failure (the data directory can't be written, the database won't
+//! open) stops the server from starting. A warning (little disk space
+//! left, no GlobalUI to serve) is printed and the server starts anyway:
+//! the API and devices work without them.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::state::StateDb;
+use crate::trace::log;
+
+/// How one check came out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Pass(String),
+    Warn(String),
+    Fail(String),
+}
+
+/// Every check, in the order run.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub checks: Vec<(&'static str, Outcome)>,
+}
+
+impl Report {
+    /// Whether nothing failed. Warnings don't count.
+    pub fn passed(&self) -> bool {
+        !self.checks.iter().any(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+    }
+
+    /// The failures, one per line, for the error that stops startup.
+    pub fn failures(&self) -> String {
+        self.checks.iter()
+            .filter_map(|(name, outcome)| match outcome {
+                Outcome::Fail(why) => Some(format!("{}: {}", name, why)),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    pub fn print(&self) {
+        log!("Preflight:");
+        for (name, outcome) in &self.checks {
+            match outcome {
+                Outcome::Pass(what) => log!("  ✓ {}: {}", name, what),
+                Outcome::Warn(what) => log!("  ⚠ {}: {}", name, what),
+                Outcome::Fail(what) => log!("  ✗ {}: {}", name, what),
+            }
+        }
+        log!();
+    }
+}
+
+/// Check `data_dir` (and the database in it) can be written, that it has
+/// `min_free_mb` to spare, and that one of `static_dirs` has GlobalUI.
+pub fn run(data_dir: &Path, static_dirs: &[String], min_free_mb: u64) -> Report {
+    let mut report = Report::default();
+    let writable = check_writable(data_dir);
+    let ok = matches!(writable, Outcome::Pass(_));
+    report.checks.push(("data directory", writable));
+    if ok {
+        report.checks.push(("database", check_database(&data_dir.join("state.db"))));
+        report.checks.push(("disk space", check_disk_space(data_dir, min_free_mb)));
+    }
+    report.checks.push(("GlobalUI", check_ui(static_dirs)));
+    report
+}
+
+/// The data directory and its telemetry directory exist, or can be made,
+/// and a file can be written there.
+fn check_writable(data_dir: &Path) -> Outcome {
+    let telemetry = data_dir.join("telemetry");
+    if let Err(e) = fs::create_dir_all(&telemetry) {
+        return Outcome::Fail(format!("can't create {}: {}", telemetry.display(), e));
+    }
+    // Root writes through permission bits; the operator still meant read-only
+    for dir in [data_dir, telemetry.as_path()] {
+        if fs::metadata(dir).map(|m| m.permissions().readonly()).unwrap_or(false) {
+            return Outcome::Fail(format!("{} is read-only", dir.display()));
+        }
+    }
+    let probe = telemetry.join(".preflight");
+    let written = fs::write(&probe, b"ok");
+    let _ = fs::remove_file(&probe);
+    match written {
+        Ok(()) => Outcome::Pass(format!("{} is writable", data_dir.display())),
+        Err(e) => Outcome::Fail(format!("can't write in {}: {}", telemetry.display(), e)),
+    }
+}
+
+/// The database opens (and migrates) and takes a write.
+fn check_database(path: &Path) -> Outcome {
+    let opened = path.to_str()
+        .ok_or_else(|| "path isn't UTF-8".to_string())
+        .and_then(StateDb::open)
+        .and_then(|db| db.with_transaction(|_| Ok(())));
+    match opened {
+        Ok(()) => Outcome::Pass(format!("{} opens", path.display())),
+        Err(e) => Outcome::Fail(format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// Free space where the data goes, from `df`.
+fn check_disk_space(data_dir: &Path, min_free_mb: u64) -> Outcome {
+    match free_mb(data_dir) {
+        Some(free) if free < min_free_mb => Outcome::Warn(format!("{} MB free, under {} MB", free, min_free_mb)),
+        Some(free) => Outcome::Pass(format!("{} MB free", free)),
+        None => Outcome::Warn("couldn't run df to check".to_string()),
+    }
+}
+
+/// Megabytes available to us on the filesystem holding `path`.
+fn free_mb(path: &Path) -> Option<u64> {
+    let output = Command::new("df").arg("-Pk").arg(path).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    let text = String::from_utf8_lossy(&output.stdout);
+    let available_kb: u64 = text.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()?;
+    Some(available_kb / 1024)
+}
+
+/// Some static directory has globalui.html.
+fn check_ui(static_dirs: &[String]) -> Outcome {
+    match static_dirs.iter().find(|dir| Path::new(dir).join("globalui.html").is_file()) {
+        Some(dir) => Outcome::Pass(format!("{}/globalui.html", dir)),
+        None => Outcome::Warn(format!("no globalui.html in {}; only the API is served", static_dirs.join(", "))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_dir(label: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("globalrts-preflight-{}-{}", label, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_read_only_data_dir_fails_cleanly() {
+        let data = temp_dir("read-only");
+        fs::set_permissions(&data, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let report = run(&data, &["no-such-public".to_string()], 0);
+        assert!(!report.passed());
+        assert!(report.failures().starts_with("data directory: "), "{}", report.failures());
+        // Nothing after a failed data directory is tried against it
+        assert_eq!(report.checks.iter().map(|(name, _)| *name).collect::<Vec<_>>(), ["data directory", "GlobalUI"]);
+        assert!(!data.join("state.db").exists());
+
+        fs::set_permissions(&data, fs::Permissions::from_mode(0o755)).unwrap();
+        let _ = fs::remove_dir_all(&data);
+    }
+
+    #[test]
+    fn a_missing_ui_only_warns() {
+        let data = temp_dir("healthy");
+        let report = run(&data.join("data"), &["no-such-public".to_string()], 0);
+        assert!(report.passed(), "{:?}", report);
+        assert!(matches!(report.checks[0].1, Outcome::Pass(_)), "{:?}", report);
+        assert!(matches!(report.checks[1].1, Outcome::Pass(_)), "{:?}", report);
+        assert!(matches!(report.checks.last().unwrap().1, Outcome::Warn(_)), "{:?}", report);
+        assert!(data.join("data/state.db").is_file());
+        let _ = fs::remove_dir_all(&data);
+    }
+}
@@ -4,6 +4,9 @@
 //! 
 //! All messages are JSON. Simple, human-readable, debuggable.
 //! Any AI or human can inspect WebSocket traffic and understand it immediately.
+//! The one exception is opt-in: a device that negotiates
+//! `BINARY_TELEMETRY_SUBPROTOCOL` may send telemetry as fixed-layout binary
+//! frames, for rates where parsing JSON is the bottleneck.
 //!
 //! ## Device Connection Flow
 //! 
@@ -18,6 +21,10 @@
 
 use serde::{Deserialize, Serialize};
 
+/// Version of the message protocol below. Bumped when a change would break
+/// an existing device or UI.
+pub const PROTOCOL_VERSION: u32 = 1;
+
 // ============================================================================
 // DEVICE → SERVER MESSAGES
 // ============================================================================
@@ -49,11 +56,17 @@ pub struct RegisterMessage {
     #[serde(default)]
     pub altitude: f64,
     
-    /// Device capabilities (optional, for future use)
+    /// What the device can do, e.g. `camera`. Stored when given;
+    /// `device:update_info` changes it later.
     #[serde(default)]
     pub capabilities: Vec<String>,
 }
 
+/// The WebSocket subprotocol a device offers to send telemetry as binary
+/// frames, each one `telemetry::BINARY_RECORD_LEN` bytes. JSON messages
+/// work as usual alongside them.
+pub const BINARY_TELEMETRY_SUBPROTOCOL: &str = "globalrts.binary-telemetry";
+
 /// Telemetry update. Sent frequently (every 100ms - 1s).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TelemetryMessage {
@@ -69,6 +82,105 @@ pub struct TelemetryMessage {
     pub battery: f64,
     #[serde(default)]
     pub sensors: serde_json::Value,
+    /// Ask for a `telemetry:ack` once the record is stored. Off by default:
+    /// high-rate devices stay fire-and-forget.
+    #[serde(default)]
+    pub ack: bool,
+}
+
+/// Longest sleep a device may announce, in seconds.
+pub const MAX_SLEEP_SECS: i64 = 7 * 86400;
+
+/// A device going into a low-power sleep. It may stay connected or hang
+/// up; either way it isn't offline until `wake_at` has passed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SleepMessage {
+    /// When it expects to wake, Unix seconds.
+    pub wake_at: i64,
+}
+
+impl SleepMessage {
+    /// Why the server won't take this sleep, if it won't.
+    pub fn validate(&self, now: i64) -> Result<(), String> {
+        if self.wake_at <= now || self.wake_at > now + MAX_SLEEP_SECS {
+            return Err(format!("wake_at must be in the next {} seconds", MAX_SLEEP_SECS));
+        }
+        Ok(())
+    }
+}
+
+/// Severities an alert may carry, least to most urgent.
+pub const ALERT_SEVERITIES: [&str; 3] = ["info", "warning", "critical"];
+
+/// Longest alert code, in bytes.
+pub const MAX_ALERT_CODE: usize = 64;
+
+/// Longest alert message, in bytes.
+pub const MAX_ALERT_MESSAGE: usize = 1024;
+
+/// A discrete event the device wants an operator to see: an obstacle, a
+/// failed task, an emergency stop. Unlike telemetry, every one is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertMessage {
+    /// One of ALERT_SEVERITIES.
+    pub severity: String,
+    /// Machine-readable, e.g. `obstacle_detected`.
+    pub code: String,
+    /// Human-readable detail.
+    #[serde(default)]
+    pub message: String,
+}
+
+impl AlertMessage {
+    /// Why the server won't take this alert, if it won't.
+    pub fn validate(&self) -> Result<(), String> {
+        if !ALERT_SEVERITIES.contains(&self.severity.as_str()) {
+            return Err(format!("severity must be one of {}", ALERT_SEVERITIES.join(", ")));
+        }
+        if self.code.is_empty() || self.code.len() > MAX_ALERT_CODE {
+            return Err(format!("code must be 1 to {} bytes", MAX_ALERT_CODE));
+        }
+        if self.message.len() > MAX_ALERT_MESSAGE {
+            return Err(format!("message exceeds {} bytes", MAX_ALERT_MESSAGE));
+        }
+        Ok(())
+    }
+}
+
+/// Most capabilities or sensors a device may declare.
+pub const MAX_INFO_ITEMS: usize = 64;
+
+/// Longest capability, sensor name or firmware version, in bytes.
+pub const MAX_INFO_TEXT: usize = 64;
+
+/// A device describing itself anew mid-session, e.g. after an OTA update,
+/// as `device:update_info`. Fields left out stay as they were.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceInfoUpdate {
+    #[serde(default)]
+    pub capabilities: Option<Vec<String>>,
+    #[serde(default)]
+    pub firmware_version: Option<String>,
+    /// Names of the sensors the device reports, e.g. `temperature`.
+    #[serde(default)]
+    pub sensors: Option<Vec<String>>,
+}
+
+impl DeviceInfoUpdate {
+    /// Why the server won't take this update, if it won't.
+    pub fn validate(&self) -> Result<(), String> {
+        let text_ok = |s: &str| !s.is_empty() && s.len() <= MAX_INFO_TEXT;
+        for (field, items) in [("capabilities", &self.capabilities), ("sensors", &self.sensors)] {
+            let Some(items) = items else { continue };
+            if items.len() > MAX_INFO_ITEMS || !items.iter().all(|item| text_ok(item)) {
+                return Err(format!("{} must be at most {} entries of 1 to {} bytes", field, MAX_INFO_ITEMS, MAX_INFO_TEXT));
+            }
+        }
+        if self.firmware_version.as_deref().is_some_and(|v| !text_ok(v)) {
+            return Err(format!("firmware_version must be 1 to {} bytes", MAX_INFO_TEXT));
+        }
+        Ok(())
+    }
 }
 
 // ============================================================================
@@ -82,19 +194,196 @@ pub struct SendCommand {
     pub command_type: String,
     #[serde(default)]
     pub payload: serde_json::Value,
+    /// Deliver marked `dryRun`: the device logs what it would do instead of
+    /// doing it. Saved with status `dry_run`.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// The UI's own id for this request, echoed as `requestId` on
+    /// command:sent, command:rejected and every command:status after.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Send only if the device meets this, e.g. `battery > 30 AND status == online`;
+    /// otherwise the command is saved as `skipped` with the reason.
+    #[serde(default)]
+    pub precondition: Option<String>,
+    /// Makes a retry safe: a second command to the same device with the same
+    /// key, within the dedupe window, is answered with the first instead of
+    /// being dispatched again.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// POSTed the command's outcome once the device finishes with it, or it
+    /// times out: for automation that doesn't keep a connection open.
+    #[serde(default)]
+    pub callback_url: Option<String>,
+}
+
+// ============================================================================
+// COMMAND PAYLOADS
+// ============================================================================
+
+/// `navigate`: where to go. Altitude is optional.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NavigatePayload {
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+}
+
+impl NavigatePayload {
+    /// Why the device can't go there, if it can't.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(-90.0..=90.0).contains(&self.latitude) {
+            return Err(format!("latitude {} out of range [-90, 90]", self.latitude));
+        }
+        if !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(format!("longitude {} out of range [-180, 180]", self.longitude));
+        }
+        Ok(())
+    }
+}
+
+/// `reboot` and `shutdown`: power control, which must be asked for in so
+/// many words. A payload without `"confirm": true` is refused.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PowerPayload {
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+impl PowerPayload {
+    /// Why `command_type` can't go out with this payload, if it can't.
+    pub fn validate(&self, command_type: &str) -> Result<(), String> {
+        if !self.confirm {
+            return Err(format!("{} must be confirmed with \"confirm\": true", command_type));
+        }
+        Ok(())
+    }
+}
+
+/// `stop`, `ring`, `photo` and `poll` take nothing: `{}` or no payload.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmptyPayload {}
+
+/// A command's payload, typed for the built-in command types. Any other
+/// type is the device's own, and its payload stays as JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandPayload {
+    Navigate(NavigatePayload),
+    Stop(EmptyPayload),
+    Ring(EmptyPayload),
+    Photo(EmptyPayload),
+    Poll(EmptyPayload),
+    Reboot(PowerPayload),
+    Shutdown(PowerPayload),
+    Other(serde_json::Value),
+}
+
+impl CommandPayload {
+    /// The command types with a typed payload.
+    pub const BUILT_IN: [&'static str; 7] = ["navigate", "stop", "ring", "photo", "poll", "reboot", "shutdown"];
+    
+    /// The command types that cycle or cut a device's power: only the
+    /// admin may send them, and each is written to the audit log.
+    pub const POWER: [&'static str; 2] = ["reboot", "shutdown"];
+    
+    /// Read `payload` as `command_type`'s. A missing field, a field of the
+    /// wrong type or one the type doesn't have (a typo) is an error, as is
+    /// a navigate target off the globe, or power control not confirmed.
+    pub fn parse(command_type: &str, payload: &serde_json::Value) -> Result<Self, String> {
+        fn typed<T: serde::de::DeserializeOwned>(payload: &serde_json::Value) -> Result<T, String> {
+            // No payload at all is an empty one
+            let payload = match payload {
+                serde_json::Value::Null => serde_json::json!({}),
+                serde_json::Value::Object(_) => payload.clone(),
+                _ => return Err("payload must be an object".to_string()),
+            };
+            serde_json::from_value(payload).map_err(|e| e.to_string())
+        }
+        match command_type {
+            "navigate" => {
+                let navigate: NavigatePayload = typed(payload)?;
+                navigate.validate()?;
+                Ok(CommandPayload::Navigate(navigate))
+            }
+            "stop" => typed(payload).map(CommandPayload::Stop),
+            "ring" => typed(payload).map(CommandPayload::Ring),
+            "photo" => typed(payload).map(CommandPayload::Photo),
+            "poll" => typed(payload).map(CommandPayload::Poll),
+            "reboot" | "shutdown" => {
+                let power: PowerPayload = typed(payload)?;
+                power.validate(command_type)?;
+                Ok(match command_type {
+                    "reboot" => CommandPayload::Reboot(power),
+                    _ => CommandPayload::Shutdown(power),
+                })
+            }
+            _ => Ok(CommandPayload::Other(payload.clone())),
+        }
+    }
 }
 
 // ============================================================================
 // SERVER → GLOBALUI MESSAGES  
 // ============================================================================
 
+/// Where a device stands in the registry. Sent as lowercase strings.
+/// "Stale" isn't one: it's worked out from `last_seen` when asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceStatus {
+    /// Connected and registered.
+    Online,
+    /// Paired, not connected.
+    Offline,
+    /// Its token was withdrawn. Pairing again makes it offline.
+    Revoked,
+    /// Said it's in a low-power sleep until `wake_at`. Not offline or
+    /// stale before then, connected or not; commands wait for it to wake.
+    Sleeping,
+}
+
+impl DeviceStatus {
+    pub const ALL: [DeviceStatus; 4] = [DeviceStatus::Online, DeviceStatus::Offline, DeviceStatus::Revoked, DeviceStatus::Sleeping];
+    
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceStatus::Online => "online",
+            DeviceStatus::Offline => "offline",
+            DeviceStatus::Revoked => "revoked",
+            DeviceStatus::Sleeping => "sleeping",
+        }
+    }
+    
+    /// Whether a device may go from this status to `next`. A revoked device
+    /// can't come straight back online, or go to sleep: it has to be paired
+    /// again first.
+    pub fn can_become(self, next: DeviceStatus) -> bool {
+        !(self == DeviceStatus::Revoked && matches!(next, DeviceStatus::Online | DeviceStatus::Sleeping))
+    }
+}
+
+impl std::str::FromStr for DeviceStatus {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, String> {
+        DeviceStatus::ALL.into_iter().find(|status| status.as_str() == s).ok_or_else(|| {
+            let known: Vec<&str> = DeviceStatus::ALL.iter().map(|status| status.as_str()).collect();
+            format!("unknown status {:?} (known: {})", s, known.join(", "))
+        })
+    }
+}
+
 /// Device info for UI display.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceInfo {
     pub id: String,
     pub name: String,
     pub device_type: String,
-    pub status: String,
+    pub status: DeviceStatus,
     pub latitude: f64,
     pub longitude: f64,
     pub altitude: f64,
@@ -102,6 +391,32 @@ pub struct DeviceInfo {
     pub speed: f64,
     pub battery: f64,
     pub last_seen: i64,
+    /// Commands waiting for the device to come online.
+    #[serde(default)]
+    pub queued_commands: i64,
+    /// Commands not yet delivered: queued, and held while in maintenance.
+    #[serde(default)]
+    pub pending_commands: i64,
+    /// `#rrggbb` to draw the device in: chosen, or derived from its id.
+    #[serde(default)]
+    pub color: String,
+    /// Icon to draw the device with: chosen, or derived from its type.
+    #[serde(default)]
+    pub icon: String,
+    /// What the device said it can do.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub firmware_version: Option<String>,
+    /// Names of the sensors the device said it reports.
+    #[serde(default)]
+    pub sensors: Vec<String>,
+    /// When a sleeping device said it would wake, Unix seconds.
+    #[serde(default)]
+    pub wake_at: Option<i64>,
+    /// Labels the registration policy gave it.
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 // ============================================================================
@@ -140,25 +455,42 @@ impl Envelope {
 //   - telemetry: Position/sensor updates
 //   - command:ack: Acknowledges receipt of command
 //   - command:complete: Command finished executing
+//   - alert: Discrete event (severity, code, message)
+//   - sleep: Going into a low-power sleep until wake_at
 //
 // Server → Device:
 //   - registered: Confirms registration
+//   - telemetry:ack: Telemetry stored (only when the device asked with ack: true)
 //   - error: Authentication/other errors
 //   - command: Execute a command
 //
 // UI → Server:
-//   - getDevices: Request list of all devices
+//   - getDevices: Request list of all devices ({"deltas": true} for deltas after it)
 //   - sendCommand: Send command to a device
 //   - dismissPairing: Dismiss/reject a pairing request
 //   - revokeDevice: Remove a device from the system
+//   - getStats: Request the fleet's headline numbers (as GET /api/stats)
+//   - batch: Several of the above, answered together ({"id", "requests": [...]})
 //
 // Server → UI:
 //   - devices:list: Full list of devices
+//   - stats: Fleet headline numbers, for getStats
+//   - batch:result: What each request of a batch got back, in order
 //   - device:online: Device connected
 //   - device:offline: Device disconnected
+//   - device:sleeping: Device went to sleep until wakeAt
 //   - device:update: Telemetry update
+//   - devices:update: Batch of coalesced telemetry updates
 //   - device:revoked: Device was removed
+//   - devices:added: Devices entered the registry (UIs on deltas)
+//   - devices:removed: Device ids that left the registry (UIs on deltas)
+//   - devices:changed: Partial devices, id plus what changed (UIs on deltas)
 //   - pairing:requests: List of pending pairing requests
 //   - command:sent: Command was sent to device
+//   - command:rejected: Command payload failed validation (not sent)
+//   - command:status: Lifecycle transition (held, queued, sent, delivered, completed, timed_out, dry_run, skipped)
 //   - command:ack: Device acknowledged command
 //   - command:complete: Device completed command
+//   - alert:new: A device raised an alert
+//   - alert:acknowledged: An operator acknowledged an alert
+//   - maintenance:state: Where command dispatch is paused (global, devices)
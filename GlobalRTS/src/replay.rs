@@ -0,0 +1,154 @@
+//! # Telemetry Replay
+//!
+//! Plays a device's recorded track back to connected UIs, for demos and
+//! incident review. Records are read from the day files as they're due and
+//! sent as `replay:update`, spaced as they were recorded divided by the
+//! speed. A replay never touches the live device state: UIs tell replayed
+//! positions from live ones by the message type.
+//!
+//! { "type": "replay:update", "data": { "replayId": "...", "deviceId": "robot-01", "timestamp": 1700000000, ... } }
+//! { "type": "replay:end", "data": { "replayId": "...", "deviceId": "robot-01", "records": 42, "cancelled": false } }
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::telemetry::TelemetryRecord;
+
+/// Fastest playback, as a multiple of real time.
+pub const MAX_SPEED: f64 = 1000.0;
+
+/// Longest wait between two replayed records, however far apart they were
+/// recorded: a device that was off overnight doesn't stall the replay.
+const MAX_PAUSE: Duration = Duration::from_secs(5);
+
+/// How often a waiting replay checks whether it was cancelled.
+const CANCEL_CHECK: Duration = Duration::from_millis(100);
+
+/// A running replay. Clones share the cancel flag.
+#[derive(Clone)]
+pub struct Replay {
+    pub id: String,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Replay {
+    pub fn new(id: String) -> Self {
+        Self { id, cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Stop at the next record.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Whether `other` is this same replay (not just one with the same id).
+    pub fn is(&self, other: &Replay) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+
+    /// Emit each record's `replay:update` data as it falls due. Returns how
+    /// many were emitted; fewer than there were if cancelled.
+    pub fn run(&self, records: impl Iterator<Item = TelemetryRecord>, device_id: &str, speed: f64, mut emit: impl FnMut(serde_json::Value)) -> usize {
+        let mut emitted = 0;
+        let mut previous = None;
+        for record in records {
+            if let Some(previous) = previous {
+                if !self.sleep(pause(previous, record.timestamp, speed)) {
+                    break;
+                }
+            }
+            if self.is_cancelled() {
+                break;
+            }
+            previous = Some(record.timestamp);
+            emit(update(&self.id, device_id, &record));
+            emitted += 1;
+        }
+        emitted
+    }
+
+    /// Sleep for `duration` unless cancelled first. False if cancelled.
+    fn sleep(&self, mut duration: Duration) -> bool {
+        while !duration.is_zero() {
+            if self.is_cancelled() {
+                return false;
+            }
+            let step = duration.min(CANCEL_CHECK);
+            thread::sleep(step);
+            duration -= step;
+        }
+        !self.is_cancelled()
+    }
+}
+
+/// Wall-clock wait between records recorded at `previous` and `next`.
+pub fn pause(previous: i64, next: i64, speed: f64) -> Duration {
+    let gap = (next - previous).max(0) as f64 / speed;
+    Duration::from_secs_f64(gap).min(MAX_PAUSE)
+}
+
+/// The `replay:update` data for one record.
+fn update(replay_id: &str, device_id: &str, record: &TelemetryRecord) -> serde_json::Value {
+    serde_json::json!({
+        "replayId": replay_id,
+        "deviceId": device_id,
+        "timestamp": record.timestamp,
+        "latitude": record.latitude,
+        "longitude": record.longitude,
+        "altitude": record.altitude,
+        "heading": record.heading,
+        "speed": record.speed,
+        "battery": record.battery,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: i64, latitude: f64) -> TelemetryRecord {
+        TelemetryRecord {
+            timestamp,
+            device_id: "robot-01".to_string(),
+            latitude,
+            longitude: -118.0,
+            altitude: 0.0,
+            heading: 0.0,
+            speed: 0.0,
+            battery: 90.0,
+            sensors: serde_json::Value::Null,
+            out_of_order: false,
+        }
+    }
+
+    #[test]
+    fn pauses_scale_with_speed_and_are_capped() {
+        assert_eq!(pause(100, 110, 10.0), Duration::from_secs(1));
+        assert_eq!(pause(100, 101, 4.0), Duration::from_millis(250));
+        assert_eq!(pause(100, 100, 1.0), Duration::ZERO);
+        assert_eq!(pause(0, 86_400, 1.0), MAX_PAUSE);
+        // Out-of-order records don't wait
+        assert_eq!(pause(110, 100, 1.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn cancelled_replay_stops_emitting() {
+        let replay = Replay::new("r1".to_string());
+        let mut seen = Vec::new();
+        let records = vec![record(0, 1.0), record(0, 2.0), record(0, 3.0)];
+        let emitted = replay.run(records.into_iter(), "robot-01", 1.0, |update| {
+            seen.push(update["latitude"].as_f64().unwrap());
+            if seen.len() == 2 {
+                replay.cancel();
+            }
+        });
+        assert_eq!(emitted, 2);
+        assert_eq!(seen, [1.0, 2.0]);
+    }
+}
@@ -0,0 +1,3064 @@
+//! # Server
+//!
+//! The command center itself: connection handling, the client table and
+//! the WebSocket message loop. `Server::run` starts it on background
+//! threads and hands back a `ServerHandle` to stop it with.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::value::RawValue;
+
+use crate::access::{AccessList, Cidr};
+use crate::logfile::RotatingLog;
+use crate::policy::RegistrationPolicy;
+use crate::appearance;
+use crate::commands::{CommandValidators, Precondition};
+use crate::replay::Replay;
+use crate::protocol::{AlertMessage, CommandPayload, Envelope, DeviceInfo, DeviceInfoUpdate, DeviceStatus, SleepMessage, TelemetryMessage, RegisterMessage, SendCommand, BINARY_TELEMETRY_SUBPROTOCOL};
+use crate::state::{self, Alert, Lease, Maintenance, PairingRequest, StateDb, PendingCommand};
+use crate::telemetry::{self, OutOfOrder, TelemetryReader, TelemetryWriter, TelemetryRecord};
+use crate::tls::{self, Stream};
+use crate::trace::{self, log};
+use crate::websocket::{Message, WebSocket, State as WsState, CLOSE_GOING_AWAY, CLOSE_NORMAL};
+use crate::{demo, http, preflight, signing, webhook};
+
+// ============================================================================
+// CONFIGURATION
+// ============================================================================
+
+/// Port the `globalrts` binary listens on.
+const PORT: u16 = 3000;
+/// Addresses to listen on, comma-separated. "::" is usually dual-stack and
+/// takes IPv4 too. Override with GLOBALRTS_BIND, e.g. "::" or "127.0.0.1,::1".
+const BIND: &str = "0.0.0.0";
+/// Static file roots, searched in order. Override with GLOBALRTS_STATIC_DIRS
+/// (comma-separated), e.g. "custom,public" to overlay custom assets.
+const PUBLIC_DIR: &str = "public";
+/// Where the device database and telemetry live. Override with
+/// GLOBALRTS_DATA_DIR.
+const DATA_DIR: &str = "data";
+
+/// How often unacknowledged commands are checked for timing out.
+const HOUSEKEEPING_INTERVAL_MS: u64 = 1000;
+
+/// How often expired pairing requests are cleared out and UIs' pairing lists
+/// checked against the database. Changes are broadcast as they happen; this
+/// catches the ones that raise no event, like a code running out.
+const PAIRING_RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Commands written to a device but not acknowledged within this many
+/// seconds are marked timed_out.
+const COMMAND_ACK_TIMEOUT_SECS: i64 = 30;
+
+/// A sleeping device not back this many seconds after its wake time,
+/// and not connected, is marked offline.
+const WAKE_GRACE_SECS: i64 = 60;
+
+/// Command statuses only the server sets. A device can't report one.
+const SERVER_STATUSES: [&str; 8] = ["queued", "sent", "delivered", "timed_out", "dry_run", "skipped", "held", "cancelled"];
+
+/// Longest status a device may report.
+const MAX_REPORTED_STATUS: usize = 32;
+
+/// Longest request_id a UI may attach to a command.
+const MAX_REQUEST_ID: usize = 128;
+
+/// Longest idempotency key a UI may send, in bytes.
+const MAX_IDEMPOTENCY_KEY: usize = 128;
+
+/// How long a command's idempotency key keeps a retry from dispatching it again.
+const IDEMPOTENCY_WINDOW_SECS: i64 = 600;
+
+/// A device not marked offline but silent this long counts as stale in
+/// the fleet stats.
+const STALE_AFTER_SECS: i64 = 60;
+
+/// Most requests one `batch` may carry.
+const MAX_BATCH_REQUESTS: usize = 32;
+
+/// UI messages that change the fleet, refused on a viewer's connection.
+const VIEWER_REFUSED: [&str; 4] = ["dismissPairing", "revokeDevice", "sendCommand", "cancelCommand"];
+
+/// How often buffered device:update messages are flushed to UIs as one
+/// devices:update batch. 0 = forward every update immediately.
+/// Override with GLOBALRTS_UPDATE_INTERVAL_MS.
+const DEVICE_UPDATE_INTERVAL_MS: u64 = 0;
+
+/// How many times a second a moving device's position is projected along
+/// its last segment between real samples, for UIs that want smooth motion.
+/// Projected updates carry `interpolated: true`. 0 = off.
+/// Override with GLOBALRTS_INTERPOLATE_HZ.
+const INTERPOLATE_HZ: u64 = 0;
+
+/// A device quiet for longer than this between samples isn't on a steady
+/// track, so nothing is projected after its next one.
+const MAX_INTERPOLATION_GAP: Duration = Duration::from_secs(10);
+
+/// Sustained bytes/sec a single WebSocket client may send, averaged over a
+/// few seconds. Clients over it are closed with 1008. 0 = no cap.
+/// Override with GLOBALRTS_WS_MAX_BYTES_PER_SEC.
+const WS_INGRESS_LIMIT_BYTES_PER_SEC: u64 = 256 * 1024;
+
+/// Largest single WebSocket message a client may send. Larger ones are
+/// refused with 1009 before they're read. 0 = no cap.
+/// Override with GLOBALRTS_WS_MAX_MESSAGE_BYTES.
+const WS_MAX_MESSAGE_BYTES: u64 = 1024 * 1024;
+
+/// Most bytes that may wait to be written to one WebSocket client. A client
+/// reading too slowly to keep under it is closed with 1008. 0 = no cap.
+/// Override with GLOBALRTS_WS_SEND_QUEUE_BYTES.
+const WS_SEND_QUEUE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Seconds a WebSocket client may take nothing we write before it's taken
+/// for dead and dropped. 0 = wait forever.
+/// Override with GLOBALRTS_WS_WRITE_TIMEOUT_SECS.
+const WS_WRITE_TIMEOUT_SECS: u64 = 30;
+
+/// Whether WebSocket clients that offer permessage-deflate get it, trading
+/// CPU for bandwidth. Enable with GLOBALRTS_WS_DEFLATE=1.
+const WS_DEFLATE: bool = false;
+
+/// Largest request body the HTTP API reads, checked against Content-Length
+/// before a byte of it is read. Larger ones get 413. 0 = no cap. Bulk
+/// imports have their own, higher cap. Override with GLOBALRTS_HTTP_MAX_BODY_BYTES.
+const HTTP_MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Largest command payload, as JSON, that may be sent to a device. Bigger
+/// ones are rejected with reason `payload_too_large` before they're saved.
+/// 0 = no cap. Override with GLOBALRTS_MAX_COMMAND_PAYLOAD_BYTES.
+const MAX_COMMAND_PAYLOAD_BYTES: u64 = 64 * 1024;
+
+/// The `reason` a command over the payload cap is rejected with.
+pub(crate) const PAYLOAD_TOO_LARGE: &str = "payload_too_large";
+
+/// Seconds a device with a pending pairing request must wait before asking
+/// for a new code. Sooner gets 429 with Retry-After. 0 = no wait.
+/// Override with GLOBALRTS_PAIR_COOLDOWN_SECS.
+const PAIR_COOLDOWN_SECS: u64 = 0;
+
+/// Seconds between flushes of buffered telemetry to the OS. 0 = every write.
+/// Override with GLOBALRTS_TELEMETRY_FLUSH_SECS.
+const TELEMETRY_FLUSH_SECS: u64 = telemetry::DEFAULT_FLUSH_INTERVAL_SECS;
+
+/// Whether each telemetry flush also waits for the disk (sync_data).
+/// Enable with GLOBALRTS_TELEMETRY_FSYNC=1.
+const TELEMETRY_FSYNC: bool = false;
+
+/// Whether each telemetry record is fsynced to a recovery log before it is
+/// buffered, so a crash loses none of it. Enable with
+/// GLOBALRTS_TELEMETRY_RECOVERY_LOG=1.
+const TELEMETRY_RECOVERY_LOG: bool = false;
+
+/// Whether telemetry files go in hash-prefix directories under each day,
+/// for fleets too big for one directory. Enable with GLOBALRTS_TELEMETRY_SHARD=1.
+const TELEMETRY_SHARD: bool = false;
+
+/// Days of telemetry kept, counting back from today's; older day files are
+/// deleted hourly. A device's own retention_days, when set, wins. 0 = keep
+/// everything. Override with GLOBALRTS_TELEMETRY_RETENTION_DAYS.
+const TELEMETRY_RETENTION_DAYS: u64 = 0;
+
+/// Free space on the data directory's disk, MB, below which the startup
+/// self-check warns. Override with GLOBALRTS_MIN_FREE_MB.
+const MIN_FREE_MB: u64 = 100;
+
+/// How often old telemetry is looked for and deleted.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Whether telemetry is written gzipped (`.jsonl.gz`), trading CPU for disk.
+/// Enable with GLOBALRTS_TELEMETRY_GZIP=1.
+const TELEMETRY_GZIP: bool = false;
+
+/// Metres a device must move, with battery and sensors unchanged, before
+/// its telemetry is written again; `last_seen` moves on regardless. 0 =
+/// write every record. Override with GLOBALRTS_TELEMETRY_MIN_DISTANCE_M.
+const TELEMETRY_MIN_DISTANCE_M: f64 = 0.0;
+
+/// What becomes of a record older than the last one stored for its device:
+/// stored as usual. Override with GLOBALRTS_TELEMETRY_OUT_OF_ORDER (`accept`,
+/// `drop` or `flag`).
+const TELEMETRY_OUT_OF_ORDER: OutOfOrder = OutOfOrder::Accept;
+
+/// Samples in a row at ground level after which a device of a type that
+/// flies is reported to UIs as maybe provisioned with the wrong type.
+/// 0 = no check. Override with GLOBALRTS_TYPE_CHECK_SAMPLES.
+const TYPE_CHECK_SAMPLES: u64 = 0;
+
+/// Device types expected to leave the ground. Override with
+/// GLOBALRTS_AIRBORNE_TYPES (comma-separated).
+const AIRBORNE_TYPES: [&str; 1] = ["drone"];
+
+/// A sample with less altitude than this, metres, is at ground level.
+const GROUND_ALTITUDE_M: f64 = 1.0;
+
+/// Size at which the log file, if there is one, is rotated, bytes. 0 =
+/// never. Override with GLOBALRTS_LOG_MAX_BYTES.
+const LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Rotated log files kept besides the one being written; older ones are
+/// deleted. Override with GLOBALRTS_LOG_KEEP.
+const LOG_KEEP: u64 = 5;
+
+/// Simulated devices run inside the server, to populate the dashboard for
+/// evaluations and UI work. 0 = none. Override with GLOBALRTS_DEMO_DEVICES
+/// (or `globalrts --demo`).
+const DEMO_DEVICES: u64 = 0;
+
+// ============================================================================
+// CONFIG
+// ============================================================================
+
+/// How a server runs. `Config::from_env()` is what the `globalrts` binary
+/// uses; `Config::default()` is the same settings without the environment.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// TCP port. 0 picks a free one (see `ServerHandle::local_addr`).
+    pub port: u16,
+    /// Addresses to listen on, all on `port`. An unspecified address
+    /// (0.0.0.0, ::) listens on every interface of its family.
+    pub bind: Vec<IpAddr>,
+    /// Static file roots, searched in order.
+    pub static_dirs: Vec<String>,
+    /// Where the device database (`state.db`) and telemetry files live.
+    pub data_dir: String,
+    /// Connection allow/deny lists and trusted proxies.
+    pub access: AccessList,
+    /// Device update coalescing interval, ms. 0 = forward every update.
+    pub update_interval_ms: u64,
+    /// Projected positions per second between real samples. 0 = off.
+    pub interpolate_hz: u64,
+    /// Per-connection WebSocket ingress cap, bytes/sec. 0 = no cap.
+    pub ingress_limit: u64,
+    /// Per-message WebSocket size cap, bytes. 0 = no cap.
+    pub max_message: u64,
+    /// Per-connection WebSocket send queue cap, bytes. 0 = no cap.
+    pub send_queue: u64,
+    /// Per-connection WebSocket write timeout, seconds. 0 = none.
+    pub ws_write_timeout_secs: u64,
+    /// Accept permessage-deflate from clients that offer it.
+    pub ws_deflate: bool,
+    /// HTTP request body cap, bytes. 0 = no cap.
+    pub max_body: u64,
+    /// Command payload cap, bytes of JSON. 0 = no cap.
+    pub max_command_payload: u64,
+    /// Seconds before a pending pairing request may be asked for again. 0 = no wait.
+    pub pair_cooldown_secs: u64,
+    /// Device ids paired without a code: exact ids, or prefixes ending in `*`.
+    /// Set from GLOBALRTS_PAIR_AUTO_APPROVE, comma-separated.
+    pub pair_auto_approve: Vec<String>,
+    /// Seconds between telemetry flushes. 0 = every write.
+    pub telemetry_flush_secs: u64,
+    /// Make every telemetry flush durable, at a cost in throughput.
+    pub telemetry_fsync: bool,
+    /// Log each telemetry record durably before buffering it, and replay
+    /// the log on startup, at a greater cost.
+    pub telemetry_recovery_log: bool,
+    /// Shard each day's telemetry files into hash-prefix directories.
+    pub telemetry_shard: bool,
+    /// Gzip telemetry files as they're written.
+    pub telemetry_gzip: bool,
+    /// Days of telemetry kept by devices without their own setting. 0 = all.
+    pub telemetry_retention_days: u64,
+    /// Metres a device must move (or change battery or sensors) for its
+    /// telemetry to be written again. 0 = write every record.
+    pub telemetry_min_distance_m: f64,
+    /// What becomes of a record older than its device's last stored one.
+    pub telemetry_out_of_order: OutOfOrder,
+    /// Samples in a row at ground level before a device of an airborne
+    /// type is reported as maybe mistyped. 0 = no check.
+    pub type_check_samples: u64,
+    /// Device types expected to leave the ground.
+    pub airborne_types: Vec<String>,
+    /// Free disk below which the startup self-check warns, MB.
+    pub min_free_mb: u64,
+    /// PEM certificate chain and private key. With both, every connection
+    /// is TLS (https://, wss://); with neither, plaintext. Set from
+    /// GLOBALRTS_TLS_CERT and GLOBALRTS_TLS_KEY.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Simulated devices to run inside the server. 0 = none.
+    pub demo_devices: u64,
+    /// File the log goes to, rotated by size; stdout without one. Set
+    /// from GLOBALRTS_LOG_FILE.
+    pub log_file: Option<String>,
+    /// Log file size that triggers a rotation, bytes. 0 = never rotate.
+    pub log_max_bytes: u64,
+    /// Rotated log files kept.
+    pub log_keep: u64,
+    /// Capabilities a device of each type gets when it registers without
+    /// declaring any, which are also the only commands such a device
+    /// takes. Types not listed take any command. Set from
+    /// GLOBALRTS_TYPE_CAPABILITIES, as `drone=navigate,takeoff,land;sensor=poll`.
+    pub type_capabilities: BTreeMap<String, Vec<String>>,
+    /// Device types, and device ids, that take one command at a time: the
+    /// next waits, queued, until the one in flight ends. Set from
+    /// GLOBALRTS_SERIAL_COMMANDS, comma-separated.
+    pub serial_commands: Vec<String>,
+    /// Loopback, private and link-local addresses that command callbacks
+    /// may still be posted to. Set from GLOBALRTS_WEBHOOK_ALLOW_CIDRS,
+    /// comma-separated.
+    pub webhook_allow: Vec<Cidr>,
+    /// Types allowed to register, the pattern names must match, and tags
+    /// by type. Set from GLOBALRTS_REGISTER_TYPES,
+    /// GLOBALRTS_REGISTER_NAME_PATTERN and GLOBALRTS_REGISTER_TAGS.
+    pub registration: RegistrationPolicy,
+    /// Token for the admin endpoints and power commands. Without one the
+    /// admin endpoints are disabled. Set from GLOBALRTS_ADMIN_TOKEN.
+    pub admin_token: Option<String>,
+    /// Token that may read and watch but not act. Set from
+    /// GLOBALRTS_VIEWER_TOKEN.
+    pub viewer_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: PORT,
+            bind: parse_bind(BIND).expect("default bind addresses"),
+            static_dirs: vec![PUBLIC_DIR.to_string()],
+            data_dir: DATA_DIR.to_string(),
+            access: AccessList::default(),
+            update_interval_ms: DEVICE_UPDATE_INTERVAL_MS,
+            interpolate_hz: INTERPOLATE_HZ,
+            ingress_limit: WS_INGRESS_LIMIT_BYTES_PER_SEC,
+            max_message: WS_MAX_MESSAGE_BYTES,
+            send_queue: WS_SEND_QUEUE_BYTES,
+            ws_write_timeout_secs: WS_WRITE_TIMEOUT_SECS,
+            ws_deflate: WS_DEFLATE,
+            max_body: HTTP_MAX_BODY_BYTES,
+            max_command_payload: MAX_COMMAND_PAYLOAD_BYTES,
+            pair_cooldown_secs: PAIR_COOLDOWN_SECS,
+            pair_auto_approve: Vec::new(),
+            telemetry_flush_secs: TELEMETRY_FLUSH_SECS,
+            telemetry_fsync: TELEMETRY_FSYNC,
+            telemetry_recovery_log: TELEMETRY_RECOVERY_LOG,
+            telemetry_shard: TELEMETRY_SHARD,
+            telemetry_gzip: TELEMETRY_GZIP,
+            telemetry_retention_days: TELEMETRY_RETENTION_DAYS,
+            telemetry_min_distance_m: TELEMETRY_MIN_DISTANCE_M,
+            telemetry_out_of_order: TELEMETRY_OUT_OF_ORDER,
+            type_check_samples: TYPE_CHECK_SAMPLES,
+            airborne_types: AIRBORNE_TYPES.map(String::from).to_vec(),
+            min_free_mb: MIN_FREE_MB,
+            tls_cert: None,
+            tls_key: None,
+            demo_devices: DEMO_DEVICES,
+            log_file: None,
+            log_max_bytes: LOG_MAX_BYTES,
+            log_keep: LOG_KEEP,
+            type_capabilities: BTreeMap::new(),
+            serial_commands: Vec::new(),
+            webhook_allow: Vec::new(),
+            registration: RegistrationPolicy::default(),
+            admin_token: None,
+            viewer_token: None,
+        }
+    }
+}
+
+impl Config {
+    /// The defaults, overridden by any GLOBALRTS_* environment variables,
+    /// which are overridden in turn by the file GLOBALRTS_CONFIG names, if
+    /// it names one.
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_settings(&Settings::load()?)
+    }
+    
+    fn from_settings(vars: &Settings) -> Result<Self, String> {
+        let tls_cert = vars.get("GLOBALRTS_TLS_CERT").filter(|v| !v.is_empty());
+        let tls_key = vars.get("GLOBALRTS_TLS_KEY").filter(|v| !v.is_empty());
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err("GLOBALRTS_TLS_CERT and GLOBALRTS_TLS_KEY go together".to_string());
+        }
+        let access = AccessList::new(
+            &vars.get("GLOBALRTS_ALLOW_CIDRS").unwrap_or_default(),
+            &vars.get("GLOBALRTS_DENY_CIDRS").unwrap_or_default(),
+        ).and_then(|access| access.with_trusted_proxies(&vars.get("GLOBALRTS_TRUSTED_PROXIES").unwrap_or_default()));
+        Ok(Self {
+            port: PORT,
+            bind: parse_bind(&vars.get("GLOBALRTS_BIND").unwrap_or_else(|| BIND.to_string()))
+                .map_err(|e| format!("invalid GLOBALRTS_BIND: {}", e))?,
+            static_dirs: static_dirs(vars),
+            data_dir: vars.get("GLOBALRTS_DATA_DIR").filter(|v| !v.is_empty()).unwrap_or_else(|| DATA_DIR.to_string()),
+            access: access.map_err(|e| format!("invalid access list: {}", e))?,
+            update_interval_ms: vars.u64("GLOBALRTS_UPDATE_INTERVAL_MS", DEVICE_UPDATE_INTERVAL_MS),
+            interpolate_hz: vars.u64("GLOBALRTS_INTERPOLATE_HZ", INTERPOLATE_HZ),
+            ingress_limit: vars.u64("GLOBALRTS_WS_MAX_BYTES_PER_SEC", WS_INGRESS_LIMIT_BYTES_PER_SEC),
+            max_message: vars.u64("GLOBALRTS_WS_MAX_MESSAGE_BYTES", WS_MAX_MESSAGE_BYTES),
+            send_queue: vars.u64("GLOBALRTS_WS_SEND_QUEUE_BYTES", WS_SEND_QUEUE_BYTES),
+            ws_write_timeout_secs: vars.u64("GLOBALRTS_WS_WRITE_TIMEOUT_SECS", WS_WRITE_TIMEOUT_SECS),
+            ws_deflate: vars.u64("GLOBALRTS_WS_DEFLATE", WS_DEFLATE as u64) != 0,
+            max_body: vars.u64("GLOBALRTS_HTTP_MAX_BODY_BYTES", HTTP_MAX_BODY_BYTES),
+            max_command_payload: vars.u64("GLOBALRTS_MAX_COMMAND_PAYLOAD_BYTES", MAX_COMMAND_PAYLOAD_BYTES),
+            pair_cooldown_secs: vars.u64("GLOBALRTS_PAIR_COOLDOWN_SECS", PAIR_COOLDOWN_SECS),
+            pair_auto_approve: vars.list("GLOBALRTS_PAIR_AUTO_APPROVE"),
+            telemetry_flush_secs: vars.u64("GLOBALRTS_TELEMETRY_FLUSH_SECS", TELEMETRY_FLUSH_SECS),
+            telemetry_fsync: vars.u64("GLOBALRTS_TELEMETRY_FSYNC", TELEMETRY_FSYNC as u64) != 0,
+            telemetry_recovery_log: vars.u64("GLOBALRTS_TELEMETRY_RECOVERY_LOG", TELEMETRY_RECOVERY_LOG as u64) != 0,
+            telemetry_shard: vars.u64("GLOBALRTS_TELEMETRY_SHARD", TELEMETRY_SHARD as u64) != 0,
+            telemetry_gzip: vars.u64("GLOBALRTS_TELEMETRY_GZIP", TELEMETRY_GZIP as u64) != 0,
+            telemetry_retention_days: vars.u64("GLOBALRTS_TELEMETRY_RETENTION_DAYS", TELEMETRY_RETENTION_DAYS),
+            telemetry_min_distance_m: vars.f64("GLOBALRTS_TELEMETRY_MIN_DISTANCE_M", TELEMETRY_MIN_DISTANCE_M),
+            telemetry_out_of_order: vars.get("GLOBALRTS_TELEMETRY_OUT_OF_ORDER").map_or(Ok(TELEMETRY_OUT_OF_ORDER), |v| OutOfOrder::parse(&v))
+                .map_err(|e| format!("invalid GLOBALRTS_TELEMETRY_OUT_OF_ORDER: {}", e))?,
+            type_check_samples: vars.u64("GLOBALRTS_TYPE_CHECK_SAMPLES", TYPE_CHECK_SAMPLES),
+            airborne_types: Some(vars.list("GLOBALRTS_AIRBORNE_TYPES")).filter(|types| !types.is_empty())
+                .unwrap_or_else(|| AIRBORNE_TYPES.map(String::from).to_vec()),
+            min_free_mb: vars.u64("GLOBALRTS_MIN_FREE_MB", MIN_FREE_MB),
+            tls_cert,
+            tls_key,
+            demo_devices: vars.u64("GLOBALRTS_DEMO_DEVICES", DEMO_DEVICES),
+            log_file: vars.get("GLOBALRTS_LOG_FILE").filter(|v| !v.is_empty()),
+            log_max_bytes: vars.u64("GLOBALRTS_LOG_MAX_BYTES", LOG_MAX_BYTES),
+            log_keep: vars.u64("GLOBALRTS_LOG_KEEP", LOG_KEEP),
+            type_capabilities: parse_type_lists(&vars.get("GLOBALRTS_TYPE_CAPABILITIES").unwrap_or_default())
+                .map_err(|e| format!("invalid GLOBALRTS_TYPE_CAPABILITIES: {}", e))?,
+            serial_commands: vars.list("GLOBALRTS_SERIAL_COMMANDS"),
+            webhook_allow: vars.list("GLOBALRTS_WEBHOOK_ALLOW_CIDRS").iter().map(|cidr| Cidr::parse(cidr)).collect::<Result<_, _>>()
+                .map_err(|e| format!("invalid GLOBALRTS_WEBHOOK_ALLOW_CIDRS: {}", e))?,
+            registration: registration_policy(vars)?,
+            admin_token: vars.get("GLOBALRTS_ADMIN_TOKEN").filter(|v| !v.trim().is_empty()),
+            viewer_token: vars.get("GLOBALRTS_VIEWER_TOKEN").filter(|v| !v.trim().is_empty()),
+        })
+    }
+    
+    /// `self` as a GLOBALRTS_CONFIG file: one `NAME=value` line per
+    /// setting, every setting included, which `from_env` reads back as
+    /// `self`. The port isn't a setting, and an empty list of airborne
+    /// types reads back as the default one.
+    pub fn to_settings(&self) -> String {
+        let join = |items: &[String]| items.join(",");
+        let flag = |on: bool| if on { "1" } else { "0" }.to_string();
+        let [allow, deny, proxies] = self.access.lists();
+        let settings = [
+            ("GLOBALRTS_BIND", self.bind.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(",")),
+            ("GLOBALRTS_STATIC_DIRS", join(&self.static_dirs)),
+            ("GLOBALRTS_DATA_DIR", self.data_dir.clone()),
+            ("GLOBALRTS_ALLOW_CIDRS", allow),
+            ("GLOBALRTS_DENY_CIDRS", deny),
+            ("GLOBALRTS_TRUSTED_PROXIES", proxies),
+            ("GLOBALRTS_UPDATE_INTERVAL_MS", self.update_interval_ms.to_string()),
+            ("GLOBALRTS_INTERPOLATE_HZ", self.interpolate_hz.to_string()),
+            ("GLOBALRTS_WS_MAX_BYTES_PER_SEC", self.ingress_limit.to_string()),
+            ("GLOBALRTS_WS_MAX_MESSAGE_BYTES", self.max_message.to_string()),
+            ("GLOBALRTS_WS_SEND_QUEUE_BYTES", self.send_queue.to_string()),
+            ("GLOBALRTS_WS_WRITE_TIMEOUT_SECS", self.ws_write_timeout_secs.to_string()),
+            ("GLOBALRTS_WS_DEFLATE", flag(self.ws_deflate)),
+            ("GLOBALRTS_HTTP_MAX_BODY_BYTES", self.max_body.to_string()),
+            ("GLOBALRTS_MAX_COMMAND_PAYLOAD_BYTES", self.max_command_payload.to_string()),
+            ("GLOBALRTS_PAIR_COOLDOWN_SECS", self.pair_cooldown_secs.to_string()),
+            ("GLOBALRTS_PAIR_AUTO_APPROVE", join(&self.pair_auto_approve)),
+            ("GLOBALRTS_TELEMETRY_FLUSH_SECS", self.telemetry_flush_secs.to_string()),
+            ("GLOBALRTS_TELEMETRY_FSYNC", flag(self.telemetry_fsync)),
+            ("GLOBALRTS_TELEMETRY_RECOVERY_LOG", flag(self.telemetry_recovery_log)),
+            ("GLOBALRTS_TELEMETRY_SHARD", flag(self.telemetry_shard)),
+            ("GLOBALRTS_TELEMETRY_GZIP", flag(self.telemetry_gzip)),
+            ("GLOBALRTS_TELEMETRY_RETENTION_DAYS", self.telemetry_retention_days.to_string()),
+            ("GLOBALRTS_TELEMETRY_MIN_DISTANCE_M", self.telemetry_min_distance_m.to_string()),
+            ("GLOBALRTS_TELEMETRY_OUT_OF_ORDER", self.telemetry_out_of_order.as_str().to_string()),
+            ("GLOBALRTS_TYPE_CHECK_SAMPLES", self.type_check_samples.to_string()),
+            ("GLOBALRTS_AIRBORNE_TYPES", join(&self.airborne_types)),
+            ("GLOBALRTS_MIN_FREE_MB", self.min_free_mb.to_string()),
+            ("GLOBALRTS_TLS_CERT", self.tls_cert.clone().unwrap_or_default()),
+            ("GLOBALRTS_TLS_KEY", self.tls_key.clone().unwrap_or_default()),
+            ("GLOBALRTS_DEMO_DEVICES", self.demo_devices.to_string()),
+            ("GLOBALRTS_LOG_FILE", self.log_file.clone().unwrap_or_default()),
+            ("GLOBALRTS_LOG_MAX_BYTES", self.log_max_bytes.to_string()),
+            ("GLOBALRTS_LOG_KEEP", self.log_keep.to_string()),
+            ("GLOBALRTS_TYPE_CAPABILITIES", format_type_lists(&self.type_capabilities)),
+            ("GLOBALRTS_SERIAL_COMMANDS", join(&self.serial_commands)),
+            ("GLOBALRTS_WEBHOOK_ALLOW_CIDRS", self.webhook_allow.iter().map(Cidr::to_string).collect::<Vec<_>>().join(",")),
+            ("GLOBALRTS_REGISTER_TYPES", join(self.registration.types())),
+            ("GLOBALRTS_REGISTER_NAME_PATTERN", self.registration.name_pattern().unwrap_or_default().to_string()),
+            ("GLOBALRTS_REGISTER_TAGS", format_type_lists(self.registration.tags())),
+            ("GLOBALRTS_ADMIN_TOKEN", self.admin_token.clone().unwrap_or_default()),
+            ("GLOBALRTS_VIEWER_TOKEN", self.viewer_token.clone().unwrap_or_default()),
+        ];
+        settings.iter().map(|(name, value)| format!("{}={}\n", name, value)).collect()
+    }
+    
+    /// The environment variable of each setting in `self` that only takes
+    /// effect on a restart and differs in `other`.
+    fn restart_only_changes(&self, other: &Config) -> Vec<&'static str> {
+        let changes = [
+            ("GLOBALRTS_BIND", self.bind != other.bind),
+            ("GLOBALRTS_STATIC_DIRS", self.static_dirs != other.static_dirs),
+            ("GLOBALRTS_DATA_DIR", self.data_dir != other.data_dir),
+            ("GLOBALRTS_ALLOW_CIDRS, GLOBALRTS_DENY_CIDRS or GLOBALRTS_TRUSTED_PROXIES", self.access != other.access),
+            ("GLOBALRTS_UPDATE_INTERVAL_MS", self.update_interval_ms != other.update_interval_ms),
+            ("GLOBALRTS_INTERPOLATE_HZ", self.interpolate_hz != other.interpolate_hz),
+            ("GLOBALRTS_TELEMETRY_FLUSH_SECS", self.telemetry_flush_secs != other.telemetry_flush_secs),
+            ("GLOBALRTS_TELEMETRY_FSYNC", self.telemetry_fsync != other.telemetry_fsync),
+            ("GLOBALRTS_TELEMETRY_RECOVERY_LOG", self.telemetry_recovery_log != other.telemetry_recovery_log),
+            ("GLOBALRTS_TELEMETRY_SHARD", self.telemetry_shard != other.telemetry_shard),
+            ("GLOBALRTS_TELEMETRY_GZIP", self.telemetry_gzip != other.telemetry_gzip),
+            ("GLOBALRTS_MIN_FREE_MB", self.min_free_mb != other.min_free_mb),
+            ("GLOBALRTS_TLS_CERT", self.tls_cert != other.tls_cert),
+            ("GLOBALRTS_TLS_KEY", self.tls_key != other.tls_key),
+            ("GLOBALRTS_DEMO_DEVICES", self.demo_devices != other.demo_devices),
+            ("GLOBALRTS_LOG_FILE", self.log_file != other.log_file),
+            ("GLOBALRTS_LOG_MAX_BYTES or GLOBALRTS_LOG_KEEP", (self.log_max_bytes, self.log_keep) != (other.log_max_bytes, other.log_keep)),
+        ];
+        changes.into_iter().filter(|(_, changed)| *changed).map(|(name, _)| name).collect()
+    }
+}
+
+// ============================================================================
+// SERVER STATE
+// ============================================================================
+
+struct Client {
+    ws: WebSocket,
+    /// Where the client connects from (past any trusted proxy).
+    ip: IpAddr,
+    client_type: ClientType,
+    device_id: Option<String>,
+    /// Token the device's messages must be signed with, if it was paired to sign.
+    signing_key: Option<String>,
+    /// A UI that keeps its device list from devices:added/removed/changed
+    /// deltas rather than per-event messages.
+    deltas: bool,
+    /// Connected with the viewer token: it may watch, not act.
+    read_only: bool,
+    /// Connected with the admin token: it may send power commands.
+    admin: bool,
+    /// Who the operator is, by their token, for command leases.
+    identity: String,
+    /// While one request of a `batch` is handled, what would have been sent
+    /// back for it, kept for the `batch:result`.
+    batch: Option<Vec<serde_json::Value>>,
+}
+
+impl Client {
+    /// Send `message` back to this client, or keep it for the batch
+    /// result if a batch is being handled.
+    fn reply(&mut self, message: &str) -> Result<(), String> {
+        match &mut self.batch {
+            Some(replies) => {
+                replies.push(serde_json::from_str(message).unwrap_or_default());
+                Ok(())
+            }
+            None => self.ws.send(message),
+        }
+    }
+}
+
+/// What became of a command once dispatched.
+pub(crate) struct Dispatched {
+    pub command_id: String,
+    /// Where it ended up: sent, queued, held, skipped or dry_run. For a
+    /// duplicate, wherever the original has got to since.
+    pub status: String,
+    /// Whether it was written to the device's socket.
+    pub sent: bool,
+    /// Why its precondition failed, if it was skipped.
+    pub skipped: Option<String>,
+    /// A retry with an idempotency key already used: nothing was dispatched,
+    /// and the rest describes the original.
+    pub duplicate: bool,
+}
+
+/// What became of a request to cancel a command.
+pub(crate) enum Cancelled {
+    /// Cancelled. Whether a `command:cancel` was written to the device:
+    /// only one already sent to it is under way there.
+    Done { device_id: String, notified: bool },
+    /// Past cancelling: it had already ended with this status.
+    Finished(String),
+    /// Its device is leased to someone else.
+    Leased,
+    Unknown,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClientType {
+    Unknown,
+    Device,
+    Ui,
+}
+
+impl ClientType {
+    fn as_str(self) -> &'static str {
+        match self {
+            ClientType::Unknown => "unknown",
+            ClientType::Device => "device",
+            ClientType::Ui => "ui",
+        }
+    }
+}
+
+/// A device's last two real samples, as they arrived.
+struct Motion {
+    /// Where it was before the latest sample, if projecting is worth it.
+    from: Option<(f64, f64, f64)>,
+    /// The latest sample: latitude, longitude and altitude.
+    to: (f64, f64, f64),
+    heading: f64,
+    speed: f64,
+    /// How long after the one before it the latest sample came.
+    gap: Duration,
+    arrived: Instant,
+}
+
+/// Shared server state, behind one mutex. Start one with `Server::run`.
+pub struct Server {
+    clients: HashMap<usize, Client>,
+    next_id: usize,
+    db: StateDb,
+    telemetry: TelemetryWriter,
+    /// Reads what `telemetry` wrote, laid out the same way.
+    telemetry_reader: TelemetryReader,
+    /// Latest update per device, waiting for the next coalesced flush.
+    pending_updates: HashMap<String, serde_json::Value>,
+    update_interval_ms: u64,
+    /// Each device's last segment, for projecting positions between samples.
+    motion: HashMap<String, Motion>,
+    /// Projected positions per second. 0 = none, and no motion is kept.
+    interpolate_hz: u64,
+    validators: CommandValidators,
+    /// Per-connection WebSocket ingress cap, bytes/sec.
+    ingress_limit: u64,
+    /// Per-message WebSocket size cap, bytes.
+    max_message: u64,
+    /// Per-connection WebSocket send queue cap, bytes.
+    send_queue: u64,
+    /// Per-connection WebSocket write timeout, seconds.
+    ws_write_timeout_secs: u64,
+    /// Accept permessage-deflate offers.
+    ws_deflate: bool,
+    /// HTTP request body cap, bytes.
+    max_body: u64,
+    /// Command payload cap, bytes of JSON.
+    max_command_payload: u64,
+    /// Wait before a pending pairing request may be renewed, seconds.
+    pair_cooldown_secs: u64,
+    /// Device id patterns paired without a code.
+    pair_auto_approve: Vec<String>,
+    /// Days of telemetry kept by devices without their own setting.
+    telemetry_retention_days: u64,
+    /// What becomes of a record older than its device's last stored one.
+    telemetry_out_of_order: OutOfOrder,
+    /// Timestamp of each device's last record kept in order, once known.
+    last_timestamps: HashMap<String, i64>,
+    /// Samples in a row at ground level before an airborne type is doubted. 0 = never.
+    type_check_samples: u64,
+    /// Device types expected to leave the ground.
+    airborne_types: Vec<String>,
+    /// Each connected device's current run of samples at ground level.
+    grounded: HashMap<String, u64>,
+    /// Default capabilities, and the commands allowed, by device type.
+    type_capabilities: BTreeMap<String, Vec<String>>,
+    /// Device types and ids that take one command at a time.
+    serial_commands: Vec<String>,
+    /// Private addresses command callbacks may go to.
+    webhook_allow: Vec<Cidr>,
+    /// Onboarding rules every registration is checked against.
+    registration: RegistrationPolicy,
+    /// The admin token, if admin endpoints are enabled.
+    admin_token: Option<String>,
+    /// The read-only viewer token, if there is one.
+    viewer_token: Option<String>,
+    /// What the server started with, for a reload to tell which changes
+    /// need a restart.
+    config: Config,
+    /// Telemetry replays in progress, by device.
+    replays: HashMap<String, Replay>,
+    /// Live feeds of one device's command statuses: the device, and where
+    /// each `command:status` goes. A feed whose reader is gone is dropped.
+    command_streams: Vec<(String, Sender<String>)>,
+    /// Device id and code of each pairing request UIs were last sent.
+    pairing_sent: Vec<(String, String)>,
+}
+
+impl Server {
+    fn new(config: &Config) -> Result<Self, String> {
+        let data_dir = std::path::Path::new(&config.data_dir);
+        let telemetry_dir = telemetry_dir(config);
+        std::fs::create_dir_all(data_dir).map_err(|e| e.to_string())?;
+        std::fs::create_dir_all(&telemetry_dir).map_err(|e| e.to_string())?;
+        
+        let telemetry = TelemetryWriter::new(&telemetry_dir)
+            .with_flush(config.telemetry_flush_secs, config.telemetry_fsync)
+            .with_sharding(config.telemetry_shard)
+            .with_gzip(config.telemetry_gzip)
+            .with_min_distance(config.telemetry_min_distance_m)
+            .with_recovery_log(config.telemetry_recovery_log);
+        // What a crash left in the recovery log goes in before anything new
+        match telemetry.recover()? {
+            0 => {}
+            n => log!("↻ Recovered {} telemetry records from the recovery log", n),
+        }
+        
+        Ok(Self {
+            clients: HashMap::new(),
+            next_id: 0,
+            db: StateDb::open(&data_dir.join("state.db").to_string_lossy())?,
+            telemetry,
+            telemetry_reader: TelemetryReader::new(&telemetry_dir)
+                .with_sharding(config.telemetry_shard),
+            pending_updates: HashMap::new(),
+            update_interval_ms: config.update_interval_ms,
+            motion: HashMap::new(),
+            interpolate_hz: config.interpolate_hz,
+            validators: CommandValidators::new(),
+            ingress_limit: config.ingress_limit,
+            max_message: config.max_message,
+            send_queue: config.send_queue,
+            ws_write_timeout_secs: config.ws_write_timeout_secs,
+            ws_deflate: config.ws_deflate,
+            max_body: config.max_body,
+            max_command_payload: config.max_command_payload,
+            pair_cooldown_secs: config.pair_cooldown_secs,
+            pair_auto_approve: config.pair_auto_approve.clone(),
+            telemetry_retention_days: config.telemetry_retention_days,
+            telemetry_out_of_order: config.telemetry_out_of_order,
+            last_timestamps: HashMap::new(),
+            type_check_samples: config.type_check_samples,
+            airborne_types: config.airborne_types.clone(),
+            grounded: HashMap::new(),
+            type_capabilities: config.type_capabilities.clone(),
+            serial_commands: config.serial_commands.clone(),
+            webhook_allow: config.webhook_allow.clone(),
+            registration: config.registration.clone(),
+            admin_token: config.admin_token.clone(),
+            viewer_token: config.viewer_token.clone(),
+            config: config.clone(),
+            replays: HashMap::new(),
+            command_streams: Vec::new(),
+            pairing_sent: Vec::new(),
+        })
+    }
+    
+    fn add_client(&mut self, ws: WebSocket, ip: IpAddr, role: http::Role, identity: String) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.clients.insert(id, Client {
+            ws,
+            ip,
+            client_type: ClientType::Unknown,
+            device_id: None,
+            signing_key: None,
+            deltas: false,
+            read_only: role == http::Role::Viewer,
+            admin: role == http::Role::Admin,
+            identity,
+            batch: None,
+        });
+        id
+    }
+    
+    fn remove_client(&mut self, id: usize) {
+        if let Some(client) = self.clients.remove(&id) {
+            if let Some(device_id) = &client.device_id {
+                self.pending_updates.remove(device_id);
+                self.motion.remove(device_id);
+                self.grounded.remove(device_id);
+                // Hanging up to sleep isn't going offline
+                let device = self.db.get_device(device_id).ok().flatten();
+                if let Some(wake_at) = device.filter(|d| d.status == DeviceStatus::Sleeping).and_then(|d| d.wake_at) {
+                    log!("✗ Device disconnected: {} (sleeping until {})", device_id, wake_at);
+                    return;
+                }
+                let _ = self.db.set_status(device_id, DeviceStatus::Offline);
+                self.broadcast_device_event(
+                    Some(&Envelope::new("device:offline", &serde_json::json!({"deviceId": device_id}))),
+                    &Envelope::new("devices:changed", &[serde_json::json!({"id": device_id, "status": DeviceStatus::Offline})]),
+                );
+                log!("✗ Device disconnected: {}", device_id);
+            }
+        }
+    }
+    
+    /// Close every client with 1001. Devices go offline as on any disconnect.
+    fn close_all_clients(&mut self) {
+        let ids: Vec<usize> = self.clients.keys().copied().collect();
+        for id in ids {
+            if let Some(client) = self.clients.get_mut(&id) {
+                client.ws.close_with(CLOSE_GOING_AWAY, "server shutting down");
+                client.ws.shutdown();
+            }
+            self.remove_client(id);
+        }
+    }
+    
+    /// Drop any other connection registered as `device_id`, keeping `client_id`.
+    /// The old client leaves the table here, so its thread's `remove_client`
+    /// later finds nothing and can't mark the device offline.
+    fn replace_device_connection(&mut self, device_id: &str, client_id: usize) {
+        let stale: Vec<usize> = self.clients.iter()
+            .filter(|(id, c)| **id != client_id && c.device_id.as_deref() == Some(device_id))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale {
+            if let Some(mut old) = self.clients.remove(&id) {
+                old.ws.close_with(CLOSE_NORMAL, "replaced by a newer connection");
+                old.ws.shutdown();
+                log!("↻ Device reconnected, dropped old connection: {}", device_id);
+            }
+        }
+    }
+    
+    fn broadcast_to_uis(&mut self, envelope: &Envelope) {
+        let json = envelope.to_json();
+        for client in self.clients.values_mut() {
+            if client.client_type == ClientType::Ui {
+                let _ = client.ws.send(&json);
+            }
+        }
+    }
+    
+    /// Broadcast a change to the device list: `legacy` to UIs that take
+    /// per-event messages (if it has one), `delta` to those on deltas.
+    fn broadcast_device_event(&mut self, legacy: Option<&Envelope>, delta: &Envelope) {
+        let legacy = legacy.map(Envelope::to_json);
+        let delta = delta.to_json();
+        for client in self.clients.values_mut() {
+            if client.client_type != ClientType::Ui {
+                continue;
+            }
+            let json = if client.deltas { Some(&delta) } else { legacy.as_ref() };
+            if let Some(json) = json {
+                let _ = client.ws.send(json);
+            }
+        }
+    }
+    
+    /// Tell UIs a device left the registry.
+    fn broadcast_device_removed(&mut self, device_id: &str) {
+        self.motion.remove(device_id);
+        self.grounded.remove(device_id);
+        self.broadcast_device_event(
+            Some(&Envelope::new("device:revoked", &serde_json::json!({"device_id": device_id}))),
+            &Envelope::new("devices:removed", &[device_id]),
+        );
+    }
+    
+    /// Forward a device update to UIs, or buffer it until the next flush
+    /// when coalescing is enabled. Only the latest update per device is kept.
+    fn queue_device_update(&mut self, device_id: &str, update: serde_json::Value) {
+        if self.update_interval_ms == 0 {
+            self.broadcast_device_event(
+                Some(&Envelope::new("device:update", &update)),
+                &Envelope::new("devices:changed", &[&update]),
+            );
+        } else {
+            self.pending_updates.insert(device_id.to_string(), update);
+        }
+    }
+    
+    /// Note a real position, starting a new segment from the last one.
+    fn track_motion(&mut self, record: &TelemetryRecord) {
+        if self.interpolate_hz == 0 {
+            return;
+        }
+        let to = (record.latitude, record.longitude, record.altitude);
+        let now = Instant::now();
+        let from = self.motion.get(&record.device_id)
+            .filter(|m| now - m.arrived <= MAX_INTERPOLATION_GAP && m.to != to)
+            .map(|m| (m.to, now - m.arrived));
+        self.motion.insert(record.device_id.clone(), Motion {
+            from: from.map(|(from, _)| from),
+            to,
+            heading: record.heading,
+            speed: record.speed,
+            gap: from.map(|(_, gap)| gap).unwrap_or_default(),
+            arrived: now,
+        });
+    }
+    
+    /// Send each moving device's projected position: its last segment
+    /// carried on at the same pace, for no longer than the gap between
+    /// its last two samples. A real update still waiting to go out wins.
+    fn send_interpolated_updates(&mut self) {
+        let mut updates = Vec::new();
+        for (device_id, m) in &self.motion {
+            let Some(from) = m.from else { continue };
+            let elapsed = m.arrived.elapsed();
+            if elapsed >= m.gap || self.pending_updates.contains_key(device_id) {
+                continue;
+            }
+            let t = elapsed.as_secs_f64() / m.gap.as_secs_f64();
+            let along = |a: f64, b: f64| b + (b - a) * t;
+            updates.push((device_id.clone(), serde_json::json!({
+                "id": device_id,
+                "latitude": along(from.0, m.to.0),
+                "longitude": along(from.1, m.to.1),
+                "altitude": along(from.2, m.to.2),
+                "heading": m.heading,
+                "speed": m.speed,
+                "interpolated": true,
+            })));
+        }
+        for (device_id, update) in updates {
+            self.queue_device_update(&device_id, update);
+        }
+    }
+    
+    /// Broadcast all buffered device updates as a single devices:update batch.
+    fn flush_device_updates(&mut self) {
+        if self.pending_updates.is_empty() {
+            return;
+        }
+        let updates: Vec<serde_json::Value> = self.pending_updates.drain().map(|(_, u)| u).collect();
+        self.broadcast_device_event(
+            Some(&Envelope::new("devices:update", &updates)),
+            &Envelope::new("devices:changed", &updates),
+        );
+    }
+    
+    /// Tell UIs a command moved to a new lifecycle status.
+    /// Lifecycle: queued → sent → delivered → completed (or timed_out, or
+    /// cancelled before it ends).
+    /// Carries the UI's requestId, if the command was sent with one.
+    fn broadcast_command_status(&mut self, command_id: &str, device_id: &str, status: &str) {
+        let mut data = serde_json::json!({
+            "commandId": command_id,
+            "deviceId": device_id,
+            "status": status,
+        });
+        if let Ok(Some(request_id)) = self.db.command_request_id(command_id) {
+            data["requestId"] = serde_json::json!(request_id);
+        }
+        if status == "skipped" {
+            if let Ok(Some(reason)) = self.db.command_reason(command_id) {
+                data["reason"] = serde_json::json!(reason);
+            }
+        }
+        self.broadcast_to_uis(&Envelope::new("command:status", &data));
+        let json = data.to_string();
+        self.command_streams.retain(|(id, feed)| id != device_id || feed.send(json.clone()).is_ok());
+    }
+    
+    /// Send a reconnected device its queued commands in the order they were
+    /// issued. Each is marked sent only once written; a failed write leaves
+    /// it and everything after it queued. One whose precondition the device
+    /// no longer meets is skipped. Nothing goes out while the device is in
+    /// maintenance or asleep; ending it delivers the lot. A device that
+    /// takes one command at a time gets only the next, and none while one
+    /// is in flight.
+    fn deliver_queued_commands(&mut self, device_id: &str, pending: Vec<PendingCommand>) {
+        if pending.is_empty() || self.db.maintenance().is_ok_and(|m| m.covers(device_id)) {
+            return;
+        }
+        let device = self.db.get_device(device_id).ok().flatten();
+        // A sleeping device gets them when it wakes
+        if device.as_ref().is_some_and(|d| d.status == DeviceStatus::Sleeping) {
+            return;
+        }
+        // One at a time gets the next once the one in flight ends
+        let serial = self.is_serial(device_id);
+        if serial && self.db.in_flight_command(device_id).map_or(true, |id| id.is_some()) {
+            return;
+        }
+        for cmd in pending {
+            let unmet = match (&cmd.precondition, &device) {
+                (Some(expr), Some(device)) => Precondition::parse(expr).map_or_else(Some, |p| p.unmet(device)),
+                _ => None,
+            };
+            if let Some(reason) = unmet {
+                if self.db.skip_queued_command(&cmd.id, &reason).unwrap_or(false) {
+                    self.broadcast_command_status(&cmd.id, device_id, "skipped");
+                }
+                log!("↻ Command skipped: {} -> {} ({})", cmd.command_type, device_id, reason);
+                continue;
+            }
+            let payload = serde_json::from_str(&cmd.payload).unwrap_or_default();
+            let command = command_envelope(&cmd.id, &cmd.command_type, &payload, cmd.seq);
+            if !send_to_device(&mut self.clients, device_id, &command) {
+                break;
+            }
+            if self.db.advance_command_status(&cmd.id, "queued", "sent").unwrap_or(false) {
+                self.broadcast_command_status(&cmd.id, device_id, "sent");
+            }
+            log!("→ Command: {} -> {} (sent from the queue, seq {})", cmd.command_type, device_id, cmd.seq);
+            if serial {
+                break;
+            }
+        }
+    }
+    
+    /// Whether `device_id` takes one command at a time: it, or its type, is
+    /// in GLOBALRTS_SERIAL_COMMANDS.
+    fn is_serial(&self, device_id: &str) -> bool {
+        if self.serial_commands.is_empty() {
+            return false;
+        }
+        self.serial_commands.iter().any(|entry| entry == device_id)
+            || self.db.get_device(device_id).ok().flatten()
+                .is_some_and(|device| self.serial_commands.contains(&device.device_type))
+    }
+    
+    /// A command to `device_id` has ended (finished, timed out or been
+    /// cancelled). If it takes one at a time, the next queued goes out.
+    fn send_next_command(&mut self, device_id: &str) {
+        if !self.is_serial(device_id) {
+            return;
+        }
+        let pending = self.db.get_pending_commands(device_id).unwrap_or_default();
+        self.deliver_queued_commands(device_id, pending);
+    }
+    
+    /// Check a command before anything is saved: its request id, its payload
+    /// against the command type's validator, and its precondition, parsed.
+    fn check_command(&self, cmd: &SendCommand) -> Result<Option<Precondition>, String> {
+        if cmd.request_id.as_deref().is_some_and(|id| id.len() > MAX_REQUEST_ID) {
+            return Err(format!("request_id is longer than {} bytes", MAX_REQUEST_ID));
+        }
+        if cmd.idempotency_key.as_deref().is_some_and(|key| key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY) {
+            return Err(format!("idempotency_key must be 1 to {} bytes", MAX_IDEMPOTENCY_KEY));
+        }
+        if let Some(url) = &cmd.callback_url {
+            webhook::check_url(url).map_err(|e| format!("callback_url {}", e))?;
+        }
+        self.validators.validate(&cmd.command_type, &cmd.payload)?;
+        cmd.precondition.as_deref().map(Precondition::parse).transpose()
+    }
+    
+    /// Count `record` towards its device's run of samples at ground level.
+    /// When the run reaches the threshold, and the device is of a type that
+    /// flies, UIs get a `device:type_warning`: it was likely provisioned
+    /// with the wrong type. Once per run; leaving the ground starts over.
+    fn check_type(&mut self, record: &TelemetryRecord) {
+        if self.type_check_samples == 0 {
+            self.grounded.clear();
+            return;
+        }
+        if record.altitude.abs() >= GROUND_ALTITUDE_M {
+            self.grounded.remove(&record.device_id);
+            return;
+        }
+        let run = self.grounded.entry(record.device_id.clone()).or_insert(0);
+        *run += 1;
+        if *run != self.type_check_samples {
+            return;
+        }
+        let Ok(Some(device)) = self.db.get_device(&record.device_id) else {
+            return;
+        };
+        if !self.airborne_types.contains(&device.device_type) {
+            return;
+        }
+        let reason = format!("altitude under {} m for {} samples in a row", GROUND_ALTITUDE_M, self.type_check_samples);
+        log!("⚠ {} is a {} but reports {}", device.id, device.device_type, reason);
+        self.broadcast_to_uis(&Envelope::new("device:type_warning", &serde_json::json!({
+            "deviceId": device.id,
+            "deviceType": device.device_type,
+            "samples": self.type_check_samples,
+            "reason": reason,
+        })));
+    }
+    
+    /// Check a command's payload against the size cap, before anything else
+    /// is done with it.
+    fn check_payload_size(&self, cmd: &SendCommand) -> Result<(), String> {
+        let size = serde_json::to_vec(&cmd.payload).map(|json| json.len()).unwrap_or(0) as u64;
+        if self.max_command_payload > 0 && size > self.max_command_payload {
+            return Err(format!("payload is {} bytes, over the {}-byte cap", size, self.max_command_payload));
+        }
+        Ok(())
+    }
+    
+    /// Hold a record to the out-of-order setting: false if it's to be
+    /// dropped, else kept, and flagged if it's older than the device's
+    /// last stored record. The first record after a start is compared with
+    /// the newest one on disk.
+    fn check_order(&mut self, record: &mut TelemetryRecord) -> bool {
+        if self.telemetry_out_of_order == OutOfOrder::Accept {
+            self.last_timestamps.remove(&record.device_id);
+            return true;
+        }
+        let last = match self.last_timestamps.get(&record.device_id) {
+            Some(last) => Some(*last),
+            None => {
+                let _ = self.telemetry.flush_device(&record.device_id);
+                self.telemetry_reader.recent(&record.device_id, 1).ok()
+                    .and_then(|records| records.last().map(|r| r.timestamp))
+            }
+        };
+        if last.is_some_and(|last| record.timestamp < last) {
+            return match self.telemetry_out_of_order {
+                OutOfOrder::Drop => false,
+                _ => {
+                    record.out_of_order = true;
+                    true
+                }
+            };
+        }
+        self.last_timestamps.insert(record.device_id.clone(), record.timestamp);
+        true
+    }
+    
+    /// Check that `device_id` takes `command_type`. A device of a type with
+    /// configured capabilities takes only its own capabilities, or its
+    /// type's if it has none; any other device takes anything.
+    fn check_capability(&self, device_id: &str, command_type: &str) -> Result<(), String> {
+        let Some(device) = self.db.get_device(device_id)? else {
+            return Ok(());
+        };
+        let Some(defaults) = self.type_capabilities.get(&device.device_type) else {
+            return Ok(());
+        };
+        let allowed = if device.capabilities.is_empty() { defaults } else { &device.capabilities };
+        if allowed.iter().any(|c| c == command_type) {
+            Ok(())
+        } else {
+            Err(format!("{} ({}) doesn't take {}", device_id, device.device_type, command_type))
+        }
+    }
+    
+    /// Save a checked command and send it, or queue, hold or skip it, telling
+    /// UIs each status it passes through.
+    fn dispatch_command(&mut self, cmd: &SendCommand, precondition: Option<&Precondition>) -> Result<Dispatched, String> {
+        // A retry of a command already saved: the original answers for it
+        if let Some(key) = &cmd.idempotency_key {
+            let since = now_unix() - IDEMPOTENCY_WINDOW_SECS;
+            if let Some((command_id, status)) = self.db.command_by_idempotency_key(&cmd.device_id, key, since)? {
+                log!("↻ Duplicate command: {} -> {} (key {}, {} is {})", cmd.command_type, cmd.device_id, key, command_id, status);
+                let skipped = if status == "skipped" { self.db.command_reason(&command_id)? } else { None };
+                return Ok(Dispatched { command_id, status, sent: false, skipped, duplicate: true });
+            }
+        }
+        
+        // In maintenance a command is held, not sent. A dry run still
+        // goes out: the device only logs it.
+        let held = !cmd.dry_run && self.db.maintenance().is_ok_and(|m| m.covers(&cmd.device_id));
+        
+        // Checked against the device as it is now. A command queued for
+        // an offline or sleeping device, or held, is checked when it's
+        // delivered instead.
+        let asleep = self.db.get_device(&cmd.device_id).ok().flatten().is_some_and(|d| d.status == DeviceStatus::Sleeping);
+        let online = !asleep && self.clients.values().any(|c| c.device_id.as_deref() == Some(cmd.device_id.as_str()));
+        // A device taking one at a time has it queued behind the one in
+        // flight and any already waiting
+        let waiting = !cmd.dry_run && !held && online && self.is_serial(&cmd.device_id)
+            && (self.db.in_flight_command(&cmd.device_id)?.is_some() || !self.db.get_pending_commands(&cmd.device_id)?.is_empty());
+        let skipped = match (precondition, online && !held && !waiting) {
+            (Some(precondition), true) => self.db.get_device(&cmd.device_id).ok().flatten()
+                .and_then(|device| precondition.unmet(&device)),
+            _ => None,
+        };
+        
+        let command_id = generate_id();
+        let payload_str = cmd.payload.to_string();
+        
+        // Saved before it's sent, so a device never runs a command with no row
+        let seq = self.db.with_transaction(|tx| {
+            // A dry run keeps its status for good: it is never queued, and the device only logs it
+            let initial = match (cmd.dry_run, held) {
+                (true, _) => "dry_run",
+                (false, true) => "held",
+                (false, false) => "queued",
+            };
+            let seq = state::insert_command(tx, &command_id, &cmd.device_id, &cmd.command_type, &payload_str, initial, cmd.request_id.as_deref())?;
+            if let Some(expr) = &cmd.precondition {
+                state::set_command_precondition(tx, &command_id, expr)?;
+            }
+            if let Some(key) = &cmd.idempotency_key {
+                state::set_command_idempotency_key(tx, &command_id, key)?;
+            }
+            if let Some(url) = &cmd.callback_url {
+                state::set_command_callback_url(tx, &command_id, url)?;
+            }
+            if let Some(reason) = &skipped {
+                state::set_command_skipped(tx, &command_id, reason)?;
+            }
+            Ok(seq)
+        })?;
+        
+        let sent = skipped.is_none() && !held && online && !waiting && {
+            let mut command = command_envelope(&command_id, &cmd.command_type, &cmd.payload, seq);
+            if cmd.dry_run {
+                command.data["dryRun"] = serde_json::json!(true);
+            }
+            send_to_device(&mut self.clients, &cmd.device_id, &command)
+        };
+        if sent && !cmd.dry_run {
+            if let Err(e) = self.db.advance_command_status(&command_id, "queued", "sent") {
+                log!("Command {} sent but not marked sent: {}", command_id, e);
+            }
+        }
+        
+        // A socket write only proves "sent"; "delivered" waits for the device's ack
+        let status = match (&skipped, cmd.dry_run, sent) {
+            (Some(_), _, _) => "skipped",
+            (None, true, _) => "dry_run",
+            (None, false, true) => "sent",
+            (None, false, false) if held => "held",
+            (None, false, false) => "queued",
+        };
+        if skipped.is_some() || cmd.dry_run || held {
+            self.broadcast_command_status(&command_id, &cmd.device_id, status);
+        } else {
+            self.broadcast_command_status(&command_id, &cmd.device_id, "queued");
+            if sent {
+                self.broadcast_command_status(&command_id, &cmd.device_id, status);
+            }
+        }
+        
+        match &skipped {
+            Some(reason) => log!("↻ Command skipped: {} -> {} ({})", cmd.command_type, cmd.device_id, reason),
+            None => log!("→ Command: {} -> {} ({})", cmd.command_type, cmd.device_id, status),
+        }
+        // Power control is on the record, however far it got
+        if CommandPayload::POWER.contains(&cmd.command_type.as_str()) {
+            let detail = format!("{} {}{}", command_id, status, if cmd.dry_run { " (dry run)" } else { "" });
+            if let Err(e) = self.db.audit("admin", &format!("command.{}", cmd.command_type), &cmd.device_id, &detail) {
+                log!("Audit log write failed: {}", e);
+            }
+        }
+        Ok(Dispatched { command_id, status: status.to_string(), sent, skipped, duplicate: false })
+    }
+    
+    /// Time out commands that were sent but never acknowledged.
+    fn expire_commands(&mut self) {
+        if let Ok(expired) = self.db.expire_unacked_commands(COMMAND_ACK_TIMEOUT_SECS) {
+            for (command_id, device_id) in expired {
+                log!("⏱ Command timed out: {} ({})", command_id, device_id);
+                self.broadcast_command_status(&command_id, &device_id, "timed_out");
+                self.post_command_callback(&command_id, &device_id, "timed_out", None);
+                self.send_next_command(&device_id);
+            }
+        }
+    }
+    
+    /// Cancel a command that hasn't ended. One still waiting here (queued
+    /// or held) just never goes out; one sent or delivered is under way on
+    /// the device, which is sent a `command:cancel` to stop it. `identity`
+    /// is the operator asking, held to the device's lease; None (the admin
+    /// token) overrides it.
+    fn cancel_command(&mut self, command_id: &str, identity: Option<&str>) -> Result<Cancelled, String> {
+        let Some((device_id, current)) = self.db.command_status(command_id)? else {
+            return Ok(Cancelled::Unknown);
+        };
+        if let (Some(identity), Some(lease)) = (identity, self.db.lease(&device_id)?) {
+            if lease.holder != identity {
+                return Ok(Cancelled::Leased);
+            }
+        }
+        if !matches!(current.as_str(), "queued" | "held" | "sent" | "delivered") {
+            return Ok(Cancelled::Finished(current));
+        }
+        // Compared against what was read, so an ack or timeout in between wins
+        if !self.db.advance_command_status(command_id, &current, "cancelled")? {
+            let status = self.db.command_status(command_id)?.map(|(_, status)| status).unwrap_or(current);
+            return Ok(Cancelled::Finished(status));
+        }
+        
+        let notified = matches!(current.as_str(), "sent" | "delivered")
+            && send_to_device(&mut self.clients, &device_id, &Envelope::new("command:cancel", &serde_json::json!({
+                "commandId": command_id,
+            })));
+        log!("✗ Command cancelled: {} ({}, was {})", command_id, device_id, current);
+        self.broadcast_command_status(command_id, &device_id, "cancelled");
+        self.post_command_callback(command_id, &device_id, "cancelled", None);
+        if matches!(current.as_str(), "sent" | "delivered") {
+            self.send_next_command(&device_id);
+        }
+        Ok(Cancelled::Done { device_id, notified })
+    }
+    
+    /// End leases that have run out, telling UIs each device is free.
+    fn expire_leases(&mut self) {
+        if let Ok(expired) = self.db.expire_leases() {
+            for lease in expired {
+                log!("⏱ Lease expired: {} ({})", lease.device_id, lease.holder);
+                self.broadcast_to_uis(&lease_message(&lease.device_id, None));
+            }
+        }
+    }
+    
+    /// Bring a sleeping device back online on hearing from it, telling UIs
+    /// and sending it what was queued while it slept.
+    fn wake_device(&mut self, device_id: &str) {
+        if !self.db.wake(device_id).unwrap_or(false) {
+            return;
+        }
+        log!("✓ Device woke: {}", device_id);
+        if let Ok(Some(device)) = self.db.get_device(device_id) {
+            self.broadcast_device_event(
+                Some(&Envelope::new("device:online", &device)),
+                &Envelope::new("devices:changed", &[serde_json::json!({"id": device_id, "status": DeviceStatus::Online, "wake_at": null})]),
+            );
+        }
+        let pending = self.db.get_pending_commands(device_id).unwrap_or_default();
+        self.deliver_queued_commands(device_id, pending);
+    }
+    
+    /// Mark offline the sleeping devices well past their wake time that
+    /// haven't reconnected. One still connected but silent counts as stale.
+    fn expire_sleeps(&mut self) {
+        let Ok(overslept) = self.db.overslept(now_unix() - WAKE_GRACE_SECS) else {
+            return;
+        };
+        for device_id in overslept {
+            if self.clients.values().any(|c| c.device_id.as_deref() == Some(device_id.as_str())) {
+                continue;
+            }
+            let _ = self.db.set_status(&device_id, DeviceStatus::Offline);
+            self.broadcast_device_event(
+                Some(&Envelope::new("device:offline", &serde_json::json!({"deviceId": device_id}))),
+                &Envelope::new("devices:changed", &[serde_json::json!({"id": device_id, "status": DeviceStatus::Offline})]),
+            );
+            log!("⏱ Device didn't wake: {}", device_id);
+        }
+    }
+    
+    /// POST a command's outcome to its callback URL, if it was sent with one.
+    fn post_command_callback(&self, command_id: &str, device_id: &str, status: &str, result: Option<&serde_json::Value>) {
+        let Ok(Some((url, command_type))) = self.db.command_callback(command_id) else {
+            return;
+        };
+        let body = serde_json::json!({
+            "command_id": command_id,
+            "device_id": device_id,
+            "command_type": command_type,
+            "status": status,
+            "result": result,
+            "timestamp": now_unix(),
+        });
+        webhook::post(&url, &body, &format!("{} {}", command_id, status), &self.webhook_allow);
+    }
+    
+    /// Broadcast pending pairing requests to all UIs. An empty list is sent
+    /// too, so a request confirmed or dismissed leaves their panels.
+    fn broadcast_pairing_requests(&mut self) {
+        if let Ok(requests) = self.db.get_pending_pairing_requests() {
+            self.pairing_sent = requests.iter().map(|r| (r.device_id.clone(), r.code.clone())).collect();
+            self.broadcast_to_uis(&pairing_requests_message(&requests));
+        }
+    }
+    
+    /// Clear out expired pairing requests, and broadcast the pending ones if
+    /// they aren't what UIs were last sent.
+    fn reconcile_pairing_requests(&mut self) {
+        let _ = self.db.cleanup_expired_requests();
+        if let Ok(requests) = self.db.get_pending_pairing_requests() {
+            let pending: Vec<(String, String)> = requests.iter().map(|r| (r.device_id.clone(), r.code.clone())).collect();
+            if pending != self.pairing_sent {
+                self.pairing_sent = pending;
+                self.broadcast_to_uis(&pairing_requests_message(&requests));
+            }
+        }
+    }
+    
+    /// Apply `config`'s live settings: WebSocket limits (for connections
+    /// opened from now on), the HTTP body and command payload caps, pairing, telemetry
+    /// retention, minimum distance and ordering, the device type check,
+    /// capabilities by device type, serial commands, the webhook allow
+    /// list and the registration policy. The rest take a restart; the names
+    /// of those that changed are returned and logged.
+    fn reload(&mut self, config: Config) -> Vec<&'static str> {
+        self.ingress_limit = config.ingress_limit;
+        self.max_message = config.max_message;
+        self.send_queue = config.send_queue;
+        self.ws_write_timeout_secs = config.ws_write_timeout_secs;
+        self.ws_deflate = config.ws_deflate;
+        self.max_body = config.max_body;
+        self.max_command_payload = config.max_command_payload;
+        self.pair_cooldown_secs = config.pair_cooldown_secs;
+        self.pair_auto_approve = config.pair_auto_approve.clone();
+        self.telemetry_retention_days = config.telemetry_retention_days;
+        self.telemetry.set_min_distance(config.telemetry_min_distance_m);
+        self.telemetry_out_of_order = config.telemetry_out_of_order;
+        self.type_check_samples = config.type_check_samples;
+        self.airborne_types = config.airborne_types.clone();
+        self.type_capabilities = config.type_capabilities.clone();
+        self.serial_commands = config.serial_commands.clone();
+        self.webhook_allow = config.webhook_allow.clone();
+        self.registration = config.registration.clone();
+        self.admin_token = config.admin_token.clone();
+        self.viewer_token = config.viewer_token.clone();
+        
+        let restart = self.config.restart_only_changes(&config);
+        log!("↻ Configuration reloaded");
+        for name in &restart {
+            log!("⚠ {} changed: takes effect after a restart", name);
+        }
+        restart
+    }
+}
+
+/// The `pairing:requests` message listing `requests`.
+fn pairing_requests_message(requests: &[PairingRequest]) -> Envelope {
+    let json: Vec<serde_json::Value> = requests.iter().map(|r| {
+        serde_json::json!({
+            "device_id": r.device_id,
+            "name": r.name,
+            "device_type": r.device_type,
+            "code": r.code,
+            "expires_at": r.expires_at,
+            "seconds_remaining": r.seconds_remaining
+        })
+    }).collect();
+    Envelope::new("pairing:requests", &serde_json::json!({"requests": json}))
+}
+
+// ============================================================================
+// HTTP API SETTINGS
+// ============================================================================
+
+/// Check a command's payload against the size cap; see `check_payload_size`.
+pub(crate) fn check_payload_size(server: &Arc<Mutex<Server>>, cmd: &SendCommand) -> Result<(), String> {
+    server.lock().map_err(|e| e.to_string())?.check_payload_size(cmd)
+}
+
+/// The server's device database. Clones share its connection.
+pub(crate) fn state_db(server: &Arc<Mutex<Server>>) -> Result<StateDb, String> {
+    server.lock().map(|s| s.db.clone()).map_err(|e| e.to_string())
+}
+
+/// The admin and viewer tokens, as configured.
+pub(crate) fn role_tokens(server: &Arc<Mutex<Server>>) -> (Option<String>, Option<String>) {
+    server.lock().map(|s| (s.admin_token.clone(), s.viewer_token.clone())).unwrap_or_default()
+}
+
+/// The HTTP request body cap, bytes. 0 = no cap.
+pub(crate) fn max_body(server: &Arc<Mutex<Server>>) -> u64 {
+    server.lock().map(|s| s.max_body).unwrap_or(HTTP_MAX_BODY_BYTES)
+}
+
+/// Seconds before a pending pairing request may be renewed. 0 = no wait.
+pub(crate) fn pair_cooldown(server: &Arc<Mutex<Server>>) -> u64 {
+    server.lock().map(|s| s.pair_cooldown_secs).unwrap_or(PAIR_COOLDOWN_SECS)
+}
+
+/// The auto-approve pattern `device_id` matches, if any does.
+pub(crate) fn auto_approve_pattern(server: &Arc<Mutex<Server>>, device_id: &str) -> Option<String> {
+    let server = server.lock().ok()?;
+    server.pair_auto_approve.iter()
+        .find(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => device_id.starts_with(prefix),
+            None => device_id == pattern.as_str(),
+        })
+        .cloned()
+}
+
+// ============================================================================
+// GROUP COMMANDS
+// ============================================================================
+
+/// Send one command to each of `device_ids`. The command is checked once,
+/// and a bad one refuses the whole group. After that each device stands
+/// alone: an unknown or revoked one fails without holding up the rest.
+pub(crate) fn send_group_command(server: &Arc<Mutex<Server>>, device_ids: &[String], cmd: &SendCommand) -> Result<Vec<Result<Dispatched, String>>, String> {
+    let mut server = server.lock().map_err(|e| e.to_string())?;
+    let precondition = server.check_command(cmd)?;
+    
+    let mut seen = std::collections::HashSet::new();
+    let results = device_ids.iter().map(|device_id| {
+        if !seen.insert(device_id.as_str()) {
+            return Err("duplicate id in group".to_string());
+        }
+        match server.db.get_device(device_id)? {
+            None => return Err("unknown device".to_string()),
+            Some(device) if device.status == DeviceStatus::Revoked => return Err("device is revoked".to_string()),
+            Some(_) => {}
+        }
+        server.check_capability(device_id, &cmd.command_type)?;
+        let cmd = SendCommand { device_id: device_id.clone(), ..cmd.clone() };
+        server.dispatch_command(&cmd, precondition.as_ref())
+    }).collect();
+    Ok(results)
+}
+
+/// Cancel a command for the admin; see `Server::cancel_command`.
+pub(crate) fn cancel_command(server: &Arc<Mutex<Server>>, command_id: &str) -> Result<Cancelled, String> {
+    server.lock().map_err(|e| e.to_string())?.cancel_command(command_id, None)
+}
+
+// ============================================================================
+// MAINTENANCE
+// ============================================================================
+
+/// Pause or resume command dispatch for `device_id`, or the whole fleet with
+/// None, and tell UIs. Resuming releases what was held, in issue order, to
+/// every device no longer paused: online ones get it now, the rest when they
+/// register. Returns the new state and how many commands were released.
+pub(crate) fn set_maintenance(server: &Arc<Mutex<Server>>, device_id: Option<&str>, active: bool) -> Result<(Maintenance, usize), String> {
+    let mut server = server.lock().map_err(|e| e.to_string())?;
+    server.db.set_maintenance(device_id, active)?;
+    let maintenance = server.db.maintenance()?;
+    server.broadcast_to_uis(&Envelope::new("maintenance:state", &maintenance_json(&maintenance)));
+    
+    let mut released = 0;
+    if !active {
+        for device_id in server.db.devices_with_held_commands()? {
+            if maintenance.covers(&device_id) {
+                continue;
+            }
+            for command_id in server.db.release_held_commands(&device_id)? {
+                server.broadcast_command_status(&command_id, &device_id, "queued");
+                released += 1;
+            }
+        }
+        let online: Vec<String> = server.clients.values().filter_map(|c| c.device_id.clone()).collect();
+        for device_id in online {
+            let pending = server.db.get_pending_commands(&device_id).unwrap_or_default();
+            server.deliver_queued_commands(&device_id, pending);
+        }
+    }
+    Ok((maintenance, released))
+}
+
+/// Maintenance state as UIs and the HTTP API see it.
+pub(crate) fn maintenance_json(maintenance: &Maintenance) -> serde_json::Value {
+    serde_json::json!({
+        "global": maintenance.global,
+        "devices": maintenance.devices,
+    })
+}
+
+// ============================================================================
+// REGISTRY CHANGES FROM THE HTTP API
+// ============================================================================
+
+/// Tell UIs on deltas about devices that entered the registry (paired or
+/// imported), or were paired again (`changed`). UIs on per-event messages
+/// learn of them when they come online, as before.
+pub(crate) fn devices_added(server: &Arc<Mutex<Server>>, devices: &[DeviceInfo], changed: bool) {
+    if devices.is_empty() {
+        return;
+    }
+    if let Ok(mut server) = server.lock() {
+        let msg_type = if changed { "devices:changed" } else { "devices:added" };
+        server.broadcast_device_event(None, &Envelope::new(msg_type, &devices));
+    }
+}
+
+/// Tell UIs the pending pairing requests changed.
+pub(crate) fn pairing_changed(server: &Arc<Mutex<Server>>) {
+    if let Ok(mut server) = server.lock() {
+        server.broadcast_pairing_requests();
+    }
+}
+
+/// A feed of `device_id`'s command statuses from now on, each as the JSON
+/// UIs get in `command:status`. It ends when the server stops.
+pub(crate) fn subscribe_commands(server: &Arc<Mutex<Server>>, device_id: &str) -> Result<Receiver<String>, String> {
+    let (feed, events) = mpsc::channel();
+    let mut server = server.lock().map_err(|e| e.to_string())?;
+    server.command_streams.push((device_id.to_string(), feed));
+    Ok(events)
+}
+
+/// Tell UIs who holds `device_id`'s command lease now, if anyone.
+pub(crate) fn lease_changed(server: &Arc<Mutex<Server>>, device_id: &str, lease: Option<&Lease>) {
+    if let Ok(mut server) = server.lock() {
+        server.broadcast_to_uis(&lease_message(device_id, lease));
+    }
+}
+
+/// `lease:changed` for UIs: the holder and expiry, or nulls once it ends.
+fn lease_message(device_id: &str, lease: Option<&Lease>) -> Envelope {
+    Envelope::new("lease:changed", &serde_json::json!({
+        "deviceId": device_id,
+        "holder": lease.map(|l| &l.holder),
+        "expiresAt": lease.map(|l| l.expires_at),
+    }))
+}
+
+/// Tell UIs a device was revoked.
+pub(crate) fn device_removed(server: &Arc<Mutex<Server>>, device_id: &str) {
+    if let Ok(mut server) = server.lock() {
+        server.broadcast_device_removed(device_id);
+    }
+}
+
+// ============================================================================
+// ALERTS FROM THE HTTP API
+// ============================================================================
+
+/// Tell UIs an operator acknowledged an alert, so they stop highlighting it.
+pub(crate) fn alert_acknowledged(server: &Arc<Mutex<Server>>, alert: &Alert) {
+    if let Ok(mut server) = server.lock() {
+        server.broadcast_to_uis(&Envelope::new("alert:acknowledged", &serde_json::json!({
+            "id": alert.id,
+            "deviceId": alert.device_id,
+            "acknowledgedAt": alert.acknowledged_at,
+            "acknowledgedBy": alert.acknowledged_by,
+        })));
+    }
+}
+
+// ============================================================================
+// REPLAY
+// ============================================================================
+
+/// The fleet's headline numbers, as `/api/stats` and `getStats` give them.
+pub(crate) fn fleet_stats(db: &StateDb, reader: &TelemetryReader) -> Result<serde_json::Value, String> {
+    let now = now_unix();
+    let today = now - now.rem_euclid(86400);
+    let counts = db.fleet_counts(now - STALE_AFTER_SECS, today)?;
+    let telemetry_today = reader.count_day(now)?;
+    Ok(serde_json::json!({
+        "devices": {
+            "total": counts.devices,
+            "online": counts.online,
+            "offline": counts.offline,
+            "sleeping": counts.sleeping,
+            "stale": counts.stale,
+            "by_type": counts.by_type,
+        },
+        "pairing_requests": counts.pairing_requests,
+        "commands_today": counts.commands,
+        "pending_commands": counts.pending_commands,
+        "telemetry_today": telemetry_today,
+        "day_start": today,
+        "stale_after_secs": STALE_AFTER_SECS,
+    }))
+}
+
+/// A reader for the server's stored telemetry.
+pub(crate) fn telemetry_reader(server: &Arc<Mutex<Server>>) -> Result<TelemetryReader, String> {
+    let server = server.lock().map_err(|e| e.to_string())?;
+    Ok(server.telemetry_reader.clone())
+}
+
+/// Store records a device uploaded over HTTP rather than streamed, and
+/// flush them so they can be read straight away. Like streamed ones they
+/// go in today's file; the device's live state is left alone.
+/// Returns how many were dropped for being out of order.
+pub(crate) fn import_telemetry(server: &Arc<Mutex<Server>>, device_id: &str, records: Vec<TelemetryRecord>) -> Result<usize, String> {
+    let mut server = server.lock().map_err(|e| e.to_string())?;
+    let mut dropped = 0;
+    for mut record in records {
+        if !server.check_order(&mut record) {
+            dropped += 1;
+            continue;
+        }
+        server.telemetry.write(&record)?;
+    }
+    if dropped > 0 {
+        log!("⚠ Dropped {} out-of-order telemetry records from {}", dropped, device_id);
+    }
+    server.telemetry.flush_device(device_id)?;
+    Ok(dropped)
+}
+
+/// Every live WebSocket connection, oldest first, with what has crossed it.
+pub(crate) fn connections(server: &Arc<Mutex<Server>>) -> Result<Vec<serde_json::Value>, String> {
+    let server = server.lock().map_err(|e| e.to_string())?;
+    let mut ids: Vec<&usize> = server.clients.keys().collect();
+    ids.sort();
+    Ok(ids.into_iter().map(|id| {
+        let client = &server.clients[id];
+        serde_json::json!({
+            "id": id,
+            "peer": client.ws.peer_addr(),
+            "ip": client.ip.to_string(),
+            "client_type": client.client_type.as_str(),
+            "device_id": client.device_id,
+            "read_only": client.read_only,
+            "uptime_secs": client.ws.uptime().as_secs(),
+            "stats": client.ws.stats(),
+        })
+    }).collect())
+}
+
+/// Devices with a live, registered WebSocket right now, by id, with how
+/// long each has been connected. This is the connection table, not the
+/// registry's status, which can lag behind a dropped connection.
+pub(crate) fn connected_devices(server: &Arc<Mutex<Server>>) -> Result<Vec<(DeviceInfo, u64)>, String> {
+    let server = server.lock().map_err(|e| e.to_string())?;
+    let mut connected: std::collections::BTreeMap<&str, u64> = std::collections::BTreeMap::new();
+    for client in server.clients.values() {
+        if let (ClientType::Device, Some(device_id)) = (client.client_type, &client.device_id) {
+            let secs = client.ws.uptime().as_secs();
+            let longest = connected.entry(device_id).or_insert(secs);
+            *longest = (*longest).max(secs);
+        }
+    }
+    let mut devices = Vec::new();
+    for (device_id, secs) in connected {
+        if let Some(device) = server.db.get_device(device_id)? {
+            devices.push((device, secs));
+        }
+    }
+    Ok(devices)
+}
+
+/// Start playing `device_id`'s telemetry from `start` to `end` back to UIs
+/// at `speed` times real time. Returns the replay id. One replay per device
+/// at a time.
+pub(crate) fn start_replay(server: &Arc<Mutex<Server>>, device_id: &str, start: i64, end: i64, speed: f64) -> Result<String, String> {
+    let (replay, reader) = {
+        let mut locked = server.lock().map_err(|e| e.to_string())?;
+        if locked.replays.contains_key(device_id) {
+            return Err(format!("a replay of {} is already running", device_id));
+        }
+        // Whatever the writer still buffers belongs in the replay too
+        locked.telemetry.flush_device(device_id)?;
+        let replay = Replay::new(generate_id());
+        locked.replays.insert(device_id.to_string(), replay.clone());
+        (replay, locked.telemetry_reader.clone())
+    };
+    
+    let server = Arc::clone(server);
+    let device_id = device_id.to_string();
+    let replay_id = replay.id.clone();
+    thread::spawn(move || {
+        let records = reader.records(&device_id, start, end);
+        let count = replay.run(records, &device_id, speed, |update| {
+            if let Ok(mut server) = server.lock() {
+                server.broadcast_to_uis(&Envelope::new("replay:update", &update));
+            }
+        });
+        
+        if let Ok(mut server) = server.lock() {
+            if server.replays.get(&device_id).is_some_and(|r| r.is(&replay)) {
+                server.replays.remove(&device_id);
+            }
+            server.broadcast_to_uis(&Envelope::new("replay:end", &serde_json::json!({
+                "replayId": replay.id,
+                "deviceId": device_id,
+                "records": count,
+                "cancelled": replay.is_cancelled(),
+            })));
+        }
+        log!("↻ Replay {} of {} ended after {} records", replay.id, device_id, count);
+    });
+    
+    Ok(replay_id)
+}
+
+/// Cancel `device_id`'s replay. False if none was running.
+pub(crate) fn cancel_replay(server: &Arc<Mutex<Server>>, device_id: &str) -> Result<bool, String> {
+    let mut server = server.lock().map_err(|e| e.to_string())?;
+    match server.replays.remove(device_id) {
+        Some(replay) => {
+            replay.cancel();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Write an envelope to a device's socket. Takes the client map directly so it
+/// can run while `server.db` is borrowed (e.g. inside a transaction).
+fn send_to_device(clients: &mut HashMap<usize, Client>, device_id: &str, envelope: &Envelope) -> bool {
+    let json = envelope.to_json();
+    for client in clients.values_mut() {
+        if client.device_id.as_deref() == Some(device_id) {
+            return client.ws.send(&json).is_ok();
+        }
+    }
+    false
+}
+
+/// The `command` message a device receives.
+fn command_envelope(command_id: &str, command_type: &str, payload: &serde_json::Value, seq: i64) -> Envelope {
+    Envelope::new("command", &serde_json::json!({
+        "commandId": command_id,
+        "type": command_type,
+        "payload": payload,
+        "seq": seq,
+    }))
+}
+
+// ============================================================================
+// MESSAGE HANDLING
+// ============================================================================
+
+/// Store a registered device's telemetry record and pass it on to UIs.
+/// With `ack`, the device hears once it's on disk. A sleeping device is
+/// awake again. One dropped for being out of order is left at that.
+fn store_telemetry(server: &mut Server, client_id: usize, mut record: TelemetryRecord, ack: bool) {
+    if !server.check_order(&mut record) {
+        log!("⚠ Dropped out-of-order telemetry from {} ({})", record.device_id, record.timestamp);
+        return;
+    }
+    let record = &record;
+    let device_id = &record.device_id;
+    server.wake_device(device_id);
+    let _ = server.db.update_telemetry(
+        device_id,
+        record.latitude,
+        record.longitude,
+        record.altitude,
+        record.heading,
+        record.speed,
+        record.battery,
+        &record.sensors,
+    );
+    let stored = server.telemetry.write(record).is_ok();
+    
+    // Acked only once flushed, so the device can drop its copy
+    if ack && stored && server.telemetry.flush_device(device_id).is_ok() {
+        if let Some(client) = server.clients.get_mut(&client_id) {
+            let _ = client.reply(&Envelope::new("telemetry:ack", &serde_json::json!({
+                "timestamp": record.timestamp
+            })).to_json());
+        }
+    }
+    
+    let device_update = serde_json::json!({
+        "id": device_id,
+        "latitude": record.latitude,
+        "longitude": record.longitude,
+        "altitude": record.altitude,
+        "heading": record.heading,
+        "speed": record.speed,
+        "battery": record.battery,
+        "status": DeviceStatus::Online,
+    });
+    
+    server.track_motion(record);
+    server.check_type(record);
+    server.queue_device_update(device_id, device_update);
+}
+
+/// A binary frame on a connection that negotiated binary telemetry: one
+/// record in the fixed layout, from a registered device. It's stamped on
+/// arrival, like JSON telemetry, so it's filed on the day it's stored.
+/// A signing device's telemetry must be signed, which a binary frame
+/// can't be, so it's refused.
+fn handle_binary_telemetry(server: &mut Server, client_id: usize, frame: &[u8]) {
+    let Some(client) = server.clients.get(&client_id) else {
+        return;
+    };
+    let Some(device_id) = client.device_id.clone() else {
+        return;
+    };
+    let decoded = if client.signing_key.is_some() {
+        Err(("bad_signature", "binary telemetry can't be signed; send it as JSON".to_string()))
+    } else {
+        TelemetryRecord::from_binary(&device_id, frame).map_err(|e| ("invalid_telemetry", e))
+    };
+    match decoded {
+        Ok(mut record) => {
+            record.timestamp = now_unix();
+            store_telemetry(server, client_id, record, false);
+        }
+        Err((code, e)) => {
+            log!("✗ Binary telemetry from {} rejected: {}", device_id, e);
+            if let Some(client) = server.clients.get_mut(&client_id) {
+                let _ = client.ws.send(&Envelope::new("error", &serde_json::json!({
+                    "code": code,
+                    "message": e
+                })).to_json());
+            }
+        }
+    }
+}
+
+fn handle_message(server: &mut Server, client_id: usize, msg: &str) {
+    let envelope: Envelope = match serde_json::from_str(msg) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    if envelope.msg_type == "batch" {
+        handle_batch(server, client_id, msg);
+        return;
+    }
+    
+    // A signing device's reports count only with a valid signature
+    if signing::SIGNED_TYPES.contains(&envelope.msg_type.as_str()) {
+        let key = server.clients.get(&client_id).and_then(|c| c.signing_key.clone());
+        if let Some(key) = key {
+            if let Err(e) = signing::verify(&key, msg) {
+                if let Some(client) = server.clients.get_mut(&client_id) {
+                    log!("✗ {} from {} rejected: {}", envelope.msg_type, client.device_id.as_deref().unwrap_or("?"), e);
+                    let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                        "code": "bad_signature",
+                        "message": format!("{} rejected: {}", envelope.msg_type, e)
+                    })).to_json());
+                }
+                return;
+            }
+        }
+    }
+    
+    // A viewer's UI watches the fleet; what it would do to it is refused
+    if VIEWER_REFUSED.contains(&envelope.msg_type.as_str()) {
+        if let Some(client) = server.clients.get_mut(&client_id).filter(|c| c.read_only) {
+            let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                "code": "read_only",
+                "message": format!("{} refused: viewer token is read-only", envelope.msg_type)
+            })).to_json());
+            return;
+        }
+    }
+    
+    match envelope.msg_type.as_str() {
+        // Device registration (with token auth)
+        "register" => {
+            if let Ok(reg) = serde_json::from_value::<RegisterMessage>(envelope.data) {
+                // Validate token
+                let token = reg.token.as_deref().unwrap_or("");
+                
+                if !token.is_empty() {
+                    // Check if token is valid
+                    match server.db.validate_token(token) {
+                        Ok(Some(stored_device_id)) => {
+                            // A token is its own device's: it can't register as another
+                            if !reg.device_id.is_empty() && reg.device_id != stored_device_id {
+                                if let Some(client) = server.clients.get_mut(&client_id) {
+                                    let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                                        "code": "invalid_token",
+                                        "message": "Token belongs to another device."
+                                    })).to_json());
+                                }
+                                log!("✗ Token for {} used to register as {}", stored_device_id, reg.device_id);
+                                return;
+                            }
+                            let device_id = stored_device_id.clone();
+                            
+                            let now = now_unix();
+                            let device = DeviceInfo {
+                                id: device_id.clone(),
+                                name: reg.name.clone(),
+                                device_type: reg.device_type.clone(),
+                                status: DeviceStatus::Online,
+                                latitude: reg.latitude,
+                                longitude: reg.longitude,
+                                altitude: reg.altitude,
+                                heading: 0.0,
+                                speed: 0.0,
+                                battery: 100.0,
+                                last_seen: now,
+                                queued_commands: 0,
+                                pending_commands: 0,
+                                color: appearance::default_color(&device_id),
+                                icon: appearance::default_icon(&reg.device_type).to_string(),
+                                capabilities: reg.capabilities.clone(),
+                                firmware_version: None,
+                                sensors: Vec::new(),
+                                wake_at: None,
+                                tags: Vec::new(),
+                            };
+                            
+                            // A revoked device has to pair again before it comes online
+                            let stored = server.db.get_device(&device_id).ok().flatten();
+                            if let Some(stored) = stored.filter(|d| !d.status.can_become(DeviceStatus::Online)) {
+                                if let Some(client) = server.clients.get_mut(&client_id) {
+                                    let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                                        "code": "invalid_status",
+                                        "message": format!("Device is {}. Please re-pair the device.", stored.status.as_str())
+                                    })).to_json());
+                                }
+                                log!("✗ {} device tried to register: {}", stored.status.as_str(), device_id);
+                                return;
+                            }
+                            
+                            // Onboarding rules: a device refused here never comes online
+                            let tags = match server.registration.check(&reg.device_type, &reg.name) {
+                                Ok(tags) => tags,
+                                Err(reason) => {
+                                    if let Some(client) = server.clients.get_mut(&client_id) {
+                                        let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                                            "code": "registration_refused",
+                                            "message": reason
+                                        })).to_json());
+                                    }
+                                    log!("✗ Registration refused: {} ({})", device_id, reason);
+                                    return;
+                                }
+                            };
+                            
+                            // A flapping device may register anew before its old
+                            // connection is noticed gone. The newest connection wins.
+                            server.replace_device_connection(&device_id, client_id);
+                            
+                            let _ = server.db.upsert_device(&device);
+                            if !tags.is_empty() {
+                                if let Err(e) = server.db.add_device_tags(&device_id, &tags) {
+                                    log!("⚠ Tags for {} not stored: {}", device_id, e);
+                                }
+                            }
+                            // Declaring none, it gets its type's defaults, if there are any
+                            let capabilities = match reg.capabilities.is_empty() {
+                                true => server.type_capabilities.get(&reg.device_type).cloned(),
+                                false => Some(reg.capabilities.clone()),
+                            };
+                            if let Some(capabilities) = capabilities {
+                                let info = DeviceInfoUpdate { capabilities: Some(capabilities), ..Default::default() };
+                                if let Err(e) = info.validate().and_then(|_| server.db.set_device_info(&device_id, &info)) {
+                                    log!("⚠ Capabilities from {} not stored: {}", device_id, e);
+                                }
+                            }
+                            let pending = server.db.get_pending_commands(&device_id).unwrap_or_default();
+                            // As stored: with its queue and any chosen appearance
+                            let device = server.db.get_device(&device_id).ok().flatten()
+                                .unwrap_or(DeviceInfo { queued_commands: pending.len() as i64, pending_commands: pending.len() as i64, ..device });
+                            let signed = server.db.requires_signature(&device_id).unwrap_or(true);
+                            
+                            let mut from = String::new();
+                            if let Some(client) = server.clients.get_mut(&client_id) {
+                                client.client_type = ClientType::Device;
+                                client.device_id = Some(device_id.clone());
+                                client.signing_key = signed.then(|| token.to_string());
+                                from = client.ip.to_string();
+                                let _ = client.reply(&Envelope::new("registered", &serde_json::json!({
+                                    "status": "ok",
+                                    "device": device,
+                                    "server_t": now_millis()
+                                })).to_json());
+                            }
+                            
+                            server.broadcast_device_event(
+                                Some(&Envelope::new("device:online", &device)),
+                                &Envelope::new("devices:changed", &[&device]),
+                            );
+                            log!("✓ Device registered: {} ({}) from {}", reg.name, reg.device_type, from);
+                            server.deliver_queued_commands(&device_id, pending);
+                        }
+                        Ok(None) => {
+                            // Invalid token
+                            if let Some(client) = server.clients.get_mut(&client_id) {
+                                let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                                    "code": "invalid_token",
+                                    "message": "Invalid or expired token. Please re-pair the device."
+                                })).to_json());
+                            }
+                            log!("✗ Invalid token from device: {}", reg.device_id);
+                        }
+                        Err(e) => {
+                            if let Some(client) = server.clients.get_mut(&client_id) {
+                                let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                                    "code": "db_error",
+                                    "message": e
+                                })).to_json());
+                            }
+                        }
+                    }
+                } else {
+                    // No token provided - reject
+                    if let Some(client) = server.clients.get_mut(&client_id) {
+                        let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                            "code": "no_token",
+                            "message": "Authentication required. Use /api/pair/request to get a token."
+                        })).to_json());
+                    }
+                    log!("✗ Device tried to register without token: {}", reg.device_id);
+                }
+            }
+        }
+        
+        // Device telemetry
+        "telemetry" => {
+            if let Ok(telem) = serde_json::from_value::<TelemetryMessage>(envelope.data.clone()) {
+                let device_id = server.clients.get(&client_id)
+                    .and_then(|c| c.device_id.clone());
+                
+                if let Some(device_id) = device_id {
+                    let record = TelemetryRecord {
+                        timestamp: now_unix(),
+                        device_id,
+                        latitude: telem.latitude,
+                        longitude: telem.longitude,
+                        altitude: telem.altitude,
+                        heading: telem.heading,
+                        speed: telem.speed,
+                        battery: telem.battery,
+                        sensors: telem.sensors,
+                        out_of_order: false,
+                    };
+                    store_telemetry(server, client_id, record, telem.ack);
+                }
+            }
+        }
+        
+        // Device alert: stored for the incident feed, pushed to UIs at once
+        "alert" => {
+            let Some(device_id) = server.clients.get(&client_id).and_then(|c| c.device_id.clone()) else {
+                return;
+            };
+            let alert = serde_json::from_value::<AlertMessage>(envelope.data)
+                .map_err(|e| e.to_string())
+                .and_then(|alert| alert.validate().map(|_| alert));
+            let alert = match alert {
+                Ok(alert) => alert,
+                Err(e) => {
+                    log!("✗ Alert from {} rejected: {}", device_id, e);
+                    if let Some(client) = server.clients.get_mut(&client_id) {
+                        let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                            "code": "invalid_alert",
+                            "message": e
+                        })).to_json());
+                    }
+                    return;
+                }
+            };
+            let alert = match server.db.insert_alert(&device_id, &alert.severity, &alert.code, &alert.message) {
+                Ok(alert) => alert,
+                Err(e) => {
+                    log!("✗ Alert from {} not stored: {}", device_id, e);
+                    return;
+                }
+            };
+            
+            log!("⚠ Alert from {}: [{}] {} {}", device_id, alert.severity, alert.code, alert.message);
+            server.broadcast_to_uis(&Envelope::new("alert:new", &serde_json::json!({
+                "id": alert.id,
+                "deviceId": alert.device_id,
+                "severity": alert.severity,
+                "code": alert.code,
+                "message": alert.message,
+                "timestamp": alert.created_at,
+            })));
+        }
+        
+        // Device going into a low-power sleep: not offline until it's due back
+        "sleep" => {
+            let Some(device_id) = server.clients.get(&client_id).and_then(|c| c.device_id.clone()) else {
+                return;
+            };
+            let slept = serde_json::from_value::<SleepMessage>(envelope.data)
+                .map_err(|e| e.to_string())
+                .and_then(|sleep| sleep.validate(now_unix()).map(|_| sleep))
+                .and_then(|sleep| match server.db.sleep(&device_id, sleep.wake_at)? {
+                    true => Ok(sleep),
+                    false => Err("device can't sleep".to_string()),
+                });
+            let sleep = match slept {
+                Ok(sleep) => sleep,
+                Err(e) => {
+                    log!("✗ Sleep from {} rejected: {}", device_id, e);
+                    if let Some(client) = server.clients.get_mut(&client_id) {
+                        let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                            "code": "invalid_sleep",
+                            "message": e
+                        })).to_json());
+                    }
+                    return;
+                }
+            };
+            
+            // Its last position is final until it wakes
+            server.pending_updates.remove(&device_id);
+            server.motion.remove(&device_id);
+            server.broadcast_device_event(
+                Some(&Envelope::new("device:sleeping", &serde_json::json!({"deviceId": device_id, "wakeAt": sleep.wake_at}))),
+                &Envelope::new("devices:changed", &[serde_json::json!({"id": device_id, "status": DeviceStatus::Sleeping, "wake_at": sleep.wake_at})]),
+            );
+            log!("⏱ Device sleeping: {} until {}", device_id, sleep.wake_at);
+        }
+        
+        // Device describing itself anew, e.g. after an OTA update
+        "device:update_info" => {
+            let Some(device_id) = server.clients.get(&client_id).and_then(|c| c.device_id.clone()) else {
+                return;
+            };
+            // Only about itself: the id it registered as, if it names one
+            let claimed = envelope.data.get("device_id").and_then(|v| v.as_str());
+            let info = match claimed {
+                Some(claimed) if claimed != device_id => Err(format!("registered as {}, not {}", device_id, claimed)),
+                _ => serde_json::from_value::<DeviceInfoUpdate>(envelope.data)
+                    .map_err(|e| e.to_string())
+                    .and_then(|info| info.validate().map(|_| info)),
+            };
+            let stored = info.and_then(|info| server.db.set_device_info(&device_id, &info))
+                .and_then(|_| server.db.get_device(&device_id))
+                .and_then(|device| device.ok_or_else(|| "device not found".to_string()));
+            let device = match stored {
+                Ok(device) => device,
+                Err(e) => {
+                    log!("✗ Info update from {} rejected: {}", device_id, e);
+                    if let Some(client) = server.clients.get_mut(&client_id) {
+                        let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                            "code": "invalid_info",
+                            "message": e
+                        })).to_json());
+                    }
+                    return;
+                }
+            };
+            
+            log!("↻ Device info updated: {} (firmware {})", device_id, device.firmware_version.as_deref().unwrap_or("unknown"));
+            server.broadcast_device_event(
+                Some(&Envelope::new("device:info_changed", &device)),
+                &Envelope::new("devices:changed", &[&device]),
+            );
+        }
+        
+        // UI requesting device list
+        "getDevices" => {
+            if let Some(client) = server.clients.get_mut(&client_id) {
+                client.client_type = ClientType::Ui;
+                client.deltas = envelope.data.get("deltas").and_then(|v| v.as_bool()).unwrap_or(false);
+                
+                if let Ok(devices) = server.db.get_all_devices() {
+                    let _ = client.reply(&Envelope::new("devices:list", &devices).to_json());
+                }
+                
+                // Also send pending pairing requests
+                if let Ok(requests) = server.db.get_pending_pairing_requests() {
+                    let _ = client.reply(&pairing_requests_message(&requests).to_json());
+                }
+                
+                // And any pause on command dispatch
+                if let Ok(maintenance) = server.db.maintenance() {
+                    if maintenance != Maintenance::default() {
+                        let _ = client.reply(&Envelope::new("maintenance:state", &maintenance_json(&maintenance)).to_json());
+                    }
+                }
+            }
+            if let Some(client) = server.clients.get(&client_id) {
+                log!("✓ GlobalUI connected from {}", client.ip);
+            }
+        }
+        
+        // UI asking for the fleet's headline numbers
+        "getStats" => {
+            let stats = fleet_stats(&server.db, &server.telemetry_reader);
+            if let Some(client) = server.clients.get_mut(&client_id) {
+                let reply = match stats {
+                    Ok(stats) => Envelope::new("stats", &stats),
+                    Err(e) => Envelope::new("error", &serde_json::json!({"code": "stats_failed", "message": e})),
+                };
+                let _ = client.reply(&reply.to_json());
+            }
+        }
+        
+        // UI dismissing a pairing request
+        "dismissPairing" => {
+            if let Some(device_id) = envelope.data.get("device_id").and_then(|v| v.as_str()) {
+                let _ = server.db.delete_pairing_request(device_id);
+                server.broadcast_pairing_requests();
+                log!("✗ Pairing dismissed: {}", device_id);
+            }
+        }
+        
+        // UI revoking a device
+        "revokeDevice" => {
+            if let Some(device_id) = envelope.data.get("device_id").and_then(|v| v.as_str()) {
+                let _ = server.db.delete_device(device_id);
+                server.broadcast_device_removed(device_id);
+                log!("✗ Device revoked: {}", device_id);
+            }
+        }
+        
+        // UI sending command to device
+        "sendCommand" => {
+            if let Ok(cmd) = serde_json::from_value::<SendCommand>(envelope.data) {
+                let request_id = cmd.request_id.as_deref();
+                let identity = server.clients.get(&client_id).map(|c| c.identity.as_str()).unwrap_or_default();
+                let admin = server.clients.get(&client_id).is_some_and(|c| c.admin);
+                // A rejection's reason, for those a UI may act on, and its error
+                let checked = match server.db.lease(&cmd.device_id) {
+                    _ if !admin && CommandPayload::POWER.contains(&cmd.command_type.as_str()) => {
+                        Err((None, format!("{} needs the admin token", cmd.command_type)))
+                    }
+                    Ok(Some(lease)) if lease.holder != identity => Err((None, "leased".to_string())),
+                    _ => server.check_payload_size(&cmd).map_err(|e| (Some(PAYLOAD_TOO_LARGE), e)).and_then(|_| {
+                        server.check_command(&cmd)
+                            .and_then(|precondition| server.check_capability(&cmd.device_id, &cmd.command_type).map(|_| precondition))
+                            .map_err(|e| (None, e))
+                    }),
+                };
+                let precondition = match checked {
+                    Ok(precondition) => precondition,
+                    Err((reason, e)) => {
+                        if let Some(client) = server.clients.get_mut(&client_id) {
+                            let mut rejected = serde_json::json!({
+                                "deviceId": cmd.device_id,
+                                "commandType": cmd.command_type,
+                                "error": e,
+                            });
+                            if let Some(reason) = reason {
+                                rejected["reason"] = serde_json::json!(reason);
+                            }
+                            if let Some(id) = request_id {
+                                rejected["requestId"] = serde_json::json!(id);
+                            }
+                            let _ = client.reply(&Envelope::new("command:rejected", &rejected).to_json());
+                        }
+                        log!("✗ Command rejected: {} -> {} ({})", cmd.command_type, cmd.device_id, e);
+                        return;
+                    }
+                };
+                
+                let dispatched = match server.dispatch_command(&cmd, precondition.as_ref()) {
+                    Ok(dispatched) => dispatched,
+                    Err(e) => {
+                        if let Some(client) = server.clients.get_mut(&client_id) {
+                            let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                                "code": "db_error",
+                                "message": e
+                            })).to_json());
+                        }
+                        return;
+                    }
+                };
+                
+                if let Some(client) = server.clients.get_mut(&client_id) {
+                    let mut reply = serde_json::json!({
+                        "commandId": dispatched.command_id,
+                        "deviceId": cmd.device_id,
+                        "status": dispatched.status,
+                        "dryRun": cmd.dry_run,
+                        "dispatched": dispatched.sent,
+                    });
+                    if let Some(id) = request_id {
+                        reply["requestId"] = serde_json::json!(id);
+                    }
+                    if let Some(reason) = &dispatched.skipped {
+                        reply["reason"] = serde_json::json!(reason);
+                    }
+                    if dispatched.duplicate {
+                        reply["duplicate"] = serde_json::json!(true);
+                    }
+                    let _ = client.reply(&Envelope::new("command:sent", &reply).to_json());
+                }
+            }
+        }
+        
+        // UI calling off a command; UIs hear of it as a command:status
+        "cancelCommand" => {
+            let Some(command_id) = envelope.data.get("commandId").and_then(|v| v.as_str()) else {
+                return;
+            };
+            let identity = server.clients.get(&client_id).map(|c| c.identity.clone()).unwrap_or_default();
+            let refused = match server.cancel_command(command_id, Some(&identity)) {
+                Ok(Cancelled::Done { .. }) => return,
+                Ok(Cancelled::Finished(status)) => format!("command is already {}", status),
+                Ok(Cancelled::Leased) => "leased".to_string(),
+                Ok(Cancelled::Unknown) => "unknown command".to_string(),
+                Err(e) => e,
+            };
+            if let Some(client) = server.clients.get_mut(&client_id) {
+                let _ = client.reply(&Envelope::new("error", &serde_json::json!({
+                    "code": "cancel_refused",
+                    "commandId": command_id,
+                    "message": refused
+                })).to_json());
+            }
+        }
+        
+        // Device acknowledging command
+        "command:ack" | "command:complete" => {
+            let Some(command_id) = envelope.data.get("commandId").and_then(|v| v.as_str()) else {
+                return;
+            };
+            let sender = server.clients.get(&client_id).and_then(|c| c.device_id.clone());
+            let Ok(Some((device_id, current))) = server.db.command_status(command_id) else {
+                return;
+            };
+            // Only the device a command was sent to reports on it
+            if sender.as_deref() != Some(device_id.as_str()) {
+                log!("✗ {} for {} from {}, not its device", envelope.msg_type, command_id, sender.as_deref().unwrap_or("?"));
+                return;
+            }
+            let reported = envelope.data.get("status").and_then(|v| v.as_str());
+            let status = match reported_status(&envelope.msg_type, reported, &current) {
+                Ok(status) => status,
+                Err(e) => {
+                    log!("✗ {} for {} ignored: {}", envelope.msg_type, command_id, e);
+                    return;
+                }
+            };
+            // Compared against what was read, so a timeout in between wins
+            if status != current && !server.db.advance_command_status(command_id, &current, status).unwrap_or(false) {
+                return;
+            }
+            
+            server.broadcast_command_status(command_id, &device_id, status);
+            // Anything past delivered is the device's last word on it
+            if status != "delivered" {
+                server.post_command_callback(command_id, &device_id, status, envelope.data.get("result"));
+            }
+            server.broadcast_to_uis(&envelope);
+            if status != "delivered" {
+                server.send_next_command(&device_id);
+            }
+        }
+        
+        // Anyone measuring latency: the client's timestamp comes back untouched.
+        // From a device it's a heartbeat too, and wakes it.
+        "ping" => {
+            if let Some(device_id) = server.clients.get(&client_id).and_then(|c| c.device_id.clone()) {
+                server.wake_device(&device_id);
+            }
+            if let Some(client) = server.clients.get_mut(&client_id) {
+                let _ = client.reply(&Envelope::new("pong", &serde_json::json!({
+                    "t": envelope.data.get("t").cloned().unwrap_or(serde_json::Value::Null),
+                    "server_t": now_millis()
+                })).to_json());
+            }
+        }
+        
+        _ => {}
+    }
+}
+
+/// A `batch` envelope, its requests kept as the text they arrived as so a
+/// signed one still verifies when handled.
+#[derive(serde::Deserialize)]
+struct Batch {
+    data: BatchData,
+}
+
+#[derive(serde::Deserialize)]
+struct BatchData {
+    #[serde(default)]
+    id: serde_json::Value,
+    requests: Vec<Box<RawValue>>,
+}
+
+/// Just the type of one of a batch's requests.
+#[derive(serde::Deserialize)]
+struct BatchRequest {
+    #[serde(rename = "type", default)]
+    msg_type: String,
+}
+
+/// A `batch`: each of its requests handled in order, as if sent on its
+/// own, and everything each would have sent back gathered into one
+/// `batch:result`. Broadcasts to other clients go out as usual. A batch
+/// inside a batch is refused.
+fn handle_batch(server: &mut Server, client_id: usize, msg: &str) {
+    let error = |message: &str| Envelope::new("error", &serde_json::json!({
+        "code": "invalid_batch",
+        "message": message
+    })).to_json();
+    let batch = match serde_json::from_str::<Batch>(msg) {
+        Ok(batch) if batch.data.requests.len() <= MAX_BATCH_REQUESTS => batch.data,
+        Ok(_) => {
+            if let Some(client) = server.clients.get_mut(&client_id) {
+                let _ = client.reply(&error(&format!("at most {} requests per batch", MAX_BATCH_REQUESTS)));
+            }
+            return;
+        }
+        Err(_) => {
+            if let Some(client) = server.clients.get_mut(&client_id) {
+                let _ = client.reply(&error("requests must be an array"));
+            }
+            return;
+        }
+    };
+    
+    let mut results = Vec::with_capacity(batch.requests.len());
+    for request in &batch.requests {
+        let msg_type = serde_json::from_str::<BatchRequest>(request.get()).map(|r| r.msg_type).unwrap_or_default();
+        let replies = if msg_type == "batch" {
+            vec![serde_json::from_str(&error("a batch can't hold another")).unwrap_or_default()]
+        } else {
+            match server.clients.get_mut(&client_id) {
+                Some(client) => client.batch = Some(Vec::new()),
+                None => return,
+            }
+            handle_message(server, client_id, request.get());
+            server.clients.get_mut(&client_id).and_then(|c| c.batch.take()).unwrap_or_default()
+        };
+        results.push(serde_json::json!({"type": msg_type, "replies": replies}));
+    }
+    if let Some(client) = server.clients.get_mut(&client_id) {
+        let _ = client.reply(&Envelope::new("batch:result", &serde_json::json!({
+            "id": batch.id,
+            "results": results
+        })).to_json());
+    }
+}
+
+/// Where a device's `command:ack` or `command:complete` moves a command that
+/// is now `current`, or why it can't. Commands only move forward: "received"
+/// (an ack's default) marks a sent command delivered; any other status the
+/// server doesn't reserve finishes a sent or delivered one ("completed" is
+/// a complete's default).
+fn reported_status<'a>(msg_type: &str, reported: Option<&'a str>, current: &str) -> Result<&'a str, String> {
+    let reported = match (msg_type, reported) {
+        ("command:complete", None) => "completed",
+        (_, None | Some("received")) => "delivered",
+        (_, Some(status)) => status,
+    };
+    if !matches!(current, "sent" | "delivered") {
+        return Err(format!("command is already {}", current));
+    }
+    if reported == "delivered" {
+        return match current {
+            "sent" => Ok("delivered"),
+            _ => Err("command is already delivered".to_string()),
+        };
+    }
+    if SERVER_STATUSES.contains(&reported) || reported.is_empty() || reported.len() > MAX_REPORTED_STATUS {
+        return Err(format!("{} is not a status a device reports", reported));
+    }
+    Ok(reported)
+}
+
+/// Where `config` keeps telemetry: `telemetry/` in its data directory.
+fn telemetry_dir(config: &Config) -> String {
+    std::path::Path::new(&config.data_dir).join("telemetry").to_string_lossy().into_owned()
+}
+
+/// Delete telemetry under `base` past its device's retention, logging what went.
+fn prune_telemetry(db: &StateDb, base: &str, default_days: u64) {
+    let pruned = db.retention_overrides()
+        .and_then(|overrides| telemetry::prune(std::path::Path::new(base), now_unix(), default_days, &overrides));
+    match pruned {
+        Ok(0) => {}
+        Ok(n) => log!("↻ Retention: deleted {} telemetry day files", n),
+        Err(e) => log!("⚠ Retention: {}", e),
+    }
+}
+
+// ============================================================================
+// RUNNING
+// ============================================================================
+
+impl Server {
+    /// Check the data directory, bind the port on every address and serve
+    /// on background threads. Returns once all are bound, or with the
+    /// self-check's failures if the data directory can't be used.
+    pub fn run(config: Config) -> Result<ServerHandle, String> {
+        if let Some(path) = &config.log_file {
+            trace::log_to(RotatingLog::open(path, config.log_max_bytes, config.log_keep)?);
+        }
+        let report = preflight::run(std::path::Path::new(&config.data_dir), &config.static_dirs, config.min_free_mb);
+        report.print();
+        if !report.passed() {
+            return Err(format!("preflight failed: {}", report.failures()));
+        }
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+            (None, None) => None,
+            _ => return Err("TLS needs both a certificate and a key".to_string()),
+        };
+        if tls.is_some() && config.demo_devices > 0 {
+            return Err("demo devices connect in plaintext, so demo mode can't run with TLS".to_string());
+        }
+        let server = Arc::new(Mutex::new(Server::new(&config)?));
+        
+        let listeners = bind(&config.bind, config.port)?;
+        let addrs = listeners.iter()
+            .map(|l| l.local_addr().map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let running = Arc::new(AtomicBool::new(true));
+        
+        // Start housekeeping thread: command timeouts and leases, and now and then
+        // pairing and telemetry retention
+        {
+            let server = Arc::clone(&server);
+            let running = Arc::clone(&running);
+            let telemetry_dir = telemetry_dir(&config);
+            thread::spawn(move || {
+                let mut reconciled = Instant::now();
+                let mut pruned: Option<Instant> = None;
+                loop {
+                    thread::sleep(Duration::from_millis(HOUSEKEEPING_INTERVAL_MS));
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let (db, retention_days) = match server.lock() {
+                        Ok(mut server) => {
+                            server.expire_commands();
+                            server.expire_leases();
+                            server.expire_sleeps();
+                            if reconciled.elapsed() >= PAIRING_RECONCILE_INTERVAL {
+                                server.reconcile_pairing_requests();
+                                reconciled = Instant::now();
+                            }
+                            (server.db.clone(), server.telemetry_retention_days)
+                        }
+                        Err(_) => continue,
+                    };
+                    // Off the lock: it walks every day directory
+                    if pruned.is_none_or(|at| at.elapsed() >= RETENTION_INTERVAL) {
+                        prune_telemetry(&db, &telemetry_dir, retention_days);
+                        pruned = Some(Instant::now());
+                    }
+                }
+            });
+        }
+        
+        // Start device update flush thread (only when coalescing is enabled)
+        if config.update_interval_ms > 0 {
+            let server = Arc::clone(&server);
+            let running = Arc::clone(&running);
+            let interval_ms = config.update_interval_ms;
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(Duration::from_millis(interval_ms));
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(mut server) = server.lock() {
+                        server.flush_device_updates();
+                    }
+                }
+            });
+        }
+        
+        // Start the interpolation thread (only when asked for)
+        if config.interpolate_hz > 0 {
+            let server = Arc::clone(&server);
+            let running = Arc::clone(&running);
+            let period = Duration::from_secs(1) / config.interpolate_hz.min(1000) as u32;
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(period);
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(mut server) = server.lock() {
+                        server.send_interpolated_updates();
+                    }
+                }
+            });
+        }
+        
+        // One accept loop per address, all feeding the same server
+        let access = Arc::new(config.access);
+        let static_dirs = Arc::new(config.static_dirs);
+        let listeners = listeners.into_iter().map(|listener| {
+            let server = Arc::clone(&server);
+            let running = Arc::clone(&running);
+            let access = Arc::clone(&access);
+            let static_dirs = Arc::clone(&static_dirs);
+            let tls = tls.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            let server = Arc::clone(&server);
+                            let access = Arc::clone(&access);
+                            let static_dirs = Arc::clone(&static_dirs);
+                            let tls = tls.clone();
+                            thread::spawn(move || {
+                                handle_connection(stream, tls.as_ref(), server, &access, &static_dirs);
+                            });
+                        }
+                        Err(e) => log!("Connection failed: {}", e),
+                    }
+                }
+            })
+        }).collect();
+        
+        let demo = if config.demo_devices > 0 {
+            let db = server.lock().map_err(|e| e.to_string())?.db.clone();
+            demo::start(&db, loopback(addrs[0]), config.demo_devices, Arc::clone(&running))?
+        } else {
+            Vec::new()
+        };
+        
+        Ok(ServerHandle { addrs, running, server, listeners, demo })
+    }
+}
+
+/// Listen on `port` at each address. Port 0 is picked by the first and
+/// reused by the rest. IPv6 goes first: a dual-stack "::" already takes
+/// IPv4, so 0.0.0.0 alongside it is skipped rather than failing.
+fn bind(addrs: &[IpAddr], mut port: u16) -> Result<Vec<TcpListener>, String> {
+    let mut addrs = addrs.to_vec();
+    addrs.sort_by_key(|ip| ip.is_ipv4());
+    addrs.dedup();
+    
+    let mut listeners: Vec<TcpListener> = Vec::new();
+    for ip in addrs {
+        let addr = SocketAddr::new(ip, port);
+        match TcpListener::bind(addr) {
+            Ok(listener) => {
+                port = listener.local_addr().map_err(|e| e.to_string())?.port();
+                listeners.push(listener);
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse && ip == IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+                && listeners.iter().any(|l| l.local_addr().is_ok_and(|a| a.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED))) => {
+                log!("→ {} is served by the dual-stack [::]:{} listener", addr, port);
+            }
+            Err(e) => return Err(format!("failed to bind to {}: {}", addr, e)),
+        }
+    }
+    Ok(listeners)
+}
+
+/// Where to reach a listener at `addr` from this machine.
+fn loopback(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+        _ => addr,
+    }
+}
+
+/// A running server. Dropping the handle leaves it running.
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+    running: Arc<AtomicBool>,
+    server: Arc<Mutex<Server>>,
+    listeners: Vec<thread::JoinHandle<()>>,
+    demo: Vec<thread::JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The first address the server listens on, with the port picked for
+    /// port 0. Every address shares the port.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+    
+    /// Every address the server listens on.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+    
+    /// Something to reload the running server's configuration with, for
+    /// another thread to keep while this one waits.
+    pub fn reloader(&self) -> Reloader {
+        Reloader { server: Arc::clone(&self.server) }
+    }
+    
+    /// Block for as long as the server accepts connections.
+    pub fn wait(self) {
+        for listener in self.listeners {
+            let _ = listener.join();
+        }
+    }
+    
+    /// Stop accepting connections, close every WebSocket with 1001 and seal
+    /// the open telemetry files. HTTP requests already in flight finish on
+    /// their own.
+    pub fn shutdown(self) -> Result<(), String> {
+        self.running.store(false, Ordering::SeqCst);
+        // An accept loop only sees the flag once a connection arrives
+        for addr in &self.addrs {
+            let _ = TcpStream::connect(loopback(*addr));
+        }
+        for listener in self.listeners {
+            listener.join().map_err(|_| "listener thread panicked".to_string())?;
+        }
+        // Demo devices see the flag within a tick and hang up
+        for device in self.demo {
+            device.join().map_err(|_| "demo device thread panicked".to_string())?;
+        }
+        
+        let mut server = self.server.lock().map_err(|e| e.to_string())?;
+        for (_, replay) in server.replays.drain() {
+            replay.cancel();
+        }
+        server.close_all_clients();
+        // Their readers see the feed end and finish the response
+        server.command_streams.clear();
+        server.telemetry.close()
+    }
+}
+
+/// Applies a new configuration to a running server without dropping its
+/// listeners or connections. From `ServerHandle::reloader`.
+#[derive(Clone)]
+pub struct Reloader {
+    server: Arc<Mutex<Server>>,
+}
+
+impl Reloader {
+    /// Take what can change live from `config`. Returns the environment
+    /// variables of settings that changed but wait for a restart.
+    pub fn reload(&self, config: Config) -> Result<Vec<&'static str>, String> {
+        let mut server = self.server.lock().map_err(|e| e.to_string())?;
+        Ok(server.reload(config))
+    }
+    
+    /// `reload` what `Config::from_env` reads now. A configuration that
+    /// doesn't parse or apply is logged, with the server's own log, and
+    /// the running one kept.
+    pub fn reload_from_env(&self) -> Result<Vec<&'static str>, String> {
+        let result = Config::from_env().and_then(|config| self.reload(config));
+        if let Err(e) = &result {
+            log!("✗ Reload failed, keeping the running configuration: {}", e);
+        }
+        result
+    }
+}
+
+fn handle_connection(stream: TcpStream, tls: Option<&Arc<rustls::ServerConfig>>, server: Arc<Mutex<Server>>, access: &AccessList, static_dirs: &[String]) {
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer.ip(),
+        Err(_) => return,
+    };
+    
+    // Refused peers are dropped before a byte is read. Behind a trusted
+    // proxy the client is only known from the headers, so it waits for them.
+    let via_proxy = access.trusts_proxy(peer);
+    if !via_proxy && !access.permits(peer) {
+        log!("✗ Connection refused: {}", peer);
+        return;
+    }
+    
+    let mut stream = match Stream::accept(stream, tls) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log!("TLS handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    let request = match http::read_request(&mut stream) {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    let _trace = trace::enter(trace::request_id(http::header_value(&request, "x-request-id")));
+    
+    let client_ip = access.client_ip(peer, http::forwarded_for(&request).as_deref(), http::header_value(&request, "x-real-ip"));
+    if via_proxy && !access.permits(client_ip) {
+        log!("✗ Connection refused: {} (via {})", client_ip, peer);
+        return;
+    }
+    
+    if http::handle_request(&mut stream, &request, static_dirs, &server) {
+        // Over TLS this says the response is complete (close_notify)
+        let _ = stream.shutdown();
+        return;
+    }
+    let role = http::ws_role(&request, &server);
+    let identity = http::ws_identity(&request);
+    
+    let deflate = server.lock().unwrap().ws_deflate;
+    let ws = match WebSocket::accept(stream, &request, deflate, &[BINARY_TELEMETRY_SUBPROTOCOL]) {
+        Ok(ws) => ws,
+        Err(e) => {
+            log!("WebSocket handshake failed: {}", e);
+            return;
+        }
+    };
+    
+    let mut ws = ws;
+    let client_id = {
+        let mut server = server.lock().unwrap();
+        ws.set_ingress_limit(server.ingress_limit);
+        ws.set_max_message(server.max_message);
+        ws.set_write_timeout(Duration::from_secs(server.ws_write_timeout_secs));
+        // Broadcasts only queue: a slow reader never holds the lock
+        if let Err(e) = ws.start_writer(server.send_queue) {
+            log!("WebSocket writer failed to start: {}", e);
+            return;
+        }
+        server.add_client(ws.try_clone().unwrap(), client_ip, role, identity)
+    };
+    
+    // Binary frames mean something only to a connection that asked for them
+    let binary_telemetry = ws.subprotocol() == Some(BINARY_TELEMETRY_SUBPROTOCOL);
+    loop {
+        match ws.read_message() {
+            Ok(Some(Message::Text(msg))) => {
+                let _trace = trace::enter(trace::new_id());
+                let mut server = server.lock().unwrap();
+                handle_message(&mut server, client_id, &msg);
+            }
+            Ok(Some(Message::Binary(frame))) => {
+                if binary_telemetry {
+                    let _trace = trace::enter(trace::new_id());
+                    let mut server = server.lock().unwrap();
+                    handle_binary_telemetry(&mut server, client_id, &frame);
+                }
+            }
+            Ok(None) => {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(_) => break,
+        }
+        
+        if ws.state != WsState::Open {
+            break;
+        }
+    }
+    
+    let mut server = server.lock().unwrap();
+    server.remove_client(client_id);
+}
+
+// ============================================================================
+// UTILITIES
+// ============================================================================
+
+fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Milliseconds since the epoch, for clients working out latency and clock offset.
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Where settings are read: the file GLOBALRTS_CONFIG names, if any,
+/// then the environment. The file wins, so editing it and reloading can
+/// change what the environment set.
+struct Settings {
+    file: HashMap<String, String>,
+}
+
+impl Settings {
+    fn load() -> Result<Self, String> {
+        let file = match std::env::var("GLOBALRTS_CONFIG") {
+            Ok(path) if !path.is_empty() => {
+                let text = std::fs::read_to_string(&path).map_err(|e| format!("can't read {}: {}", path, e))?;
+                parse_settings(&text).map_err(|e| format!("{}: {}", path, e))?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Self { file })
+    }
+    
+    fn get(&self, name: &str) -> Option<String> {
+        self.file.get(name).cloned().or_else(|| std::env::var(name).ok())
+    }
+    
+    fn u64(&self, name: &str, default: u64) -> u64 {
+        self.get(name)
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(default)
+    }
+    
+    fn f64(&self, name: &str, default: f64) -> f64 {
+        self.get(name)
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(default)
+    }
+    
+    /// A comma-separated list, blanks dropped.
+    fn list(&self, name: &str) -> Vec<String> {
+        self.get(name)
+            .unwrap_or_default()
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    }
+}
+
+/// `NAME=value` lines, as in a shell environment file. Blank lines and
+/// `#` comments are skipped, and quotes around a value dropped.
+fn parse_settings(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut settings = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line.strip_prefix("export ").unwrap_or(line).split_once('=')
+            .ok_or_else(|| format!("line {}: expected NAME=value", n + 1))?;
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')].iter()
+            .find_map(|(open, close)| value.strip_prefix(*open)?.strip_suffix(*close))
+            .unwrap_or(value);
+        settings.insert(name.trim().to_string(), value.to_string());
+    }
+    Ok(settings)
+}
+
+/// Comma-separated IP addresses, IPv6 with or without brackets.
+fn parse_bind(list: &str) -> Result<Vec<IpAddr>, String> {
+    let addrs = list.split(',')
+        .map(|a| a.trim().trim_start_matches('[').trim_end_matches(']'))
+        .filter(|a| !a.is_empty())
+        .map(|a| a.parse().map_err(|_| format!("{} is not an IP address", a)))
+        .collect::<Result<Vec<IpAddr>, String>>()?;
+    if addrs.is_empty() {
+        return Err("no addresses to listen on".to_string());
+    }
+    Ok(addrs)
+}
+
+/// `type=item,item;type=...`, each type once. Blank = none.
+fn parse_type_lists(text: &str) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut types = BTreeMap::new();
+    for entry in text.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (device_type, items) = entry.split_once('=')
+            .ok_or_else(|| format!("{} is not type=list", entry))?;
+        let device_type = device_type.trim();
+        let items: Vec<String> = items.split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+        if device_type.is_empty() || items.is_empty() {
+            return Err(format!("{} needs a type and at least one item", entry));
+        }
+        if types.insert(device_type.to_string(), items).is_some() {
+            return Err(format!("{} is listed twice", device_type));
+        }
+    }
+    Ok(types)
+}
+
+/// `parse_type_lists` in reverse.
+fn format_type_lists(types: &BTreeMap<String, Vec<String>>) -> String {
+    types.iter()
+        .map(|(device_type, items)| format!("{}={}", device_type, items.join(",")))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// The registration policy from GLOBALRTS_REGISTER_TYPES,
+/// GLOBALRTS_REGISTER_NAME_PATTERN and GLOBALRTS_REGISTER_TAGS.
+fn registration_policy(vars: &Settings) -> Result<RegistrationPolicy, String> {
+    let tags = parse_type_lists(&vars.get("GLOBALRTS_REGISTER_TAGS").unwrap_or_default())
+        .map_err(|e| format!("invalid GLOBALRTS_REGISTER_TAGS: {}", e))?;
+    let pattern = vars.get("GLOBALRTS_REGISTER_NAME_PATTERN").filter(|p| !p.is_empty());
+    RegistrationPolicy::new(vars.list("GLOBALRTS_REGISTER_TYPES"), pattern.as_deref(), tags)
+        .map_err(|e| format!("invalid GLOBALRTS_REGISTER_NAME_PATTERN: {}", e))
+}
+
+/// Static file roots from GLOBALRTS_STATIC_DIRS, or just PUBLIC_DIR.
+fn static_dirs(vars: &Settings) -> Vec<String> {
+    let dirs: Vec<String> = vars.get("GLOBALRTS_STATIC_DIRS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|d| d.trim().trim_end_matches('/').to_string())
+        .filter(|d| !d.is_empty())
+        .collect();
+    if dirs.is_empty() { vec![PUBLIC_DIR.to_string()] } else { dirs }
+}
+
+fn generate_id() -> String {
+    format!("{:x}-{:04x}", now_unix(), rand_u16())
+}
+
+fn rand_u16() -> u16 {
+    let t = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    ((t >> 16) ^ t) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `config` written out with `to_settings` and read back.
+    fn round_trip(config: &Config) -> Config {
+        let settings = Settings { file: parse_settings(&config.to_settings()).unwrap() };
+        Config::from_settings(&settings).unwrap()
+    }
+
+    #[test]
+    fn settings_read_back_as_the_config_they_came_from() {
+        let default = Config::default();
+        assert_eq!(format!("{:?}", round_trip(&default)), format!("{:?}", default));
+
+        let mut tags = BTreeMap::new();
+        tags.insert("drone".to_string(), vec!["aerial".to_string(), "fleet-a".to_string()]);
+        let config = Config {
+            bind: vec!["::".parse().unwrap(), "127.0.0.1".parse().unwrap()],
+            static_dirs: vec!["custom".to_string(), "public".to_string()],
+            data_dir: "/var/lib/globalrts".to_string(),
+            access: AccessList::new("10.0.0.0/8", "10.0.0.7").unwrap().with_trusted_proxies("::1").unwrap(),
+            ws_deflate: true,
+            pair_auto_approve: vec!["lab-*".to_string()],
+            telemetry_min_distance_m: 2.5,
+            telemetry_out_of_order: OutOfOrder::Flag,
+            tls_cert: Some("cert.pem".to_string()),
+            tls_key: Some("key.pem".to_string()),
+            log_file: Some("logs/server.log".to_string()),
+            webhook_allow: vec![Cidr::parse("10.0.0.0/8").unwrap(), Cidr::parse("::1").unwrap()],
+            type_capabilities: parse_type_lists("drone=navigate,land;sensor=poll").unwrap(),
+            registration: RegistrationPolicy::new(vec!["drone".to_string()], Some("^[a-z]+-\\d{2}$"), tags).unwrap(),
+            admin_token: Some("admin-secret".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(format!("{:?}", round_trip(&config)), format!("{:?}", config));
+    }
+}
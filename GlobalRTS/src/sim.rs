@@ -0,0 +1,472 @@
+//! # Simulated Devices
+//!
+//! A simulated robot, phone or drone: its position and battery, how it
+//! moves, and how it answers commands. The `simulator` binary runs one
+//! against a server; demo mode runs several inside the server itself.
+//!
+//! Both talk to the server as a real device would, over a WebSocket,
+//! through `crate::client`.
+
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+
+use crate::protocol::{CommandPayload, TelemetryMessage};
+
+// ============================================================================
+// PROTOCOL
+// ============================================================================
+
+#[derive(Serialize)]
+struct Envelope<T> {
+    #[serde(rename = "type")]
+    msg_type: String,
+    data: T,
+}
+
+#[derive(Serialize)]
+struct RegisterData<'a> {
+    device_id: &'a str,
+    device_type: &'a str,
+    name: &'a str,
+    latitude: f64,
+    longitude: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct CommandEnvelope {
+    #[serde(rename = "type")]
+    msg_type: String,
+    data: serde_json::Value,
+}
+
+// ============================================================================
+// DEVICE STATE
+// ============================================================================
+
+pub struct DeviceState {
+    pub lat: f64,
+    pub lon: f64,
+    pub heading: f64,
+    pub speed: f64,
+    pub battery: f64,
+    pub target: Option<(f64, f64)>,
+    /// The command taking it to `target`, which a `command:cancel` for it stops.
+    pub command: Option<String>,
+    /// `off` once told to shut down: whatever runs the device should stop.
+    pub status: String,
+    /// Highest command `seq` handled, so a re-delivered one isn't run twice.
+    pub last_seq: i64,
+    /// Print nothing: for devices running inside the server, whose log
+    /// isn't theirs to fill.
+    pub quiet: bool,
+}
+
+impl DeviceState {
+    pub fn new() -> Self {
+        // Start in Downtown LA with random offset
+        Self {
+            lat: 34.0522 + (rand_f64() - 0.5) * 0.01,
+            lon: -118.2437 + (rand_f64() - 0.5) * 0.01,
+            heading: rand_f64() * 360.0,
+            speed: 0.0,
+            battery: 85.0 + rand_f64() * 15.0,
+            target: None,
+            command: None,
+            status: "idle".to_string(),
+            last_seq: 0,
+            quiet: false,
+        }
+    }
+    
+    pub fn update(&mut self) {
+        // Move towards target if set
+        if let Some((target_lat, target_lon)) = self.target {
+            let dlat = target_lat - self.lat;
+            let dlon = target_lon - self.lon;
+            let dist = (dlat * dlat + dlon * dlon).sqrt();
+            
+            if dist < 0.0001 {
+                // Arrived
+                self.lat = target_lat;
+                self.lon = target_lon;
+                self.speed = 0.0;
+                self.target = None;
+                self.command = None;
+                self.status = "idle".to_string();
+                self.log("   ✓ Arrived at destination");
+            } else {
+                // Move
+                let step = 0.0002; // ~22m per tick
+                self.lat += (dlat / dist) * step;
+                self.lon += (dlon / dist) * step;
+                self.heading = dlon.atan2(dlat).to_degrees();
+                self.speed = step * 111000.0; // Approximate m/s
+            }
+        }
+        
+        // Drain battery
+        self.battery = (self.battery - 0.001).max(0.0);
+    }
+    
+    /// When idle, set off for somewhere within about 500 m, so a device
+    /// nobody is commanding still moves about the map.
+    pub fn wander(&mut self) {
+        if self.target.is_none() {
+            self.target = Some((self.lat + (rand_f64() - 0.5) * 0.01, self.lon + (rand_f64() - 0.5) * 0.01));
+            self.status = "moving".to_string();
+        }
+    }
+    
+    fn log(&self, line: &str) {
+        if !self.quiet {
+            println!("{}", line);
+        }
+    }
+}
+
+impl Default for DeviceState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn rand_f64() -> f64 {
+    let t = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    ((t % 1000000) as f64) / 1000000.0
+}
+
+// ============================================================================
+// MESSAGES
+// ============================================================================
+
+/// The message a device registers with, starting where `state` is. A
+/// provisioned device passes its `token`.
+pub fn register_message(state: &DeviceState, device_id: &str, device_type: &str, name: &str, token: Option<&str>) -> String {
+    let reg = Envelope {
+        msg_type: "register".to_string(),
+        data: RegisterData {
+            device_id,
+            device_type,
+            name,
+            latitude: state.lat,
+            longitude: state.lon,
+            token,
+        },
+    };
+    serde_json::to_string(&reg).unwrap_or_default()
+}
+
+/// The device's current state as a telemetry record.
+pub fn telemetry(state: &DeviceState) -> TelemetryMessage {
+    TelemetryMessage {
+        latitude: state.lat,
+        longitude: state.lon,
+        altitude: 0.0,
+        heading: state.heading,
+        speed: state.speed,
+        battery: state.battery,
+        sensors: serde_json::Value::Null,
+        ack: false,
+    }
+}
+
+/// The device's current state as a telemetry message.
+pub fn telemetry_message(state: &DeviceState) -> String {
+    let telem = Envelope {
+        msg_type: "telemetry".to_string(),
+        data: telemetry(state),
+    };
+    serde_json::to_string(&telem).unwrap_or_default()
+}
+
+/// What the device answers a message from the server with: replies to a
+/// command, nothing to anything else. A `command:cancel` stops the command
+/// it names, if that's the one under way.
+pub fn handle_message(state: &mut DeviceState, msg: &str) -> Vec<String> {
+    match serde_json::from_str::<CommandEnvelope>(msg) {
+        Ok(env) if env.msg_type == "command" => respond(state, &env.data),
+        Ok(env) if env.msg_type == "command:cancel" => {
+            cancel(state, env.data.get("commandId").and_then(|v| v.as_str()).unwrap_or(""));
+            Vec::new()
+        }
+        _ => Vec::new(),
+    }
+}
+
+// ============================================================================
+// COMMANDS
+// ============================================================================
+
+/// What the simulated device did with a command.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Marked dry run: logged, not executed, not reported back.
+    DryRun,
+    /// Under way (e.g. navigating); finishes later.
+    Started,
+    /// Done already.
+    Completed,
+    /// Done, and the device reports its state now rather than at the next tick.
+    Report,
+    /// Its payload didn't read as its type's: reported failed, not run.
+    Failed,
+    /// Its `seq` was handled already: a re-delivery, skipped silently.
+    Repeat,
+    Unknown,
+}
+
+/// Run a command and return the messages the device answers it with.
+pub fn respond(state: &mut DeviceState, data: &serde_json::Value) -> Vec<String> {
+    let cmd_id = data.get("commandId").and_then(|v| v.as_str()).unwrap_or("");
+    
+    let outcome = run_command(state, data);
+    if outcome == Outcome::DryRun || outcome == Outcome::Repeat {
+        return Vec::new();
+    }
+    
+    // Acknowledge
+    let mut replies = vec![serde_json::json!({
+        "type": "command:ack",
+        "data": { "commandId": cmd_id, "status": "received" }
+    }).to_string()];
+    
+    if outcome == Outcome::Report {
+        replies.push(telemetry_message(state));
+    }
+    if outcome == Outcome::Completed || outcome == Outcome::Report {
+        replies.push(serde_json::json!({
+            "type": "command:complete",
+            "data": { "commandId": cmd_id, "status": "completed" }
+        }).to_string());
+    }
+    if outcome == Outcome::Failed {
+        replies.push(serde_json::json!({
+            "type": "command:complete",
+            "data": { "commandId": cmd_id, "status": "failed" }
+        }).to_string());
+    }
+    replies
+}
+
+/// Apply a command to the device state, unless it is a dry run, which is
+/// only logged, or has a `seq` already handled. Commands without one
+/// always run.
+pub fn run_command(state: &mut DeviceState, data: &serde_json::Value) -> Outcome {
+    let cmd_type = data.get("type").and_then(|v| v.as_str()).unwrap_or("");
+    let payload = data.get("payload").cloned().unwrap_or_default();
+    let dry_run = data.get("dryRun").and_then(|v| v.as_bool()).unwrap_or(false);
+    let seq = data.get("seq").and_then(|v| v.as_i64()).unwrap_or(0);
+    let cmd_id = data.get("commandId").and_then(|v| v.as_str()).unwrap_or("");
+    
+    state.log(&format!("\n📥 Command: {}{}", cmd_type, if dry_run { " (dry run)" } else { "" }));
+    
+    if seq > 0 {
+        if seq <= state.last_seq {
+            state.log(&format!("   ↺ Already handled seq {}, skipping", seq));
+            return Outcome::Repeat;
+        }
+        state.last_seq = seq;
+    }
+    
+    if dry_run {
+        state.log(&format!("   🧪 Would run {} with {}", cmd_type, payload));
+        return Outcome::DryRun;
+    }
+    
+    let command = match CommandPayload::parse(cmd_type, &payload) {
+        Ok(command) => command,
+        Err(e) => {
+            state.log(&format!("   ❌ Bad {} payload: {}", cmd_type, e));
+            return Outcome::Failed;
+        }
+    };
+    
+    match command {
+        CommandPayload::Navigate(target) => {
+            state.target = Some((target.latitude, target.longitude));
+            state.command = Some(cmd_id.to_string());
+            state.status = "moving".to_string();
+            state.log(&format!("   🚀 Navigating to {:.6}, {:.6}", target.latitude, target.longitude));
+            Outcome::Started
+        }
+        CommandPayload::Stop(_) => {
+            state.target = None;
+            state.command = None;
+            state.speed = 0.0;
+            state.status = "idle".to_string();
+            state.log("   🛑 Stopped");
+            Outcome::Completed
+        }
+        CommandPayload::Ring(_) => {
+            state.log("   🔔 RING RING RING!");
+            state.status = "ringing".to_string();
+            thread::sleep(Duration::from_secs(2));
+            state.status = "idle".to_string();
+            Outcome::Completed
+        }
+        CommandPayload::Poll(_) => {
+            state.log("   📡 Reporting now");
+            Outcome::Report
+        }
+        // A real device answers first, then goes; this one starts afresh
+        // where it stands
+        CommandPayload::Reboot(_) => {
+            state.target = None;
+            state.command = None;
+            state.speed = 0.0;
+            state.status = "idle".to_string();
+            state.log("   🔄 Rebooting");
+            Outcome::Completed
+        }
+        // ...and stops for good, once its answers are sent
+        CommandPayload::Shutdown(_) => {
+            state.target = None;
+            state.command = None;
+            state.speed = 0.0;
+            state.status = "off".to_string();
+            state.log("   ⏻ Shutting down");
+            Outcome::Completed
+        }
+        CommandPayload::Photo(_) | CommandPayload::Other(_) => {
+            state.log("   ❓ Unknown command");
+            Outcome::Unknown
+        }
+    }
+}
+
+/// Stop `command_id` if it's the command under way. The server has marked
+/// it cancelled already, so nothing is reported back. False if it wasn't
+/// running (finished, or never arrived).
+pub fn cancel(state: &mut DeviceState, command_id: &str) -> bool {
+    if command_id.is_empty() || state.command.as_deref() != Some(command_id) {
+        return false;
+    }
+    state.target = None;
+    state.command = None;
+    state.speed = 0.0;
+    state.status = "idle".to_string();
+    state.log("   ✋ Cancelled");
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_run_leaves_the_device_untouched() {
+        let mut state = DeviceState::new();
+        let (lat, lon) = (state.lat, state.lon);
+        let navigate = serde_json::json!({
+            "commandId": "c1", "type": "navigate", "dryRun": true,
+            "payload": {"latitude": 35.0, "longitude": -119.0}
+        });
+
+        assert_eq!(run_command(&mut state, &navigate), Outcome::DryRun);
+        assert_eq!(state.target, None);
+        assert_eq!(state.status, "idle");
+        assert_eq!((state.lat, state.lon), (lat, lon));
+
+        let mut navigate = navigate;
+        navigate["dryRun"] = serde_json::json!(false);
+        assert_eq!(run_command(&mut state, &navigate), Outcome::Started);
+        assert_eq!(state.target, Some((35.0, -119.0)));
+    }
+
+    #[test]
+    fn a_malformed_payload_fails_instead_of_running() {
+        let mut state = DeviceState::new();
+        let navigate = serde_json::json!({
+            "commandId": "c3", "type": "navigate",
+            "payload": {"lattitude": 35.0, "longitude": -119.0}
+        });
+
+        let replies: Vec<serde_json::Value> = respond(&mut state, &navigate).iter()
+            .map(|msg| serde_json::from_str(msg).unwrap())
+            .collect();
+        assert_eq!(state.target, None);
+        assert_eq!(replies[1]["type"], "command:complete");
+        assert_eq!(replies[1]["data"]["status"], "failed");
+    }
+
+    #[test]
+    fn a_redelivered_command_is_not_run_twice() {
+        let mut state = DeviceState::new();
+        let stop = serde_json::json!({"commandId": "c4", "type": "stop", "payload": {}, "seq": 2});
+
+        assert_eq!(respond(&mut state, &stop).len(), 2);
+        assert!(respond(&mut state, &stop).is_empty());
+        // An earlier seq arriving late is as good as seen
+        let mut ring = stop.clone();
+        ring["seq"] = serde_json::json!(1);
+        assert_eq!(run_command(&mut state, &ring), Outcome::Repeat);
+        ring["seq"] = serde_json::json!(3);
+        ring["type"] = serde_json::json!("poll");
+        assert_eq!(run_command(&mut state, &ring), Outcome::Report);
+    }
+
+    #[test]
+    fn a_cancel_stops_only_the_navigate_under_way() {
+        let mut state = DeviceState::new();
+        let navigate = serde_json::json!({
+            "commandId": "c5", "type": "navigate",
+            "payload": {"latitude": 35.0, "longitude": -119.0}
+        });
+        run_command(&mut state, &navigate);
+        state.update();
+
+        assert!(!cancel(&mut state, "c4"));
+        assert_eq!(state.target, Some((35.0, -119.0)));
+        let cancel = serde_json::json!({"type": "command:cancel", "data": {"commandId": "c5"}});
+        assert!(handle_message(&mut state, &cancel.to_string()).is_empty());
+        assert_eq!(state.target, None);
+        assert_eq!((state.status.as_str(), state.speed), ("idle", 0.0));
+    }
+
+    #[test]
+    fn a_shutdown_answers_then_switches_the_device_off() {
+        let mut state = DeviceState::new();
+        state.target = Some((35.0, -119.0));
+        let reboot = serde_json::json!({"commandId": "c6", "type": "reboot", "payload": {"confirm": true}});
+        assert_eq!(run_command(&mut state, &reboot), Outcome::Completed);
+        assert_eq!((state.target, state.status.as_str()), (None, "idle"));
+
+        let shutdown = serde_json::json!({"commandId": "c7", "type": "shutdown", "payload": {"confirm": true}});
+        assert_eq!(respond(&mut state, &shutdown).len(), 2);
+        assert_eq!(state.status, "off");
+    }
+
+    #[test]
+    fn poll_reports_telemetry_straight_away() {
+        let mut state = DeviceState::new();
+        let poll = serde_json::json!({"commandId": "c2", "type": "poll", "payload": {}});
+
+        let replies: Vec<serde_json::Value> = respond(&mut state, &poll).iter()
+            .map(|msg| serde_json::from_str(msg).unwrap())
+            .collect();
+        let types: Vec<&str> = replies.iter().map(|r| r["type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["command:ack", "telemetry", "command:complete"]);
+        assert_eq!(replies[1]["data"]["latitude"], state.lat);
+        assert_eq!(replies[2]["data"]["commandId"], "c2");
+    }
+
+    #[test]
+    fn an_idle_device_wanders_off_nearby() {
+        let mut state = DeviceState::new();
+        state.wander();
+        let (lat, lon) = state.target.unwrap();
+        assert!((lat - state.lat).abs() <= 0.005 && (lon - state.lon).abs() <= 0.005);
+        assert_eq!(state.status, "moving");
+        // One already heading somewhere keeps going there
+        state.target = Some((35.0, -119.0));
+        state.wander();
+        assert_eq!(state.target, Some((35.0, -119.0)));
+    }
+}
@@ -12,15 +12,90 @@
 //! - devices: Registered devices and their current state
 //! - pairing_requests: Pending 6-digit code pairing requests
 //! - commands: Command queue and history
+//! - ui_prefs: Opaque per-operator UI layout preferences
+//! - alerts: Discrete events devices raised, kept as an incident feed
+//! - maintenance: Where command dispatch is paused, fleet-wide or per device
 //! 
 //! Telemetry (high-volume time-series) goes to flat files instead.
 
-use rusqlite::{Connection, params};
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior, params};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
-use crate::protocol::DeviceInfo;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use crate::appearance;
+use crate::protocol::{DeviceInfo, DeviceInfoUpdate, DeviceStatus};
+use crate::telemetry::{haversine_m, EARTH_RADIUS_M};
+use crate::trace::log;
 
-/// Thread-safe database handle.
+/// Shortest pre-issued token an import accepts. Tokens are a device's only
+/// credential, so short ones are refused rather than trusted.
+const MIN_IMPORT_TOKEN_LEN: usize = 32;
+
+/// How long a pairing code stays valid.
+const PAIRING_TTL_SECS: i64 = 300;
+
+/// Codes drawn before a pairing request gives up on finding one no other
+/// pending request holds.
+const PAIRING_CODE_ATTEMPTS: usize = 100;
+
+/// How far the wall clock may drift from the monotonic clock over one
+/// pairing request before it counts as a jump.
+const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+/// Monotonic creation time of each pending pairing request, keyed by
+/// device_id, with the code it was issued for. The wall-clock `expires_at`
+/// in SQLite can't be trusted across NTP corrections or VM migrations; this
+/// can. Process-wide because every HTTP request opens its own StateDb.
+/// Lost on restart, when the wall clock is all there is.
+static PAIRING_STARTED: Mutex<Option<HashMap<String, (String, Instant)>>> = Mutex::new(None);
+
+/// Longest sensor name a device filter may name.
+pub const MAX_SENSOR_NAME: usize = 64;
+
+/// How `devices_by_sensor` compares a device's latest reading to a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorOp {
+    Gt,
+    Lt,
+    Eq,
+}
+
+impl SensorOp {
+    fn sql(self) -> &'static str {
+        match self {
+            SensorOp::Gt => ">",
+            SensorOp::Lt => "<",
+            SensorOp::Eq => "=",
+        }
+    }
+}
+
+impl std::str::FromStr for SensorOp {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "gt" => Ok(SensorOp::Gt),
+            "lt" => Ok(SensorOp::Lt),
+            "eq" => Ok(SensorOp::Eq),
+            _ => Err(format!("unknown op {:?} (known: gt, lt, eq)", s)),
+        }
+    }
+}
+
+/// Whether `name` may be filtered on: letters, digits, `_` and `-`.
+pub fn check_sensor_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_SENSOR_NAME
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!("sensor must be 1-{} letters, digits, '_' or '-'", MAX_SENSOR_NAME));
+    }
+    Ok(())
+}
+
+/// Thread-safe database handle. Clones share the connection.
+#[derive(Clone)]
 pub struct StateDb {
     conn: Arc<Mutex<Connection>>,
 }
@@ -34,6 +109,194 @@ pub struct PairingRequest {
     pub code: String,
     pub expires_at: i64,
     pub created_at: i64,
+    /// Seconds until the code expires, by the server's own clocks, so a
+    /// countdown needn't trust the viewer's clock to agree.
+    pub seconds_remaining: i64,
+}
+
+/// A device to provision directly, without the pairing code flow.
+#[derive(Debug, Clone)]
+pub struct DeviceImport {
+    pub id: String,
+    pub name: String,
+    pub device_type: String,
+    /// Pre-issued token; one is generated when None.
+    pub token: Option<String>,
+    pub signed: bool,
+}
+
+/// A device row in full, token and all, as a registry export carries it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceRecord {
+    pub id: String,
+    pub name: String,
+    pub device_type: String,
+    pub status: DeviceStatus,
+    #[serde(default)]
+    pub latitude: f64,
+    #[serde(default)]
+    pub longitude: f64,
+    #[serde(default)]
+    pub altitude: f64,
+    #[serde(default)]
+    pub heading: f64,
+    #[serde(default)]
+    pub speed: f64,
+    #[serde(default)]
+    pub battery: f64,
+    #[serde(default)]
+    pub last_seen: i64,
+    /// None once revoked.
+    pub token: Option<String>,
+    #[serde(default)]
+    pub paired_at: i64,
+    #[serde(default)]
+    pub signed: bool,
+    /// Chosen appearance; None is the default.
+    pub color: Option<String>,
+    pub icon: Option<String>,
+    /// The last telemetry `sensors` object.
+    pub last_sensors: Option<serde_json::Value>,
+    pub retention_days: Option<i64>,
+    pub capabilities: Option<Vec<String>>,
+    pub firmware_version: Option<String>,
+    pub sensors: Option<Vec<String>>,
+    pub wake_at: Option<i64>,
+    pub tags: Option<Vec<String>>,
+}
+
+/// What `restore_devices` does with a device whose id is already here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreConflict {
+    /// Keep the one here.
+    Merge,
+    /// Overwrite it with the restored one.
+    Replace,
+}
+
+/// Headline numbers for the whole fleet.
+#[derive(Debug, Clone, Default)]
+pub struct FleetCounts {
+    /// Paired devices.
+    pub devices: i64,
+    /// Neither offline, sleeping nor stale.
+    pub online: i64,
+    pub offline: i64,
+    /// Asleep, and not yet due to wake.
+    pub sleeping: i64,
+    /// Not marked offline, but silent for a while.
+    pub stale: i64,
+    pub by_type: BTreeMap<String, i64>,
+    /// Pairing requests whose code hasn't expired.
+    pub pairing_requests: i64,
+    /// Commands created in the window, by status.
+    pub commands: BTreeMap<String, i64>,
+    /// Commands not yet delivered, however old: queued or held.
+    pub pending_commands: i64,
+}
+
+/// An event a device raised.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    /// Increases with every alert, so it orders them and pages through them.
+    pub id: i64,
+    pub device_id: String,
+    pub severity: String,
+    pub code: String,
+    pub message: String,
+    pub created_at: i64,
+    /// When an operator acknowledged it, and who; None while it's open.
+    pub acknowledged_at: Option<i64>,
+    pub acknowledged_by: Option<String>,
+}
+
+/// Where command dispatch is paused.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Maintenance {
+    /// Paused for the whole fleet.
+    pub global: bool,
+    /// Paused for these devices, whatever `global` says.
+    pub devices: Vec<String>,
+}
+
+impl Maintenance {
+    /// Whether commands for `device_id` are held.
+    pub fn covers(&self, device_id: &str) -> bool {
+        self.global || self.devices.iter().any(|d| d == device_id)
+    }
+}
+
+/// Something done that an operator may later need to account for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Increases with every entry.
+    pub id: i64,
+    pub at: i64,
+    /// Who or what did it: "server" for the server acting on its own config.
+    pub actor: String,
+    /// What was done, e.g. `pair.auto_approve`.
+    pub action: String,
+    /// What it was done to, usually a device id.
+    pub target: String,
+    pub detail: String,
+}
+
+/// An operator's exclusive hold on commanding one device, until it expires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub device_id: String,
+    /// The holder's identity: a hash of their token (see /api/whoami).
+    pub holder: String,
+    pub acquired_at: i64,
+    pub expires_at: i64,
+}
+
+/// What a device-scoped API token may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenScope {
+    /// Reading the device's own stats, commands and telemetry.
+    Read,
+    /// Uploading the device's telemetry.
+    Telemetry,
+}
+
+impl TokenScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Telemetry => "telemetry",
+        }
+    }
+    
+    pub fn parse(s: &str) -> Option<TokenScope> {
+        match s {
+            "read" => Some(TokenScope::Read),
+            "telemetry" => Some(TokenScope::Telemetry),
+            _ => None,
+        }
+    }
+}
+
+/// An API token a device issued itself, good for one scope on that device
+/// only. Its registration token stays the one it connects with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopedToken {
+    pub token: String,
+    pub device_id: String,
+    pub scope: TokenScope,
+    pub created_at: i64,
+}
+
+/// A command waiting for its device to come back online.
+#[derive(Debug, Clone)]
+pub struct PendingCommand {
+    pub id: String,
+    pub command_type: String,
+    pub payload: String,
+    /// Per-device sequence number, 1 for the device's first command.
+    pub seq: i64,
+    /// Checked again against the device before it's sent.
+    pub precondition: Option<String>,
 }
 
 impl StateDb {
@@ -80,94 +343,233 @@ impl StateDb {
                 payload TEXT DEFAULT '{}',
                 status TEXT DEFAULT 'pending',
                 created_at INTEGER DEFAULT 0,
+                updated_at INTEGER DEFAULT 0,
                 FOREIGN KEY (device_id) REFERENCES devices(id)
             );
             
+            -- UI preferences: opaque JSON blob per operator identity
+            CREATE TABLE IF NOT EXISTS ui_prefs (
+                ui_id TEXT PRIMARY KEY,
+                prefs TEXT NOT NULL,
+                updated_at INTEGER DEFAULT 0
+            );
+            
+            -- Alerts: discrete device events, newest has the highest id
+            CREATE TABLE IF NOT EXISTS alerts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id TEXT NOT NULL,
+                severity TEXT NOT NULL,
+                code TEXT NOT NULL,
+                message TEXT DEFAULT '',
+                created_at INTEGER NOT NULL
+            );
+            
+            -- Maintenance: command dispatch paused; device_id '' is the whole fleet
+            CREATE TABLE IF NOT EXISTS maintenance (
+                device_id TEXT PRIMARY KEY,
+                started_at INTEGER NOT NULL
+            );
+            
+            -- Command leases: one holder per device until expires_at
+            CREATE TABLE IF NOT EXISTS leases (
+                device_id TEXT PRIMARY KEY,
+                holder TEXT NOT NULL,
+                acquired_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            
+            -- API tokens a device issued itself, each limited to one scope
+            CREATE TABLE IF NOT EXISTS api_tokens (
+                token TEXT PRIMARY KEY,
+                device_id TEXT NOT NULL,
+                scope TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            
+            -- Audit log: actions taken, oldest has the lowest id
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                at INTEGER NOT NULL,
+                actor TEXT NOT NULL,
+                action TEXT NOT NULL,
+                target TEXT NOT NULL,
+                detail TEXT DEFAULT ''
+            );
+            
             -- Indexes for fast lookups
             CREATE INDEX IF NOT EXISTS idx_devices_status ON devices(status);
             CREATE INDEX IF NOT EXISTS idx_devices_token ON devices(token);
+            CREATE INDEX IF NOT EXISTS idx_devices_location ON devices(latitude, longitude);
             CREATE INDEX IF NOT EXISTS idx_commands_device ON commands(device_id);
             CREATE INDEX IF NOT EXISTS idx_pairing_code ON pairing_requests(code);
             CREATE INDEX IF NOT EXISTS idx_pairing_expires ON pairing_requests(expires_at);
+            CREATE INDEX IF NOT EXISTS idx_alerts_device ON alerts(device_id, created_at);
             "
         ).map_err(|e| e.to_string())?;
         
+        // Columns added after the first release. Older databases get them here.
+        add_column_if_missing(&conn, "commands", "updated_at", "INTEGER DEFAULT 0")?;
+        add_column_if_missing(&conn, "commands", "seq", "INTEGER DEFAULT 0")?;
+        add_column_if_missing(&conn, "commands", "request_id", "TEXT")?;
+        add_column_if_missing(&conn, "commands", "precondition", "TEXT")?;
+        add_column_if_missing(&conn, "commands", "reason", "TEXT")?;
+        add_column_if_missing(&conn, "commands", "idempotency_key", "TEXT")?;
+        add_column_if_missing(&conn, "commands", "callback_url", "TEXT")?;
+        add_column_if_missing(&conn, "pairing_requests", "signed", "INTEGER DEFAULT 0")?;
+        add_column_if_missing(&conn, "devices", "signed", "INTEGER DEFAULT 0")?;
+        add_column_if_missing(&conn, "devices", "color", "TEXT")?;
+        add_column_if_missing(&conn, "devices", "icon", "TEXT")?;
+        add_column_if_missing(&conn, "devices", "last_sensors", "TEXT")?;
+        add_column_if_missing(&conn, "devices", "retention_days", "INTEGER")?;
+        add_column_if_missing(&conn, "devices", "capabilities", "TEXT")?;
+        add_column_if_missing(&conn, "devices", "firmware_version", "TEXT")?;
+        add_column_if_missing(&conn, "devices", "sensors", "TEXT")?;
+        add_column_if_missing(&conn, "devices", "wake_at", "INTEGER")?;
+        add_column_if_missing(&conn, "devices", "tags", "TEXT")?;
+        add_column_if_missing(&conn, "alerts", "acknowledged_at", "INTEGER")?;
+        add_column_if_missing(&conn, "alerts", "acknowledged_by", "TEXT")?;
+        normalize_statuses(&conn)?;
+        
         Ok(Self {
             conn: Arc::new(Mutex::new(conn)),
         })
     }
     
+    /// Run several writes atomically. Commits if the closure returns Ok,
+    /// rolls back every write if it returns Err.
+    pub fn with_transaction<T, F>(&self, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&Transaction) -> Result<T, String>,
+    {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        // IMMEDIATE takes the write lock up front. A deferred transaction that
+        // reads and then writes can't wait for another connection's writer
+        // (SQLite answers "database is locked" at once rather than deadlock)
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate).map_err(|e| e.to_string())?;
+        
+        // Dropping an uncommitted transaction rolls it back
+        let result = f(&tx)?;
+        tx.commit().map_err(|e| e.to_string())?;
+        
+        Ok(result)
+    }
+    
     // ========================================================================
     // PAIRING
     // ========================================================================
     
     /// Create a new pairing request with a 6-character alphanumeric code.
-    /// Returns the generated code.
-    pub fn create_pairing_request(&self, device_id: &str, name: &str, device_type: &str) -> Result<String, String> {
-        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+    /// `signed` devices must sign their messages once paired (see signing.rs).
+    /// Returns the generated code, which no other pending request holds.
+    pub fn create_pairing_request(&self, device_id: &str, name: &str, device_type: &str, signed: bool) -> Result<String, String> {
         let now = now_unix();
-        let expires_at = now + 300; // 5 minutes
+        let expires_at = now + PAIRING_TTL_SECS;
         
-        // Generate 6-character alphanumeric code
-        let code = generate_code();
-        
-        // Delete any existing request for this device
-        conn.execute(
-            "DELETE FROM pairing_requests WHERE device_id = ?1",
-            params![device_id],
-        ).map_err(|e| e.to_string())?;
+        let code = self.with_transaction(|tx| {
+            // Generate 6-character alphanumeric code, drawing again while
+            // another device's request has it
+            let mut taken = tx.prepare(
+                "SELECT 1 FROM pairing_requests WHERE code = ?1 AND device_id != ?2"
+            ).map_err(|e| e.to_string())?;
+            let mut code = None;
+            for _ in 0..PAIRING_CODE_ATTEMPTS {
+                let candidate = generate_code();
+                if !taken.exists(params![candidate, device_id]).map_err(|e| e.to_string())? {
+                    code = Some(candidate);
+                    break;
+                }
+            }
+            let code = code.ok_or("no free pairing code; try again")?;
+            
+            // Delete any existing request for this device
+            tx.execute(
+                "DELETE FROM pairing_requests WHERE device_id = ?1",
+                params![device_id],
+            ).map_err(|e| e.to_string())?;
+            
+            // Insert new request
+            tx.execute(
+                "INSERT INTO pairing_requests (device_id, name, device_type, code, created_at, expires_at, signed)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![device_id, name, device_type, code, now, expires_at, signed],
+            ).map_err(|e| e.to_string())?;
+            
+            Ok(code)
+        })?;
         
-        // Insert new request
-        conn.execute(
-            "INSERT INTO pairing_requests (device_id, name, device_type, code, created_at, expires_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![device_id, name, device_type, code, now, expires_at],
-        ).map_err(|e| e.to_string())?;
+        pairing_started(|started| {
+            started.insert(device_id.to_string(), (code.clone(), Instant::now()));
+        });
         
         Ok(code)
     }
     
+    /// Seconds since `device_id`'s pending pairing request was made, if it
+    /// has one.
+    pub fn pairing_request_age(&self, device_id: &str) -> Result<Option<i64>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let now = now_unix();
+        
+        conn.query_row(
+            "SELECT ?2 - created_at FROM pairing_requests WHERE device_id = ?1",
+            params![device_id, now],
+            |row| row.get(0),
+        ).optional().map_err(|e| e.to_string())
+    }
+    
     /// Validate a pairing code and create the device with a token.
     /// Returns the auth token on success.
     pub fn confirm_pairing(&self, device_id: &str, code: &str) -> Result<String, String> {
-        let conn = self.conn.lock().map_err(|e| e.to_string())?;
-        let now = now_unix();
+        self.confirm_pairing_at(device_id, code, now_unix())
+    }
+    
+    /// `confirm_pairing` with the wall clock reading `now`.
+    fn confirm_pairing_at(&self, device_id: &str, code: &str, now: i64) -> Result<String, String> {
+        let token = self.with_transaction(|tx| {
+            // Find the pairing request
+            let request: Option<(String, String, i64, i64, bool)> = tx.query_row(
+                "SELECT name, device_type, created_at, expires_at, signed FROM pairing_requests 
+                 WHERE device_id = ?1 AND code = ?2",
+                params![device_id, code],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)),
+            ).ok();
+            
+            let (name, device_type, created_at, expires_at, signed) = request.ok_or("Invalid or expired code")?;
+            if pairing_expired(device_id, code, created_at, expires_at, now) {
+                return Err("Invalid or expired code".to_string());
+            }
+            
+            // Generate auth token
+            let token = generate_token();
+            
+            // Create or update device with token
+            tx.execute(
+                "INSERT INTO devices (id, name, device_type, status, token, paired_at, last_seen, signed)
+                 VALUES (?1, ?2, ?3, 'offline', ?4, ?5, ?5, ?6)
+                 ON CONFLICT(id) DO UPDATE SET
+                    name = ?2,
+                    device_type = ?3,
+                    token = ?4,
+                    paired_at = ?5,
+                    signed = ?6,
+                    status = CASE status WHEN 'revoked' THEN 'offline' ELSE status END",
+                params![device_id, name, device_type, token, now, signed],
+            ).map_err(|e| e.to_string())?;
+            
+            // Delete the pairing request
+            tx.execute(
+                "DELETE FROM pairing_requests WHERE device_id = ?1",
+                params![device_id],
+            ).map_err(|e| e.to_string())?;
+            
+            Ok(token)
+        })?;
         
-        // Find the pairing request
-        let request: Option<(String, String, String)> = conn.query_row(
-            "SELECT name, device_type, code FROM pairing_requests 
-             WHERE device_id = ?1 AND code = ?2 AND expires_at > ?3",
-            params![device_id, code, now],
-            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
-        ).ok();
+        pairing_started(|started| {
+            started.remove(device_id);
+        });
         
-        match request {
-            Some((name, device_type, _)) => {
-                // Generate auth token
-                let token = generate_token();
-                
-                // Create or update device with token
-                conn.execute(
-                    "INSERT INTO devices (id, name, device_type, status, token, paired_at, last_seen)
-                     VALUES (?1, ?2, ?3, 'offline', ?4, ?5, ?5)
-                     ON CONFLICT(id) DO UPDATE SET
-                        name = ?2,
-                        device_type = ?3,
-                        token = ?4,
-                        paired_at = ?5",
-                    params![device_id, name, device_type, token, now],
-                ).map_err(|e| e.to_string())?;
-                
-                // Delete the pairing request
-                conn.execute(
-                    "DELETE FROM pairing_requests WHERE device_id = ?1",
-                    params![device_id],
-                ).map_err(|e| e.to_string())?;
-                
-                Ok(token)
-            }
-            None => Err("Invalid or expired code".to_string()),
-        }
+        Ok(token)
     }
     
     /// Get all pending pairing requests (not expired).
@@ -177,10 +579,10 @@ impl StateDb {
         
         let mut stmt = conn.prepare(
             "SELECT device_id, name, device_type, code, expires_at, created_at 
-             FROM pairing_requests WHERE expires_at > ?1 ORDER BY created_at DESC"
+             FROM pairing_requests ORDER BY created_at DESC"
         ).map_err(|e| e.to_string())?;
         
-        let requests = stmt.query_map(params![now], |row| {
+        let requests = stmt.query_map([], |row| {
             Ok(PairingRequest {
                 device_id: row.get(0)?,
                 name: row.get(1)?,
@@ -188,10 +590,18 @@ impl StateDb {
                 code: row.get(3)?,
                 expires_at: row.get(4)?,
                 created_at: row.get(5)?,
+                seconds_remaining: 0,
             })
         }).map_err(|e| e.to_string())?;
         
-        requests.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
+        let requests = requests.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        Ok(requests.into_iter()
+            .filter(|r| !pairing_expired(&r.device_id, &r.code, r.created_at, r.expires_at, now))
+            .map(|r| PairingRequest {
+                seconds_remaining: pairing_remaining(&r.device_id, &r.code, r.expires_at, now),
+                ..r
+            })
+            .collect())
     }
     
     /// Delete a pairing request (dismiss/reject).
@@ -203,6 +613,10 @@ impl StateDb {
             params![device_id],
         ).map_err(|e| e.to_string())?;
         
+        pairing_started(|started| {
+            started.remove(device_id);
+        });
+        
         Ok(())
     }
     
@@ -211,10 +625,26 @@ impl StateDb {
         let conn = self.conn.lock().map_err(|e| e.to_string())?;
         let now = now_unix();
         
-        let deleted = conn.execute(
-            "DELETE FROM pairing_requests WHERE expires_at <= ?1",
-            params![now],
+        let mut stmt = conn.prepare(
+            "SELECT device_id, code, created_at, expires_at FROM pairing_requests"
         ).map_err(|e| e.to_string())?;
+        let requests = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<(String, String, i64, i64)>, _>>()
+            .map_err(|e| e.to_string())?;
+        
+        let mut deleted = 0;
+        for (device_id, code, created_at, expires_at) in requests {
+            if pairing_expired(&device_id, &code, created_at, expires_at, now) {
+                deleted += conn.execute(
+                    "DELETE FROM pairing_requests WHERE device_id = ?1 AND code = ?2",
+                    params![device_id, code],
+                ).map_err(|e| e.to_string())?;
+                pairing_started(|started| {
+                    started.remove(&device_id);
+                });
+            }
+        }
         
         Ok(deleted)
     }
@@ -236,6 +666,20 @@ impl StateDb {
         Ok(device_id)
     }
     
+    /// Whether a device was paired to sign its messages.
+    pub fn requires_signature(&self, device_id: &str) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        match conn.query_row(
+            "SELECT signed FROM devices WHERE id = ?1",
+            params![device_id],
+            |row| row.get(0),
+        ) {
+            Ok(signed) => Ok(signed),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+    
     /// Revoke a device (delete token, effectively un-pairing).
     pub fn revoke_device(&self, device_id: &str) -> Result<(), String> {
         let conn = self.conn.lock().map_err(|e| e.to_string())?;
@@ -244,6 +688,8 @@ impl StateDb {
             "UPDATE devices SET token = NULL, status = 'revoked' WHERE id = ?1",
             params![device_id],
         ).map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM api_tokens WHERE device_id = ?1", params![device_id])
+            .map_err(|e| e.to_string())?;
         
         Ok(())
     }
@@ -256,37 +702,149 @@ impl StateDb {
             "DELETE FROM devices WHERE id = ?1",
             params![device_id],
         ).map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM api_tokens WHERE device_id = ?1", params![device_id])
+            .map_err(|e| e.to_string())?;
         
         Ok(())
     }
     
+    /// Issue `device_id` a new API token limited to `scope`.
+    pub fn create_scoped_token(&self, device_id: &str, scope: TokenScope) -> Result<ScopedToken, String> {
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes).map_err(|e| e.to_string())?;
+        let scoped = ScopedToken {
+            token: bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            device_id: device_id.to_string(),
+            scope,
+            created_at: now_unix(),
+        };
+        
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO api_tokens (token, device_id, scope, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![scoped.token, scoped.device_id, scope.as_str(), scoped.created_at],
+        ).map_err(|e| e.to_string())?;
+        Ok(scoped)
+    }
+    
+    /// The scoped token `token`, if it is one.
+    pub fn scoped_token(&self, token: &str) -> Result<Option<ScopedToken>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let row: Option<(String, String, i64)> = conn.query_row(
+            "SELECT device_id, scope, created_at FROM api_tokens WHERE token = ?1",
+            params![token],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).optional().map_err(|e| e.to_string())?;
+        
+        Ok(row.and_then(|(device_id, scope, created_at)| Some(ScopedToken {
+            token: token.to_string(),
+            device_id,
+            scope: TokenScope::parse(&scope)?,
+            created_at,
+        })))
+    }
+    
+    /// Revoke every scoped token `device_id` has issued. Returns how many.
+    pub fn delete_scoped_tokens(&self, device_id: &str) -> Result<usize, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM api_tokens WHERE device_id = ?1", params![device_id])
+            .map_err(|e| e.to_string())
+    }
+    
     // ========================================================================
     // DEVICE MANAGEMENT
     // ========================================================================
     
-    /// Register or update a device.
-    pub fn upsert_device(&self, device: &DeviceInfo) -> Result<(), String> {
+    /// Insert paired devices, each with a token, in one transaction.
+    /// Returns every row's token or the reason it was refused. If any row is
+    /// refused, nothing is inserted.
+    pub fn import_devices(&self, devices: &[DeviceImport]) -> Result<Vec<Result<String, String>>, String> {
+        let mut results = Vec::with_capacity(devices.len());
+        let committed = self.with_transaction(|tx| {
+            let now = now_unix();
+            let (mut ids, mut tokens) = (HashSet::new(), HashSet::new());
+            for device in devices {
+                results.push(import_device(tx, device, &mut ids, &mut tokens, now));
+            }
+            if results.iter().any(|r| r.is_err()) {
+                return Err("import refused".to_string());
+            }
+            Ok(())
+        });
+        
+        match committed {
+            Ok(()) => Ok(results),
+            // Rolled back: the rows say why
+            Err(_) if results.iter().any(|r| r.is_err()) => Ok(results),
+            Err(e) => Err(e),
+        }
+    }
+    
+    /// Every device row, revoked ones too, ordered by id.
+    pub fn export_devices(&self) -> Result<Vec<DeviceRecord>, String> {
         let conn = self.conn.lock().map_err(|e| e.to_string())?;
         
-        conn.execute(
-            "INSERT INTO devices (id, name, device_type, status, latitude, longitude, altitude, heading, speed, battery, last_seen)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
-             ON CONFLICT(id) DO UPDATE SET
-                name = ?2,
-                device_type = ?3,
-                status = ?4,
-                latitude = ?5,
-                longitude = ?6,
-                altitude = ?7,
-                heading = ?8,
-                speed = ?9,
-                battery = ?10,
-                last_seen = ?11",
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM devices ORDER BY id", RECORD_COLUMNS))
+            .map_err(|e| e.to_string())?;
+        
+        let devices = stmt.query_map([], record_from_row).map_err(|e| e.to_string())?;
+        
+        devices.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
+    }
+    
+    /// Put exported device rows back, in one transaction. Returns each row's
+    /// outcome ("restored", "replaced" or "skipped") or the reason it was
+    /// refused; if any row is refused, nothing changes. Restored devices are
+    /// offline until they connect, or revoked if they were.
+    pub fn restore_devices(&self, devices: &[DeviceRecord], conflict: RestoreConflict) -> Result<Vec<Result<&'static str, String>>, String> {
+        let mut results = Vec::with_capacity(devices.len());
+        let committed = self.with_transaction(|tx| {
+            let (mut ids, mut tokens) = (HashSet::new(), HashSet::new());
+            for device in devices {
+                results.push(restore_device(tx, device, conflict, &mut ids, &mut tokens));
+            }
+            if results.iter().any(|r| r.is_err()) {
+                return Err("restore refused".to_string());
+            }
+            Ok(())
+        });
+        
+        match committed {
+            Ok(()) => Ok(results),
+            // Rolled back: the rows say why
+            Err(_) if results.iter().any(|r| r.is_err()) => Ok(results),
+            Err(e) => Err(e),
+        }
+    }
+    
+    /// Register or update a device. False if its stored status can't become
+    /// the new one (a revoked device coming online), and nothing changed.
+    pub fn upsert_device(&self, device: &DeviceInfo) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        
+        let changed = conn.execute(
+            &format!(
+                "INSERT INTO devices (id, name, device_type, status, latitude, longitude, altitude, heading, speed, battery, last_seen)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+                 ON CONFLICT(id) DO UPDATE SET
+                    name = ?2,
+                    device_type = ?3,
+                    status = ?4,
+                    latitude = ?5,
+                    longitude = ?6,
+                    altitude = ?7,
+                    heading = ?8,
+                    speed = ?9,
+                    battery = ?10,
+                    last_seen = ?11
+                 WHERE {}",
+                may_become(device.status)
+            ),
             params![
                 device.id,
                 device.name,
                 device.device_type,
-                device.status,
+                device.status.as_str(),
                 device.latitude,
                 device.longitude,
                 device.altitude,
@@ -297,161 +855,1154 @@ impl StateDb {
             ],
         ).map_err(|e| e.to_string())?;
         
-        Ok(())
+        Ok(changed > 0)
     }
     
-    /// Update device telemetry (position, battery, etc).
-    pub fn update_telemetry(&self, device_id: &str, lat: f64, lon: f64, alt: f64, heading: f64, speed: f64, battery: f64) -> Result<(), String> {
+    /// Update device telemetry (position, battery, etc). `sensors` replaces
+    /// the stored readings when it's an object; anything else keeps the last.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_telemetry(&self, device_id: &str, lat: f64, lon: f64, alt: f64, heading: f64, speed: f64, battery: f64, sensors: &serde_json::Value) -> Result<(), String> {
         let conn = self.conn.lock().map_err(|e| e.to_string())?;
         let now = now_unix();
+        let sensors = sensors.is_object().then(|| sensors.to_string());
         
         conn.execute(
-            "UPDATE devices SET 
+            &format!("UPDATE devices SET 
                 latitude = ?1, longitude = ?2, altitude = ?3,
                 heading = ?4, speed = ?5, battery = ?6,
-                status = 'online', last_seen = ?7
-             WHERE id = ?8",
-            params![lat, lon, alt, heading, speed, battery, now, device_id],
+                status = 'online', last_seen = ?7,
+                last_sensors = COALESCE(?9, last_sensors)
+             WHERE id = ?8 AND {}", may_become(DeviceStatus::Online)),
+            params![lat, lon, alt, heading, speed, battery, now, device_id, sensors],
         ).map_err(|e| e.to_string())?;
         
         Ok(())
     }
     
-    /// Set device status (online, offline, etc).
-    pub fn set_status(&self, device_id: &str, status: &str) -> Result<(), String> {
+    /// Move a device to `status`. False if there's no such device or it
+    /// can't go there from where it is.
+    pub fn set_status(&self, device_id: &str, status: DeviceStatus) -> Result<bool, String> {
         let conn = self.conn.lock().map_err(|e| e.to_string())?;
         let now = now_unix();
         
-        conn.execute(
-            "UPDATE devices SET status = ?1, last_seen = ?2 WHERE id = ?3",
-            params![status, now, device_id],
+        let changed = conn.execute(
+            &format!("UPDATE devices SET status = ?1, last_seen = ?2 WHERE id = ?3 AND {}", may_become(status)),
+            params![status.as_str(), now, device_id],
         ).map_err(|e| e.to_string())?;
         
-        Ok(())
+        Ok(changed > 0)
     }
     
-    /// Get all devices (only paired ones with tokens).
-    pub fn get_all_devices(&self) -> Result<Vec<DeviceInfo>, String> {
+    /// Put a device to sleep until `wake_at`. False if there's no such
+    /// device or it's revoked.
+    pub fn sleep(&self, device_id: &str, wake_at: i64) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        
+        let changed = conn.execute(
+            &format!("UPDATE devices SET status = 'sleeping', wake_at = ?1, last_seen = ?2 WHERE id = ?3 AND {}",
+                may_become(DeviceStatus::Sleeping)),
+            params![wake_at, now_unix(), device_id],
+        ).map_err(|e| e.to_string())?;
+        
+        Ok(changed > 0)
+    }
+    
+    /// Bring a sleeping device back online. False if it wasn't asleep.
+    pub fn wake(&self, device_id: &str) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        
+        let changed = conn.execute(
+            "UPDATE devices SET status = 'online', last_seen = ?1 WHERE id = ?2 AND status = 'sleeping'",
+            params![now_unix(), device_id],
+        ).map_err(|e| e.to_string())?;
+        
+        Ok(changed > 0)
+    }
+    
+    /// Sleeping devices that were due to wake before `due`.
+    pub fn overslept(&self, due: i64) -> Result<Vec<String>, String> {
         let conn = self.conn.lock().map_err(|e| e.to_string())?;
         
         let mut stmt = conn.prepare(
-            "SELECT id, name, device_type, status, latitude, longitude, altitude, heading, speed, battery, last_seen 
-             FROM devices WHERE token IS NOT NULL ORDER BY last_seen DESC"
+            "SELECT id FROM devices WHERE status = 'sleeping' AND wake_at < ?1 ORDER BY wake_at"
         ).map_err(|e| e.to_string())?;
         
-        let devices = stmt.query_map([], |row| {
-            Ok(DeviceInfo {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                device_type: row.get(2)?,
-                status: row.get(3)?,
-                latitude: row.get(4)?,
-                longitude: row.get(5)?,
-                altitude: row.get(6)?,
-                heading: row.get(7)?,
-                speed: row.get(8)?,
-                battery: row.get(9)?,
-                last_seen: row.get(10)?,
-            })
-        }).map_err(|e| e.to_string())?;
+        let ids = stmt.query_map(params![due], |row| row.get(0)).map_err(|e| e.to_string())?;
+        
+        ids.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
+    }
+    
+    /// Get all devices (only paired ones with tokens).
+    pub fn get_all_devices(&self) -> Result<Vec<DeviceInfo>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM devices WHERE token IS NOT NULL ORDER BY last_seen DESC",
+            DEVICE_COLUMNS
+        )).map_err(|e| e.to_string())?;
+        
+        let devices = stmt.query_map([], device_from_row).map_err(|e| e.to_string())?;
+        
+        devices.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
+    }
+    
+    /// Paired devices whose latest `sensor` reading is a number that compares
+    /// `op` to `value`. Devices that never reported it, or reported something
+    /// other than a number, don't match.
+    pub fn devices_by_sensor(&self, sensor: &str, op: SensorOp, value: f64) -> Result<Vec<DeviceInfo>, String> {
+        check_sensor_name(sensor)?;
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let path = format!("$.\"{}\"", sensor);
+        
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM devices
+             WHERE token IS NOT NULL
+               AND json_type(last_sensors, ?1) IN ('integer', 'real')
+               AND json_extract(last_sensors, ?1) {} ?2
+             ORDER BY last_seen DESC",
+            DEVICE_COLUMNS, op.sql()
+        )).map_err(|e| e.to_string())?;
+        
+        let devices = stmt.query_map(params![path, value], device_from_row).map_err(|e| e.to_string())?;
         
         devices.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
     }
     
+    /// Paired devices within `radius_m` metres of a point, nearest first.
+    /// The index on latitude and longitude narrows them to a bounding box;
+    /// the great-circle distance decides. A box reaching a pole or across
+    /// the antimeridian is bounded by latitude alone.
+    pub fn get_devices_near(&self, lat: f64, lon: f64, radius_m: f64) -> Result<Vec<DeviceInfo>, String> {
+        let angle = radius_m / EARTH_RADIUS_M;
+        let d_lat = angle.to_degrees();
+        let d_lon = (angle.sin() / lat.to_radians().cos()).asin().to_degrees();
+        let (min_lat, max_lat) = (lat - d_lat, lat + d_lat);
+        let (min_lon, max_lon) = match (lon - d_lon, lon + d_lon) {
+            (min, max) if min_lat > -90.0 && max_lat < 90.0 && min >= -180.0 && max <= 180.0 => (min, max),
+            _ => (-180.0, 180.0),
+        };
+        
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM devices
+             WHERE token IS NOT NULL
+               AND latitude BETWEEN ?1 AND ?2
+               AND longitude BETWEEN ?3 AND ?4",
+            DEVICE_COLUMNS
+        )).map_err(|e| e.to_string())?;
+        let devices = stmt.query_map(params![min_lat, max_lat, min_lon, max_lon], device_from_row).map_err(|e| e.to_string())?;
+        let devices = devices.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        
+        let mut near: Vec<(f64, DeviceInfo)> = devices.into_iter()
+            .map(|d| (haversine_m(lat, lon, d.latitude, d.longitude), d))
+            .filter(|(distance, _)| *distance <= radius_m)
+            .collect();
+        near.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(near.into_iter().map(|(_, d)| d).collect())
+    }
+    
     /// Get a single device by ID.
     pub fn get_device(&self, device_id: &str) -> Result<Option<DeviceInfo>, String> {
         let conn = self.conn.lock().map_err(|e| e.to_string())?;
         
         let device = conn.query_row(
-            "SELECT id, name, device_type, status, latitude, longitude, altitude, heading, speed, battery, last_seen 
-             FROM devices WHERE id = ?1",
+            &format!("SELECT {} FROM devices WHERE id = ?1", DEVICE_COLUMNS),
             params![device_id],
-            |row| {
-                Ok(DeviceInfo {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    device_type: row.get(2)?,
-                    status: row.get(3)?,
-                    latitude: row.get(4)?,
-                    longitude: row.get(5)?,
-                    altitude: row.get(6)?,
-                    heading: row.get(7)?,
-                    speed: row.get(8)?,
-                    battery: row.get(9)?,
-                    last_seen: row.get(10)?,
-                })
-            },
+            device_from_row,
         ).ok();
         
         Ok(device)
     }
     
-    // ========================================================================
-    // COMMANDS
-    // ========================================================================
-    
-    /// Save a command.
-    pub fn save_command(&self, id: &str, device_id: &str, command_type: &str, payload: &str, status: &str) -> Result<(), String> {
+    /// Choose a device's color and icon. `None` leaves one as it is,
+    /// `Some(None)` goes back to the default. False if there's no such device.
+    pub fn set_appearance(&self, device_id: &str, color: Option<Option<&str>>, icon: Option<Option<&str>>) -> Result<bool, String> {
         let conn = self.conn.lock().map_err(|e| e.to_string())?;
-        let now = now_unix();
         
-        conn.execute(
-            "INSERT INTO commands (id, device_id, command_type, payload, status, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![id, device_id, command_type, payload, status, now],
+        let updated = conn.execute(
+            "UPDATE devices SET
+                color = CASE WHEN ?2 THEN ?3 ELSE color END,
+                icon = CASE WHEN ?4 THEN ?5 ELSE icon END
+             WHERE id = ?1",
+            params![device_id, color.is_some(), color.flatten(), icon.is_some(), icon.flatten()],
         ).map_err(|e| e.to_string())?;
         
-        Ok(())
+        Ok(updated > 0)
     }
     
-    /// Update command status.
-    pub fn update_command_status(&self, id: &str, status: &str) -> Result<(), String> {
+    /// Store what a device says about itself. Fields the update leaves out
+    /// stay as they were. False if there's no such device.
+    pub fn set_device_info(&self, device_id: &str, info: &DeviceInfoUpdate) -> Result<bool, String> {
         let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let list = |items: &Option<Vec<String>>| items.as_ref().map(|items| serde_json::json!(items).to_string());
         
-        conn.execute(
-            "UPDATE commands SET status = ?1 WHERE id = ?2",
-            params![status, id],
+        let updated = conn.execute(
+            "UPDATE devices SET
+                capabilities = COALESCE(?2, capabilities),
+                firmware_version = COALESCE(?3, firmware_version),
+                sensors = COALESCE(?4, sensors)
+             WHERE id = ?1",
+            params![device_id, list(&info.capabilities), info.firmware_version, list(&info.sensors)],
         ).map_err(|e| e.to_string())?;
         
-        Ok(())
+        Ok(updated > 0)
     }
     
-    /// Clone for thread sharing.
-    #[allow(dead_code)]
-    pub fn clone(&self) -> Self {
-        Self {
-            conn: Arc::clone(&self.conn),
+    /// Add `tags` to those a device has, each once. False if there's no
+    /// such device.
+    pub fn add_device_tags(&self, device_id: &str, tags: &[String]) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let stored: Option<Option<String>> = conn.query_row(
+            "SELECT tags FROM devices WHERE id = ?1",
+            params![device_id],
+            |row| row.get(0),
+        ).optional().map_err(|e| e.to_string())?;
+        let Some(stored) = stored else {
+            return Ok(false);
+        };
+        let mut all: Vec<String> = stored.and_then(|t| serde_json::from_str(&t).ok()).unwrap_or_default();
+        for tag in tags {
+            if !all.contains(tag) {
+                all.push(tag.clone());
+            }
         }
-    }
-}
-
-// ============================================================================
-// UTILITIES
-// ============================================================================
-
-/// Get current unix timestamp.
-fn now_unix() -> i64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_secs() as i64)
-        .unwrap_or(0)
-}
-
-/// Generate a 6-character alphanumeric code (A-Z, 0-9).
-fn generate_code() -> String {
-    let chars = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789"; // Removed confusable chars: I, O, 0, 1
-    let mut code = String::with_capacity(6);
-    let t = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_nanos())
-        .unwrap_or(0);
-    
-    for i in 0..6 {
-        let idx = ((t >> (i * 8)) ^ (t >> (i * 4 + 3))) as usize % chars.len();
-        code.push(chars[idx] as char);
+        conn.execute(
+            "UPDATE devices SET tags = ?2 WHERE id = ?1",
+            params![device_id, serde_json::json!(all).to_string()],
+        ).map_err(|e| e.to_string())?;
+        Ok(true)
     }
     
-    code
+    /// Keep a device's telemetry `days` days instead of the fleet's default;
+    /// `None` goes back to the default. False if there's no such device.
+    pub fn set_retention(&self, device_id: &str, days: Option<u64>) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        
+        let updated = conn.execute(
+            "UPDATE devices SET retention_days = ?2 WHERE id = ?1",
+            params![device_id, days.map(|d| d as i64)],
+        ).map_err(|e| e.to_string())?;
+        
+        Ok(updated > 0)
+    }
+    
+    /// Devices with their own telemetry retention, in days.
+    pub fn retention_overrides(&self) -> Result<HashMap<String, u64>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        
+        let mut stmt = conn.prepare(
+            "SELECT id, retention_days FROM devices WHERE retention_days IS NOT NULL"
+        ).map_err(|e| e.to_string())?;
+        let overrides = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?.max(0) as u64)))
+            .map_err(|e| e.to_string())?;
+        
+        overrides.collect::<Result<HashMap<_, _>, _>>().map_err(|e| e.to_string())
+    }
+    
+    /// Count the fleet in a few aggregate queries under one lock. Devices
+    /// not heard from since `seen_since` are stale; commands count from
+    /// `commands_since`.
+    pub fn fleet_counts(&self, seen_since: i64, commands_since: i64) -> Result<FleetCounts, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut counts = FleetCounts::default();
+        
+        // A sleeping device is silent on purpose until it's due to wake
+        (counts.devices, counts.offline, counts.sleeping, counts.stale) = conn.query_row(
+            "SELECT COUNT(*),
+                    COALESCE(SUM(status = 'offline'), 0),
+                    COALESCE(SUM(status = 'sleeping' AND wake_at >= ?2), 0),
+                    COALESCE(SUM(status != 'offline' AND last_seen < ?1
+                                 AND NOT (status = 'sleeping' AND wake_at >= ?2)), 0)
+             FROM devices WHERE token IS NOT NULL",
+            params![seen_since, now_unix()],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        ).map_err(|e| e.to_string())?;
+        counts.online = counts.devices - counts.offline - counts.sleeping - counts.stale;
+        
+        let mut stmt = conn.prepare(
+            "SELECT device_type, COUNT(*) FROM devices WHERE token IS NOT NULL GROUP BY device_type"
+        ).map_err(|e| e.to_string())?;
+        counts.by_type = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+        
+        counts.pairing_requests = conn.query_row(
+            "SELECT COUNT(*) FROM pairing_requests WHERE expires_at > ?1",
+            params![now_unix()],
+            |row| row.get(0),
+        ).map_err(|e| e.to_string())?;
+        
+        let mut stmt = conn.prepare(
+            "SELECT status, COUNT(*) FROM commands WHERE created_at >= ?1 GROUP BY status"
+        ).map_err(|e| e.to_string())?;
+        counts.commands = stmt.query_map(params![commands_since], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+        
+        counts.pending_commands = conn.query_row(
+            "SELECT COUNT(*) FROM commands WHERE status IN ('queued', 'held')",
+            [],
+            |row| row.get(0),
+        ).map_err(|e| e.to_string())?;
+        
+        Ok(counts)
+    }
+    
+    /// Commands not yet delivered (queued or held), by device. Devices
+    /// with none are left out.
+    pub fn pending_commands_by_device(&self) -> Result<BTreeMap<String, i64>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare(
+            "SELECT device_id, COUNT(*) FROM commands WHERE status IN ('queued', 'held') GROUP BY device_id"
+        ).map_err(|e| e.to_string())?;
+        let counts = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string());
+        counts
+    }
+    
+    // ========================================================================
+    // COMMANDS
+    // ========================================================================
+    
+    /// A device's queued commands, oldest first.
+    pub fn get_pending_commands(&self, device_id: &str) -> Result<Vec<PendingCommand>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        
+        // seq breaks ties between commands queued in the same second
+        let mut stmt = conn.prepare(
+            "SELECT id, command_type, payload, seq, precondition FROM commands
+             WHERE device_id = ?1 AND status = 'queued' ORDER BY created_at, seq"
+        ).map_err(|e| e.to_string())?;
+        
+        let commands = stmt.query_map(params![device_id], |row| {
+            Ok(PendingCommand {
+                id: row.get(0)?,
+                command_type: row.get(1)?,
+                payload: row.get(2)?,
+                seq: row.get(3)?,
+                precondition: row.get(4)?,
+            })
+        }).map_err(|e| e.to_string())?;
+        
+        commands.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
+    }
+    
+    /// The command `device_id` has been sent and hasn't finished, if any:
+    /// the oldest, should there be more than one.
+    pub fn in_flight_command(&self, device_id: &str) -> Result<Option<String>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        match conn.query_row(
+            "SELECT id FROM commands WHERE device_id = ?1 AND status IN ('sent', 'delivered')
+             ORDER BY created_at, seq LIMIT 1",
+            params![device_id],
+            |row| row.get(0),
+        ) {
+            Ok(id) => Ok(Some(id)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+    
+    /// Move a command from status `from` to `status`. False if it had
+    /// already moved on (a timeout, say) and nothing changed.
+    pub fn advance_command_status(&self, id: &str, from: &str, status: &str) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let changed = conn.execute(
+            "UPDATE commands SET status = ?1, updated_at = ?2 WHERE id = ?3 AND status = ?4",
+            params![status, now_unix(), id, from],
+        ).map_err(|e| e.to_string())?;
+        Ok(changed > 0)
+    }
+    
+    /// A command's device and current status; None if there's no such command.
+    pub fn command_status(&self, id: &str) -> Result<Option<(String, String)>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        match conn.query_row(
+            "SELECT device_id, status FROM commands WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ) {
+            Ok(found) => Ok(Some(found)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+    
+    /// Skip a queued command whose precondition the device doesn't meet.
+    /// False if it had already moved on.
+    pub fn skip_queued_command(&self, id: &str, reason: &str) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let changed = conn.execute(
+            "UPDATE commands SET status = 'skipped', reason = ?1, updated_at = ?2 WHERE id = ?3 AND status = 'queued'",
+            params![reason, now_unix(), id],
+        ).map_err(|e| e.to_string())?;
+        Ok(changed > 0)
+    }
+    
+    /// Why a command is where it is, when the server decided (a skip).
+    pub fn command_reason(&self, id: &str) -> Result<Option<String>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        match conn.query_row("SELECT reason FROM commands WHERE id = ?1", params![id], |row| row.get(0)) {
+            Ok(reason) => Ok(reason),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+    
+    /// The newest command sent to `device_id` with `key` since `since`, as
+    /// (id, status): the one a retry with the same key stands for.
+    pub fn command_by_idempotency_key(&self, device_id: &str, key: &str, since: i64) -> Result<Option<(String, String)>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        match conn.query_row(
+            "SELECT id, status FROM commands WHERE device_id = ?1 AND idempotency_key = ?2 AND created_at >= ?3
+             ORDER BY created_at DESC, seq DESC LIMIT 1",
+            params![device_id, key, since],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ) {
+            Ok(found) => Ok(Some(found)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+    
+    /// Where to POST a command's outcome, and its type, if it was sent with
+    /// a callback URL.
+    pub fn command_callback(&self, id: &str) -> Result<Option<(String, String)>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        match conn.query_row(
+            "SELECT callback_url, command_type FROM commands WHERE id = ?1 AND callback_url IS NOT NULL",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ) {
+            Ok(found) => Ok(Some(found)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+    
+    /// The id the UI gave a command when it sent it, if any.
+    pub fn command_request_id(&self, id: &str) -> Result<Option<String>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        match conn.query_row("SELECT request_id FROM commands WHERE id = ?1", params![id], |row| row.get(0)) {
+            Ok(request_id) => Ok(request_id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+    
+    /// Move a device's held commands back to the queue, so they go out in the
+    /// order they were issued. Returns their ids, oldest first.
+    pub fn release_held_commands(&self, device_id: &str) -> Result<Vec<String>, String> {
+        self.with_transaction(|tx| {
+            let mut stmt = tx.prepare(
+                "SELECT id FROM commands WHERE device_id = ?1 AND status = 'held' ORDER BY created_at, seq"
+            ).map_err(|e| e.to_string())?;
+            let ids = stmt.query_map(params![device_id], |row| row.get(0))
+                .map_err(|e| e.to_string())?
+                .collect::<Result<Vec<String>, _>>()
+                .map_err(|e| e.to_string())?;
+            
+            tx.execute(
+                "UPDATE commands SET status = 'queued', updated_at = ?1 WHERE device_id = ?2 AND status = 'held'",
+                params![now_unix(), device_id],
+            ).map_err(|e| e.to_string())?;
+            
+            Ok(ids)
+        })
+    }
+    
+    /// Devices with commands held for maintenance.
+    pub fn devices_with_held_commands(&self) -> Result<Vec<String>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT device_id FROM commands WHERE status = 'held' ORDER BY device_id"
+        ).map_err(|e| e.to_string())?;
+        let devices = stmt.query_map([], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(devices)
+    }
+    
+    /// Mark commands that were sent but never acknowledged as timed_out.
+    /// Returns (command_id, device_id) for each command that changed.
+    pub fn expire_unacked_commands(&self, timeout_secs: i64) -> Result<Vec<(String, String)>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let cutoff = now_unix() - timeout_secs;
+        
+        let mut stmt = conn.prepare(
+            "SELECT id, device_id FROM commands WHERE status = 'sent' AND updated_at <= ?1"
+        ).map_err(|e| e.to_string())?;
+        
+        let expired = stmt.query_map(params![cutoff], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<(String, String)>, _>>()
+            .map_err(|e| e.to_string())?;
+        
+        conn.execute(
+            "UPDATE commands SET status = 'timed_out', updated_at = ?1
+             WHERE status = 'sent' AND updated_at <= ?2",
+            params![now_unix(), cutoff],
+        ).map_err(|e| e.to_string())?;
+        
+        Ok(expired)
+    }
+    
+    // ========================================================================
+    // ALERTS
+    // ========================================================================
+    
+    /// Store an alert a device raised, returning it as stored.
+    pub fn insert_alert(&self, device_id: &str, severity: &str, code: &str, message: &str) -> Result<Alert, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let now = now_unix();
+        
+        conn.execute(
+            "INSERT INTO alerts (device_id, severity, code, message, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![device_id, severity, code, message, now],
+        ).map_err(|e| e.to_string())?;
+        
+        Ok(Alert {
+            id: conn.last_insert_rowid(),
+            device_id: device_id.to_string(),
+            severity: severity.to_string(),
+            code: code.to_string(),
+            message: message.to_string(),
+            created_at: now,
+            acknowledged_at: None,
+            acknowledged_by: None,
+        })
+    }
+    
+    /// The latest `limit` alerts raised at or after `since`, from one device
+    /// or all of them, oldest first. `unacknowledged` leaves out those an
+    /// operator has acknowledged.
+    pub fn get_alerts(&self, device_id: Option<&str>, since: i64, unacknowledged: bool, limit: usize) -> Result<Vec<Alert>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        
+        let mut stmt = conn.prepare(
+            "SELECT id, device_id, severity, code, message, created_at, acknowledged_at, acknowledged_by FROM alerts
+             WHERE (?1 IS NULL OR device_id = ?1) AND created_at >= ?2 AND (?3 = 0 OR acknowledged_at IS NULL)
+             ORDER BY id DESC LIMIT ?4"
+        ).map_err(|e| e.to_string())?;
+        
+        let alerts = stmt.query_map(params![device_id, since, unacknowledged, limit as i64], alert_from_row)
+            .map_err(|e| e.to_string())?;
+        
+        let mut alerts = alerts.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        alerts.reverse();
+        Ok(alerts)
+    }
+    
+    /// One alert by id.
+    pub fn get_alert(&self, id: i64) -> Result<Option<Alert>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        match conn.query_row(
+            "SELECT id, device_id, severity, code, message, created_at, acknowledged_at, acknowledged_by FROM alerts WHERE id = ?1",
+            params![id],
+            alert_from_row,
+        ) {
+            Ok(alert) => Ok(Some(alert)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+    
+    /// Mark an alert acknowledged by `by`. False if there's no such alert or
+    /// it was already acknowledged; the first acknowledgment stands.
+    pub fn acknowledge_alert(&self, id: i64, by: &str) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let changed = conn.execute(
+            "UPDATE alerts SET acknowledged_at = ?1, acknowledged_by = ?2 WHERE id = ?3 AND acknowledged_at IS NULL",
+            params![now_unix(), by, id],
+        ).map_err(|e| e.to_string())?;
+        Ok(changed > 0)
+    }
+    
+    // ========================================================================
+    // MAINTENANCE
+    // ========================================================================
+    
+    /// Where command dispatch is paused.
+    pub fn maintenance(&self) -> Result<Maintenance, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare("SELECT device_id FROM maintenance ORDER BY device_id")
+            .map_err(|e| e.to_string())?;
+        let scopes = stmt.query_map([], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        
+        let (global, devices): (Vec<_>, Vec<_>) = scopes.into_iter().partition(|d| d.is_empty());
+        Ok(Maintenance { global: !global.is_empty(), devices })
+    }
+    
+    /// Pause (`active`) or resume command dispatch for one device, or with
+    /// None for the whole fleet. Pausing what's already paused keeps the
+    /// original start.
+    pub fn set_maintenance(&self, device_id: Option<&str>, active: bool) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let scope = device_id.unwrap_or("");
+        
+        if active {
+            conn.execute(
+                "INSERT OR IGNORE INTO maintenance (device_id, started_at) VALUES (?1, ?2)",
+                params![scope, now_unix()],
+            ).map_err(|e| e.to_string())?;
+        } else {
+            conn.execute("DELETE FROM maintenance WHERE device_id = ?1", params![scope])
+                .map_err(|e| e.to_string())?;
+        }
+        
+        Ok(())
+    }
+    
+    // ========================================================================
+    // AUDIT LOG
+    // ========================================================================
+    
+    /// Record that `actor` did `action` to `target`.
+    pub fn audit(&self, actor: &str, action: &str, target: &str, detail: &str) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO audit_log (at, actor, action, target, detail) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![now_unix(), actor, action, target, detail],
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+    
+    /// The latest `limit` audit entries at or after `since`, oldest first,
+    /// for one action if given.
+    pub fn audit_log(&self, action: Option<&str>, since: i64, limit: usize) -> Result<Vec<AuditEntry>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare(
+            "SELECT id, at, actor, action, target, detail FROM audit_log
+             WHERE (?1 IS NULL OR action = ?1) AND at >= ?2
+             ORDER BY id DESC LIMIT ?3"
+        ).map_err(|e| e.to_string())?;
+        let entries = stmt.query_map(params![action, since, limit as i64], |row| {
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                at: row.get(1)?,
+                actor: row.get(2)?,
+                action: row.get(3)?,
+                target: row.get(4)?,
+                detail: row.get(5)?,
+            })
+        }).map_err(|e| e.to_string())?;
+        
+        let mut entries = entries.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        entries.reverse();
+        Ok(entries)
+    }
+    
+    // ========================================================================
+    // LEASES
+    // ========================================================================
+    
+    /// Take `device_id`'s command lease for `holder` for `ttl_secs`, or
+    /// extend it if they hold it already. Returns the lease in force after:
+    /// theirs, or another holder's unexpired one, which is left as it was.
+    pub fn acquire_lease(&self, device_id: &str, holder: &str, ttl_secs: i64) -> Result<Lease, String> {
+        let now = now_unix();
+        self.with_transaction(|tx| {
+            tx.execute(
+                "INSERT INTO leases (device_id, holder, acquired_at, expires_at) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(device_id) DO UPDATE SET
+                     acquired_at = CASE WHEN holder = excluded.holder AND expires_at > ?3
+                                        THEN acquired_at ELSE excluded.acquired_at END,
+                     holder = excluded.holder,
+                     expires_at = excluded.expires_at
+                 WHERE holder = excluded.holder OR expires_at <= ?3",
+                params![device_id, holder, now, now + ttl_secs],
+            ).map_err(|e| e.to_string())?;
+            tx.query_row(
+                "SELECT device_id, holder, acquired_at, expires_at FROM leases WHERE device_id = ?1",
+                params![device_id],
+                lease_from_row,
+            ).map_err(|e| e.to_string())
+        })
+    }
+    
+    /// The unexpired lease on `device_id`, if there is one.
+    pub fn lease(&self, device_id: &str) -> Result<Option<Lease>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row(
+            "SELECT device_id, holder, acquired_at, expires_at FROM leases WHERE device_id = ?1 AND expires_at > ?2",
+            params![device_id, now_unix()],
+            lease_from_row,
+        ).optional().map_err(|e| e.to_string())
+    }
+    
+    /// Every unexpired lease, by device.
+    pub fn leases(&self) -> Result<Vec<Lease>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare(
+            "SELECT device_id, holder, acquired_at, expires_at FROM leases WHERE expires_at > ?1 ORDER BY device_id"
+        ).map_err(|e| e.to_string())?;
+        let leases = stmt.query_map(params![now_unix()], lease_from_row)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string());
+        leases
+    }
+    
+    /// End the lease on `device_id` if `holder` has it, or whoever has it
+    /// with None. Returns whether a lease was ended.
+    pub fn release_lease(&self, device_id: &str, holder: Option<&str>) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let released = conn.execute(
+            "DELETE FROM leases WHERE device_id = ?1 AND expires_at > ?2 AND (?3 IS NULL OR holder = ?3)",
+            params![device_id, now_unix(), holder],
+        ).map_err(|e| e.to_string())?;
+        Ok(released > 0)
+    }
+    
+    /// Delete leases that have run out, returning them.
+    pub fn expire_leases(&self) -> Result<Vec<Lease>, String> {
+        let now = now_unix();
+        self.with_transaction(|tx| {
+            let mut stmt = tx.prepare(
+                "SELECT device_id, holder, acquired_at, expires_at FROM leases WHERE expires_at <= ?1"
+            ).map_err(|e| e.to_string())?;
+            let expired = stmt.query_map(params![now], lease_from_row)
+                .map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())?;
+            tx.execute("DELETE FROM leases WHERE expires_at <= ?1", params![now])
+                .map_err(|e| e.to_string())?;
+            Ok(expired)
+        })
+    }
+    
+    // ========================================================================
+    // UI PREFERENCES
+    // ========================================================================
+    
+    /// Get the stored preferences blob for a UI identity.
+    pub fn get_prefs(&self, ui_id: &str) -> Result<Option<String>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        
+        let prefs = conn.query_row(
+            "SELECT prefs FROM ui_prefs WHERE ui_id = ?1",
+            params![ui_id],
+            |row| row.get(0),
+        ).ok();
+        
+        Ok(prefs)
+    }
+    
+    /// Store (replace) the preferences blob for a UI identity.
+    pub fn set_prefs(&self, ui_id: &str, prefs: &str) -> Result<(), String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let now = now_unix();
+        
+        conn.execute(
+            "INSERT INTO ui_prefs (ui_id, prefs, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(ui_id) DO UPDATE SET prefs = ?2, updated_at = ?3",
+            params![ui_id, prefs, now],
+        ).map_err(|e| e.to_string())?;
+        
+        Ok(())
+    }
+}
+
+// ============================================================================
+// TRANSACTION-SAFE WRITES
+// ============================================================================
+//
+// Take a plain connection so they work both standalone and inside
+// `with_transaction` (a Transaction derefs to a Connection).
+
+/// One row of `import_devices`. `ids` and `tokens` are those already taken
+/// earlier in the same import.
+fn import_device(conn: &Connection, device: &DeviceImport, ids: &mut HashSet<String>, tokens: &mut HashSet<String>, now: i64) -> Result<String, String> {
+    if !crate::telemetry::is_valid_device_id(&device.id) {
+        return Err("id is missing or invalid".to_string());
+    }
+    if !ids.insert(device.id.clone()) {
+        return Err("duplicate id in import".to_string());
+    }
+    let exists = |sql: &str, value: &str| -> Result<bool, String> {
+        conn.query_row(sql, params![value], |_| Ok(()))
+            .map(|_| true)
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(false),
+                e => Err(e.to_string()),
+            })
+    };
+    if exists("SELECT 1 FROM devices WHERE id = ?1", &device.id)? {
+        return Err("device already exists".to_string());
+    }
+    
+    let token = match &device.token {
+        Some(token) => {
+            if token.len() < MIN_IMPORT_TOKEN_LEN || !token.chars().all(|c| c.is_ascii_graphic()) {
+                return Err(format!("token must be at least {} printable ASCII characters", MIN_IMPORT_TOKEN_LEN));
+            }
+            if tokens.contains(token) || exists("SELECT 1 FROM devices WHERE token = ?1", token)? {
+                return Err("token already in use".to_string());
+            }
+            token.clone()
+        }
+        None => loop {
+            let token = generate_token();
+            if !tokens.contains(&token) && !exists("SELECT 1 FROM devices WHERE token = ?1", &token)? {
+                break token;
+            }
+        },
+    };
+    tokens.insert(token.clone());
+    
+    conn.execute(
+        "INSERT INTO devices (id, name, device_type, status, token, paired_at, last_seen, signed)
+         VALUES (?1, ?2, ?3, 'offline', ?4, ?5, ?5, ?6)",
+        params![device.id, device.name, device.device_type, token, now, device.signed],
+    ).map_err(|e| e.to_string())?;
+    Ok(token)
+}
+
+/// One row of `restore_devices`. `ids` and `tokens` are those already
+/// restored earlier in the same call.
+fn restore_device(conn: &Connection, device: &DeviceRecord, conflict: RestoreConflict, ids: &mut HashSet<String>, tokens: &mut HashSet<String>) -> Result<&'static str, String> {
+    if !crate::telemetry::is_valid_device_id(&device.id) {
+        return Err("id is missing or invalid".to_string());
+    }
+    if !ids.insert(device.id.clone()) {
+        return Err("duplicate id in restore".to_string());
+    }
+    let exists: bool = conn.query_row("SELECT 1 FROM devices WHERE id = ?1", params![device.id], |_| Ok(()))
+        .optional().map_err(|e| e.to_string())?.is_some();
+    if exists && conflict == RestoreConflict::Merge {
+        return Ok("skipped");
+    }
+    if let Some(token) = &device.token {
+        let taken = conn.query_row("SELECT 1 FROM devices WHERE token = ?1 AND id != ?2", params![token, device.id], |_| Ok(()))
+            .optional().map_err(|e| e.to_string())?.is_some();
+        if taken || !tokens.insert(token.clone()) {
+            return Err("token already in use".to_string());
+        }
+    }
+    
+    // Nobody is connected to what was just restored
+    let status = match device.status {
+        DeviceStatus::Revoked => DeviceStatus::Revoked,
+        _ => DeviceStatus::Offline,
+    };
+    let list = |items: &Option<Vec<String>>| items.as_ref().map(|items| serde_json::json!(items).to_string());
+    conn.execute(
+        "INSERT INTO devices (id, name, device_type, status, latitude, longitude, altitude, heading, speed, battery,
+                              last_seen, token, paired_at, signed, color, icon, last_sensors, retention_days,
+                              capabilities, firmware_version, sensors, wake_at, tags)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)
+         ON CONFLICT(id) DO UPDATE SET
+            name = excluded.name, device_type = excluded.device_type, status = excluded.status,
+            latitude = excluded.latitude, longitude = excluded.longitude, altitude = excluded.altitude,
+            heading = excluded.heading, speed = excluded.speed, battery = excluded.battery,
+            last_seen = excluded.last_seen, token = excluded.token, paired_at = excluded.paired_at,
+            signed = excluded.signed, color = excluded.color, icon = excluded.icon,
+            last_sensors = excluded.last_sensors, retention_days = excluded.retention_days,
+            capabilities = excluded.capabilities, firmware_version = excluded.firmware_version,
+            sensors = excluded.sensors, wake_at = excluded.wake_at, tags = excluded.tags",
+        params![
+            device.id, device.name, device.device_type, status.as_str(),
+            device.latitude, device.longitude, device.altitude, device.heading, device.speed, device.battery,
+            device.last_seen, device.token, device.paired_at, device.signed, device.color, device.icon,
+            device.last_sensors.as_ref().map(|sensors| sensors.to_string()), device.retention_days,
+            list(&device.capabilities), device.firmware_version, list(&device.sensors), device.wake_at,
+            list(&device.tags),
+        ],
+    ).map_err(|e| e.to_string())?;
+    Ok(if exists { "replaced" } else { "restored" })
+}
+
+/// What `record_from_row` reads, in order.
+const RECORD_COLUMNS: &str = "id, name, device_type, status, latitude, longitude, altitude, heading, speed, battery,
+    last_seen, token, paired_at, signed, color, icon, last_sensors, retention_days, capabilities, firmware_version,
+    sensors, wake_at, tags";
+
+/// A device row in full, as selected by `export_devices`.
+fn record_from_row(row: &rusqlite::Row) -> rusqlite::Result<DeviceRecord> {
+    let status: String = row.get(3)?;
+    // Stored as JSON text; unreadable is as good as unset
+    let list = |text: Option<String>| text.and_then(|t| serde_json::from_str::<Vec<String>>(&t).ok());
+    let last_sensors: Option<String> = row.get(16)?;
+    Ok(DeviceRecord {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        device_type: row.get(2)?,
+        status: status.parse().unwrap_or(DeviceStatus::Offline),
+        latitude: row.get::<_, Option<f64>>(4)?.unwrap_or(0.0),
+        longitude: row.get::<_, Option<f64>>(5)?.unwrap_or(0.0),
+        altitude: row.get::<_, Option<f64>>(6)?.unwrap_or(0.0),
+        heading: row.get::<_, Option<f64>>(7)?.unwrap_or(0.0),
+        speed: row.get::<_, Option<f64>>(8)?.unwrap_or(0.0),
+        battery: row.get::<_, Option<f64>>(9)?.unwrap_or(0.0),
+        last_seen: row.get::<_, Option<i64>>(10)?.unwrap_or(0),
+        token: row.get(11)?,
+        paired_at: row.get::<_, Option<i64>>(12)?.unwrap_or(0),
+        signed: row.get::<_, Option<bool>>(13)?.unwrap_or(false),
+        color: row.get(14)?,
+        icon: row.get(15)?,
+        last_sensors: last_sensors.and_then(|t| serde_json::from_str(&t).ok()),
+        retention_days: row.get(17)?,
+        capabilities: list(row.get(18)?),
+        firmware_version: row.get(19)?,
+        sensors: list(row.get(20)?),
+        wake_at: row.get(21)?,
+        tags: list(row.get(22)?),
+    })
+}
+
+/// An alert row, as selected by `get_alerts` and `get_alert`.
+fn alert_from_row(row: &rusqlite::Row) -> rusqlite::Result<Alert> {
+    Ok(Alert {
+        id: row.get(0)?,
+        device_id: row.get(1)?,
+        severity: row.get(2)?,
+        code: row.get(3)?,
+        message: row.get(4)?,
+        created_at: row.get(5)?,
+        acknowledged_at: row.get(6)?,
+        acknowledged_by: row.get(7)?,
+    })
+}
+
+/// A lease row: device_id, holder, acquired_at, expires_at.
+fn lease_from_row(row: &rusqlite::Row) -> rusqlite::Result<Lease> {
+    Ok(Lease {
+        device_id: row.get(0)?,
+        holder: row.get(1)?,
+        acquired_at: row.get(2)?,
+        expires_at: row.get(3)?,
+    })
+}
+
+/// What `device_from_row` reads, in order.
+const DEVICE_COLUMNS: &str = "id, name, device_type, status, latitude, longitude, altitude, heading, speed, battery, last_seen,
+    (SELECT COUNT(*) FROM commands WHERE device_id = devices.id AND status = 'queued'), color, icon,
+    capabilities, firmware_version, sensors, CASE status WHEN 'sleeping' THEN wake_at END,
+    (SELECT COUNT(*) FROM commands WHERE device_id = devices.id AND status IN ('queued', 'held')), tags";
+
+/// A device row, as selected by `get_all_devices` and `get_device`. Unset
+/// colors and icons come back as the defaults.
+fn device_from_row(row: &rusqlite::Row) -> rusqlite::Result<DeviceInfo> {
+    let id: String = row.get(0)?;
+    let device_type: String = row.get(2)?;
+    let status: String = row.get(3)?;
+    let color: Option<String> = row.get(12)?;
+    let icon: Option<String> = row.get(13)?;
+    // Stored as JSON arrays; missing or unreadable is none declared
+    let list = |json: Option<String>| json.and_then(|j| serde_json::from_str(&j).ok()).unwrap_or_default();
+    Ok(DeviceInfo {
+        capabilities: list(row.get(14)?),
+        firmware_version: row.get(15)?,
+        sensors: list(row.get(16)?),
+        wake_at: row.get(17)?,
+        color: color.unwrap_or_else(|| appearance::default_color(&id)),
+        icon: icon.unwrap_or_else(|| appearance::default_icon(&device_type).to_string()),
+        id,
+        name: row.get(1)?,
+        device_type,
+        // Normalized on open; anything else since is nobody's live connection
+        status: status.parse().unwrap_or(DeviceStatus::Offline),
+        latitude: row.get(4)?,
+        longitude: row.get(5)?,
+        altitude: row.get(6)?,
+        heading: row.get(7)?,
+        speed: row.get(8)?,
+        battery: row.get(9)?,
+        last_seen: row.get(10)?,
+        queued_commands: row.get(11)?,
+        pending_commands: row.get(18)?,
+        tags: list(row.get(19)?),
+    })
+}
+
+/// Save a command with the device's next sequence number, which is returned.
+/// Devices can spot a gap between consecutive commands by it.
+pub fn insert_command(conn: &Connection, id: &str, device_id: &str, command_type: &str, payload: &str, status: &str, request_id: Option<&str>) -> Result<i64, String> {
+    let now = now_unix();
+    
+    let seq: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(seq), 0) + 1 FROM commands WHERE device_id = ?1",
+        params![device_id],
+        |row| row.get(0),
+    ).map_err(|e| e.to_string())?;
+    
+    conn.execute(
+        "INSERT INTO commands (id, device_id, command_type, payload, status, created_at, updated_at, seq, request_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6, ?7, ?8)",
+        params![id, device_id, command_type, payload, status, now, seq, request_id],
+    ).map_err(|e| e.to_string())?;
+    
+    Ok(seq)
+}
+
+/// Update command status.
+pub fn set_command_status(conn: &Connection, id: &str, status: &str) -> Result<(), String> {
+    let now = now_unix();
+    
+    conn.execute(
+        "UPDATE commands SET status = ?1, updated_at = ?2 WHERE id = ?3",
+        params![status, now, id],
+    ).map_err(|e| e.to_string())?;
+    
+    Ok(())
+}
+
+/// Keep a command's precondition, to check again if it's delivered later.
+pub fn set_command_precondition(conn: &Connection, id: &str, precondition: &str) -> Result<(), String> {
+    conn.execute(
+        "UPDATE commands SET precondition = ?1 WHERE id = ?2",
+        params![precondition, id],
+    ).map_err(|e| e.to_string())?;
+    
+    Ok(())
+}
+
+/// Record the key a UI may retry a command with.
+pub fn set_command_idempotency_key(conn: &Connection, id: &str, key: &str) -> Result<(), String> {
+    conn.execute(
+        "UPDATE commands SET idempotency_key = ?1 WHERE id = ?2",
+        params![key, id],
+    ).map_err(|e| e.to_string())?;
+    
+    Ok(())
+}
+
+/// Record where to POST a command's outcome.
+pub fn set_command_callback_url(conn: &Connection, id: &str, url: &str) -> Result<(), String> {
+    conn.execute(
+        "UPDATE commands SET callback_url = ?1 WHERE id = ?2",
+        params![url, id],
+    ).map_err(|e| e.to_string())?;
+    
+    Ok(())
+}
+
+/// Mark a command skipped: its precondition wasn't met, for `reason`.
+pub fn set_command_skipped(conn: &Connection, id: &str, reason: &str) -> Result<(), String> {
+    conn.execute(
+        "UPDATE commands SET status = 'skipped', reason = ?1, updated_at = ?2 WHERE id = ?3",
+        params![reason, now_unix(), id],
+    ).map_err(|e| e.to_string())?;
+    
+    Ok(())
+}
+
+// ============================================================================
+// UTILITIES
+// ============================================================================
+
+/// Add a column to an existing table unless it is already there.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<(), String> {
+    let exists = conn
+        .prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1", table))
+        .and_then(|mut stmt| stmt.exists(params![column]))
+        .map_err(|e| e.to_string())?;
+    
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .map_err(|e| e.to_string())?;
+    }
+    
+    Ok(())
+}
+
+/// SQL that holds when a device row's `status` may become `next`.
+fn may_become(next: DeviceStatus) -> String {
+    let from: Vec<String> = DeviceStatus::ALL.iter()
+        .filter(|status| status.can_become(next))
+        .map(|status| format!("'{}'", status.as_str()))
+        .collect();
+    format!("status IN ({})", from.join(", "))
+}
+
+/// Rewrite device statuses from before they were checked: case and spacing
+/// are evened out, and anything still unknown ("idle", a typo) becomes offline.
+fn normalize_statuses(conn: &Connection) -> Result<(), String> {
+    let known: Vec<String> = DeviceStatus::ALL.iter().map(|status| format!("'{}'", status.as_str())).collect();
+    conn.execute_batch(&format!(
+        "UPDATE devices SET status = lower(trim(status)) WHERE status != lower(trim(status));
+         UPDATE devices SET status = 'offline' WHERE status IS NULL OR status NOT IN ({});",
+        known.join(", ")
+    )).map_err(|e| e.to_string())
+}
+
+/// Run `f` on the monotonic pairing-start table.
+fn pairing_started<T>(f: impl FnOnce(&mut HashMap<String, (String, Instant)>) -> T) -> T {
+    let mut started = PAIRING_STARTED.lock().unwrap_or_else(|e| e.into_inner());
+    f(started.get_or_insert_with(HashMap::new))
+}
+
+/// Whether a pairing request is past its TTL. Judged by the monotonic clock
+/// when this process issued the request, so wall-clock jumps in either
+/// direction don't matter. Otherwise (after a restart) by the wall clock,
+/// refusing requests created in the future: the clock has gone backward and
+/// their real age is unknown.
+fn pairing_expired(device_id: &str, code: &str, created_at: i64, expires_at: i64, now: i64) -> bool {
+    let wall_age = now - created_at;
+    let started = pairing_started(|started| {
+        started.get(device_id).filter(|(c, _)| c == code).map(|(_, at)| at.elapsed().as_secs() as i64)
+    });
+    
+    if let Some(age) = started {
+        let skew = wall_age - age;
+        let expired = age >= PAIRING_TTL_SECS;
+        if skew.abs() > MAX_CLOCK_SKEW_SECS && expired != (expires_at <= now) {
+            log!("⚠ Clock jumped {}s during pairing for {}; using monotonic age ({}s)", skew, device_id, age);
+        }
+        return expired;
+    }
+    
+    if -wall_age > MAX_CLOCK_SKEW_SECS {
+        log!("⚠ Pairing for {} refused: created {}s in the future, clock may have gone backward", device_id, -wall_age);
+        return true;
+    }
+    expires_at <= now
+}
+
+/// Seconds left on an unexpired pairing request: by the monotonic clock
+/// when this process issued it, otherwise by the wall clock.
+fn pairing_remaining(device_id: &str, code: &str, expires_at: i64, now: i64) -> i64 {
+    let age = pairing_started(|started| {
+        started.get(device_id).filter(|(c, _)| c == code).map(|(_, at)| at.elapsed().as_secs() as i64)
+    });
+    match age {
+        Some(age) => PAIRING_TTL_SECS - age,
+        None => expires_at - now,
+    }
+    .max(0)
+}
+
+/// Get current unix timestamp.
+fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Generate a 6-character alphanumeric code (A-Z, 0-9).
+fn generate_code() -> String {
+    let chars = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789"; // Removed confusable chars: I, O, 0, 1
+    let mut code = String::with_capacity(6);
+    let t = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    
+    for i in 0..6 {
+        let idx = ((t >> (i * 8)) ^ (t >> (i * 4 + 3))) as usize % chars.len();
+        code.push(chars[idx] as char);
+    }
+    
+    code
 }
 
 /// Generate a 64-character hex token.
@@ -471,3 +2022,351 @@ fn generate_token() -> String {
     token.truncate(64);
     token
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(id: &str, token: Option<&str>) -> DeviceImport {
+        DeviceImport {
+            id: id.to_string(),
+            name: id.to_string(),
+            device_type: "robot".to_string(),
+            token: token.map(str::to_string),
+            signed: false,
+        }
+    }
+
+    /// A database in a fresh file under the system temp dir.
+    fn temp_db(label: &str) -> (StateDb, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("globalrts-state-{}-{}.db", label, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (StateDb::open(path.to_str().unwrap()).unwrap(), path)
+    }
+
+    #[test]
+    fn device_info_updates_only_what_they_name() {
+        let (db, path) = temp_db("info");
+        let code = db.create_pairing_request("robot-01", "Robot", "robot", false).unwrap();
+        db.confirm_pairing("robot-01", &code).unwrap();
+        let device = || db.get_device("robot-01").unwrap().unwrap();
+        assert_eq!((device().capabilities.len(), device().firmware_version), (0, None));
+
+        let caps = DeviceInfoUpdate { capabilities: Some(vec!["camera".to_string()]), ..Default::default() };
+        assert!(db.set_device_info("robot-01", &caps).unwrap());
+        let firmware = DeviceInfoUpdate { firmware_version: Some("2.4.1".to_string()), ..Default::default() };
+        assert!(db.set_device_info("robot-01", &firmware).unwrap());
+        assert_eq!(device().firmware_version.as_deref(), Some("2.4.1"));
+        assert_eq!(device().capabilities, ["camera"]);
+        assert!(!db.set_device_info("robot-99", &firmware).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn devices_near_a_point_are_those_within_the_radius_nearest_first() {
+        let (db, path) = temp_db("near");
+        // Downtown LA, Santa Monica (~22 km), Pasadena (~14 km), San Diego (~180 km)
+        for (id, lat, lon) in [("robot-01", 34.0522, -118.2437), ("robot-02", 34.0195, -118.4912),
+                               ("robot-03", 34.1478, -118.1445), ("robot-04", 32.7157, -117.1611)] {
+            let code = db.create_pairing_request(id, id, "robot", false).unwrap();
+            db.confirm_pairing(id, &code).unwrap();
+            db.update_telemetry(id, lat, lon, 0.0, 0.0, 0.0, 100.0, &serde_json::json!({})).unwrap();
+        }
+        let near = |radius_m: f64| -> Vec<String> {
+            db.get_devices_near(34.0522, -118.2437, radius_m).unwrap().into_iter().map(|d| d.id).collect()
+        };
+        assert_eq!(near(1_000.0), ["robot-01"]);
+        assert_eq!(near(15_000.0), ["robot-01", "robot-03"]);
+        assert_eq!(near(25_000.0), ["robot-01", "robot-03", "robot-02"]);
+        assert_eq!(near(200_000.0).len(), 4);
+        // Across the antimeridian
+        db.update_telemetry("robot-04", -16.5, 179.9, 0.0, 0.0, 0.0, 100.0, &serde_json::json!({})).unwrap();
+        let ids: Vec<String> = db.get_devices_near(-16.5, -179.9, 30_000.0).unwrap().into_iter().map(|d| d.id).collect();
+        assert_eq!(ids, ["robot-04"]);
+        // Near the east edge of a circle far north, past `d_lat / cos(lat)`
+        db.update_telemetry("robot-03", 81.0, 36.5, 0.0, 0.0, 0.0, 100.0, &serde_json::json!({})).unwrap();
+        let ids: Vec<String> = db.get_devices_near(80.0, 10.0, 500_000.0).unwrap().into_iter().map(|d| d.id).collect();
+        assert_eq!(ids, ["robot-03"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn concurrent_pairing_requests_get_distinct_codes() {
+        let (db, path) = temp_db("codes");
+        let threads: Vec<_> = (0..64).map(|i| {
+            let db = db.clone();
+            std::thread::spawn(move || db.create_pairing_request(&format!("robot-{:02}", i), "Robot", "robot", false).unwrap())
+        }).collect();
+        let mut issued: Vec<String> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        let pending = db.get_pending_pairing_requests().unwrap();
+        assert_eq!(pending.len(), 64);
+        let mut active: Vec<String> = pending.into_iter().map(|r| r.code).collect();
+        active.sort();
+        active.dedup();
+        assert_eq!(active.len(), 64, "a code was handed out twice");
+        issued.sort();
+        assert_eq!(issued, active);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn failing_transaction_rolls_back_every_write() {
+        let (db, path) = temp_db("rollback");
+        let code = db.create_pairing_request("robot-01", "Robot 1", "robot", false).unwrap();
+        db.confirm_pairing("robot-01", &code).unwrap();
+        let result: Result<(), String> = db.with_transaction(|tx| {
+            insert_command(tx, "cmd-1", "robot-01", "stop", "{}", "queued", None)?;
+            set_command_status(tx, "cmd-1", "sent")?;
+            Err("second step failed".to_string())
+        });
+        assert_eq!(result, Err("second step failed".to_string()));
+        assert!(db.command_status("cmd-1").unwrap().is_none());
+
+        db.with_transaction(|tx| insert_command(tx, "cmd-1", "robot-01", "stop", "{}", "queued", None)).unwrap();
+        assert_eq!(db.get_device("robot-01").unwrap().unwrap().queued_commands, 1);
+        assert_eq!(db.command_status("cmd-1").unwrap(), Some(("robot-01".to_string(), "queued".to_string())));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn pairing_survives_wall_clock_jumps_in_either_direction() {
+        let (db, path) = temp_db("skew");
+        let code = db.create_pairing_request("robot-back", "Robot", "robot", false).unwrap();
+        // An hour back: the request looks fresh either way, and is
+        assert!(db.confirm_pairing_at("robot-back", &code, now_unix() - 3600).is_ok());
+
+        // An hour forward: the wall clock says expired, but only a moment has passed
+        let code = db.create_pairing_request("robot-fwd", "Robot", "robot", false).unwrap();
+        assert_eq!(db.get_pending_pairing_requests().unwrap().len(), 1);
+        assert!(db.confirm_pairing_at("robot-fwd", &code, now_unix() + 3600).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn after_a_restart_requests_from_the_future_are_refused() {
+        let (db, path) = temp_db("skew-restart");
+        let code = db.create_pairing_request("robot-restart", "Robot", "robot", false).unwrap();
+        let created_at = db.get_pending_pairing_requests().unwrap()[0].created_at;
+        // A new process has no monotonic record of the request
+        pairing_started(|started| started.remove("robot-restart"));
+
+        let err = db.confirm_pairing_at("robot-restart", &code, created_at - 3600).unwrap_err();
+        assert_eq!(err, "Invalid or expired code");
+        assert!(db.confirm_pairing_at("robot-restart", &code, created_at + PAIRING_TTL_SECS).is_err());
+        assert!(db.confirm_pairing_at("robot-restart", &code, created_at + 10).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn transactions_on_separate_connections_wait_for_each_other() {
+        let (db, path) = temp_db("contention");
+        let workers: Vec<_> = (0..4).map(|worker| {
+            let db = StateDb::open(path.to_str().unwrap()).unwrap();
+            std::thread::spawn(move || {
+                for n in 0..25 {
+                    // Confirming reads, then writes: the pattern that can't upgrade under contention
+                    let id = format!("robot-{}-{}", worker, n);
+                    let code = db.create_pairing_request(&id, &id, "robot", false).unwrap();
+                    db.confirm_pairing(&id, &code).unwrap();
+                }
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(db.get_all_devices().unwrap().len(), 100);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn import_is_all_or_nothing() {
+        let (db, path) = temp_db("import");
+        let token = "f".repeat(40);
+        let batch = [import("robot-01", Some(&token)), import("robot-02", None), import("robot-01", None), import("robot-03", Some("short"))];
+        let results = db.import_devices(&batch).unwrap();
+        assert!(results[0].is_ok() && results[1].is_ok(), "{:?}", results);
+        assert_eq!(results[2], Err("duplicate id in import".to_string()));
+        assert!(results[3].is_err());
+        assert!(db.get_all_devices().unwrap().is_empty());
+
+        let results = db.import_devices(&batch[..2]).unwrap();
+        assert_eq!(results[0], Ok(token.clone()));
+        assert_eq!(db.validate_token(&token).unwrap(), Some("robot-01".to_string()));
+        assert_eq!(db.validate_token(results[1].as_ref().unwrap()).unwrap(), Some("robot-02".to_string()));
+
+        // Already there now, and the token is taken
+        let again = db.import_devices(&[import("robot-01", None), import("robot-04", Some(&token))]).unwrap();
+        assert_eq!(again, vec![Err("device already exists".to_string()), Err("token already in use".to_string())]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn revoked_devices_cannot_come_straight_back_online() {
+        let (db, path) = temp_db("status");
+        let code = db.create_pairing_request("robot-01", "Robot", "robot", false).unwrap();
+        db.confirm_pairing("robot-01", &code).unwrap();
+        let status = |db: &StateDb| db.get_device("robot-01").unwrap().unwrap().status;
+        assert!(db.set_status("robot-01", DeviceStatus::Online).unwrap());
+        assert!(db.set_status("robot-01", DeviceStatus::Offline).unwrap());
+        assert!(!db.set_status("robot-99", DeviceStatus::Offline).unwrap());
+
+        db.revoke_device("robot-01").unwrap();
+        assert_eq!(status(&db), DeviceStatus::Revoked);
+        assert!(!db.set_status("robot-01", DeviceStatus::Online).unwrap());
+        db.update_telemetry("robot-01", 1.0, 2.0, 0.0, 0.0, 0.0, 50.0, &serde_json::Value::Null).unwrap();
+        let mut device = db.get_device("robot-01").unwrap().unwrap();
+        assert_eq!((device.status, device.latitude), (DeviceStatus::Revoked, 0.0));
+        device.status = DeviceStatus::Online;
+        assert!(!db.upsert_device(&device).unwrap());
+        assert_eq!(status(&db), DeviceStatus::Revoked);
+
+        // Re-pairing is the way back, through offline
+        let code = db.create_pairing_request("robot-01", "Robot", "robot", false).unwrap();
+        db.confirm_pairing("robot-01", &code).unwrap();
+        assert_eq!(status(&db), DeviceStatus::Offline);
+        assert!(db.upsert_device(&device).unwrap());
+        assert_eq!(status(&db), DeviceStatus::Online);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn a_sleeping_device_is_not_stale_until_it_is_due_to_wake() {
+        let (db, path) = temp_db("sleep");
+        let code = db.create_pairing_request("robot-01", "Robot", "robot", false).unwrap();
+        db.confirm_pairing("robot-01", &code).unwrap();
+        let now = now_unix();
+        assert!(db.sleep("robot-01", now + 600).unwrap());
+        assert!(!db.sleep("robot-99", now + 600).unwrap());
+        let device = db.get_device("robot-01").unwrap().unwrap();
+        assert_eq!((device.status, device.wake_at), (DeviceStatus::Sleeping, Some(now + 600)));
+
+        // Silent past the stale threshold, but asleep on purpose
+        let counts = db.fleet_counts(now + 1, 0).unwrap();
+        assert_eq!((counts.online, counts.sleeping, counts.stale), (0, 1, 0));
+        assert!(db.overslept(now + 600).unwrap().is_empty());
+        assert_eq!(db.overslept(now + 601).unwrap(), ["robot-01"]);
+
+        assert!(db.wake("robot-01").unwrap());
+        assert!(!db.wake("robot-01").unwrap());
+        let device = db.get_device("robot-01").unwrap().unwrap();
+        assert_eq!((device.status, device.wake_at), (DeviceStatus::Online, None));
+
+        db.revoke_device("robot-01").unwrap();
+        assert!(!db.sleep("robot-01", now + 600).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn an_exported_registry_restores_into_an_empty_database() {
+        let (db, path) = temp_db("export");
+        for id in ["robot-01", "robot-02", "drone-01"] {
+            let code = db.create_pairing_request(id, id, "robot", id == "drone-01").unwrap();
+            db.confirm_pairing(id, &code).unwrap();
+        }
+        db.update_telemetry("robot-01", 1.0, 2.0, 3.0, 90.0, 1.5, 80.0, &serde_json::json!({"temp": 21.5})).unwrap();
+        db.set_status("robot-01", DeviceStatus::Offline).unwrap();
+        db.set_appearance("robot-01", Some(Some("#ff8800")), None).unwrap();
+        db.set_retention("robot-01", Some(7)).unwrap();
+        let info = DeviceInfoUpdate { capabilities: Some(vec!["navigate".to_string()]), firmware_version: Some("2.4.1".to_string()), ..Default::default() };
+        db.set_device_info("robot-01", &info).unwrap();
+        db.revoke_device("robot-02").unwrap();
+        let exported = db.export_devices().unwrap();
+        assert_eq!(exported.len(), 3);
+
+        let (fresh, fresh_path) = temp_db("restore");
+        let results = fresh.restore_devices(&exported, RestoreConflict::Merge).unwrap();
+        assert_eq!(results, vec![Ok("restored"); 3]);
+        assert_eq!(fresh.export_devices().unwrap(), exported);
+        let token = exported.iter().find(|d| d.id == "robot-01").and_then(|d| d.token.clone()).unwrap();
+        assert_eq!(fresh.validate_token(&token).unwrap(), Some("robot-01".to_string()));
+
+        // Merging keeps what's here; replacing overwrites it
+        let mut renamed = exported.clone();
+        renamed[0].name = "Renamed".to_string();
+        assert_eq!(fresh.restore_devices(&renamed, RestoreConflict::Merge).unwrap(), vec![Ok("skipped"); 3]);
+        assert_eq!(fresh.export_devices().unwrap(), exported);
+        assert_eq!(fresh.restore_devices(&renamed, RestoreConflict::Replace).unwrap(), vec![Ok("replaced"); 3]);
+        assert_eq!(fresh.export_devices().unwrap(), renamed);
+
+        // A token another device holds refuses the lot
+        let mut stolen = exported[0].clone();
+        stolen.id = "robot-09".to_string();
+        let results = fresh.restore_devices(&[exported[0].clone(), stolen], RestoreConflict::Replace).unwrap();
+        assert_eq!(results[1], Err("token already in use".to_string()));
+        assert_eq!(fresh.export_devices().unwrap(), renamed);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&fresh_path);
+    }
+
+    #[test]
+    fn opening_normalizes_old_status_strings() {
+        let (db, path) = temp_db("normalize");
+        for (id, status) in [("robot-01", " Online "), ("robot-02", "idle"), ("robot-03", "REVOKED")] {
+            let code = db.create_pairing_request(id, id, "robot", false).unwrap();
+            db.confirm_pairing(id, &code).unwrap();
+            db.conn.lock().unwrap().execute("UPDATE devices SET status = ?1 WHERE id = ?2", params![status, id]).unwrap();
+        }
+        drop(db);
+
+        let db = StateDb::open(path.to_str().unwrap()).unwrap();
+        let raw: Vec<String> = db.conn.lock().unwrap()
+            .prepare("SELECT status FROM devices ORDER BY id").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(raw, ["online", "offline", "revoked"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn a_lease_is_held_by_one_holder_until_released_or_expired() {
+        let (db, path) = temp_db("leases");
+        let first = db.acquire_lease("robot-01", "alice", 60).unwrap();
+        assert_eq!(first.holder, "alice");
+
+        // Someone else gets the lease in force back, unchanged
+        assert_eq!(db.acquire_lease("robot-01", "bob", 600).unwrap(), first);
+        assert!(!db.release_lease("robot-01", Some("bob")).unwrap());
+
+        // The holder extends it, keeping when it was taken
+        let renewed = db.acquire_lease("robot-01", "alice", 600).unwrap();
+        assert_eq!(renewed.acquired_at, first.acquired_at);
+        assert_eq!(renewed.expires_at, first.acquired_at + 600);
+        assert_eq!(db.leases().unwrap(), [renewed]);
+
+        assert!(db.release_lease("robot-01", Some("alice")).unwrap());
+        assert_eq!(db.lease("robot-01").unwrap(), None);
+
+        // One that has run out is nobody's, and anyone may take it
+        db.acquire_lease("robot-01", "alice", 0).unwrap();
+        assert_eq!(db.lease("robot-01").unwrap(), None);
+        assert_eq!(db.acquire_lease("robot-01", "bob", 60).unwrap().holder, "bob");
+        db.acquire_lease("robot-02", "alice", 0).unwrap();
+        let expired = db.expire_leases().unwrap();
+        assert_eq!(expired.iter().map(|l| l.device_id.as_str()).collect::<Vec<_>>(), ["robot-02"]);
+
+        // With no holder named, whoever has it loses it
+        assert!(db.release_lease("robot-01", None).unwrap());
+        assert!(db.leases().unwrap().is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn scoped_tokens_go_with_their_device() {
+        let (db, path) = temp_db("scoped");
+        let code = db.create_pairing_request("robot-01", "Robot", "robot", false).unwrap();
+        db.confirm_pairing("robot-01", &code).unwrap();
+        let read = db.create_scoped_token("robot-01", TokenScope::Read).unwrap();
+        let telemetry = db.create_scoped_token("robot-01", TokenScope::Telemetry).unwrap();
+        assert_eq!(read.token.len(), 64);
+        assert_ne!(read.token, telemetry.token);
+        assert_eq!(db.scoped_token(&telemetry.token).unwrap(), Some(telemetry.clone()));
+        // Not a registration token
+        assert_eq!(db.validate_token(&read.token).unwrap(), None);
+
+        db.revoke_device("robot-01").unwrap();
+        assert_eq!(db.scoped_token(&read.token).unwrap(), None);
+        assert_eq!(db.scoped_token(&telemetry.token).unwrap(), None);
+        let _ = std::fs::remove_file(&path);
+    }
+}
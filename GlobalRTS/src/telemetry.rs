@@ -12,16 +12,68 @@
 //! STRUCTURE:
 //! data/telemetry/YYYY/MM/DD/{device-id}.jsonl
 //! 
+//! SHARDING:
+//! With thousands of devices one day directory gets slow to list and open
+//! files in. Sharded, each file goes one level down, in a directory named
+//! for the first byte of the SHA-256 of its device id:
+//! data/telemetry/YYYY/MM/DD/{ab}/{device-id}.jsonl
+//! so no directory holds more than a 256th of the fleet. Writer and reader
+//! must agree. A sharded reader still finds files written before sharding
+//! was turned on.
+//! 
 //! Each line is a JSON object with timestamp and telemetry data.
 //! JSONL (JSON Lines) is simple, streamable, and universally readable.
+//!
+//! COMPRESSION:
+//! Where disk is tighter than CPU, the writer can gzip as it goes, to
+//! `{device-id}.jsonl.gz`. Each flush is a gzip sync flush, so everything
+//! flushed can be read back while the file is still being written, and
+//! the file is finished as a gzip member when it's sealed. A restart
+//! appends a new member. `zcat` reads the result; so does the reader here,
+//! which looks for both kinds of file whichever way the writer is set.
+//!
+//! RETENTION:
+//! `prune` deletes day files older than a retention period, whole days
+//! back from today. Each device may have its own period; devices without
+//! one get the fleet's. Today's files are never touched.
+//!
+//! INTEGRITY:
+//! When a day's file is closed (the day rolls over, or the writer closes),
+//! its SHA-256 goes in a sibling `{device-id}.jsonl.sha256`, in `sha256sum`
+//! format. The digest is kept up to date as lines are written, so sealing
+//! never re-reads the file. `sha256sum -c` or `verify_day_file` detects
+//! bit-rot later. The file still being written today has no checksum yet.
+//!
+//! DURABILITY:
+//! Lines are buffered and flushed to the OS every few seconds (and on an
+//! acked write). With fsync on, every flush also waits for the disk
+//! (`sync_data`), so a power cut loses nothing already flushed. The cost is
+//! a disk round-trip per flush: on an SD card or spinning disk that can cap
+//! acked writes at tens to hundreds per second, where without it they're
+//! limited only by memory bandwidth.
+//!
+//! RECOVERY LOG:
+//! What's still buffered is lost if the process dies. Where that isn't
+//! acceptable, the writer can keep `recovery.log` beside the day
+//! directories: each record is appended to it, and fsynced, before it is
+//! buffered. Once a flush has put everything buffered on disk (fsynced
+//! too, whatever the fsync setting) the log is emptied. On startup,
+//! `recover` writes whatever the log still holds to its day files,
+//! skipping records a flush got there before the crash, and empties it.
+//! Every record costs a disk round-trip, as with fsync but more so.
 
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Read;
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
+use crate::gzip::{GzipDecoder, GzipEncoder};
+use crate::sha256::{self, Sha256};
+use crate::trace::log;
 
 /// A single telemetry record.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,13 +88,307 @@ pub struct TelemetryRecord {
     pub battery: f64,
     #[serde(default)]
     pub sensors: serde_json::Value,
+    /// Older than the device's last stored record, kept under
+    /// `OutOfOrder::Flag`. Left out of the JSON when false.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub out_of_order: bool,
+}
+
+/// What happens to a record older than the last one stored for its device.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutOfOrder {
+    /// Stored like any other.
+    #[default]
+    Accept,
+    /// Not stored, so each device's files stay in time order.
+    Drop,
+    /// Stored, marked `out_of_order` for readers to skip or sort.
+    Flag,
+}
+
+impl OutOfOrder {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "" | "accept" => Ok(Self::Accept),
+            "drop" => Ok(Self::Drop),
+            "flag" => Ok(Self::Flag),
+            other => Err(format!("{:?} isn't accept, drop or flag", other)),
+        }
+    }
+
+    /// As GLOBALRTS_TELEMETRY_OUT_OF_ORDER names it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Accept => "accept",
+            Self::Drop => "drop",
+            Self::Flag => "flag",
+        }
+    }
+}
+
+/// The fields of a `TelemetryRecord`, as named in JSON: what a read's
+/// `?fields=` may ask for.
+pub const RECORD_FIELDS: [&str; 9] = [
+    "timestamp", "device_id", "latitude", "longitude", "altitude", "heading", "speed", "battery", "sensors",
+];
+
+/// Parse a comma-separated `?fields=` list, keeping request order and
+/// dropping repeats. An unknown name is an error.
+pub fn parse_fields(list: &str) -> Result<Vec<&'static str>, String> {
+    let mut fields = Vec::new();
+    for name in list.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        let field = RECORD_FIELDS
+            .iter()
+            .find(|&&known| known == name)
+            .ok_or_else(|| format!("unknown field '{}' (known: {})", name, RECORD_FIELDS.join(", ")))?;
+        if !fields.contains(field) {
+            fields.push(*field);
+        }
+    }
+    if fields.is_empty() {
+        return Err("fields must name at least one field".to_string());
+    }
+    Ok(fields)
+}
+
+impl TelemetryRecord {
+    /// Just `fields` of the record, built directly rather than cut out of
+    /// the whole serialized record. Names come from `parse_fields`.
+    pub fn project(&self, fields: &[&str]) -> serde_json::Value {
+        let mut object = serde_json::Map::new();
+        for &field in fields {
+            let value = match field {
+                "timestamp" => self.timestamp.into(),
+                "device_id" => self.device_id.clone().into(),
+                "latitude" => self.latitude.into(),
+                "longitude" => self.longitude.into(),
+                "altitude" => self.altitude.into(),
+                "heading" => self.heading.into(),
+                "speed" => self.speed.into(),
+                "battery" => self.battery.into(),
+                "sensors" => self.sensors.clone(),
+                _ => continue,
+            };
+            object.insert(field.to_string(), value);
+        }
+        serde_json::Value::Object(object)
+    }
+    
+    /// Decode a binary telemetry frame (see `BINARY_RECORD_LEN`) from
+    /// `device_id`. Every value must be finite: the record is stored as
+    /// JSON, which has no NaN.
+    pub fn from_binary(device_id: &str, frame: &[u8]) -> Result<Self, String> {
+        if frame.len() != BINARY_RECORD_LEN {
+            return Err(format!("binary telemetry is {} bytes, not {}", frame.len(), BINARY_RECORD_LEN));
+        }
+        let word = |i: usize| -> [u8; 8] { frame[i * 8..i * 8 + 8].try_into().unwrap_or_default() };
+        let mut values = [0f64; 6];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = f64::from_le_bytes(word(i + 1));
+            if !value.is_finite() {
+                return Err(format!("binary telemetry field {} isn't a finite number", i + 1));
+            }
+        }
+        let [latitude, longitude, altitude, heading, speed, battery] = values;
+        Ok(Self {
+            timestamp: i64::from_le_bytes(word(0)),
+            device_id: device_id.to_string(),
+            latitude,
+            longitude,
+            altitude,
+            heading,
+            speed,
+            battery,
+            sensors: serde_json::Value::Null,
+            out_of_order: false,
+        })
+    }
+    
+    /// The record as a binary telemetry frame. Sensors don't fit the
+    /// layout and are left out.
+    pub fn to_binary(&self) -> [u8; BINARY_RECORD_LEN] {
+        let mut frame = [0u8; BINARY_RECORD_LEN];
+        frame[..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        let values = [self.latitude, self.longitude, self.altitude, self.heading, self.speed, self.battery];
+        for (i, value) in values.iter().enumerate() {
+            frame[(i + 1) * 8..(i + 2) * 8].copy_from_slice(&value.to_le_bytes());
+        }
+        frame
+    }
 }
 
-/// Telemetry writer that manages file handles per device.
+/// Length of a binary telemetry frame: a timestamp (i64) then latitude,
+/// longitude, altitude, heading, speed and battery (f64), all little-endian,
+/// with no padding.
+pub const BINARY_RECORD_LEN: usize = 56;
+
+/// Seconds between flushes of every open file, unless configured otherwise.
+pub const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 5;
+
+/// Bytes read at a time when reading a day file from its end.
+const TAIL_CHUNK_BYTES: u64 = 64 * 1024;
+
+/// The recovery log's name, in the telemetry directory.
+const RECOVERY_LOG: &str = "recovery.log";
+
+/// Telemetry writer that manages file handles per device. Clones share them.
+#[derive(Clone)]
 pub struct TelemetryWriter {
     base_path: PathBuf,
-    writers: Arc<Mutex<HashMap<String, BufWriter<File>>>>,
+    writers: Arc<Mutex<HashMap<String, DayFile>>>,
     last_flush: Arc<Mutex<i64>>,
+    /// Seconds between periodic flushes. 0 = flush after every write.
+    flush_interval_secs: i64,
+    /// `sync_data` after every flush.
+    fsync: bool,
+    /// Files go in a hash-prefix directory under the day.
+    sharded: bool,
+    /// Files are written gzipped, as `.jsonl.gz`.
+    gzip: bool,
+    /// A record that moved less than this from its device's last stored
+    /// one, with the same battery and sensors, isn't written. 0 = off.
+    min_distance_m: f64,
+    /// Each device's last written record and the day it went into, for
+    /// `min_distance_m`.
+    last_stored: Arc<Mutex<HashMap<String, (i64, TelemetryRecord)>>>,
+    /// Where records go before they're buffered, if anywhere.
+    recovery: Option<Arc<Mutex<RecoveryLog>>>,
+}
+
+/// The append-only log records are fsynced to before they're buffered.
+/// Each line is the record's arrival time, a tab, and its JSON line.
+struct RecoveryLog {
+    path: PathBuf,
+    /// Opened on the first record.
+    file: Option<File>,
+}
+
+impl RecoveryLog {
+    fn append(&mut self, arrival: i64, json: &str) -> Result<(), String> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                if let Some(dir) = self.path.parent() {
+                    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+                }
+                let file = OpenOptions::new().create(true).append(true).open(&self.path).map_err(|e| e.to_string())?;
+                self.file.insert(file)
+            }
+        };
+        file.write_all(format!("{}\t{}\n", arrival, json).as_bytes()).map_err(|e| e.to_string())?;
+        file.sync_data().map_err(|e| e.to_string())
+    }
+    
+    /// Empty the log: everything in it is on disk in its day file.
+    fn clear(&mut self) -> Result<(), String> {
+        match self.file.as_mut() {
+            Some(file) => {
+                file.set_len(0).map_err(|e| e.to_string())?;
+                file.sync_data().map_err(|e| e.to_string())
+            }
+            None if self.path.exists() => File::create(&self.path).map(|_| ()).map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// One device's open day file, with the digest of everything in it so far.
+struct DayFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    hasher: Sha256,
+    /// Flushes go all the way to disk.
+    sync: bool,
+    /// Compresses lines on their way to `writer`, for a `.jsonl.gz` file.
+    gzip: Option<GzipEncoder<Vec<u8>>>,
+}
+
+impl DayFile {
+    /// Open for append. A file already holding data (a restart mid-day) is
+    /// hashed once, and any checksum it had is dropped until it is sealed again.
+    /// With `gzip`, what's appended is a new gzip member.
+    fn open(path: &Path, sync: bool, gzip: bool) -> Result<Self, String> {
+        let mut hasher = Sha256::new();
+        let mut existing_len = 0;
+        if let Ok(mut existing) = File::open(path) {
+            let mut buf = [0u8; 64 * 1024];
+            loop {
+                let n = existing.read(&mut buf).map_err(|e| e.to_string())?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                existing_len += n;
+            }
+            let _ = fs::remove_file(checksum_path(path));
+        }
+        
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+        
+        let encoder = gzip.then(|| GzipEncoder::new(Vec::new()));
+        let mut day_file = Self { path: path.to_path_buf(), writer: BufWriter::new(file), hasher, sync, gzip: encoder };
+        
+        // A writer that died left its gzip member open at its last flush.
+        // Finished off, the file stays one any gzip tool reads.
+        if gzip && existing_len > 0 {
+            let mut decoder = GzipDecoder::new(File::open(path).map_err(|e| e.to_string())?);
+            if std::io::copy(&mut decoder, &mut std::io::sink()).is_ok() {
+                if let Some(closing) = decoder.closing_bytes() {
+                    day_file.append(&closing)?;
+                }
+            }
+        }
+        Ok(day_file)
+    }
+    
+    fn write_line(&mut self, line: &str) -> Result<(), String> {
+        let mut bytes = Vec::with_capacity(line.len() + 1);
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        match self.gzip.as_mut() {
+            // Held until the next flush, so the file only ever ends at a sync flush
+            Some(gz) => gz.write_all(&bytes).map_err(|e| e.to_string()),
+            None => self.append(&bytes),
+        }
+    }
+    
+    /// Append bytes as they go on disk, keeping the digest in step.
+    fn append(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.writer.write_all(bytes).map_err(|e| e.to_string())?;
+        self.hasher.update(bytes);
+        Ok(())
+    }
+    
+    /// Hand our buffer to the OS, and with `sync` wait for it to reach the disk.
+    /// Compressed lines are sync-flushed first, so they can be read back.
+    fn flush(&mut self) -> Result<(), String> {
+        if let Some(gz) = self.gzip.as_mut() {
+            gz.sync_flush().map_err(|e| e.to_string())?;
+            let compressed = std::mem::take(gz.get_mut());
+            self.append(&compressed)?;
+        }
+        self.writer.flush().map_err(|e| e.to_string())?;
+        if self.sync {
+            self.writer.get_ref().sync_data().map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+    
+    /// Flush and write the `.sha256` sidecar. The file is done after this.
+    fn seal(mut self) -> Result<(), String> {
+        if let Some(gz) = self.gzip.take() {
+            let compressed = gz.finish().map_err(|e| e.to_string())?;
+            self.append(&compressed)?;
+        }
+        self.flush()?;
+        let name = self.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let line = format!("{}  {}\n", sha256::hex(&self.hasher.finalize()), name);
+        fs::write(checksum_path(&self.path), line).map_err(|e| e.to_string())
+    }
 }
 
 impl TelemetryWriter {
@@ -52,52 +398,124 @@ impl TelemetryWriter {
             base_path: PathBuf::from(base_path),
             writers: Arc::new(Mutex::new(HashMap::new())),
             last_flush: Arc::new(Mutex::new(0)),
+            flush_interval_secs: DEFAULT_FLUSH_INTERVAL_SECS as i64,
+            fsync: false,
+            sharded: false,
+            gzip: false,
+            min_distance_m: 0.0,
+            last_stored: Arc::new(Mutex::new(HashMap::new())),
+            recovery: None,
         }
     }
     
+    /// Flush every `interval_secs` (0 = after every write), and with `fsync`
+    /// make each flush durable.
+    pub fn with_flush(mut self, interval_secs: u64, fsync: bool) -> Self {
+        self.flush_interval_secs = interval_secs as i64;
+        self.fsync = fsync;
+        self
+    }
+    
+    /// Put each device's files in a hash-prefix directory under the day.
+    pub fn with_sharding(mut self, sharded: bool) -> Self {
+        self.sharded = sharded;
+        self
+    }
+    
+    /// Write gzipped `.jsonl.gz` files instead of plain `.jsonl`.
+    pub fn with_gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+    
+    /// Skip records that moved less than `metres` from the device's last
+    /// stored one with nothing else that matters changed, so a parked
+    /// device's GPS jitter isn't written every second. 0 = write them all.
+    pub fn with_min_distance(mut self, metres: f64) -> Self {
+        self.set_min_distance(metres);
+        self
+    }
+    
+    /// Keep a recovery log, so a crash loses nothing buffered. Call
+    /// `recover` before the first write to replay what a crash left.
+    pub fn with_recovery_log(mut self, enabled: bool) -> Self {
+        self.recovery = enabled.then(|| Arc::new(Mutex::new(RecoveryLog {
+            path: self.base_path.join(RECOVERY_LOG),
+            file: None,
+        })));
+        self
+    }
+    
+    /// `with_min_distance` on a writer already in use, for a reload.
+    pub fn set_min_distance(&mut self, metres: f64) {
+        self.min_distance_m = if metres.is_finite() { metres.max(0.0) } else { 0.0 };
+    }
+    
     /// Write a telemetry record.
-    /// Creates directory structure and file as needed.
+    /// Creates directory structure and file as needed. With a minimum
+    /// distance, a repeat of the last stored record is dropped instead,
+    /// though each day's file still gets the device's first record.
     pub fn write(&self, record: &TelemetryRecord) -> Result<(), String> {
         let now = now_unix();
-        let (year, month, day) = date_parts(now);
-        
-        // Build path: data/telemetry/YYYY/MM/DD/{device-id}.jsonl
-        let dir = self.base_path
-            .join(format!("{:04}", year))
-            .join(format!("{:02}", month))
-            .join(format!("{:02}", day));
-        
-        let file_path = dir.join(format!("{}.jsonl", record.device_id));
+        let day = now.div_euclid(86400);
+        if self.min_distance_m > 0.0 {
+            let mut last_stored = self.last_stored.lock().map_err(|e| e.to_string())?;
+            match last_stored.get(&record.device_id) {
+                Some((last_day, last)) if *last_day == day && is_repeat(last, record, self.min_distance_m) => return Ok(()),
+                _ => { last_stored.insert(record.device_id.clone(), (day, record.clone())); }
+            }
+        }
+        let file_path = device_file(&self.base_path, now, &record.device_id, self.sharded, self.gzip);
+        let dir = file_path.parent().unwrap_or(&self.base_path).to_path_buf();
         
         // Get or create writer
         let mut writers = self.writers.lock().map_err(|e| e.to_string())?;
         
+        // New day: seal yesterday's file before starting today's
+        if writers.get(&record.device_id).is_some_and(|f| f.path != file_path) {
+            if let Some(old) = writers.remove(&record.device_id) {
+                seal_logged(old);
+            }
+        }
+        
         let writer = if let Some(w) = writers.get_mut(&record.device_id) {
             w
         } else {
             // Create directory if needed
             fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
-            
-            // Open file for append
-            let file = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&file_path)
-                .map_err(|e| e.to_string())?;
-            
-            writers.insert(record.device_id.clone(), BufWriter::new(file));
+            writers.insert(record.device_id.clone(), DayFile::open(&file_path, self.sync(), self.gzip)?);
             writers.get_mut(&record.device_id).unwrap()
         };
         
-        // Write JSON line
+        // Write JSON line, to the recovery log first if there is one
         let json = serde_json::to_string(record).map_err(|e| e.to_string())?;
-        writeln!(writer, "{}", json).map_err(|e| e.to_string())?;
+        if let Some(recovery) = &self.recovery {
+            recovery.lock().map_err(|e| e.to_string())?.append(now, &json)?;
+        }
+        writer.write_line(&json)?;
         
-        // Periodic flush (every 5 seconds)
+        // Periodic flush
         let mut last_flush = self.last_flush.lock().map_err(|e| e.to_string())?;
-        if now - *last_flush > 5 {
+        if self.flush_interval_secs == 0 || now - *last_flush > self.flush_interval_secs {
+            let mut flushed = true;
             for w in writers.values_mut() {
-                let _ = w.flush();
+                flushed &= w.flush().is_ok();
+            }
+            if flushed {
+                if let Err(e) = self.clear_recovery_log() {
+                    log!("⚠ Could not empty the telemetry recovery log: {}", e);
+                }
+            }
+            // Devices that went quiet before midnight don't write again to roll over
+            let today = self.day_dir(now);
+            let stale: Vec<String> = writers.iter()
+                .filter(|(_, f)| !f.path.starts_with(&today))
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in stale {
+                if let Some(old) = writers.remove(&id) {
+                    seal_logged(old);
+                }
             }
             *last_flush = now;
         }
@@ -105,25 +523,808 @@ impl TelemetryWriter {
         Ok(())
     }
     
+    /// Flush one device's writer, so what it has written is on disk (in the
+    /// OS's hands, or with fsync on the disk itself) rather than in our buffer.
+    pub fn flush_device(&self, device_id: &str) -> Result<(), String> {
+        let mut writers = self.writers.lock().map_err(|e| e.to_string())?;
+        if let Some(w) = writers.get_mut(device_id) {
+            w.flush()?;
+        }
+        Ok(())
+    }
+    
     /// Flush all writers.
     #[allow(dead_code)]
     pub fn flush(&self) -> Result<(), String> {
         let mut writers = self.writers.lock().map_err(|e| e.to_string())?;
         for w in writers.values_mut() {
-            w.flush().map_err(|e| e.to_string())?;
+            w.flush()?;
         }
-        Ok(())
+        self.clear_recovery_log()
     }
     
-    /// Clone for thread sharing.
-    #[allow(dead_code)]
-    pub fn clone(&self) -> Self {
-        Self {
-            base_path: self.base_path.clone(),
-            writers: Arc::clone(&self.writers),
-            last_flush: Arc::clone(&self.last_flush),
+    /// Seal every open file with its checksum. Writing again afterwards
+    /// reopens the file and drops the checksum until the next seal.
+    pub fn close(&self) -> Result<(), String> {
+        let mut writers = self.writers.lock().map_err(|e| e.to_string())?;
+        for (_, file) in writers.drain() {
+            file.seal()?;
+        }
+        self.clear_recovery_log()
+    }
+    
+    /// Write what the recovery log holds to the day files, as it would have
+    /// been had the writer not died, and empty it. Records already in their
+    /// file (flushed before the log could be emptied) aren't written twice.
+    /// Returns how many were written. Without a recovery log, does nothing.
+    pub fn recover(&self) -> Result<usize, String> {
+        let Some(recovery) = &self.recovery else {
+            return Ok(0);
+        };
+        let path = recovery.lock().map_err(|e| e.to_string())?.path.clone();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        
+        // Each file's lines, in the order they were logged. A torn last
+        // line never made it into a buffer either.
+        let mut files: Vec<(PathBuf, Vec<&str>)> = Vec::new();
+        for line in text.lines() {
+            let Some((arrival, json)) = line.split_once('\t') else { continue };
+            let (Ok(arrival), Ok(record)) = (arrival.parse::<i64>(), serde_json::from_str::<TelemetryRecord>(json)) else { continue };
+            if !is_valid_device_id(&record.device_id) {
+                continue;
+            }
+            let file = device_file(&self.base_path, arrival, &record.device_id, self.sharded, self.gzip);
+            match files.iter_mut().find(|(path, _)| *path == file) {
+                Some((_, lines)) => lines.push(json),
+                None => files.push((file, vec![json])),
+            }
+        }
+        
+        let mut writers = self.writers.lock().map_err(|e| e.to_string())?;
+        let today = self.day_dir(now_unix());
+        let mut recovered = 0;
+        for (file, lines) in files {
+            // The longest run at the end of the file that the log starts with
+            let mut tail = Vec::new();
+            if file.is_file() {
+                if self.gzip { read_tail_gzip(&file, lines.len(), &mut tail)? } else { read_tail(&file, lines.len(), &mut tail)? }
+            }
+            let tail: Vec<String> = tail.iter().rev().filter_map(|r| serde_json::to_string(r).ok()).collect();
+            let written = (0..=tail.len()).rev()
+                .find(|n| tail[tail.len() - n..].iter().zip(&lines).all(|(a, b)| a == b))
+                .unwrap_or(0);
+            if written == lines.len() {
+                continue;
+            }
+            
+            if let Some(dir) = file.parent() {
+                fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+            }
+            let mut day_file = DayFile::open(&file, true, self.gzip)?;
+            // A line the crash cut short ends here, not in the first one recovered
+            if !self.gzip && !ends_with_newline(&file)? {
+                day_file.append(b"\n")?;
+            }
+            for line in &lines[written..] {
+                day_file.write_line(line)?;
+            }
+            day_file.flush()?;
+            recovered += lines.len() - written;
+            // Today's stays open for what comes next; an earlier day's is done
+            if file.starts_with(&today) {
+                if let Some(device_id) = serde_json::from_str::<TelemetryRecord>(lines[0]).ok().map(|r| r.device_id) {
+                    writers.insert(device_id, day_file);
+                    continue;
+                }
+            }
+            seal_logged(day_file);
+        }
+        self.clear_recovery_log()?;
+        Ok(recovered)
+    }
+    
+    /// Flushes go to disk: asked for, or needed before the recovery log
+    /// can be emptied.
+    fn sync(&self) -> bool {
+        self.fsync || self.recovery.is_some()
+    }
+    
+    fn clear_recovery_log(&self) -> Result<(), String> {
+        match &self.recovery {
+            Some(recovery) => recovery.lock().map_err(|e| e.to_string())?.clear(),
+            None => Ok(()),
         }
     }
+    
+    fn day_dir(&self, timestamp: i64) -> PathBuf {
+        day_dir(&self.base_path, timestamp)
+    }
+}
+
+/// data/telemetry/YYYY/MM/DD for a unix timestamp.
+fn day_dir(base_path: &Path, timestamp: i64) -> PathBuf {
+    let (year, month, day) = date_parts(timestamp);
+    base_path
+        .join(format!("{:04}", year))
+        .join(format!("{:02}", month))
+        .join(format!("{:02}", day))
+}
+
+/// The shard directory for a device: two hex digits of its id's SHA-256.
+pub fn shard(device_id: &str) -> String {
+    sha256::hex(&sha256::digest(device_id.as_bytes()))[..2].to_string()
+}
+
+/// Where a device's records for the day of `timestamp` go.
+fn device_file(base_path: &Path, timestamp: i64, device_id: &str, sharded: bool, gzip: bool) -> PathBuf {
+    let dir = day_dir(base_path, timestamp);
+    let dir = if sharded { dir.join(shard(device_id)) } else { dir };
+    dir.join(format!("{}.jsonl{}", device_id, if gzip { ".gz" } else { "" }))
+}
+
+/// Whether a day file was written gzipped.
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// A day file's bytes, decompressed if it was written gzipped.
+type DayReader = Box<dyn Read + Send>;
+
+/// Open a day file to read, plain or gzipped.
+fn open_day_file(path: &Path) -> std::io::Result<DayReader> {
+    let file = File::open(path)?;
+    Ok(if is_gzip(path) { Box::new(GzipDecoder::new(file)) } else { Box::new(file) })
+}
+
+/// Whether `path` is empty or its last byte is a newline.
+fn ends_with_newline(path: &Path) -> Result<bool, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    if file.seek(SeekFrom::End(0)).map_err(|e| e.to_string())? == 0 {
+        return Ok(true);
+    }
+    let mut last = [0u8];
+    file.seek(SeekFrom::End(-1)).and_then(|_| file.read_exact(&mut last)).map_err(|e| e.to_string())?;
+    Ok(last[0] == b'\n')
+}
+
+/// Seal a file that's being rotated out; a failure only costs its checksum.
+fn seal_logged(file: DayFile) {
+    let path = file.path.clone();
+    if let Err(e) = file.seal() {
+        log!("⚠ Could not write checksum for {}: {}", path.display(), e);
+    }
+}
+
+// ============================================================================
+// INTEGRITY
+// ============================================================================
+
+/// What a day file's checksum says about it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Integrity {
+    /// Contents match the recorded digest.
+    Verified,
+    /// Contents differ from the recorded digest.
+    Corrupt,
+    /// No checksum: the file is still open, or predates checksums.
+    Unsealed,
+}
+
+/// `{file}.sha256` next to a day file.
+fn checksum_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+/// Check a day file against its `.sha256` sidecar.
+pub fn verify_day_file(path: &Path) -> Result<Integrity, String> {
+    let recorded = match fs::read_to_string(checksum_path(path)) {
+        Ok(line) => line.split_whitespace().next().unwrap_or_default().to_lowercase(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Integrity::Unsealed),
+        Err(e) => return Err(e.to_string()),
+    };
+    
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    
+    if sha256::hex(&hasher.finalize()) == recorded {
+        Ok(Integrity::Verified)
+    } else {
+        Ok(Integrity::Corrupt)
+    }
+}
+
+// ============================================================================
+// READING
+// ============================================================================
+
+/// Reads stored telemetry under one base directory.
+#[derive(Debug, Clone)]
+pub struct TelemetryReader {
+    base_path: PathBuf,
+    sharded: bool,
+}
+
+impl TelemetryReader {
+    pub fn new(base_path: impl Into<PathBuf>) -> Self {
+        Self { base_path: base_path.into(), sharded: false }
+    }
+    
+    /// Look for files where a writer `with_sharding` puts them (and, for
+    /// days from before it was, where they were).
+    pub fn with_sharding(mut self, sharded: bool) -> Self {
+        self.sharded = sharded;
+        self
+    }
+    
+    /// A device's records with `start <= timestamp <= end`, read lazily.
+    /// A day file that can't be opened or read part way is an `Err` item,
+    /// and reading goes on with the next day. Torn lines are skipped.
+    pub fn iter(&self, device_id: &str, start: i64, end: i64) -> impl Iterator<Item = Result<TelemetryRecord, String>> {
+        let mut range = self.records(device_id, start, end);
+        std::iter::from_fn(move || range.next_result())
+    }
+    
+    /// `iter` without the errors, which are logged and skipped.
+    pub fn records(&self, device_id: &str, start: i64, end: i64) -> TelemetryRange {
+        let files = days_between(&self.base_path, start, end).iter()
+            .flat_map(|dir| self.day_files(dir, device_id))
+            .collect();
+        range_over(files, start, end)
+    }
+    
+    /// Where a device's records for one day may be, in the order they were
+    /// begun: a sharded file after the flat one, and within each the plain
+    /// file before the gzipped one.
+    fn day_files(&self, dir: &Path, device_id: &str) -> Vec<PathBuf> {
+        let mut dirs = vec![dir.to_path_buf()];
+        if self.sharded {
+            dirs.push(dir.join(shard(device_id)));
+        }
+        dirs.iter()
+            .flat_map(|dir| [dir.join(format!("{}.jsonl", device_id)), dir.join(format!("{}.jsonl.gz", device_id))])
+            .collect()
+    }
+    
+    /// A device's last `n` records, oldest first, whenever they were taken.
+    /// Day files are read backward from the newest, a chunk at a time, and
+    /// reading stops as soon as there are `n`: a short trail costs the tail
+    /// of one file, not a scan of the range. Torn lines are skipped.
+    pub fn recent(&self, device_id: &str, n: usize) -> Result<Vec<TelemetryRecord>, String> {
+        let mut records = Vec::new();
+        for (_, dir) in day_dirs(&self.base_path).into_iter().rev() {
+            if records.len() >= n {
+                break;
+            }
+            for path in self.day_files(&dir, device_id).iter().rev().filter(|path| path.is_file()) {
+                if is_gzip(path) {
+                    read_tail_gzip(path, n, &mut records)?;
+                } else {
+                    read_tail(path, n, &mut records)?;
+                }
+            }
+        }
+        records.reverse();
+        Ok(records)
+    }
+    
+    /// Records stored for every device on the (UTC) day of `timestamp`, one
+    /// per line, sharded or not. Lines still in a writer's buffer aren't on
+    /// disk to count.
+    pub fn count_day(&self, timestamp: i64) -> Result<u64, String> {
+        let day = day_dir(&self.base_path, timestamp);
+        let mut count = 0;
+        let mut dirs = vec![day.clone()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.to_string()),
+            };
+            for entry in entries {
+                let path = entry.map_err(|e| e.to_string())?.path();
+                // Shard directories sit one level down
+                if path.is_dir() && path.parent() == Some(day.as_path()) {
+                    dirs.push(path);
+                    continue;
+                }
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                if !name.ends_with(".jsonl") && !name.ends_with(".jsonl.gz") {
+                    continue;
+                }
+                count += count_lines(&path)?;
+            }
+        }
+        Ok(count)
+    }
+    
+    /// Every day file a device has, oldest first, with its size on disk and
+    /// the records in it. Lines are counted, not estimated, so this reads
+    /// (and for gzipped files, decompresses) each file once.
+    pub fn files(&self, device_id: &str) -> Result<Vec<StoredFile>, String> {
+        let mut files = Vec::new();
+        for (day, dir) in day_dirs(&self.base_path) {
+            let (year, month, date) = date_parts(day * 86400);
+            for path in self.day_files(&dir, device_id).into_iter().filter(|path| path.is_file()) {
+                let bytes = fs::metadata(&path).map_err(|e| format!("{}: {}", path.display(), e))?.len();
+                files.push(StoredFile {
+                    date: format!("{:04}-{:02}-{:02}", year, month, date),
+                    path: path.strip_prefix(&self.base_path).unwrap_or(&path).to_string_lossy().into_owned(),
+                    bytes,
+                    lines: count_lines(&path)?,
+                    gzip: is_gzip(&path),
+                });
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// One of a device's day files, as `TelemetryReader::files` finds it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StoredFile {
+    /// The UTC day the file holds, YYYY-MM-DD.
+    pub date: String,
+    /// Where the file is, under the telemetry directory.
+    pub path: String,
+    /// Size on disk: compressed, for a gzipped file.
+    pub bytes: u64,
+    /// Records in the file, one per line.
+    pub lines: u64,
+    pub gzip: bool,
+}
+
+/// Newlines in a day file, decompressed if it's gzipped.
+fn count_lines(path: &Path) -> Result<u64, String> {
+    let mut file = open_day_file(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut buf = [0u8; 64 * 1024];
+    let mut count = 0;
+    loop {
+        let n = file.read(&mut buf).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if n == 0 {
+            return Ok(count);
+        }
+        count += buf[..n].iter().filter(|&&b| b == b'\n').count() as u64;
+    }
+}
+
+/// Iterator over one device's stored records within a time range.
+/// Walks day files in chronological order, one line at a time - nothing is
+/// buffered beyond the current line.
+pub struct TelemetryRange {
+    files: std::vec::IntoIter<PathBuf>,
+    /// The day file being read, and its lines.
+    lines: Option<(PathBuf, Lines<BufReader<DayReader>>)>,
+    start: i64,
+    end: i64,
+    verify: bool,
+    corrupt: Vec<PathBuf>,
+}
+
+impl TelemetryRange {
+    /// Check each sealed file against its checksum before reading it.
+    /// Records from a corrupt file are still returned; the file is listed
+    /// in `corrupt_files`. Costs one extra read of every sealed file.
+    pub fn verify_checksums(mut self) -> Self {
+        self.verify = true;
+        self
+    }
+    
+    /// Files read so far whose contents don't match their checksum.
+    pub fn corrupt_files(&self) -> &[PathBuf] {
+        &self.corrupt
+    }
+}
+
+impl TelemetryRange {
+    /// The next record, or the error that ended a day file early.
+    fn next_result(&mut self) -> Option<Result<TelemetryRecord, String>> {
+        loop {
+            if let Some((path, lines)) = self.lines.as_mut() {
+                match lines.next() {
+                    Some(Ok(line)) => {
+                        // Skip torn or corrupt lines rather than ending the stream
+                        if let Ok(record) = serde_json::from_str::<TelemetryRecord>(&line) {
+                            if record.timestamp >= self.start && record.timestamp <= self.end {
+                                return Some(Ok(record));
+                            }
+                        }
+                        continue;
+                    }
+                    Some(Err(e)) => {
+                        let error = format!("{}: {}", path.display(), e);
+                        self.lines = None;
+                        return Some(Err(error));
+                    }
+                    None => self.lines = None,
+                }
+            }
+            
+            let path = self.files.next()?;
+            if self.verify && verify_day_file(&path) == Ok(Integrity::Corrupt) {
+                log!("⚠ Checksum mismatch: {}", path.display());
+                self.corrupt.push(path.clone());
+            }
+            match open_day_file(&path) {
+                Ok(file) => self.lines = Some((path, BufReader::new(file).lines())),
+                Err(e) => return Some(Err(format!("{}: {}", path.display(), e))),
+            }
+        }
+    }
+}
+
+impl Iterator for TelemetryRange {
+    type Item = TelemetryRecord;
+    
+    fn next(&mut self) -> Option<TelemetryRecord> {
+        loop {
+            match self.next_result()? {
+                Ok(record) => return Some(record),
+                Err(e) => log!("⚠ Telemetry read error: {}", e),
+            }
+        }
+    }
+}
+
+/// Read a device's records with `start <= timestamp <= end` (unix seconds),
+/// from an unsharded layout.
+pub fn read_range(base_path: &Path, device_id: &str, start: i64, end: i64) -> TelemetryRange {
+    TelemetryReader::new(base_path).records(device_id, start, end)
+}
+
+/// Add `path`'s records to `out`, newest first, until it holds `n`.
+fn read_tail(path: &Path, n: usize, out: &mut Vec<TelemetryRecord>) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    let mut file = File::open(path).map_err(error)?;
+    let mut pos = file.seek(SeekFrom::End(0)).map_err(error)?;
+    // The start of a line whose beginning is in a chunk not read yet
+    let mut partial = Vec::new();
+    
+    while out.len() < n && pos > 0 {
+        let len = pos.min(TAIL_CHUNK_BYTES);
+        pos -= len;
+        let mut chunk = vec![0u8; len as usize];
+        file.seek(SeekFrom::Start(pos)).map_err(error)?;
+        file.read_exact(&mut chunk).map_err(error)?;
+        chunk.extend_from_slice(&partial);
+        
+        // Lines after the chunk's first newline are whole; at the start of
+        // the file, so is the first
+        let whole_from = match chunk.iter().position(|&b| b == b'\n') {
+            _ if pos == 0 => 0,
+            Some(i) => i + 1,
+            None => {
+                partial = chunk;
+                continue;
+            }
+        };
+        for line in chunk[whole_from..].rsplit(|&b| b == b'\n') {
+            if out.len() >= n {
+                break;
+            }
+            if let Ok(record) = serde_json::from_slice::<TelemetryRecord>(line) {
+                out.push(record);
+            }
+        }
+        partial = chunk[..whole_from.saturating_sub(1)].to_vec();
+    }
+    Ok(())
+}
+
+/// `read_tail` for a gzipped file, which can only be read from the start:
+/// every line is decompressed, and only the last ones kept.
+fn read_tail_gzip(path: &Path, n: usize, out: &mut Vec<TelemetryRecord>) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    let wanted = n.saturating_sub(out.len());
+    let mut last = std::collections::VecDeque::with_capacity(wanted.min(1024));
+    for line in BufReader::new(open_day_file(path).map_err(error)?).split(b'\n') {
+        if let Ok(record) = serde_json::from_slice::<TelemetryRecord>(&line.map_err(error)?) {
+            if last.len() == wanted {
+                last.pop_front();
+            }
+            if wanted > 0 {
+                last.push_back(record);
+            }
+        }
+    }
+    out.extend(last.into_iter().rev());
+    Ok(())
+}
+
+/// The day directories from `start`'s day to `end`'s, oldest first.
+fn days_between(base_path: &Path, start: i64, end: i64) -> Vec<PathBuf> {
+    let first_day = start.div_euclid(86400);
+    let last_day = end.div_euclid(86400);
+    day_dirs(base_path)
+        .into_iter()
+        .filter(|(day, _)| *day >= first_day && *day <= last_day)
+        .map(|(_, dir)| dir)
+        .collect()
+}
+
+/// A range over whichever of `files` exist, in order.
+fn range_over(files: Vec<PathBuf>, start: i64, end: i64) -> TelemetryRange {
+    let files: Vec<PathBuf> = files.into_iter().filter(|path| path.is_file()).collect();
+    TelemetryRange {
+        files: files.into_iter(),
+        lines: None,
+        start,
+        end,
+        verify: false,
+        corrupt: Vec::new(),
+    }
+}
+
+// ============================================================================
+// STATISTICS
+// ============================================================================
+
+/// Mean Earth radius, for haversine distances.
+pub(crate) const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Summary of a run of records. Built in one pass, in constant memory.
+/// Everything is zero or None for an empty range.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TelemetryStats {
+    pub count: u64,
+    pub first_timestamp: Option<i64>,
+    pub last_timestamp: Option<i64>,
+    /// Seconds from the first record to the last.
+    pub span_secs: i64,
+    pub battery: Option<Summary>,
+    pub speed: Option<Summary>,
+    /// Great-circle distance along consecutive positions, in meters.
+    pub distance_m: f64,
+}
+
+/// Min, max and mean of one field.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+impl TelemetryStats {
+    /// Fold records (oldest first) into a summary.
+    pub fn from_records(records: impl Iterator<Item = TelemetryRecord>) -> Self {
+        let mut stats = Self::default();
+        let mut battery = Running::default();
+        let mut speed = Running::default();
+        let mut last_position: Option<(f64, f64)> = None;
+        
+        for record in records {
+            stats.count += 1;
+            stats.first_timestamp.get_or_insert(record.timestamp);
+            stats.last_timestamp = Some(record.timestamp);
+            battery.add(record.battery);
+            speed.add(record.speed);
+            if let Some((lat, lon)) = last_position {
+                stats.distance_m += haversine_m(lat, lon, record.latitude, record.longitude);
+            }
+            last_position = Some((record.latitude, record.longitude));
+        }
+        
+        if let (Some(first), Some(last)) = (stats.first_timestamp, stats.last_timestamp) {
+            stats.span_secs = last - first;
+        }
+        stats.battery = battery.summary();
+        stats.speed = speed.summary();
+        stats
+    }
+}
+
+/// Running min/max/sum for `Summary`.
+#[derive(Default)]
+struct Running {
+    min: f64,
+    max: f64,
+    sum: f64,
+    n: u64,
+}
+
+impl Running {
+    fn add(&mut self, value: f64) {
+        if self.n == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.sum += value;
+        self.n += 1;
+    }
+    
+    fn summary(&self) -> Option<Summary> {
+        (self.n > 0).then(|| Summary { min: self.min, max: self.max, avg: self.sum / self.n as f64 })
+    }
+}
+
+/// Whether `record` says nothing `last` didn't: it's within `min_distance_m`
+/// of it, with the same battery and sensors.
+fn is_repeat(last: &TelemetryRecord, record: &TelemetryRecord, min_distance_m: f64) -> bool {
+    last.battery == record.battery
+        && last.sensors == record.sensors
+        && haversine_m(last.latitude, last.longitude, record.latitude, record.longitude) < min_distance_m
+}
+
+/// Great-circle distance between two lat/lon points, in meters.
+pub(crate) fn haversine_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+// ============================================================================
+// HEATMAP
+// ============================================================================
+
+/// The finest heatmap cell, in degrees: about 11 m of latitude.
+pub const MIN_HEATMAP_CELL: f64 = 0.0001;
+
+/// Most cells a heatmap holds. Past this the grid is coarsened, so a wide
+/// range at a fine cell still comes back at a size the UI can draw.
+pub const MAX_HEATMAP_CELLS: usize = 10_000;
+
+/// Visit counts over a grid of `cell`-degree squares of latitude and
+/// longitude. Each record counts once in the square its position falls in.
+#[derive(Debug, Clone)]
+pub struct Heatmap {
+    cell: f64,
+    max_cells: usize,
+    counts: HashMap<(i64, i64), u64>,
+}
+
+impl Heatmap {
+    pub fn new(cell: f64, max_cells: usize) -> Self {
+        Self { cell, max_cells: max_cells.max(1), counts: HashMap::new() }
+    }
+    
+    /// Count a record's position. Positions that aren't finite are skipped.
+    pub fn add(&mut self, record: &TelemetryRecord) {
+        if !record.latitude.is_finite() || !record.longitude.is_finite() {
+            return;
+        }
+        let key = ((record.latitude / self.cell).floor() as i64, (record.longitude / self.cell).floor() as i64);
+        *self.counts.entry(key).or_insert(0) += 1;
+        // Doubling the cell merges each 2×2 block, so nothing is re-read
+        while self.counts.len() > self.max_cells {
+            self.cell *= 2.0;
+            let mut merged = HashMap::with_capacity(self.counts.len() / 2);
+            for ((lat, lon), count) in self.counts.drain() {
+                *merged.entry((lat.div_euclid(2), lon.div_euclid(2))).or_insert(0) += count;
+            }
+            self.counts = merged;
+        }
+    }
+    
+    /// The cell size in use: the one asked for, or coarser if capped.
+    pub fn cell(&self) -> f64 {
+        self.cell
+    }
+    
+    /// `[lat, lon, weight]` for each visited cell, at the cell's centre,
+    /// south to north then west to east.
+    pub fn cells(&self) -> Vec<[f64; 3]> {
+        let mut keys: Vec<_> = self.counts.iter().collect();
+        keys.sort_unstable_by_key(|(key, _)| **key);
+        keys.into_iter()
+            .map(|(&(lat, lon), &count)| [(lat as f64 + 0.5) * self.cell, (lon as f64 + 0.5) * self.cell, count as f64])
+            .collect()
+    }
+}
+
+// ============================================================================
+// RETENTION
+// ============================================================================
+
+/// Delete day files under `base_path` older than their device's retention:
+/// `overrides[device_id]` days if it has one, else `default_days`, where 0
+/// keeps everything. A file from `n` days before today's (as of `now`) goes
+/// once `n` reaches the retention. Checksums go with their files, and
+/// directories left empty are removed. Returns the day files deleted.
+pub fn prune(base_path: &Path, now: i64, default_days: u64, overrides: &HashMap<String, u64>) -> Result<usize, String> {
+    let today = now.div_euclid(86400);
+    let mut deleted = 0;
+    for (day, dir) in day_dirs(base_path) {
+        let age = today - day;
+        if age <= 0 {
+            continue;
+        }
+        let mut dirs = vec![dir.clone()];
+        while let Some(current) = dirs.pop() {
+            let entries = fs::read_dir(&current).map_err(|e| format!("{}: {}", current.display(), e))?;
+            for entry in entries {
+                let path = entry.map_err(|e| e.to_string())?.path();
+                // Shard directories sit one level down
+                if path.is_dir() && path.parent() == Some(dir.as_path()) {
+                    dirs.push(path);
+                    continue;
+                }
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                let Some(device_id) = name.strip_suffix(".jsonl").or_else(|| name.strip_suffix(".jsonl.gz")) else {
+                    continue;
+                };
+                let keep = overrides.get(device_id).copied().unwrap_or(default_days);
+                if keep == 0 || (age as u64) < keep {
+                    continue;
+                }
+                fs::remove_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                let _ = fs::remove_file(checksum_path(&path));
+                deleted += 1;
+            }
+        }
+        // Each only goes if it's empty: shards, then the day, month and year
+        for shard in subdirs(&dir) {
+            let _ = fs::remove_dir(shard);
+        }
+        for empty in dir.ancestors().take(3) {
+            if fs::remove_dir(empty).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+/// All subdirectories of `dir`.
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_dir()).collect())
+        .unwrap_or_default()
+}
+
+/// Device IDs become file names, so they must not contain path separators.
+pub fn is_valid_device_id(device_id: &str) -> bool {
+    !device_id.is_empty()
+        && !device_id.contains("..")
+        && !device_id.contains(['/', '\\'])
+}
+
+/// List all YYYY/MM/DD directories as (days since epoch, path), oldest first.
+fn day_dirs(base_path: &Path) -> Vec<(i64, PathBuf)> {
+    let mut days = Vec::new();
+    for (year, year_dir) in numbered_subdirs(base_path) {
+        for (month, month_dir) in numbered_subdirs(&year_dir) {
+            for (day, day_dir) in numbered_subdirs(&month_dir) {
+                days.push((days_from_civil(year, month as u32, day as u32), day_dir));
+            }
+        }
+    }
+    days.sort();
+    days
+}
+
+/// Subdirectories whose names are plain numbers.
+fn numbered_subdirs(dir: &Path) -> Vec<(i32, PathBuf)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| {
+            let n = e.file_name().to_str()?.parse().ok()?;
+            Some((n, e.path()))
+        })
+        .collect()
 }
 
 /// Get current unix timestamp.
@@ -173,6 +1374,425 @@ fn date_parts(timestamp: i64) -> (i32, u32, u32) {
     (year, month as u32, day as u32)
 }
 
+/// Days since 1970-01-01 for a calendar date (inverse of `date_parts`).
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let mut days = 0i64;
+    for y in 1970..year {
+        days += if is_leap_year(y) { 366 } else { 365 };
+    }
+    let days_in_months: [i64; 12] = if is_leap_year(year) {
+        [31, 29, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31]
+    } else {
+        [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31]
+    };
+    for days_in_month in days_in_months.iter().take((month as usize).saturating_sub(1)) {
+        days += days_in_month;
+    }
+    days + day as i64 - 1
+}
+
 fn is_leap_year(year: i32) -> bool {
     (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: i64, latitude: f64, longitude: f64, speed: f64, battery: f64) -> TelemetryRecord {
+        TelemetryRecord {
+            timestamp,
+            device_id: "robot-01".to_string(),
+            latitude,
+            longitude,
+            altitude: 0.0,
+            heading: 0.0,
+            speed,
+            battery,
+            sensors: serde_json::Value::Null,
+            out_of_order: false,
+        }
+    }
+
+    #[test]
+    fn only_a_flagged_record_says_it_is_out_of_order() {
+        let line = serde_json::to_string(&record(100, 34.0, -118.0, 0.0, 90.0)).unwrap();
+        assert!(!line.contains("out_of_order"), "{}", line);
+        let flagged = TelemetryRecord { out_of_order: true, ..record(100, 34.0, -118.0, 0.0, 90.0) };
+        let line = serde_json::to_string(&flagged).unwrap();
+        assert!(serde_json::from_str::<TelemetryRecord>(&line).unwrap().out_of_order, "{}", line);
+
+        assert_eq!(OutOfOrder::parse("flag"), Ok(OutOfOrder::Flag));
+        assert_eq!(OutOfOrder::parse(""), Ok(OutOfOrder::Accept));
+        assert!(OutOfOrder::parse("sort").is_err());
+    }
+
+    #[test]
+    fn stats_over_a_known_series() {
+        // North along a meridian, one degree of latitude every 100 seconds
+        let series = vec![
+            record(1000, 0.0, 10.0, 2.0, 90.0),
+            record(1100, 1.0, 10.0, 4.0, 80.0),
+            record(1200, 2.0, 10.0, 6.0, 70.0),
+            record(1300, 3.0, 10.0, 0.0, 60.0),
+        ];
+        let stats = TelemetryStats::from_records(series.into_iter());
+
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.first_timestamp, Some(1000));
+        assert_eq!(stats.last_timestamp, Some(1300));
+        assert_eq!(stats.span_secs, 300);
+        assert_eq!(stats.battery, Some(Summary { min: 60.0, max: 90.0, avg: 75.0 }));
+        assert_eq!(stats.speed, Some(Summary { min: 0.0, max: 6.0, avg: 3.0 }));
+        // Three degrees of arc: 3 * pi/180 * R
+        let expected = 3.0_f64.to_radians() * EARTH_RADIUS_M;
+        assert!((stats.distance_m - expected).abs() < 1e-6, "{}", stats.distance_m);
+    }
+
+    #[test]
+    fn empty_range_is_all_zeros_and_nones() {
+        let stats = TelemetryStats::from_records(std::iter::empty());
+        assert_eq!(stats, TelemetryStats::default());
+        assert_eq!(serde_json::to_value(&stats).unwrap()["battery"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn reader_streams_days_in_order_and_reports_bad_files() {
+        let base = std::env::temp_dir().join(format!("globalrts-telemetry-days-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let writer = TelemetryWriter::new(base.to_str().unwrap());
+        // Four days, 100 records each, a minute apart
+        let first = 1_700_006_400;
+        let day_file = |day: i64| writer.day_dir(first + day * 86400).join("robot-01.jsonl");
+        for day in 0..4 {
+            fs::create_dir_all(day_file(day).parent().unwrap()).unwrap();
+            let mut lines = String::new();
+            for i in 0..100 {
+                lines.push_str(&serde_json::to_string(&record(first + day * 86400 + i * 60, 34.0, -118.0, 1.0, 90.0)).unwrap());
+                lines.push('\n');
+            }
+            fs::write(day_file(day), lines).unwrap();
+        }
+        // Day 1 turns to garbage part way; day 3 vanishes once reading has begun
+        let mut garbled = fs::read(day_file(1)).unwrap();
+        garbled.truncate(garbled.len() / 2);
+        garbled.extend_from_slice(b"\xff\xfe\n");
+        fs::write(day_file(1), &garbled).unwrap();
+        
+        let reader = TelemetryReader::new(&base);
+        let mut records = reader.iter("robot-01", first, first + 4 * 86400);
+        assert_eq!(records.next().unwrap().unwrap().timestamp, first);
+        fs::remove_file(day_file(3)).unwrap();
+        
+        let (mut count, mut errors, mut last) = (1, 0, first);
+        for item in records {
+            match item {
+                Ok(record) => {
+                    assert!(record.timestamp > last);
+                    last = record.timestamp;
+                    count += 1;
+                }
+                Err(_) => errors += 1,
+            }
+        }
+        // Days 0 and 2 whole, day 1 up to its bad bytes; those and the missing day 3 are the errors
+        assert_eq!(errors, 2);
+        assert!(count > 200 && count <= 250, "{}", count);
+        assert_eq!(last, first + 2 * 86400 + 99 * 60);
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn recent_reads_back_across_days_and_chunks() {
+        let base = std::env::temp_dir().join(format!("globalrts-telemetry-recent-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        // Three days of 1000 records, each day file bigger than a tail chunk
+        let first = 1_700_006_400;
+        let mut all = Vec::new();
+        for day in 0..3 {
+            let dir = day_dir(&base, first + day * 86400);
+            fs::create_dir_all(&dir).unwrap();
+            let mut lines = String::new();
+            for i in 0..1000 {
+                let record = record(first + day * 86400 + i * 60, 34.0 + i as f64 * 1e-4, -118.0, 1.0, 90.0);
+                lines.push_str(&serde_json::to_string(&record).unwrap());
+                lines.push('\n');
+                all.push(record.timestamp);
+            }
+            assert!(lines.len() as u64 > TAIL_CHUNK_BYTES);
+            // The newest day ends in a torn line, as after a crash mid-write
+            if day == 2 {
+                lines.push_str("{\"timestamp\": 17");
+            }
+            fs::write(dir.join("robot-01.jsonl"), lines).unwrap();
+        }
+        
+        let reader = TelemetryReader::new(&base);
+        let timestamps = |n| reader.recent("robot-01", n).unwrap().iter().map(|r| r.timestamp).collect::<Vec<_>>();
+        assert_eq!(timestamps(5), all[all.len() - 5..]);
+        // Most of one day and part of the one before
+        assert_eq!(timestamps(1500), all[all.len() - 1500..]);
+        assert_eq!(timestamps(10_000), all);
+        assert!(timestamps(0).is_empty());
+        assert!(reader.recent("robot-02", 5).unwrap().is_empty());
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn sharded_writes_are_found_by_a_sharded_reader() {
+        let base = std::env::temp_dir().join(format!("globalrts-telemetry-shard-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let now = now_unix();
+        // A day begun unsharded, then sharding turned on
+        let flat = TelemetryWriter::new(base.to_str().unwrap());
+        flat.write(&record(now - 10, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        flat.close().unwrap();
+        let writer = TelemetryWriter::new(base.to_str().unwrap()).with_sharding(true);
+        for t in 0..5 {
+            writer.write(&record(now - 5 + t, 34.1, -118.0, 1.0, 89.0)).unwrap();
+        }
+        writer.close().unwrap();
+        
+        let shard = shard("robot-01");
+        assert_eq!(shard.len(), 2);
+        assert!(day_dir(&base, now).join(&shard).join("robot-01.jsonl").is_file());
+        
+        let sharded = TelemetryReader::new(&base).with_sharding(true);
+        assert_eq!(sharded.records("robot-01", now - 60, now + 1).count(), 6);
+        assert_eq!(TelemetryReader::new(&base).records("robot-01", now - 60, now + 1).count(), 1);
+        assert_eq!(sharded.count_day(now), Ok(6));
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn prune_keeps_devices_with_longer_retention() {
+        let base = std::env::temp_dir().join(format!("globalrts-telemetry-prune-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let now = now_unix();
+        let day = 86400;
+        // The writer files by today's date, so older days are laid out by hand
+        for sharded in [false, true] {
+            for (device, age) in [("robot-01", 0), ("robot-01", 100), ("safety-01", 100), ("safety-01", 400), ("robot-02", 30)] {
+                let at = now - age * day;
+                let path = device_file(&base, at, device, sharded, false);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                let line = serde_json::to_string(&TelemetryRecord { device_id: device.to_string(), ..record(at, 34.0, -118.0, 1.0, 90.0) }).unwrap();
+                fs::write(&path, line + "\n").unwrap();
+                fs::write(checksum_path(&path), "0\n").unwrap();
+            }
+        }
+        let overrides = HashMap::from([("safety-01".to_string(), 365)]);
+        
+        // A flat and a sharded file for each device-day past its retention
+        assert_eq!(prune(&base, now, 90, &overrides), Ok(4));
+        let reader = TelemetryReader::new(&base).with_sharding(true);
+        assert_eq!(reader.records("robot-01", 0, now + 1).count(), 2, "today's kept");
+        assert_eq!(reader.records("robot-02", 0, now + 1).count(), 2, "inside the default");
+        assert_eq!(reader.records("safety-01", 0, now + 1).map(|r| (now - r.timestamp) / day).collect::<Vec<_>>(), [100, 100]);
+        assert!(!checksum_path(&device_file(&base, now - 100 * day, "robot-01", false, false)).exists(), "checksums go too");
+        assert!(!day_dir(&base, now - 400 * day).exists(), "emptied days go");
+        
+        // No default keeps everything but what an override says
+        assert_eq!(prune(&base, now, 0, &HashMap::from([("safety-01".to_string(), 30)])), Ok(2));
+        assert_eq!(reader.records("robot-02", 0, now + 1).count(), 2);
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn fsynced_flush_puts_buffered_lines_on_disk() {
+        let base = std::env::temp_dir().join(format!("globalrts-telemetry-fsync-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let now = now_unix();
+        let writer = TelemetryWriter::new(base.to_str().unwrap()).with_flush(3600, true);
+        let path = writer.day_dir(now).join("robot-01.jsonl");
+        let lines = || fs::read_to_string(&path).unwrap().lines().count();
+        
+        // The first write flushes (nothing has yet); the next waits for the interval
+        writer.write(&record(now, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        writer.write(&record(now, 34.1, -118.0, 1.0, 89.0)).unwrap();
+        assert_eq!(lines(), 1);
+        
+        writer.flush_device("robot-01").unwrap();
+        assert_eq!(lines(), 2);
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn a_crash_loses_nothing_buffered_with_a_recovery_log() {
+        let base = std::env::temp_dir().join(format!("globalrts-telemetry-recovery-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let now = now_unix();
+        let writer = TelemetryWriter::new(base.to_str().unwrap()).with_flush(3600, false).with_recovery_log(true);
+        let path = writer.day_dir(now).join("robot-01.jsonl");
+        let reader = TelemetryReader::new(&base);
+        let timestamps = || reader.records("robot-01", now - 1000, now + 1).map(|r| r.timestamp).collect::<Vec<_>>();
+        
+        // The first write flushes and empties the log; the rest wait in the buffer
+        for t in 0..5 {
+            writer.write(&record(now - 100 + t, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        }
+        assert_eq!(timestamps().len(), 1);
+        // One device's flush doesn't empty the log, so it holds records already on disk
+        writer.flush_device("robot-01").unwrap();
+        for t in 5..7 {
+            writer.write(&record(now - 100 + t, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        }
+        // The process dies: nothing more is flushed, and the last line was cut short
+        std::mem::forget(writer);
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"timestamp\":17").unwrap();
+        assert_eq!(timestamps().len(), 5);
+        
+        let restarted = TelemetryWriter::new(base.to_str().unwrap()).with_recovery_log(true);
+        assert_eq!(restarted.recover(), Ok(2));
+        assert_eq!(timestamps(), (now - 100..now - 93).collect::<Vec<_>>());
+        assert_eq!(fs::read_to_string(base.join(RECOVERY_LOG)).unwrap(), "");
+        assert_eq!(restarted.recover(), Ok(0));
+        
+        // Recovered into today's file, which stays open for what comes next
+        restarted.write(&record(now - 93, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        restarted.close().unwrap();
+        assert_eq!(timestamps().len(), 8);
+        assert_eq!(verify_day_file(&path), Ok(Integrity::Verified));
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn gzipped_writes_read_back_mid_stream_and_across_a_restart() {
+        let base = std::env::temp_dir().join(format!("globalrts-telemetry-gzip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let now = now_unix();
+        let writer = TelemetryWriter::new(base.to_str().unwrap()).with_flush(3600, false).with_gzip(true);
+        let path = writer.day_dir(now).join("robot-01.jsonl.gz");
+        let reader = TelemetryReader::new(&base);
+        let count = || reader.records("robot-01", now - 1000, now + 1).count();
+        
+        for t in 0..300 {
+            writer.write(&record(now - 900 + t, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        }
+        // Only what's been flushed is there, and all of it reads back while the file is open
+        assert_eq!(count(), 1);
+        writer.flush_device("robot-01").unwrap();
+        assert_eq!(count(), 300);
+        let recent = reader.recent("robot-01", 5).unwrap();
+        assert_eq!(recent.iter().map(|r| r.timestamp).collect::<Vec<_>>(), (now - 605..now - 600).collect::<Vec<_>>());
+        
+        // The process dies with lines unflushed; they're lost, the rest isn't
+        writer.write(&record(now - 500, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        drop(writer);
+        let restarted = TelemetryWriter::new(base.to_str().unwrap()).with_gzip(true);
+        for t in 0..100 {
+            restarted.write(&record(now - 400 + t, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        }
+        restarted.close().unwrap();
+        assert_eq!(count(), 400);
+        assert_eq!(reader.count_day(now), Ok(400));
+        assert_eq!(verify_day_file(&path), Ok(Integrity::Verified));
+        
+        // Far smaller than the same lines uncompressed, and whole members all
+        // through: the decoder checks each one's CRC and length
+        let plain: usize = reader.records("robot-01", now - 1000, now + 1).map(|r| serde_json::to_string(&r).unwrap().len() + 1).sum();
+        assert!(fs::metadata(&path).unwrap().len() < plain as u64 / 4);
+        let mut lines = Vec::new();
+        GzipDecoder::new(File::open(&path).unwrap()).read_to_end(&mut lines).unwrap();
+        assert_eq!(lines.len(), plain);
+        assert_eq!(lines.iter().filter(|&&b| b == b'\n').count(), 400);
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn sealed_files_verify_until_a_byte_changes() {
+        let base = std::env::temp_dir().join(format!("globalrts-telemetry-sha-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let now = now_unix();
+        let writer = TelemetryWriter::new(base.to_str().unwrap());
+        for t in 0..20 {
+            writer.write(&record(now - 20 + t, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        }
+        let path = writer.day_dir(now).join("robot-01.jsonl");
+        assert_eq!(verify_day_file(&path), Ok(Integrity::Unsealed));
+        
+        writer.close().unwrap();
+        assert_eq!(verify_day_file(&path), Ok(Integrity::Verified));
+        let sidecar = fs::read_to_string(checksum_path(&path)).unwrap();
+        assert_eq!(sidecar, format!("{}  robot-01.jsonl\n", sha256::hex(&sha256::digest(&fs::read(&path).unwrap()))));
+        
+        // Flip one bit in the middle
+        let mut bytes = fs::read(&path).unwrap();
+        let mid = bytes.len() / 2;
+        bytes[mid] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(verify_day_file(&path), Ok(Integrity::Corrupt));
+        
+        let mut range = read_range(&base, "robot-01", now - 100, now + 1).verify_checksums();
+        range.by_ref().for_each(drop);
+        assert_eq!(range.corrupt_files(), std::slice::from_ref(&path));
+        
+        // Writing again reopens the file and retires the old checksum
+        bytes[mid] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        writer.write(&record(now, 34.0, -118.0, 1.0, 90.0)).unwrap();
+        assert_eq!(verify_day_file(&path), Ok(Integrity::Unsealed));
+        writer.close().unwrap();
+        assert_eq!(verify_day_file(&path), Ok(Integrity::Verified));
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn haversine_matches_a_known_distance() {
+        // Los Angeles to New York, about 3936 km
+        let d = haversine_m(34.0522, -118.2437, 40.7128, -74.0060);
+        assert!((d - 3_936_000.0).abs() < 5_000.0, "{}", d);
+    }
+    
+    #[test]
+    fn binary_frames_round_trip() {
+        let mut sent = record(1_700_000_000, 34.052235, -118.243683, 2.5, 87.5);
+        sent.altitude = -12.25;
+        sent.heading = 359.9;
+        let frame = sent.to_binary();
+        assert_eq!(frame.len(), BINARY_RECORD_LEN);
+        assert_eq!(frame[..8], 1_700_000_000i64.to_le_bytes());
+        assert_eq!(frame[8..16], 34.052235f64.to_le_bytes());
+
+        let decoded = TelemetryRecord::from_binary("robot-01", &frame).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&sent).unwrap());
+
+        assert!(TelemetryRecord::from_binary("robot-01", &frame[..55]).is_err());
+        let mut nan = frame;
+        nan[48..].copy_from_slice(&f64::NAN.to_le_bytes());
+        assert!(TelemetryRecord::from_binary("robot-01", &nan).is_err());
+    }
+    
+    #[test]
+    fn heatmap_counts_visits_per_cell() {
+        let mut heatmap = Heatmap::new(0.001, MAX_HEATMAP_CELLS);
+        // One robot parks in a cell for three reports, then drives east
+        // through two more; another crosses the first cell once, south of zero
+        for (t, lon) in [(0, 0.0005), (1, 0.0005), (2, 0.0005), (3, 0.0015), (4, 0.0025)] {
+            heatmap.add(&record(t, 0.0005, lon, 0.0, 0.0));
+        }
+        heatmap.add(&record(5, 0.0005, 0.0005, 0.0, 0.0));
+        heatmap.add(&record(6, -0.0005, 0.0005, 0.0, 0.0));
+        heatmap.add(&record(7, f64::NAN, 0.0005, 0.0, 0.0));
+        
+        let cells: Vec<(i64, i64, f64)> = heatmap.cells().iter()
+            .map(|[lat, lon, weight]| ((lat * 1e4).round() as i64, (lon * 1e4).round() as i64, *weight))
+            .collect();
+        assert_eq!(cells, [(-5, 5, 1.0), (5, 5, 4.0), (5, 15, 1.0), (5, 25, 1.0)]);
+        assert_eq!(heatmap.cell(), 0.001);
+    }
+    
+    #[test]
+    fn heatmap_coarsens_past_its_cap() {
+        let mut heatmap = Heatmap::new(0.001, 2);
+        for lon in [0.0005, 0.0015, 0.0025, 0.0035] {
+            heatmap.add(&record(0, 0.0005, lon, 0.0, 0.0));
+        }
+        // Four cells doesn't fit in two: the cell doubles and pairs merge
+        assert_eq!(heatmap.cell(), 0.002);
+        let cells: Vec<(i64, i64, f64)> = heatmap.cells().iter()
+            .map(|[lat, lon, weight]| ((lat * 1e4).round() as i64, (lon * 1e4).round() as i64, *weight))
+            .collect();
+        assert_eq!(cells, [(10, 10, 2.0), (10, 30, 2.0)]);
+    }
+}
@@ -0,0 +1,183 @@
+//! # TLS
+//!
+//! Optional TLS termination, so `https://` and `wss://` work without a
+//! proxy in front. Off unless a certificate and key are configured; then
+//! every connection is TLS and plaintext ones fail the handshake.
+//!
+//! rustls with ring does the cryptography: pure Rust (ring's assembly
+//! aside), compiled into the binary, no OpenSSL to install.
+//!
+//! `Stream` is what the HTTP and WebSocket code read and write, plain or
+//! TLS. A TLS session can't be split the way a socket can, so clones share
+//! it behind a lock. The WebSocket reader never blocks while holding it:
+//! its socket is non-blocking.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+/// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The server's side of TLS: the certificate chain at `cert_path` and
+/// the private key at `key_path`, both PEM.
+pub fn server_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("can't read certificates from {}: {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", cert_path));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("can't read a private key from {}: {}", key_path, e))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("{} and {} don't go together: {}", cert_path, key_path, e))?;
+    Ok(Arc::new(config))
+}
+
+/// A connection, plain or TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Arc<Mutex<StreamOwned<ServerConnection, TcpStream>>>),
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Plain(stream)
+    }
+}
+
+impl Stream {
+    /// Wrap an accepted connection: as it is without `tls`, otherwise once
+    /// the client has finished the TLS handshake.
+    pub fn accept(mut stream: TcpStream, tls: Option<&Arc<ServerConfig>>) -> Result<Self, String> {
+        let Some(config) = tls else {
+            return Ok(Stream::Plain(stream));
+        };
+        let mut conn = ServerConnection::new(Arc::clone(config)).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(|e| e.to_string())?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(|e| e.to_string())?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream).map_err(|e| e.to_string())?;
+        }
+        stream.set_write_timeout(None).map_err(|e| e.to_string())?;
+        Ok(Stream::Tls(Arc::new(Mutex::new(StreamOwned::new(conn, stream)))))
+    }
+
+    /// Another handle on the same connection. TLS clones share the session.
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Plain(stream) => stream.try_clone().map(Stream::Plain),
+            Stream::Tls(tls) => Ok(Stream::Tls(Arc::clone(tls))),
+        }
+    }
+
+    /// Whether this is a TLS connection.
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Stream::Tls(_))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Plain(stream) => stream.peer_addr(),
+            Stream::Tls(tls) => lock(tls)?.sock.peer_addr(),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(tls) => lock(tls)?.sock.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.set_write_timeout(timeout),
+            Stream::Tls(tls) => lock(tls)?.sock.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Tls(tls) => lock(tls)?.sock.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Shut the connection down in both directions, telling a TLS client
+    /// first (close_notify) if the socket will take it.
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.shutdown(Shutdown::Both),
+            Stream::Tls(tls) => {
+                let mut tls = lock(tls)?;
+                let tls = &mut *tls;
+                tls.conn.send_close_notify();
+                let _ = write_pending(&mut tls.conn, &mut tls.sock);
+                tls.sock.shutdown(Shutdown::Both)
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(tls) => lock(tls)?.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    /// On a non-blocking TLS socket, what a full socket won't take waits in
+    /// the session, and the next write (or `flush`) sends it first: WouldBlock
+    /// means none of `buf` was taken.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(tls) => {
+                let mut tls = lock(tls)?;
+                let tls = &mut *tls;
+                write_pending(&mut tls.conn, &mut tls.sock)?;
+                let n = tls.conn.writer().write(buf)?;
+                match write_pending(&mut tls.conn, &mut tls.sock) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(n),
+                    Err(e) => Err(e),
+                    Ok(()) => Ok(n),
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(tls) => {
+                let mut tls = lock(tls)?;
+                let tls = &mut *tls;
+                write_pending(&mut tls.conn, &mut tls.sock)
+            }
+        }
+    }
+}
+
+fn lock(tls: &Mutex<StreamOwned<ServerConnection, TcpStream>>) -> io::Result<MutexGuard<'_, StreamOwned<ServerConnection, TcpStream>>> {
+    tls.lock().map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Send whatever TLS records the session has waiting.
+fn write_pending(conn: &mut ServerConnection, sock: &mut TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        conn.write_tls(sock)?;
+    }
+    Ok(())
+}
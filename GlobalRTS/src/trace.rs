@@ -0,0 +1,157 @@
+//! # Request Tracing
+//!
+//! A short correlation id for every HTTP request and WebSocket message, so
+//! one request can be followed through the log. Log lines written while
+//! handling it start with the id in brackets, and HTTP responses carry it
+//! back in `X-Request-Id`. A client (or a proxy in front) that sends its
+//! own `X-Request-Id` has it kept, as long as it's short and plain enough
+//! to put in a log line.
+//!
+//! Each connection is served on its own thread, so the id being handled
+//! is kept per thread: the response writers and `log!` find it there
+//! rather than having it passed to every one of them.
+//!
+//! `log!` writes to stdout, or to the log file when one is configured
+//! (see `logfile`).
+//!
+//! [4f0c9a7e1b2d3c58] ✓ Paired: robot-01
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::logfile::RotatingLog;
+
+/// Longest incoming request id kept. A longer one is replaced.
+pub const MAX_ID_LEN: usize = 64;
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Where `log!` writes instead of stdout, once set.
+static LOG_FILE: Mutex<Option<RotatingLog>> = Mutex::new(None);
+
+/// A fresh id: 16 hex digits from the OS's CSPRNG.
+pub fn new_id() -> String {
+    let mut bytes = [0u8; 8];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        // No OS randomness: the id only has to tell requests apart
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        bytes = (nanos as u64).to_be_bytes();
+    }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The id to trace a request by: `incoming`, if the client sent a usable
+/// one, otherwise a fresh one.
+pub fn request_id(incoming: Option<&str>) -> String {
+    match incoming.map(str::trim) {
+        Some(id) if is_valid_id(id) => id.to_string(),
+        _ => new_id(),
+    }
+}
+
+/// Letters, digits and `-_.:`, up to `MAX_ID_LEN`: nothing that could
+/// split a header or forge a log line.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Trace what this thread does as `id` until the returned guard drops,
+/// when whatever was traced before is back.
+pub fn enter(id: String) -> Scope {
+    let previous = CURRENT.with(|current| current.replace(Some(id)));
+    Scope { previous }
+}
+
+/// From `enter`.
+pub struct Scope {
+    previous: Option<String>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// The id this thread is handling, if any.
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Header lines giving the id being handled back to the client (and
+/// letting a browser script read it), or nothing outside a request.
+pub fn response_headers() -> String {
+    match current() {
+        Some(id) => format!("X-Request-Id: {}\r\nAccess-Control-Expose-Headers: X-Request-Id\r\n", id),
+        None => String::new(),
+    }
+}
+
+/// Send `log!` to `log` from now on, rather than stdout.
+pub fn log_to(log: RotatingLog) {
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(log);
+}
+
+/// Write one log line, with the id being handled in front. A line the
+/// log file won't take goes to stdout, so it isn't lost.
+pub fn emit(message: fmt::Arguments) {
+    let message = message.to_string();
+    let line = match current() {
+        Some(id) if !message.is_empty() => format!("[{}] {}", id, message),
+        _ => message,
+    };
+    let mut file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    match file.as_mut().map(|log| log.write_line(&line)) {
+        Some(Ok(())) => {}
+        Some(Err(e)) => println!("{} (log file: {})", line, e),
+        None => println!("{}", line),
+    }
+}
+
+/// `println!`, with the id being handled in front, to the log.
+macro_rules! log {
+    () => {
+        $crate::trace::emit(format_args!(""))
+    };
+    ($($arg:tt)*) => {
+        $crate::trace::emit(format_args!($($arg)*))
+    };
+}
+pub(crate) use log;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_kept_only_when_plain() {
+        assert_eq!(request_id(Some("checkout-42.retry:1")), "checkout-42.retry:1");
+        for bad in ["", "two words", "line\r\nX-Admin: yes", &"a".repeat(MAX_ID_LEN + 1)] {
+            let id = request_id(Some(bad));
+            assert_eq!(id.len(), 16, "{:?} kept as {:?}", bad, id);
+        }
+        assert_ne!(new_id(), new_id());
+    }
+
+    #[test]
+    fn a_scope_restores_the_id_before_it() {
+        assert_eq!(current(), None);
+        let outer = enter("outer".to_string());
+        {
+            let _inner = enter("inner".to_string());
+            assert_eq!(current().as_deref(), Some("inner"));
+        }
+        assert_eq!(current().as_deref(), Some("outer"));
+        assert!(response_headers().starts_with("X-Request-Id: outer\r\n"));
+        drop(outer);
+        assert_eq!(current(), None);
+        assert_eq!(response_headers(), "");
+    }
+}
@@ -0,0 +1,30 @@
+//! # Version Info
+//!
+//! What's deployed: crate version, git commit, build time and protocol
+//! version. The commit and build time are filled in by `build.rs`.
+
+use crate::protocol::PROTOCOL_VERSION;
+
+/// Crate version from Cargo.toml.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short git commit the binary was built from, if known.
+pub const GIT_COMMIT: Option<&str> = option_env!("GLOBALRTS_GIT_COMMIT");
+
+/// Unix time of the build.
+pub const BUILD_TIME: &str = env!("GLOBALRTS_BUILD_TIME");
+
+/// The body of `GET /api/version`.
+pub fn info() -> serde_json::Value {
+    serde_json::json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "build_time": BUILD_TIME.parse::<i64>().unwrap_or(0),
+        "protocol_version": PROTOCOL_VERSION,
+    })
+}
+
+/// One line for the startup banner, e.g. `v1.0.0 • 3f2a9c1 • protocol 1`.
+pub fn banner() -> String {
+    format!("v{} • {} • protocol {}", VERSION, GIT_COMMIT.unwrap_or("unknown commit"), PROTOCOL_VERSION)
+}
@@ -0,0 +1,293 @@
+//! # Webhooks
+//!
+//! Outbound POSTs of JSON, for automation that would rather be told when
+//! something happens than hold a connection open waiting for it.
+//!
+//! Deliveries wait in a bounded queue for one worker thread, so a slow or
+//! dead receiver never holds up the server; when the queue is full, new
+//! ones are logged and dropped. Each is tried a few times, pausing longer
+//! each time; one that still fails is logged and dropped. A 2xx answer is
+//! success, anything else a failure.
+//!
+//! Anyone who can send a command can name a callback URL, so a webhook
+//! never goes to a loopback, private or link-local address unless
+//! GLOBALRTS_WEBHOOK_ALLOW_CIDRS lets it. The host is resolved once, and
+//! checked, and the POST goes to that address.
+//!
+//! http:// goes over a plain socket. https:// goes through the system's
+//! curl, as the Oura proxy does: rustls is here to serve TLS, and checking
+//! a receiver's certificate would take a set of trusted roots the binary
+//! doesn't carry. curl uses the system's.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::access::Cidr;
+use crate::trace::log;
+
+/// Longest URL a webhook may be given.
+pub const MAX_URL_BYTES: usize = 2048;
+
+/// Tries per delivery.
+const ATTEMPTS: u32 = 3;
+
+/// Pause before the first retry; each one after waits twice as long.
+const RETRY_AFTER: Duration = Duration::from_secs(2);
+
+/// How long one try may take to connect, and then to be answered.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Deliveries that may wait for the worker before new ones are dropped.
+const QUEUE: usize = 256;
+
+/// A POST on its way, and how far its tries have got.
+struct Delivery {
+    url: String,
+    body: String,
+    what: String,
+    /// Addresses it may go to that would otherwise be refused.
+    allow: Vec<Cidr>,
+    attempt: u32,
+    /// How long to wait before the next try.
+    pause: Duration,
+}
+
+/// Whether `url` is one a webhook can be sent to.
+pub fn check_url(url: &str) -> Result<(), String> {
+    let ok = url.len() <= MAX_URL_BYTES
+        && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+        && parse_url(url).is_some();
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("must be an http:// or https:// URL of at most {} bytes", MAX_URL_BYTES))
+    }
+}
+
+/// POST `body` to `url` in the background. `what` names it in the log.
+/// Private addresses in `allow` may be posted to; other private ones are
+/// refused.
+pub fn post(url: &str, body: &serde_json::Value, what: &str, allow: &[Cidr]) {
+    static WORKER: OnceLock<SyncSender<Delivery>> = OnceLock::new();
+    let queue = WORKER.get_or_init(|| {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE);
+        thread::spawn(move || work(receiver));
+        sender
+    });
+    let delivery = Delivery {
+        url: url.to_string(),
+        body: body.to_string(),
+        what: what.to_string(),
+        allow: allow.to_vec(),
+        attempt: 1,
+        pause: RETRY_AFTER,
+    };
+    if queue.try_send(delivery).is_err() {
+        log!("✗ Webhook: {} to {} dropped: {} already waiting", what, url, QUEUE);
+    }
+}
+
+/// The worker: deliveries as they come, and retries as they fall due.
+fn work(queue: Receiver<Delivery>) {
+    let mut retries: Vec<(Instant, Delivery)> = Vec::new();
+    loop {
+        let soonest = (0..retries.len()).min_by_key(|&i| retries[i].0);
+        let delivery = match soonest {
+            Some(i) if retries[i].0 <= Instant::now() => retries.swap_remove(i).1,
+            Some(i) => match queue.recv_timeout(retries[i].0 - Instant::now()) {
+                Ok(delivery) => delivery,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match queue.recv() {
+                Ok(delivery) => delivery,
+                Err(_) => return,
+            },
+        };
+        if let Some(retry) = deliver(delivery) {
+            retries.push(retry);
+        }
+    }
+}
+
+/// Try `delivery` once. When it's to be tried again, and when.
+fn deliver(mut delivery: Delivery) -> Option<(Instant, Delivery)> {
+    let result = parse_url(&delivery.url).ok_or_else(|| "bad URL".to_string()).and_then(|target| {
+        let addr = resolve(&target, &delivery.allow)?;
+        if target.https { post_curl(&delivery.url, &target, addr, &delivery.body) } else { post_http(&target, addr, &delivery.body) }
+    });
+    match result {
+        Ok(()) => {
+            log!("→ Webhook: {} to {}", delivery.what, delivery.url);
+            None
+        }
+        Err(e) if delivery.attempt == ATTEMPTS => {
+            log!("✗ Webhook: {} to {} dropped after {} tries: {}", delivery.what, delivery.url, ATTEMPTS, e);
+            None
+        }
+        Err(_) => {
+            let due = Instant::now() + delivery.pause;
+            delivery.attempt += 1;
+            delivery.pause *= 2;
+            Some((due, delivery))
+        }
+    }
+}
+
+/// Where a webhook URL points.
+#[derive(Debug, PartialEq)]
+struct Target {
+    https: bool,
+    /// `host:port`, to resolve.
+    addr: String,
+    /// The Host header.
+    authority: String,
+    /// The request path, with any query.
+    path: String,
+}
+
+/// The target of an http:// or https:// URL.
+fn parse_url(url: &str) -> Option<Target> {
+    let (https, rest) = match url.strip_prefix("https://") {
+        Some(rest) => (true, rest),
+        None => (false, url.strip_prefix("http://")?),
+    };
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !authority.ends_with(']') => (host, port.parse::<u16>().ok()?),
+        _ => (authority, if https { 443 } else { 80 }),
+    };
+    if host.is_empty() || host.contains('@') {
+        return None;
+    }
+    let path = if path.starts_with('?') { format!("/{}", path) } else { path.to_string() };
+    Some(Target { https, addr: format!("{}:{}", host, port), authority: authority.to_string(), path })
+}
+
+/// The address to POST to: the target's first, if a webhook may go there.
+fn resolve(target: &Target, allow: &[Cidr]) -> Result<SocketAddr, String> {
+    let addr = target.addr.to_socket_addrs().map_err(|e| e.to_string())?.next().ok_or("host not found")?;
+    if !may_post_to(addr.ip(), allow) {
+        return Err(format!("{} is a private address", addr.ip()));
+    }
+    Ok(addr)
+}
+
+/// Whether a webhook may go to `ip`: a public address, or one in `allow`.
+fn may_post_to(ip: IpAddr, allow: &[Cidr]) -> bool {
+    let private = match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local() || ip.is_unspecified(),
+    };
+    !private || allow.iter().any(|cidr| cidr.contains(ip))
+}
+
+fn post_http(target: &Target, addr: SocketAddr, body: &str) -> Result<(), String> {
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nUser-Agent: GlobalRTS/{}\r\nConnection: close\r\n\r\n{}",
+        target.path, target.authority, body.len(), crate::version::VERSION, body
+    );
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+    // The status line is all that's wanted
+    let mut response = Vec::new();
+    let mut buf = [0u8; 256];
+    while !response.contains(&b'\n') {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split_whitespace().nth(1).unwrap_or("none");
+    if status.starts_with('2') { Ok(()) } else { Err(format!("status {}", status)) }
+}
+
+fn post_curl(url: &str, target: &Target, addr: SocketAddr, body: &str) -> Result<(), String> {
+    // Pinned to the address that was checked, so a second lookup can't differ
+    let host = target.addr.rsplit_once(':').map_or("", |(host, _)| host);
+    let ip = match addr.ip() {
+        IpAddr::V6(ip) => format!("[{}]", ip),
+        ip => ip.to_string(),
+    };
+    let mut child = Command::new("curl")
+        .args([
+            "-s", "-o", "/dev/null", "-w", "%{http_code}",
+            "--max-time", &TIMEOUT.as_secs().to_string(),
+            "--resolve", &format!("{}:{}:{}", host, addr.port(), ip),
+            "-X", "POST",
+            "-H", "Content-Type: application/json",
+            "--data-binary", "@-",
+            url,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("curl: {}", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body.as_bytes()).map_err(|e| e.to_string())?;
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    let status = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if status.starts_with('2') { Ok(()) } else { Err(format!("status {}", status)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_checked_and_split() {
+        assert_eq!(
+            parse_url("http://hooks.local:8080/done?x=1"),
+            Some(Target {
+                https: false,
+                addr: "hooks.local:8080".to_string(),
+                authority: "hooks.local:8080".to_string(),
+                path: "/done?x=1".to_string(),
+            })
+        );
+        assert_eq!(parse_url("http://10.0.0.5").unwrap().addr, "10.0.0.5:80");
+        assert_eq!(parse_url("https://10.0.0.5").unwrap().addr, "10.0.0.5:443");
+        assert_eq!(parse_url("http://[::1]:9000/hook").unwrap().addr, "[::1]:9000");
+        assert_eq!(parse_url("http://[::1]/hook").unwrap().addr, "[::1]:80");
+        assert_eq!(parse_url("http://host?a=b").unwrap().path, "/?a=b");
+
+        assert!(check_url("https://example.com/hook").is_ok());
+        for bad in ["ftp://example.com", "http://", "http://host:notaport/", "http://user@host/", "http://a b/", "example.com"] {
+            assert!(check_url(bad).is_err(), "{}", bad);
+        }
+        assert!(check_url(&format!("http://h/{}", "x".repeat(MAX_URL_BYTES))).is_err());
+    }
+
+    #[test]
+    fn private_addresses_are_refused_unless_allowed() {
+        for private in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!may_post_to(private.parse().unwrap(), &[]), "{}", private);
+        }
+        for public in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(may_post_to(public.parse().unwrap(), &[]), "{}", public);
+        }
+        let allow = [Cidr::parse("10.0.0.0/8").unwrap()];
+        assert!(may_post_to("10.1.2.3".parse().unwrap(), &allow));
+        assert!(!may_post_to("192.168.1.1".parse().unwrap(), &allow));
+
+        let target = parse_url("http://127.0.0.1:9/hook").unwrap();
+        assert!(resolve(&target, &[]).unwrap_err().contains("private"));
+        assert_eq!(resolve(&target, &[Cidr::parse("127.0.0.1").unwrap()]).unwrap(), "127.0.0.1:9".parse().unwrap());
+    }
+}
@@ -9,26 +9,96 @@
 //! - Any AI can read and understand this completely
 //!
 //! IMPLEMENTS:
-//! - HTTP upgrade handshake
-//! - Text frame encoding/decoding
+//! - HTTP upgrade handshake, version 13 only: a client asking for another
+//!   is refused with a 400 naming 13, as the RFC says
+//! - Text frame encoding/decoding; binary frames decoded for whoever wants them
 //! - Ping/pong for keepalive
 //! - Clean close handshake
 //! - Client masking (required by spec)
+//! - Per-connection ingress rate cap and message size cap
+//! - Close codes and reasons, so clients can tell why they were closed
+//! - Per-connection send queue with its own writer thread, so one slow
+//!   reader can't hold up writes to everyone else
+//! - Frames split across TCP segments, reassembled over as many reads as
+//!   it takes
+//! - Fragmented messages, with RFC 6455's rules on continuation frames
+//!   enforced: breaking them closes the connection with 1002
+//! - permessage-deflate (RFC 7692), when the server allows it and the client
+//!   offers it, with or without context takeover in either direction
+//! - Frame and byte counts per connection, for debugging
+//! - Subprotocol negotiation: the first the client offers that the server
+//!   speaks is chosen
 
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use sha1::{Sha1, Digest};
 use base64::Engine;
+use serde::Serialize;
+
+use crate::gzip::{GzipDecoder, GzipEncoder};
+use crate::tls::Stream;
 
 /// WebSocket GUID from RFC 6455. This is a magic constant that never changes.
 const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
+/// The only protocol version spoken (RFC 6455), and what a client asking
+/// for another is told to use instead.
+const WS_VERSION: &str = "13";
+
 /// Frame opcodes from RFC 6455
+const OPCODE_CONTINUATION: u8 = 0x0;
 const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
 const OPCODE_CLOSE: u8 = 0x8;
 const OPCODE_PING: u8 = 0x9;
 const OPCODE_PONG: u8 = 0xA;
 
+/// Set on the first frame of a compressed message (RFC 7692 §6).
+const RSV1: u8 = 0x40;
+
+/// How every compressed message ends once its last four bytes are put
+/// back: the empty stored block of a sync flush (RFC 7692 §7.2.1).
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The most a compressed message may inflate to when messages have no
+/// size cap: a megabyte on the wire could otherwise make a gigabyte.
+const MAX_INFLATED: u64 = 64 * 1024 * 1024;
+
+/// Close code for a normal closure (RFC 6455 §7.4.1).
+pub const CLOSE_NORMAL: u16 = 1000;
+
+/// Close code for a server going down (RFC 6455 §7.4.1).
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Close code for a frame that breaks the protocol (RFC 6455 §7.4.1).
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Close code for a message whose data doesn't decode (RFC 6455 §7.4.1).
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+
+/// Close code for a client that broke a server policy (RFC 6455 §7.4.1).
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+/// Close code for a message too big to process (RFC 6455 §7.4.1).
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Longest close reason: control frames carry at most 125 bytes, 2 of them the code.
+const MAX_CLOSE_REASON: usize = 123;
+
+/// How long a closing connection's writer keeps trying to get its last
+/// frames (the close frame among them) to a reader that isn't reading.
+const CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+/// How long the writer waits on a full socket before trying again.
+const WRITE_RETRY: Duration = Duration::from_millis(5);
+
+/// Seconds of history the ingress cap averages over. Short bursts above
+/// the cap are fine as long as the window's total stays under it.
+const INGRESS_WINDOW_SECS: usize = 5;
+
 /// WebSocket connection state
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum State {
@@ -37,17 +107,371 @@ pub enum State {
     Closed,
 }
 
-/// A WebSocket connection wrapping a TCP stream.
+/// A WebSocket connection wrapping a TCP stream, plain or TLS.
 pub struct WebSocket {
-    stream: TcpStream,
+    stream: Stream,
     pub state: State,
+    ingress: IngressMeter,
+    /// Largest frame payload accepted, in bytes. 0 = no cap.
+    max_message: u64,
+    /// Outgoing frames for the writer thread, once started. Shared by clones.
+    outbox: Option<Arc<Outbox>>,
+    /// The start of a frame whose rest hasn't arrived yet.
+    partial: Vec<u8>,
+    /// A fragmented message so far: its opcode, whether it's compressed,
+    /// and the payloads of the frames that have come.
+    fragments: Option<(u8, bool, Vec<u8>)>,
+    /// permessage-deflate, if negotiated. Shared by clones.
+    deflate: Option<Arc<Deflate>>,
+    /// The end of what the client's compressed messages decoded to, for its
+    /// next one to refer back into. Empty without client context takeover.
+    inflate_window: Vec<u8>,
+    /// What has crossed the connection. Shared by clones.
+    stats: Arc<Mutex<FrameStats>>,
+    /// When the handshake finished.
+    opened: Instant,
+    /// The subprotocol agreed in the handshake, if any.
+    subprotocol: Option<String>,
+    /// How long the writer thread waits on a socket taking nothing before
+    /// it gives the connection up. Zero = forever.
+    write_timeout: Duration,
+}
+
+/// A whole message from the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Frames of each kind, one direction of one connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct OpcodeCounts {
+    pub text: u64,
+    pub binary: u64,
+    pub close: u64,
+    pub ping: u64,
+    pub pong: u64,
+    /// Continuations and reserved opcodes.
+    pub other: u64,
+}
+
+impl OpcodeCounts {
+    fn count(&mut self, opcode: u8) {
+        match opcode & 0x0F {
+            OPCODE_TEXT => self.text += 1,
+            OPCODE_BINARY => self.binary += 1,
+            OPCODE_CLOSE => self.close += 1,
+            OPCODE_PING => self.ping += 1,
+            OPCODE_PONG => self.pong += 1,
+            _ => self.other += 1,
+        }
+    }
+}
+
+/// What has crossed one connection so far. Outgoing frames count when
+/// they're queued; incoming ones once whole.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct FrameStats {
+    pub frames_in: OpcodeCounts,
+    pub frames_out: OpcodeCounts,
+    /// Bytes on the wire, frame headers included.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Frames refused or messages that didn't decode: too big, too fast,
+    /// compressed wrongly, not UTF-8.
+    pub protocol_errors: u64,
+}
+
+/// permessage-deflate parameters agreed in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DeflateParams {
+    /// The server compresses each message on its own, with an empty window.
+    pub server_no_context_takeover: bool,
+    /// So does the client, so each of its messages inflates on its own.
+    pub client_no_context_takeover: bool,
+    /// The client asked for server_max_window_bits=15, which the response
+    /// has to repeat. (Smaller windows aren't offered, so those are declined.)
+    server_max_window_bits: bool,
+}
+
+impl DeflateParams {
+    /// The first permessage-deflate offer in a Sec-WebSocket-Extensions
+    /// value the server can take. None if there isn't one: an unknown or
+    /// repeated parameter, or a window the encoder can't keep to, and the
+    /// connection goes uncompressed.
+    pub fn negotiate(extensions: &str) -> Option<Self> {
+        extensions.split(',').find_map(|offer| {
+            let mut parts = offer.split(';').map(str::trim);
+            if parts.next() != Some("permessage-deflate") {
+                return None;
+            }
+            let mut params = Self::default();
+            let mut seen = Vec::new();
+            for part in parts {
+                let (name, value) = match part.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (part, None),
+                };
+                if seen.contains(&name) {
+                    return None;
+                }
+                seen.push(name);
+                let bits = value.map(|v| v.parse::<u8>().ok().filter(|b| (8..=15).contains(b)));
+                match (name, bits) {
+                    ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                    ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                    ("server_max_window_bits", Some(Some(15))) => params.server_max_window_bits = true,
+                    // Any window the client keeps to fits in the decoder's
+                    ("client_max_window_bits", None | Some(Some(_))) => {}
+                    _ => return None,
+                }
+            }
+            Some(params)
+        })
+    }
+    
+    /// The Sec-WebSocket-Extensions value that accepts the offer.
+    pub fn response(&self) -> String {
+        let mut response = "permessage-deflate".to_string();
+        if self.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits {
+            response.push_str("; server_max_window_bits=15");
+        }
+        response
+    }
+}
+
+/// The compressing side of permessage-deflate. One encoder per connection,
+/// locked from compressing a message until its frame is queued, so frames
+/// go out in the order their context was built in.
+struct Deflate {
+    params: DeflateParams,
+    encoder: Mutex<GzipEncoder<Vec<u8>>>,
+}
+
+impl Deflate {
+    fn new(params: DeflateParams) -> Self {
+        Self { params, encoder: Mutex::new(GzipEncoder::raw(Vec::new())) }
+    }
+    
+    /// One message's payload: `data` deflated to a sync flush, less the
+    /// four bytes every sync flush ends with.
+    fn compress(&self, encoder: &mut GzipEncoder<Vec<u8>>, data: &[u8]) -> Result<Vec<u8>, String> {
+        if self.params.server_no_context_takeover {
+            *encoder = GzipEncoder::raw(Vec::new());
+        }
+        encoder.write_all(data).map_err(|e| e.to_string())?;
+        encoder.sync_flush().map_err(|e| e.to_string())?;
+        let mut payload = std::mem::take(encoder.get_mut());
+        debug_assert!(payload.ends_with(&DEFLATE_TAIL));
+        payload.truncate(payload.len().saturating_sub(DEFLATE_TAIL.len()));
+        Ok(payload)
+    }
+}
+
+/// Frames waiting for a connection's writer thread.
+struct Outbox {
+    queue: Mutex<OutQueue>,
+    ready: Condvar,
+    /// Most bytes that may wait. 0 = no cap.
+    capacity: usize,
+}
+
+struct OutQueue {
+    frames: VecDeque<Vec<u8>>,
+    bytes: usize,
+    /// Set once a close frame is queued: nothing more is accepted.
+    closing_since: Option<Instant>,
+    /// Shut the socket down once the queue is written.
+    shutdown: bool,
+}
+
+impl Outbox {
+    fn new(capacity: usize) -> Self {
+        Self {
+            queue: Mutex::new(OutQueue { frames: VecDeque::new(), bytes: 0, closing_since: None, shutdown: false }),
+            ready: Condvar::new(),
+            capacity,
+        }
+    }
+    
+    /// Queue a frame. A queue that would go over capacity drops everything
+    /// not yet written and closes with 1008 instead: a reader that far
+    /// behind isn't coming back.
+    fn push(&self, frame: Vec<u8>, closes: bool) -> Result<(), String> {
+        let mut queue = self.queue.lock().map_err(|e| e.to_string())?;
+        if queue.closing_since.is_some() {
+            return Err("Connection closing".to_string());
+        }
+        let result = if !closes && self.capacity > 0 && queue.bytes + frame.len() > self.capacity {
+            queue.frames.clear();
+            queue.frames.push_back(encode_frame(&close_payload(CLOSE_POLICY_VIOLATION, "send queue full"), OPCODE_CLOSE));
+            queue.shutdown = true;
+            Err("send queue full".to_string())
+        } else {
+            queue.frames.push_back(frame);
+            Ok(())
+        };
+        queue.bytes = queue.frames.iter().map(Vec::len).sum();
+        if closes || result.is_err() {
+            queue.closing_since = Some(Instant::now());
+        }
+        self.ready.notify_one();
+        result
+    }
+    
+    /// Stop taking frames; shut the socket once what's queued is written.
+    fn shutdown(&self) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.closing_since.get_or_insert_with(Instant::now);
+            queue.shutdown = true;
+            self.ready.notify_one();
+        }
+    }
+}
+
+/// Drain `outbox` to `stream` until the connection closes, or until no one
+/// else holds the outbox. A write that fails, or makes no progress for
+/// `timeout` (zero = no limit), takes the connection down with it: the
+/// socket is shut in both directions, so its reader sees the end too.
+fn run_writer(mut stream: Stream, outbox: Arc<Outbox>, timeout: Duration) {
+    loop {
+        let (frame, grace_until) = {
+            let Ok(mut queue) = outbox.queue.lock() else { return };
+            loop {
+                if let Some(frame) = queue.frames.pop_front() {
+                    queue.bytes -= frame.len();
+                    break (Some(frame), queue.closing_since.map(|t| t + CLOSE_GRACE));
+                }
+                if queue.closing_since.is_some() {
+                    break (None, None);
+                }
+                if Arc::strong_count(&outbox) == 1 {
+                    return;
+                }
+                queue = match outbox.ready.wait_timeout(queue, Duration::from_secs(1)) {
+                    Ok((queue, _)) => queue,
+                    Err(_) => return,
+                };
+            }
+        };
+        
+        let Some(frame) = frame else { break };
+        // Closing only starts the clock once it's flagged, so check as we go
+        let give_up = || {
+            let deadline = grace_until.or_else(|| outbox.queue.lock().ok()?.closing_since.map(|t| t + CLOSE_GRACE));
+            deadline.is_some_and(|d| Instant::now() > d)
+        };
+        if write_patiently(&mut stream, &frame, timeout, give_up).is_err() {
+            outbox.shutdown();
+            break;
+        }
+    }
+    
+    if outbox.queue.lock().map(|q| q.shutdown).unwrap_or(true) {
+        let _ = stream.shutdown();
+    }
+}
+
+/// Write all of `frame` to a non-blocking stream, waiting out a full socket
+/// until `give_up` says to stop, or until it has taken nothing for
+/// `timeout` (zero = no limit). Over TLS, the frame's last records may
+/// still be waiting once it's taken, so they're flushed the same way.
+fn write_patiently(stream: &mut Stream, frame: &[u8], timeout: Duration, give_up: impl Fn() -> bool) -> Result<(), String> {
+    let mut written = 0;
+    let mut progress = Instant::now();
+    loop {
+        let step = if written < frame.len() {
+            stream.write(&frame[written..])
+        } else {
+            stream.flush().map(|()| 0)
+        };
+        match step {
+            Ok(0) if written < frame.len() => return Err("connection closed".to_string()),
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                written += n;
+                progress = Instant::now();
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                if give_up() {
+                    return Err("reader stopped reading".to_string());
+                }
+                if !timeout.is_zero() && progress.elapsed() > timeout {
+                    return Err("write timed out".to_string());
+                }
+                thread::sleep(WRITE_RETRY);
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// Bytes read per second over the last `INGRESS_WINDOW_SECS` seconds.
+struct IngressMeter {
+    /// Bytes/sec allowed on average over the window. 0 = no cap.
+    limit: u64,
+    start: Instant,
+    /// (second since start, bytes read in it), one slot per window second
+    buckets: [(u64, u64); INGRESS_WINDOW_SECS],
+}
+
+impl IngressMeter {
+    fn new() -> Self {
+        Self { limit: 0, start: Instant::now(), buckets: [(0, 0); INGRESS_WINDOW_SECS] }
+    }
+    
+    /// Count `bytes` as read now. True if the window is over the cap.
+    fn record(&mut self, bytes: u64) -> bool {
+        if self.limit == 0 {
+            return false;
+        }
+        let window = INGRESS_WINDOW_SECS as u64;
+        let sec = self.start.elapsed().as_secs();
+        let slot = &mut self.buckets[(sec % window) as usize];
+        if slot.0 != sec {
+            *slot = (sec, 0);
+        }
+        slot.1 += bytes;
+        let total: u64 = self.buckets.iter()
+            .filter(|(s, _)| sec - s < window)
+            .map(|(_, b)| b)
+            .sum();
+        total > self.limit.saturating_mul(window)
+    }
 }
 
 #[allow(dead_code)]
 impl WebSocket {
     /// Perform server-side WebSocket handshake.
-    /// Takes a TCP stream that has received an HTTP upgrade request.
-    pub fn accept(mut stream: TcpStream, request: &str) -> Result<Self, String> {
+    /// Takes a stream that has received an HTTP upgrade request.
+    /// With `deflate`, a permessage-deflate offer the server can take is
+    /// accepted; otherwise, or without one, messages go uncompressed.
+    /// Of the subprotocols the client offers, the first in `subprotocols`
+    /// is chosen; with none in common, none is.
+    /// A client asking for a version other than 13 (or none) gets a 400
+    /// naming 13 in `Sec-WebSocket-Version`, so it can retry with that.
+    pub fn accept(stream: impl Into<Stream>, request: &str, deflate: bool, subprotocols: &[&str]) -> Result<Self, String> {
+        let mut stream = stream.into();
+        let versions = header_values(request, "sec-websocket-version");
+        if !versions.iter().flat_map(|v| v.split(',')).any(|v| v.trim() == WS_VERSION) {
+            let response = format!(
+                "HTTP/1.1 400 Bad Request\r\n\
+                 Sec-WebSocket-Version: {}\r\n\
+                 Content-Length: 0\r\n\
+                 Connection: close\r\n\r\n",
+                WS_VERSION
+            );
+            let _ = stream.write_all(response.as_bytes());
+            return Err(format!("Unsupported Sec-WebSocket-Version: {}", versions.join(", ")));
+        }
+        
         // Extract Sec-WebSocket-Key from request headers
         let key = request
             .lines()
@@ -63,13 +487,31 @@ impl WebSocket {
         let hash = hasher.finalize();
         let accept = base64::engine::general_purpose::STANDARD.encode(hash);
         
+        // Offers may be spread over several header lines
+        let offers = header_values(request, "sec-websocket-extensions");
+        let params = deflate.then(|| DeflateParams::negotiate(&offers.join(","))).flatten();
+        let mut extensions = params
+            .map(|p| format!("Sec-WebSocket-Extensions: {}\r\n", p.response()))
+            .unwrap_or_default();
+        
+        let subprotocol = header_values(request, "sec-websocket-protocol")
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .find(|offered| subprotocols.contains(offered))
+            .map(str::to_string);
+        if let Some(chosen) = &subprotocol {
+            extensions.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", chosen));
+        }
+        
         // Send upgrade response
         let response = format!(
             "HTTP/1.1 101 Switching Protocols\r\n\
              Upgrade: websocket\r\n\
              Connection: Upgrade\r\n\
-             Sec-WebSocket-Accept: {}\r\n\r\n",
-            accept
+             Sec-WebSocket-Accept: {}\r\n\
+             {}\r\n",
+            accept, extensions
         );
         
         stream.write_all(response.as_bytes()).map_err(|e| e.to_string())?;
@@ -78,58 +520,147 @@ impl WebSocket {
         Ok(Self {
             stream,
             state: State::Open,
+            ingress: IngressMeter::new(),
+            max_message: 0,
+            outbox: None,
+            partial: Vec::new(),
+            fragments: None,
+            deflate: params.map(|p| Arc::new(Deflate::new(p))),
+            inflate_window: Vec::new(),
+            stats: Arc::new(Mutex::new(FrameStats::default())),
+            opened: Instant::now(),
+            subprotocol,
+            write_timeout: Duration::ZERO,
         })
     }
     
-    /// Read a message from the WebSocket.
+    /// Frames and bytes so far, in and out, across every clone.
+    pub fn stats(&self) -> FrameStats {
+        self.stats.lock().map(|s| *s).unwrap_or_default()
+    }
+    
+    /// How long since the handshake.
+    pub fn uptime(&self) -> Duration {
+        self.opened.elapsed()
+    }
+    
+    fn count_error(&self) {
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.protocol_errors += 1;
+        }
+    }
+    
+    /// The subprotocol agreed, if any was.
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+    
+    /// The permessage-deflate parameters agreed, if any were.
+    pub fn deflate(&self) -> Option<DeflateParams> {
+        self.deflate.as_ref().map(|d| d.params)
+    }
+    
+    /// Send through a queue of at most `capacity` bytes (0 = no cap) and a
+    /// writer thread of this connection's own. Call before cloning: clones
+    /// share the queue. A reader that falls so far behind that the queue
+    /// overflows is closed with 1008.
+    pub fn start_writer(&mut self, capacity: u64) -> Result<(), String> {
+        let stream = self.stream.try_clone().map_err(|e| e.to_string())?;
+        let outbox = Arc::new(Outbox::new(capacity as usize));
+        self.outbox = Some(Arc::clone(&outbox));
+        let timeout = self.write_timeout;
+        thread::spawn(move || run_writer(stream, outbox, timeout));
+        Ok(())
+    }
+    
+    /// Give the connection up when the peer takes nothing we write for
+    /// `timeout` (zero = wait forever). Call before `start_writer`.
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        self.write_timeout = timeout;
+    }
+    
+    /// Cap sustained ingress at `bytes_per_sec` (0 = no cap). A client over
+    /// it is closed with 1008.
+    pub fn set_ingress_limit(&mut self, bytes_per_sec: u64) {
+        self.ingress.limit = bytes_per_sec;
+    }
+    
+    /// Refuse frames with payloads over `bytes` (0 = no cap). A client
+    /// sending one is closed with 1009 before the payload is read.
+    pub fn set_max_message(&mut self, bytes: u64) {
+        self.max_message = bytes;
+    }
+    
+    /// Read a text message from the WebSocket, dropping binary ones.
     /// Returns None if no complete message available (non-blocking).
-    /// Returns Some(message) for text messages.
-    /// Handles ping/pong automatically.
     pub fn read(&mut self) -> Result<Option<String>, String> {
+        match self.read_message()? {
+            Some(Message::Text(text)) => Ok(Some(text)),
+            _ => Ok(None),
+        }
+    }
+    
+    /// Read a message from the WebSocket, text or binary.
+    /// Returns None if no complete message available (non-blocking).
+    /// Handles ping/pong automatically.
+    /// A frame that has only partly arrived is kept until the rest does.
+    pub fn read_message(&mut self) -> Result<Option<Message>, String> {
         if self.state != State::Open {
             return Ok(None);
         }
         
-        // Try to read frame header (2 bytes minimum)
-        let mut header = [0u8; 2];
-        match self.stream.read_exact(&mut header) {
-            Ok(_) => {}
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
-            Err(e) => {
-                self.state = State::Closed;
-                return Err(e.to_string());
-            }
+        // Frame header: 2 bytes, then any extended length and masking key
+        if !self.fill(2)? {
+            return Ok(None);
+        }
+        let fin = (self.partial[0] & 0x80) != 0;
+        let compressed = (self.partial[0] & RSV1) != 0;
+        let opcode = self.partial[0] & 0x0F;
+        let masked = (self.partial[1] & 0x80) != 0;
+        let ext_len = match self.partial[1] & 0x7F {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        // Client messages are always masked
+        let header_len = 2 + ext_len + if masked { 4 } else { 0 };
+        if !self.fill(header_len)? {
+            return Ok(None);
         }
         
-        let _fin = (header[0] & 0x80) != 0;
-        let opcode = header[0] & 0x0F;
-        let masked = (header[1] & 0x80) != 0;
-        let mut payload_len = (header[1] & 0x7F) as usize;
+        let ext = &self.partial[2..2 + ext_len];
+        let payload_len = match ext_len {
+            2 => u16::from_be_bytes([ext[0], ext[1]]) as usize,
+            8 => u64::from_be_bytes(ext.try_into().unwrap_or_default()) as usize,
+            _ => (self.partial[1] & 0x7F) as usize,
+        };
+        let mask = masked.then(|| {
+            let m = &self.partial[2 + ext_len..header_len];
+            [m[0], m[1], m[2], m[3]]
+        });
         
-        // Extended payload length
-        if payload_len == 126 {
-            let mut ext = [0u8; 2];
-            self.stream.read_exact(&mut ext).map_err(|e| e.to_string())?;
-            payload_len = u16::from_be_bytes(ext) as usize;
-        } else if payload_len == 127 {
-            let mut ext = [0u8; 8];
-            self.stream.read_exact(&mut ext).map_err(|e| e.to_string())?;
-            payload_len = u64::from_be_bytes(ext) as usize;
+        // Refuse before allocating: the length is whatever the client claims
+        if self.max_message > 0 && payload_len as u64 > self.max_message {
+            self.count_error();
+            self.partial.clear();
+            self.close_with(CLOSE_MESSAGE_TOO_BIG, "message too big");
+            return Err(format!("message of {} bytes is over the cap", payload_len));
         }
         
-        // Read masking key (client messages are always masked)
-        let mask = if masked {
-            let mut m = [0u8; 4];
-            self.stream.read_exact(&mut m).map_err(|e| e.to_string())?;
-            Some(m)
-        } else {
-            None
-        };
+        if !self.fill(header_len + payload_len)? {
+            return Ok(None);
+        }
+        let mut payload = std::mem::take(&mut self.partial).split_off(header_len);
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.frames_in.count(opcode);
+            stats.bytes_in += (header_len + payload_len) as u64;
+        }
         
-        // Read payload
-        let mut payload = vec![0u8; payload_len];
-        if payload_len > 0 {
-            self.stream.read_exact(&mut payload).map_err(|e| e.to_string())?;
+        // Every frame counts toward the cap, control frames included
+        if self.ingress.record((header_len + payload_len) as u64) {
+            self.count_error();
+            self.close_with(CLOSE_POLICY_VIOLATION, "ingress rate exceeded");
+            return Err("ingress rate exceeded".to_string());
         }
         
         // Unmask if needed
@@ -141,9 +672,31 @@ impl WebSocket {
         
         // Handle by opcode
         match opcode {
-            OPCODE_TEXT => {
-                let text = String::from_utf8(payload).map_err(|e| e.to_string())?;
-                Ok(Some(text))
+            OPCODE_TEXT | OPCODE_BINARY => {
+                if self.fragments.is_some() {
+                    return Err(self.protocol_error("new message before the last one's final fragment"));
+                }
+                if !fin {
+                    self.fragments = Some((opcode, compressed, payload));
+                    return Ok(None);
+                }
+                self.message(opcode, compressed, payload).map(Some)
+            }
+            OPCODE_CONTINUATION => {
+                let Some((first, compressed, mut so_far)) = self.fragments.take() else {
+                    return Err(self.protocol_error("continuation frame without a message to continue"));
+                };
+                so_far.extend_from_slice(&payload);
+                if self.max_message > 0 && so_far.len() as u64 > self.max_message {
+                    self.count_error();
+                    self.close_with(CLOSE_MESSAGE_TOO_BIG, "message too big");
+                    return Err(format!("fragmented message of {}+ bytes is over the cap", so_far.len()));
+                }
+                if !fin {
+                    self.fragments = Some((first, compressed, so_far));
+                    return Ok(None);
+                }
+                self.message(first, compressed, so_far).map(Some)
             }
             OPCODE_CLOSE => {
                 self.state = State::Closing;
@@ -162,48 +715,148 @@ impl WebSocket {
         }
     }
     
-    /// Send a text message.
+    /// A whole text or binary message, from the payload of its frames.
+    fn message(&mut self, opcode: u8, compressed: bool, payload: Vec<u8>) -> Result<Message, String> {
+        let payload = if compressed { self.inflate(&payload)? } else { payload };
+        if opcode == OPCODE_BINARY {
+            return Ok(Message::Binary(payload));
+        }
+        let text = String::from_utf8(payload).map_err(|e| {
+            self.count_error();
+            e.to_string()
+        })?;
+        Ok(Message::Text(text))
+    }
+    
+    /// Close with 1002 for breaking the framing rules, and say why.
+    fn protocol_error(&mut self, why: &str) -> String {
+        self.count_error();
+        self.fragments = None;
+        self.close_with(CLOSE_PROTOCOL_ERROR, why);
+        why.to_string()
+    }
+    
+    /// A compressed message's payload, inflated. The message size cap
+    /// applies to what it inflates to as well, and without one MAX_INFLATED
+    /// does.
+    fn inflate(&mut self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let Some(params) = self.deflate() else {
+            self.count_error();
+            self.close_with(CLOSE_PROTOCOL_ERROR, "compressed frame without permessage-deflate");
+            return Err("compressed frame without permessage-deflate".to_string());
+        };
+        
+        let history: &[u8] = if params.client_no_context_takeover { &[] } else { &self.inflate_window };
+        let mut decoder = GzipDecoder::raw(payload.chain(&DEFLATE_TAIL[..]), history);
+        let limit = if self.max_message > 0 { self.max_message } else { MAX_INFLATED };
+        let mut message = Vec::new();
+        if let Err(e) = (&mut decoder).take(limit.saturating_add(1)).read_to_end(&mut message) {
+            self.count_error();
+            self.close_with(CLOSE_INVALID_DATA, "bad compressed data");
+            return Err(e.to_string());
+        }
+        if message.len() as u64 > limit {
+            self.count_error();
+            self.close_with(CLOSE_MESSAGE_TOO_BIG, "message too big");
+            return Err(format!("message inflates to over {} bytes", limit));
+        }
+        
+        if !params.client_no_context_takeover {
+            self.inflate_window = decoder.window().to_vec();
+        }
+        Ok(message)
+    }
+    
+    /// Read until `partial` holds `want` bytes. False if the socket has
+    /// nothing more for now: what did arrive waits for the next call.
+    fn fill(&mut self, want: usize) -> Result<bool, String> {
+        let mut have = self.partial.len();
+        if have >= want {
+            return Ok(true);
+        }
+        self.partial.resize(want, 0);
+        while have < want {
+            match self.stream.read(&mut self.partial[have..]) {
+                Ok(0) => {
+                    self.partial.truncate(have);
+                    self.state = State::Closed;
+                    return Err(if have == 0 { "connection closed" } else { "connection closed mid-frame" }.to_string());
+                }
+                Ok(n) => have += n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.partial.truncate(have);
+                    return Ok(false);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.partial.truncate(have);
+                    self.state = State::Closed;
+                    return Err(e.to_string());
+                }
+            }
+        }
+        Ok(true)
+    }
+    
+    /// Send a text message, compressed if permessage-deflate was agreed.
     pub fn send(&mut self, message: &str) -> Result<(), String> {
         if self.state != State::Open {
             return Err("Connection not open".to_string());
         }
-        self.write_frame(message.as_bytes(), OPCODE_TEXT)
+        match self.deflate.clone() {
+            Some(deflate) if !message.is_empty() => {
+                let mut encoder = deflate.encoder.lock().map_err(|e| e.to_string())?;
+                let payload = deflate.compress(&mut encoder, message.as_bytes())?;
+                self.write_frame(&payload, OPCODE_TEXT | RSV1)
+            }
+            _ => self.write_frame(message.as_bytes(), OPCODE_TEXT),
+        }
     }
     
     /// Write a WebSocket frame. Server frames are NOT masked.
     fn write_frame(&mut self, payload: &[u8], opcode: u8) -> Result<(), String> {
-        let len = payload.len();
-        let mut frame = Vec::with_capacity(10 + len);
-        
-        // First byte: FIN + opcode
-        frame.push(0x80 | opcode);
-        
-        // Second byte: length (no mask bit for server->client)
-        if len < 126 {
-            frame.push(len as u8);
-        } else if len < 65536 {
-            frame.push(126);
-            frame.extend_from_slice(&(len as u16).to_be_bytes());
-        } else {
-            frame.push(127);
-            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        let frame = encode_frame(payload, opcode);
+        let len = frame.len() as u64;
+        match &self.outbox {
+            Some(outbox) => outbox.push(frame, opcode == OPCODE_CLOSE),
+            None => self.stream.write_all(&frame).map_err(|e| e.to_string()),
+        }?;
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.frames_out.count(opcode);
+            stats.bytes_out += len;
         }
-        
-        // Payload (unmasked)
-        frame.extend_from_slice(payload);
-        
-        self.stream.write_all(&frame).map_err(|e| e.to_string())
+        Ok(())
     }
     
-    /// Close the connection gracefully.
+    /// Close the connection gracefully (1000, no reason).
     pub fn close(&mut self) {
+        self.close_with(CLOSE_NORMAL, "");
+    }
+    
+    /// Close with a status code and a short reason, so the client can tell
+    /// why. Reasons over 123 bytes are cut at a character boundary.
+    pub fn close_with(&mut self, code: u16, reason: &str) {
         if self.state == State::Open {
             self.state = State::Closing;
-            let _ = self.write_frame(&[], OPCODE_CLOSE);
+            let _ = self.write_frame(&close_payload(code, reason), OPCODE_CLOSE);
             self.state = State::Closed;
         }
     }
     
+    /// Shut the connection down in both directions. Affects every clone,
+    /// so a thread blocked reading another clone sees the connection end.
+    /// With a writer thread, frames already queued (a close frame, say) go
+    /// out first, within a few seconds' grace.
+    pub fn shutdown(&mut self) {
+        self.state = State::Closed;
+        match &self.outbox {
+            Some(outbox) => outbox.shutdown(),
+            None => {
+                let _ = self.stream.shutdown();
+            }
+        }
+    }
+    
     /// Get the peer address.
     pub fn peer_addr(&self) -> String {
         self.stream.peer_addr().map(|a| a.to_string()).unwrap_or_default()
@@ -214,6 +867,434 @@ impl WebSocket {
         Ok(WebSocket {
             stream: self.stream.try_clone().map_err(|e| e.to_string())?,
             state: self.state,
+            ingress: IngressMeter::new(),
+            max_message: self.max_message,
+            outbox: self.outbox.clone(),
+            partial: Vec::new(),
+            fragments: None,
+            deflate: self.deflate.clone(),
+            inflate_window: Vec::new(),
+            stats: Arc::clone(&self.stats),
+            opened: self.opened,
+            subprotocol: self.subprotocol.clone(),
+            write_timeout: self.write_timeout,
         })
     }
 }
+
+/// Every value of header `name` in `request`, which may be spread over
+/// several header lines.
+fn header_values<'a>(request: &'a str, name: &str) -> Vec<&'a str> {
+    request
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+        .collect()
+}
+
+/// A whole frame: FIN + opcode (and RSV1, if given), length, unmasked payload.
+fn encode_frame(payload: &[u8], opcode: u8) -> Vec<u8> {
+    let len = payload.len();
+    let mut frame = Vec::with_capacity(10 + len);
+    
+    // First byte: FIN + opcode
+    frame.push(0x80 | opcode);
+    
+    // Second byte: length (no mask bit for server->client)
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len < 65536 {
+        frame.push(126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    
+    // Payload (unmasked)
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Close frame body: 2-byte big-endian code, then the UTF-8 reason.
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut end = reason.len().min(MAX_CLOSE_REASON);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    /// The next whole message `ws` reads within a couple of seconds.
+    fn read_within(ws: &mut WebSocket) -> Option<String> {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            if let Some(message) = ws.read().unwrap() {
+                return Some(message);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        None
+    }
+
+    /// A server end handshaken with `extensions` offered, the client end,
+    /// and the handshake response the client got.
+    fn handshake(extensions: &str, deflate: bool) -> (WebSocket, TcpStream, String) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let request = format!(
+            "GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Extensions: {}\r\n\r\n",
+            extensions
+        );
+        let ws = WebSocket::accept(stream, &request, deflate, &[]).unwrap();
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8];
+            client.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        (ws, client, String::from_utf8(response).unwrap())
+    }
+
+    /// A masked client frame: `first` is FIN, RSV and opcode.
+    fn masked_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![first, 0x80 | 126];
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// The first byte and payload of the next server frame.
+    fn read_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        client.read_exact(&mut head).unwrap();
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut ext = [0u8; 2];
+                client.read_exact(&mut ext).unwrap();
+                u16::from_be_bytes(ext) as usize
+            }
+            127 => {
+                let mut ext = [0u8; 8];
+                client.read_exact(&mut ext).unwrap();
+                u64::from_be_bytes(ext) as usize
+            }
+            n => n as usize,
+        };
+        let mut payload = vec![0u8; len];
+        client.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+
+    /// What a client's deflater sends for `text`: a sync flush, less its tail.
+    fn deflate_message(encoder: &mut GzipEncoder<Vec<u8>>, text: &str) -> Vec<u8> {
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.sync_flush().unwrap();
+        let mut payload = std::mem::take(encoder.get_mut());
+        payload.truncate(payload.len() - DEFLATE_TAIL.len());
+        payload
+    }
+
+    fn inflate_message(payload: &[u8], history: &[u8]) -> (String, Vec<u8>) {
+        let mut decoder = GzipDecoder::raw(payload.chain(&DEFLATE_TAIL[..]), history);
+        let mut text = String::new();
+        decoder.read_to_string(&mut text).unwrap();
+        (text, decoder.window().to_vec())
+    }
+
+    #[test]
+    fn deflate_offers_are_taken_or_declined() {
+        let plain = DeflateParams::default();
+        let both = DeflateParams { server_no_context_takeover: true, client_no_context_takeover: true, ..plain };
+        assert_eq!(DeflateParams::negotiate("permessage-deflate"), Some(plain));
+        // What browsers send
+        assert_eq!(DeflateParams::negotiate("permessage-deflate; client_max_window_bits"), Some(plain));
+        assert_eq!(
+            DeflateParams::negotiate("permessage-deflate; server_no_context_takeover; client_no_context_takeover"),
+            Some(both)
+        );
+        assert_eq!(both.response(), "permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+        let full_window = DeflateParams::negotiate("permessage-deflate; server_max_window_bits=\"15\"").unwrap();
+        assert_eq!(full_window.response(), "permessage-deflate; server_max_window_bits=15");
+
+        // Declined: the connection falls back to uncompressed
+        for offer in [
+            "",
+            "x-webkit-deflate-frame",
+            "permessage-deflate; server_max_window_bits=10",
+            "permessage-deflate; client_max_window_bits=99",
+            "permessage-deflate; server_no_context_takeover=1",
+            "permessage-deflate; client_no_context_takeover; client_no_context_takeover",
+            "permessage-deflate; mystery_param",
+        ] {
+            assert_eq!(DeflateParams::negotiate(offer), None, "{}", offer);
+        }
+        // A later offer can still be taken
+        assert_eq!(
+            DeflateParams::negotiate("permessage-deflate; server_max_window_bits=9, permessage-deflate; client_no_context_takeover"),
+            Some(DeflateParams { client_no_context_takeover: true, ..plain })
+        );
+    }
+
+    #[test]
+    fn compressed_messages_decode_with_and_without_context_takeover() {
+        let text = r#"{"type":"device:update","data":{"id":"robot-01","latitude":34.0522,"longitude":-118.2437,"battery":87}}"#;
+        for offer in ["permessage-deflate", "permessage-deflate; server_no_context_takeover; client_no_context_takeover"] {
+            let (mut ws, mut client, response) = handshake(offer, true);
+            assert!(response.contains(&format!("Sec-WebSocket-Extensions: {}\r\n", offer)), "{}", response);
+            let takeover = !ws.deflate().unwrap().server_no_context_takeover;
+
+            // Server to client: the second copy refers back to the first only with takeover
+            ws.send(text).unwrap();
+            ws.send(text).unwrap();
+            let (first_byte, first) = read_frame(&mut client);
+            let (_, second) = read_frame(&mut client);
+            assert_eq!(first_byte, 0xC1, "FIN, RSV1, text");
+            let (decoded, window) = inflate_message(&first, &[]);
+            assert_eq!(decoded, text);
+            let history = if takeover { window } else { Vec::new() };
+            assert_eq!(inflate_message(&second, &history).0, text);
+            if takeover {
+                assert!(second.len() < first.len() / 4, "{} then {}", first.len(), second.len());
+            } else {
+                assert_eq!(second, first);
+            }
+
+            // Client to server, the same way, with an uncompressed message between
+            let mut encoder = GzipEncoder::raw(Vec::new());
+            client.write_all(&masked_frame(0xC1, &deflate_message(&mut encoder, text))).unwrap();
+            client.write_all(&masked_frame(0x81, b"plain")).unwrap();
+            if !takeover {
+                encoder = GzipEncoder::raw(Vec::new());
+            }
+            client.write_all(&masked_frame(0xC1, &deflate_message(&mut encoder, text))).unwrap();
+            assert_eq!(read_within(&mut ws).as_deref(), Some(text));
+            assert_eq!(read_within(&mut ws).as_deref(), Some("plain"));
+            assert_eq!(read_within(&mut ws).as_deref(), Some(text));
+        }
+    }
+
+    #[test]
+    fn without_deflate_the_offer_is_ignored_and_compressed_frames_refused() {
+        let (mut ws, mut client, response) = handshake("permessage-deflate", false);
+        assert!(!response.to_lowercase().contains("sec-websocket-extensions"), "{}", response);
+        assert_eq!(ws.deflate(), None);
+
+        ws.send("hello").unwrap();
+        assert_eq!(read_frame(&mut client), (0x81, b"hello".to_vec()));
+
+        let mut encoder = GzipEncoder::raw(Vec::new());
+        client.write_all(&masked_frame(0xC1, &deflate_message(&mut encoder, "hello"))).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(ws.read().is_err());
+        let (close, payload) = read_frame(&mut client);
+        assert_eq!((close, u16::from_be_bytes([payload[0], payload[1]])), (0x88, CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn fragmented_messages_are_reassembled_around_control_frames() {
+        let (mut ws, mut client, _) = handshake("", false);
+        client.write_all(&masked_frame(0x01, b"hel")).unwrap();
+        client.write_all(&masked_frame(0x89, b"ping")).unwrap();
+        client.write_all(&masked_frame(0x00, b"lo ")).unwrap();
+        client.write_all(&masked_frame(0x80, b"world")).unwrap();
+        assert_eq!(read_within(&mut ws).as_deref(), Some("hello world"));
+        assert_eq!(read_frame(&mut client), (0x8A, b"ping".to_vec()));
+    }
+
+    #[test]
+    fn an_orphan_continuation_frame_is_a_protocol_error() {
+        let (mut ws, mut client, _) = handshake("", false);
+        client.write_all(&masked_frame(0x80, b"stray")).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(ws.read().is_err());
+        let (close, payload) = read_frame(&mut client);
+        assert_eq!((close, u16::from_be_bytes([payload[0], payload[1]])), (0x88, CLOSE_PROTOCOL_ERROR));
+        assert_eq!(ws.stats().protocol_errors, 1);
+    }
+
+    #[test]
+    fn a_new_message_inside_a_fragmented_one_is_a_protocol_error() {
+        let (mut ws, mut client, _) = handshake("", false);
+        client.write_all(&masked_frame(0x01, b"first half")).unwrap();
+        client.write_all(&masked_frame(0x82, b"interloper")).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(ws.read(), Ok(None));
+        assert!(ws.read().is_err());
+        let (close, payload) = read_frame(&mut client);
+        assert_eq!((close, u16::from_be_bytes([payload[0], payload[1]])), (0x88, CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn a_compressed_message_may_not_inflate_past_the_cap() {
+        let (mut ws, mut client, _) = handshake("permessage-deflate", true);
+        ws.set_max_message(1000);
+        let mut encoder = GzipEncoder::raw(Vec::new());
+        // A few dozen bytes on the wire, 5000 once inflated
+        client.write_all(&masked_frame(0xC1, &deflate_message(&mut encoder, &"x".repeat(5000)))).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(ws.read().is_err());
+        let (close, payload) = read_frame(&mut client);
+        assert_eq!((close, u16::from_be_bytes([payload[0], payload[1]])), (0x88, CLOSE_MESSAGE_TOO_BIG));
+        let stats = ws.stats();
+        assert_eq!((stats.frames_in.text, stats.frames_out.close, stats.protocol_errors), (1, 1, 1));
+    }
+
+    #[test]
+    fn close_frame_carries_code_and_reason() {
+        let frame = encode_frame(&close_payload(CLOSE_POLICY_VIOLATION, "ingress rate exceeded"), OPCODE_CLOSE);
+        assert_eq!(frame[0], 0x88);
+        assert_eq!(frame[1] as usize, 2 + "ingress rate exceeded".len());
+        assert_eq!(u16::from_be_bytes([frame[2], frame[3]]), 1008);
+        assert_eq!(&frame[4..], b"ingress rate exceeded");
+
+        assert_eq!(encode_frame(&close_payload(CLOSE_NORMAL, ""), OPCODE_CLOSE), [0x88, 0x02, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn overflowing_queue_keeps_only_a_1008_close() {
+        let outbox = Outbox::new(100);
+        assert_eq!(outbox.push(vec![0; 60], false), Ok(()));
+        assert_eq!(outbox.push(vec![0; 40], false), Ok(()));
+        assert!(outbox.push(vec![0; 1], false).is_err());
+        assert!(outbox.push(vec![0; 1], false).is_err(), "nothing more once closing");
+
+        let queue = outbox.queue.lock().unwrap();
+        assert!(queue.shutdown);
+        assert_eq!(queue.frames.len(), 1);
+        assert_eq!(queue.frames[0], encode_frame(&close_payload(1008, "send queue full"), OPCODE_CLOSE));
+    }
+
+    #[test]
+    fn frames_split_across_segments_are_reassembled() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut ws = WebSocket::accept(stream, "GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", false, &[]).unwrap();
+
+        // A masked client text frame with a 16-bit extended length
+        let text = "x".repeat(300);
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x81, 0x80 | 126];
+        frame.extend_from_slice(&(text.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&mask);
+        frame.extend(text.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+        // Split mid-header, then mid-payload: nothing until the last piece lands
+        for (start, end) in [(0, 1), (1, 50)] {
+            client.write_all(&frame[start..end]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(ws.read(), Ok(None));
+        }
+        client.write_all(&frame[50..]).unwrap();
+        assert_eq!(read_within(&mut ws), Some(text));
+
+        // The connection carries on with the next frame
+        client.write_all(&[0x81, 0x82, 0, 0, 0, 0, b'o', b'k']).unwrap();
+        assert_eq!(read_within(&mut ws).as_deref(), Some("ok"));
+    }
+
+    #[test]
+    fn only_version_13_is_accepted_and_others_are_told_so() {
+        let upgrade = |version: &str| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let request = format!("GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n", version);
+            let accepted = WebSocket::accept(stream, &request, false, &[]);
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8];
+                client.read_exact(&mut byte).unwrap();
+                response.push(byte[0]);
+            }
+            (accepted.is_ok(), String::from_utf8(response).unwrap())
+        };
+
+        let (ok, response) = upgrade("Sec-WebSocket-Version: 13\r\n");
+        assert!(ok);
+        assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", response);
+
+        for version in ["Sec-WebSocket-Version: 8\r\n", "Sec-WebSocket-Version: 14\r\n", ""] {
+            let (ok, response) = upgrade(version);
+            assert!(!ok, "{:?}", version);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+            assert!(response.contains("Sec-WebSocket-Version: 13\r\n"), "{}", response);
+            assert!(!response.contains("Sec-WebSocket-Accept"), "{}", response);
+        }
+    }
+
+    #[test]
+    fn subprotocols_are_chosen_from_the_clients_offers() {
+        let offer = |offers: &str, ours: &[&str]| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let request = format!("GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n", offers);
+            let ws = WebSocket::accept(stream, &request, false, ours).unwrap();
+            (ws, client_response(&mut client), client)
+        };
+        fn client_response(client: &mut TcpStream) -> String {
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8];
+                client.read_exact(&mut byte).unwrap();
+                response.push(byte[0]);
+            }
+            String::from_utf8(response).unwrap()
+        }
+
+        // The client's order wins, over one line or several
+        let (ws, response, _) = offer("Sec-WebSocket-Protocol: chat, bin\r\nSec-WebSocket-Protocol: json\r\n", &["json", "bin"]);
+        assert_eq!(ws.subprotocol(), Some("bin"));
+        assert!(response.contains("Sec-WebSocket-Protocol: bin\r\n"), "{}", response);
+
+        let (ws, response, _) = offer("Sec-WebSocket-Protocol: chat\r\n", &["bin"]);
+        assert_eq!(ws.subprotocol(), None);
+        assert!(!response.contains("Sec-WebSocket-Protocol"), "{}", response);
+
+        // Binary messages come through read_message, and read skips them
+        let (mut ws, _, mut client) = offer("", &[]);
+        assert_eq!(ws.try_clone().unwrap().subprotocol(), None);
+        client.write_all(&[0x82, 0x83, 0, 0, 0, 0, 1, 2, 3]).unwrap();
+        client.write_all(&[0x81, 0x82, 0, 0, 0, 0, b'o', b'k']).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut messages = Vec::new();
+        while messages.len() < 2 && Instant::now() < deadline {
+            match ws.read_message().unwrap() {
+                Some(message) => messages.push(message),
+                None => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+        assert_eq!(messages, [Message::Binary(vec![1, 2, 3]), Message::Text("ok".to_string())]);
+    }
+
+    #[test]
+    fn long_close_reasons_are_cut_to_fit_a_control_frame() {
+        // 'é' is two bytes; 62 of them straddle the 123-byte limit
+        let reason = "é".repeat(62);
+        let payload = close_payload(CLOSE_MESSAGE_TOO_BIG, &reason);
+        assert_eq!(payload.len(), 2 + 122);
+        assert_eq!(std::str::from_utf8(&payload[2..]).unwrap(), "é".repeat(61));
+
+        let payload = close_payload(CLOSE_MESSAGE_TOO_BIG, &"x".repeat(500));
+        assert_eq!(payload.len(), 125);
+        assert_eq!(encode_frame(&payload, OPCODE_CLOSE)[1], 125);
+    }
+}
@@ -0,0 +1,371 @@
+//! Who may connect and what they may do: access lists, proxies, TLS, the
+//! viewer token, device-scoped tokens and per-operator preferences.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{admin_config, TestServer, ADMIN, TIMEOUT};
+use globalrts::access::AccessList;
+use globalrts::Config;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde_json::{json, Value};
+
+// ============================================================================
+// ACCESS LISTS
+// ============================================================================
+
+// Connection allow/deny lists: a denied peer is dropped before the server
+// reads its request.
+
+#[test]
+fn denied_peer_is_closed_without_a_response() {
+    let access = AccessList::new("127.0.0.0/8", "127.0.0.1/32").unwrap();
+    let server = TestServer::start_with("access-deny", Config { access, ..Config::default() });
+
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let _ = stream.write_all(b"GET /api/devices HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    assert!(response.is_empty(), "{}", String::from_utf8_lossy(&response));
+}
+
+// ============================================================================
+// TRUSTED PROXIES
+// ============================================================================
+
+// Behind a trusted proxy (here: the test itself, on 127.0.0.1), the
+// client's address comes from X-Forwarded-For / X-Real-IP, and the
+// allow/deny lists apply to it rather than to the proxy.
+
+fn proxy_config() -> Config {
+    let access = AccessList::new("", "203.0.113.7").unwrap().with_trusted_proxies("127.0.0.1").unwrap();
+    Config { access, ..Config::default() }
+}
+
+/// GET /api/version with extra header lines; the raw response.
+fn get_with(server: &TestServer, headers: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let request = format!("GET /api/version HTTP/1.1\r\nHost: 127.0.0.1\r\n{}\r\n", headers);
+    let _ = stream.write_all(request.as_bytes());
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[test]
+fn forwarded_client_is_checked_against_the_lists() {
+    let server = TestServer::start_with("proxy", proxy_config());
+
+    assert!(get_with(&server, "").starts_with("HTTP/1.1 200"));
+    assert!(get_with(&server, "X-Forwarded-For: 198.51.100.1\r\n").starts_with("HTTP/1.1 200"));
+
+    // The denied client, however it's forwarded
+    assert_eq!(get_with(&server, "X-Forwarded-For: 203.0.113.7\r\n"), "");
+    assert_eq!(get_with(&server, "X-Forwarded-For: 198.51.100.1, 203.0.113.7\r\n"), "");
+    assert_eq!(get_with(&server, "X-Forwarded-For: 198.51.100.1\r\nX-Forwarded-For: 203.0.113.7\r\n"), "");
+    assert_eq!(get_with(&server, "X-Real-IP: 203.0.113.7\r\n"), "");
+
+    // Left of the nearest untrusted hop is the client's own say-so
+    assert!(get_with(&server, "X-Forwarded-For: 203.0.113.7, 198.51.100.1\r\n").starts_with("HTTP/1.1 200"));
+}
+
+// ============================================================================
+// TLS
+// ============================================================================
+
+// With GLOBALRTS_TLS_CERT and GLOBALRTS_TLS_KEY set, the server speaks
+// TLS: API calls over https://, WebSockets over wss://, and plaintext
+// refused. The certificate in `tests/fixtures/tls` is for localhost and
+// 127.0.0.1, signed by the test CA next to it.
+
+fn start(label: &str) -> TestServer {
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+    TestServer::start_with(label, Config {
+        tls_cert: Some(format!("{}/cert.pem", fixtures)),
+        tls_key: Some(format!("{}/key.pem", fixtures)),
+        ..Config::default()
+    })
+}
+
+/// A TLS connection to the server that trusts only the test CA.
+fn connect(port: u16) -> StreamOwned<ClientConnection, TcpStream> {
+    let ca = CertificateDer::from_pem_file(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/ca.pem")).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(ca).unwrap();
+    let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let name = ServerName::try_from("localhost").unwrap();
+    let conn = ClientConnection::new(Arc::new(config), name).unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    StreamOwned::new(conn, stream)
+}
+
+#[test]
+fn api_calls_work_over_tls_and_plaintext_is_refused() {
+    let server = start("tls-api");
+
+    let mut tls = connect(server.port);
+    tls.write_all(b"GET /api/version HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    let _ = tls.read_to_end(&mut response);
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let body: Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+
+    // A plaintext request gets a TLS alert at most, never a response
+    let mut plain = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    plain.set_read_timeout(Some(TIMEOUT)).unwrap();
+    plain.write_all(b"GET /api/version HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    let _ = plain.read_to_end(&mut response);
+    assert!(!response.starts_with(b"HTTP/"), "{}", String::from_utf8_lossy(&response));
+}
+
+#[test]
+fn websockets_upgrade_over_tls() {
+    let server = start("tls-ws");
+
+    let mut tls = connect(server.port);
+    tls.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        tls.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&head));
+
+    // A masked text frame asking for the device list
+    let message = json!({"type": "getDevices", "data": {}}).to_string();
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x81, 0x80 | message.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(message.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    tls.write_all(&frame).unwrap();
+
+    let mut header = [0u8; 2];
+    tls.read_exact(&mut header).unwrap();
+    assert_eq!(header[0], 0x81);
+    let len = match header[1] {
+        126 => {
+            let mut ext = [0u8; 2];
+            tls.read_exact(&mut ext).unwrap();
+            u16::from_be_bytes(ext) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0u8; len];
+    tls.read_exact(&mut payload).unwrap();
+    let reply: Value = serde_json::from_slice(&payload).unwrap();
+    assert_eq!(reply["type"], "devices:list", "{}", reply);
+}
+
+// ============================================================================
+// VIEWER TOKEN
+// ============================================================================
+
+// The viewer token reads and watches: it lists devices and follows the
+// fleet, but can't revoke, command or otherwise change anything.
+
+const VIEWER: &str = "viewer-secret";
+
+fn viewer_token_config() -> Config {
+    Config { viewer_token: Some(VIEWER.to_string()), ..admin_config() }
+}
+
+#[test]
+fn a_viewer_lists_devices_but_cannot_revoke_one() {
+    let server = TestServer::start_with("viewer-http", viewer_token_config());
+    server.pair("robot-01", "robot");
+
+    let (status, devices) = server.http("GET", "/api/devices", None, Some(VIEWER));
+    assert_eq!(status, 200, "{}", devices);
+    assert_eq!(devices["devices"][0]["id"], "robot-01");
+
+    // Leaving the token off is no way round it
+    for token in [Some(VIEWER), None] {
+        let (status, refused) = server.http("DELETE", "/api/devices/robot-01", None, token);
+        assert_eq!(status, 403, "{}", refused);
+    }
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"].as_array().unwrap().len(), 1, "{}", devices);
+
+    for (token, role) in [(Some(VIEWER), "viewer"), (Some(ADMIN), "admin"), (None, "viewer"), (Some("someone"), "viewer")] {
+        let (_, whoami) = server.http("GET", "/api/whoami", None, token);
+        assert_eq!(whoami["role"], role, "{:?}", token);
+    }
+}
+
+#[test]
+fn a_viewer_ui_watches_but_cannot_command() {
+    let server = TestServer::start_with("viewer-ws", viewer_token_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    // Browsers can't set headers on a WebSocket: the token rides the URL
+    let mut viewer = server.ws(&format!("/?token={}", VIEWER), "");
+    viewer.send(&json!({"type": "getDevices", "data": {}}));
+    assert_eq!(viewer.recv_type("devices:list")["data"][0]["id"], "robot-01");
+
+    viewer.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "ring", "payload": {}}}));
+    assert_eq!(viewer.recv_type("error")["data"]["code"], "read_only");
+    viewer.send(&json!({"type": "revokeDevice", "data": {"device_id": "robot-01"}}));
+    assert_eq!(viewer.recv_type("error")["data"]["code"], "read_only");
+    let mut tokenless = server.ui(None);
+    tokenless.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "ring", "payload": {}}}));
+    assert_eq!(tokenless.recv_type("error")["data"]["code"], "read_only");
+    assert!(device.collect_type("command", Duration::from_millis(300)).is_empty());
+
+    // The admin's UI next to it still acts, and the viewer sees it happen
+    let mut operator = server.ui(Some(ADMIN));
+    operator.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "ring", "payload": {}}}));
+    assert_eq!(operator.recv_type("command:sent")["data"]["status"], "sent");
+    device.recv_type("command");
+    viewer.recv_type("command:status");
+}
+
+#[test]
+fn a_made_up_token_is_only_a_viewer() {
+    let server = TestServer::start_with("viewer-made-up", viewer_token_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    let (status, refused) = server.http("DELETE", "/api/devices/robot-01", None, Some("made-up"));
+    assert_eq!(status, 403, "{}", refused);
+    let (_, devices) = server.http("GET", "/api/devices", None, Some("made-up"));
+    assert_eq!(devices["devices"].as_array().unwrap().len(), 1, "{}", devices);
+
+    let mut ui = server.ws("/?token=made-up", "");
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "ring", "payload": {}}}));
+    assert_eq!(ui.recv_type("error")["data"]["code"], "read_only");
+    assert!(device.collect_type("command", Duration::from_millis(300)).is_empty());
+
+    // A device's own token still does what its routes allow
+    let (status, issued) = server.http("POST", "/api/devices/robot-01/tokens", Some(&json!({"scope": "telemetry"})), Some(&token));
+    assert_eq!(status, 200, "{}", issued);
+}
+
+// ============================================================================
+// SCOPED TOKENS
+// ============================================================================
+
+// A device issues itself tokens limited to one scope: a telemetry token
+// uploads its records and nothing else, a read token reads its own data.
+
+#[test]
+fn a_telemetry_token_uploads_telemetry_but_cannot_read_commands() {
+    let server = TestServer::start("scoped-tokens");
+    let token = server.pair("robot-1", "robot");
+    server.pair("robot-10", "robot");
+
+    let (status, issued) = server.http("POST", "/api/devices/robot-1/tokens", Some(&json!({"scope": "telemetry"})), Some(&token));
+    assert_eq!(status, 200, "{}", issued);
+    assert_eq!(issued["scope"], "telemetry");
+    let telemetry = issued["token"].as_str().unwrap().to_string();
+    assert_ne!(telemetry, token);
+
+    let upload = json!({"records": [
+        {"timestamp": 1_700_000_000, "latitude": 34.0, "longitude": -118.0, "battery": 80},
+        {"latitude": 34.1, "longitude": -118.1, "battery": 79}
+    ]});
+    let (status, stored) = server.http("POST", "/api/telemetry/robot-1", Some(&upload), Some(&telemetry));
+    assert_eq!(status, 200, "{}", stored);
+    assert_eq!(stored["stored"], 2);
+    let (_, recent) = server.http("GET", "/api/telemetry/robot-1/recent", None, None);
+    assert_eq!(recent["count"], 2, "{}", recent);
+    assert_eq!(recent["records"][0]["timestamp"], 1_700_000_000);
+
+    // Nothing outside its scope, and nothing for another device
+    let (status, refused) = server.http("GET", "/api/devices/robot-1/commands/stream", None, Some(&telemetry));
+    assert_eq!(status, 403, "{}", refused);
+    assert_eq!(server.http("GET", "/api/devices/robot-1/stats", None, Some(&telemetry)).0, 403);
+    assert_eq!(server.http("GET", "/api/devices", None, Some(&telemetry)).0, 403);
+    assert_eq!(server.http("POST", "/api/telemetry/robot-10", Some(&upload), Some(&telemetry)).0, 403);
+    assert_eq!(server.http("GET", "/api/whoami", None, Some(&telemetry)).0, 200);
+
+    // Revoked with the registration token, it's no credential at all
+    let (_, revoked) = server.http("DELETE", "/api/devices/robot-1/tokens", None, Some(&token));
+    assert_eq!(revoked["revoked"], 1);
+    assert_eq!(server.http("POST", "/api/telemetry/robot-1", Some(&upload), Some(&telemetry)).0, 401);
+}
+
+#[test]
+fn a_read_token_reads_but_cannot_upload() {
+    let server = TestServer::start("scoped-tokens-read");
+    let token = server.pair("robot-01", "robot");
+
+    let (_, issued) = server.http("POST", "/api/devices/robot-01/tokens", Some(&json!({"scope": "read"})), Some(&token));
+    let read = issued["token"].as_str().unwrap().to_string();
+    let (status, stats) = server.http("GET", "/api/devices/robot-01/stats", None, Some(&read));
+    assert_eq!(status, 200, "{}", stats);
+    assert_eq!(server.http("GET", "/api/telemetry/robot-01/files", None, Some(&read)).0, 200);
+
+    let upload = json!({"records": [{"latitude": 34.0, "longitude": -118.0}]});
+    assert_eq!(server.http("POST", "/api/telemetry/robot-01", Some(&upload), Some(&read)).0, 403);
+    // The registration token still uploads
+    assert_eq!(server.http("POST", "/api/telemetry/robot-01", Some(&upload), Some(&token)).0, 200);
+
+    // Only the device itself issues them, and only for known scopes
+    let (status, _) = server.http("POST", "/api/devices/robot-01/tokens", Some(&json!({"scope": "read"})), Some(&read));
+    assert_eq!(status, 403);
+    assert_eq!(server.http("POST", "/api/devices/robot-01/tokens", Some(&json!({"scope": "read"})), None).0, 401);
+    assert_eq!(server.http("POST", "/api/devices/robot-01/tokens", Some(&json!({"scope": "admin"})), Some(&token)).0, 400);
+    assert_eq!(server.http("POST", "/api/telemetry/robot-01", Some(&upload), None).0, 401);
+}
+
+// ============================================================================
+// PREFERENCES
+// ============================================================================
+
+// UI layout preferences: stored per operator, with a size cap.
+
+#[test]
+fn prefs_are_stored_and_read_back_per_token() {
+    let server = TestServer::start("prefs-store");
+    let (_, empty) = server.http("GET", "/api/prefs", None, Some("alice"));
+    assert!(empty["prefs"].is_null());
+
+    let prefs = json!({"layers": ["trails", "labels"], "zoom": 14});
+    let (status, _) = server.http("PUT", "/api/prefs", Some(&prefs), Some("alice"));
+    assert_eq!(status, 200);
+    let (_, stored) = server.http("GET", "/api/prefs", None, Some("alice"));
+    assert_eq!(stored["prefs"], prefs);
+    let (_, other) = server.http("GET", "/api/prefs", None, Some("bob"));
+    assert!(other["prefs"].is_null());
+
+    // Without a token there's nobody to keep them for
+    assert_eq!(server.http("GET", "/api/prefs", None, None).0, 401);
+    assert_eq!(server.http("PUT", "/api/prefs", Some(&prefs), None).0, 401);
+}
+
+#[test]
+fn oversized_prefs_are_refused() {
+    let server = TestServer::start("prefs-cap");
+    let big = json!({"notes": "x".repeat(17 * 1024)});
+    let (status, _) = server.http("PUT", "/api/prefs", Some(&big), Some("alice"));
+    assert_eq!(status, 413);
+    let (_, stored) = server.http("GET", "/api/prefs", None, Some("alice"));
+    assert!(stored["prefs"].is_null());
+}
+
+#[test]
+fn oversized_prefs_are_refused_before_the_body_is_sent() {
+    let server = TestServer::start("prefs-declared");
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    // Headers only: a server waiting for the body would time out instead
+    write!(stream, "PUT /api/prefs HTTP/1.1\r\nHost: 127.0.0.1\r\nAuthorization: Bearer alice\r\nContent-Length: 1000000\r\n\r\n").unwrap();
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(&head, b"HTTP/1.1 413");
+}
@@ -0,0 +1,1004 @@
+//! Commands: their lifecycle, and everything that decides whether, when
+//! and to whom one is sent.
+
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{admin_config, TestServer, Ws, ADMIN, TIMEOUT};
+use globalrts::access::Cidr;
+use globalrts::client::DeviceClient;
+use globalrts::sim::{self, DeviceState};
+use globalrts::Config;
+use serde_json::{json, Value};
+
+// ============================================================================
+// LIFECYCLE
+// ============================================================================
+
+// The command lifecycle: queued → sent → delivered → completed, and who
+// may move a command along it.
+
+/// Send a command from the UI; returns its id once the device has it.
+fn send_command(ui: &mut Ws, device: &mut Ws, device_id: &str) -> String {
+    ui.send(&json!({"type": "sendCommand", "data": {
+        "device_id": device_id, "command_type": "ring", "payload": {}
+    }}));
+    let sent = ui.recv_type("command:sent");
+    assert_eq!(sent["data"]["status"], "sent", "{}", sent);
+    let command_id = sent["data"]["commandId"].as_str().unwrap().to_string();
+    let command = device.recv_type("command");
+    assert_eq!(command["data"]["commandId"], command_id.as_str());
+    command_id
+}
+
+fn report(device: &mut Ws, msg_type: &str, command_id: &str, status: Option<&str>) {
+    let mut data = json!({"commandId": command_id});
+    if let Some(status) = status {
+        data["status"] = json!(status);
+    }
+    device.send(&json!({"type": msg_type, "data": data}));
+}
+
+fn stored_status(server: &TestServer, command_id: &str) -> String {
+    let db = rusqlite::Connection::open(server.data_dir.join("state.db")).unwrap();
+    db.query_row("SELECT status FROM commands WHERE id = ?1", [command_id], |row| row.get(0)).unwrap()
+}
+
+/// Wait for the `command:status` saying `command_id` is now `status`,
+/// past any others broadcast in between.
+fn await_status(ui: &mut Ws, command_id: &str, status: &str) -> Value {
+    ui.recv_matching("command:status", |m| m["data"]["commandId"] == command_id && m["data"]["status"] == status)
+}
+
+#[test]
+fn command_walks_the_full_lifecycle() {
+    let server = TestServer::start("cmd-lifecycle");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    let command_id = send_command(&mut ui, &mut device, "robot-01");
+    assert_eq!(stored_status(&server, &command_id), "sent");
+
+    report(&mut device, "command:ack", &command_id, Some("received"));
+    let status = await_status(&mut ui, &command_id, "delivered");
+    assert_eq!(status["data"]["deviceId"], "robot-01");
+
+    report(&mut device, "command:complete", &command_id, None);
+    await_status(&mut ui, &command_id, "completed");
+    assert_eq!(stored_status(&server, &command_id), "completed");
+}
+
+#[test]
+fn only_the_commands_device_can_report() {
+    let server = TestServer::start("cmd-owner");
+    let token = server.pair("robot-01", "robot");
+    let other = server.pair("robot-02", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut intruder = server.device("robot-02", "robot", &other);
+    let mut ui = server.ui(None);
+
+    let command_id = send_command(&mut ui, &mut device, "robot-01");
+    report(&mut intruder, "command:complete", &command_id, Some("completed"));
+    report(&mut ui, "command:complete", &command_id, Some("completed"));
+
+    // The device's own ack still delivers it: nothing before it stuck
+    report(&mut device, "command:ack", &command_id, None);
+    let status = await_status(&mut ui, &command_id, "delivered");
+    assert_eq!(status["data"]["deviceId"], "robot-01");
+    assert_eq!(stored_status(&server, &command_id), "delivered");
+}
+
+#[test]
+fn statuses_only_move_forward() {
+    let server = TestServer::start("cmd-forward");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    let command_id = send_command(&mut ui, &mut device, "robot-01");
+    // Statuses the server keeps to itself
+    report(&mut device, "command:complete", &command_id, Some("queued"));
+    report(&mut device, "command:complete", &command_id, Some("timed_out"));
+    assert_eq!(stored_status(&server, &command_id), "sent");
+
+    report(&mut device, "command:complete", &command_id, Some("failed"));
+    await_status(&mut ui, &command_id, "failed");
+
+    // Finished stays finished. The pong says both reports were handled.
+    report(&mut device, "command:ack", &command_id, Some("received"));
+    report(&mut device, "command:complete", &command_id, Some("completed"));
+    device.send(&json!({"type": "ping", "data": {}}));
+    device.recv_type("pong");
+    let late = ui.collect_type("command:status", std::time::Duration::from_millis(300));
+    assert!(late.is_empty(), "{:?}", late);
+    assert_eq!(stored_status(&server, &command_id), "failed");
+}
+
+#[test]
+fn queued_commands_arrive_in_order_on_reconnect() {
+    let server = TestServer::start("cmd-fifo");
+    let token = server.pair("robot-01", "robot");
+    let mut ui = server.ui(None);
+
+    let mut issued = Vec::new();
+    for command_type in ["ring", "photo", "stop"] {
+        ui.send(&json!({"type": "sendCommand", "data": {
+            "device_id": "robot-01", "command_type": command_type, "payload": {}
+        }}));
+        let sent = ui.recv_type("command:sent");
+        assert_eq!(sent["data"]["status"], "queued", "{}", sent);
+        issued.push(sent["data"]["commandId"].as_str().unwrap().to_string());
+    }
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["queued_commands"], 3, "{}", devices);
+
+    let mut device = server.device("robot-01", "robot", &token);
+    for (n, (command_id, command_type)) in issued.iter().zip(["ring", "photo", "stop"]).enumerate() {
+        let command = device.recv_type("command");
+        assert_eq!(command["data"]["commandId"], command_id.as_str(), "{}", command);
+        assert_eq!(command["data"]["type"], command_type);
+        assert_eq!(command["data"]["seq"], n as u64 + 1);
+        await_status(&mut ui, command_id, "sent");
+        assert_eq!(stored_status(&server, command_id), "sent");
+    }
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["queued_commands"], 0, "{}", devices);
+}
+
+#[test]
+fn sequence_numbers_count_up_per_device() {
+    let server = TestServer::start("cmd-seq");
+    let robot_token = server.pair("robot-01", "robot");
+    let drone_token = server.pair("drone-01", "drone");
+    let mut robot = server.device("robot-01", "robot", &robot_token);
+    let mut drone = server.device("drone-01", "drone", &drone_token);
+    let mut ui = server.ui(None);
+
+    let mut seen = Vec::new();
+    for (device_id, expected) in [("robot-01", 1), ("drone-01", 1), ("robot-01", 2), ("robot-01", 3), ("drone-01", 2)] {
+        let device = if device_id == "robot-01" { &mut robot } else { &mut drone };
+        ui.send(&json!({"type": "sendCommand", "data": {
+            "device_id": device_id, "command_type": "ring", "payload": {}
+        }}));
+        let command = device.recv_type("command");
+        assert_eq!(command["data"]["seq"], expected, "{}", command);
+        seen.push((command["data"]["commandId"].as_str().unwrap().to_string(), expected));
+        // Once the UI hears of it, it's committed
+        ui.recv_type("command:sent");
+    }
+
+    // What went out is what was stored
+    let db = rusqlite::Connection::open(server.data_dir.join("state.db")).unwrap();
+    for (command_id, seq) in seen {
+        let stored: i64 = db.query_row("SELECT seq FROM commands WHERE id = ?1", [&command_id], |row| row.get(0)).unwrap();
+        assert_eq!(stored, seq, "{}", command_id);
+    }
+}
+
+#[test]
+fn dry_runs_are_marked_and_stay_dry_run() {
+    let server = TestServer::start("cmd-dry-run");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    ui.send(&json!({"type": "sendCommand", "data": {
+        "device_id": "robot-01", "command_type": "navigate", "dry_run": true,
+        "payload": {"latitude": 34.06, "longitude": -118.25}
+    }}));
+    let status = ui.recv_type("command:status");
+    assert_eq!(status["data"]["status"], "dry_run", "{}", status);
+    let sent = ui.recv_type("command:sent");
+    assert_eq!(sent["data"]["status"], "dry_run", "{}", sent);
+    assert_eq!(sent["data"]["dispatched"], true);
+    let command_id = sent["data"]["commandId"].as_str().unwrap().to_string();
+
+    let command = device.recv_type("command");
+    assert_eq!(command["data"]["commandId"], command_id.as_str());
+    assert_eq!(command["data"]["dryRun"], true, "{}", command);
+
+    // Nothing a device reports moves it on
+    report(&mut device, "command:complete", &command_id, Some("completed"));
+    assert_eq!(stored_status(&server, &command_id), "dry_run");
+}
+
+#[test]
+fn request_id_follows_the_command() {
+    let server = TestServer::start("cmd-request-id");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    ui.send(&json!({"type": "sendCommand", "data": {
+        "device_id": "robot-01", "command_type": "ring", "payload": {}, "request_id": "req-1"
+    }}));
+    let sent = ui.recv_type("command:sent");
+    assert_eq!(sent["data"]["requestId"], "req-1", "{}", sent);
+    let command_id = sent["data"]["commandId"].as_str().unwrap().to_string();
+    device.recv_type("command");
+
+    report(&mut device, "command:ack", &command_id, None);
+    let status = await_status(&mut ui, &command_id, "delivered");
+    assert_eq!(status["data"]["requestId"], "req-1", "{}", status);
+    report(&mut device, "command:complete", &command_id, None);
+    let status = await_status(&mut ui, &command_id, "completed");
+    assert_eq!(status["data"]["requestId"], "req-1", "{}", status);
+
+    // Rejected commands still say which request they were
+    ui.send(&json!({"type": "sendCommand", "data": {
+        "device_id": "robot-01", "command_type": "navigate", "payload": {}, "request_id": "req-2"
+    }}));
+    let rejected = ui.recv_type("command:rejected");
+    assert_eq!(rejected["data"]["requestId"], "req-2", "{}", rejected);
+
+    // Without one, none is made up
+    let command_id = send_command(&mut ui, &mut device, "robot-01");
+    report(&mut device, "command:ack", &command_id, None);
+    assert!(await_status(&mut ui, &command_id, "delivered")["data"].get("requestId").is_none());
+}
+
+#[test]
+fn preconditions_decide_whether_a_command_goes_out() {
+    let server = TestServer::start("cmd-precondition");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+    device.send(&json!({"type": "telemetry", "data": {"latitude": 34.05, "longitude": -118.24, "battery": 22, "ack": true}}));
+    device.recv_type("telemetry:ack");
+
+    let navigate = |precondition: &str| json!({"type": "sendCommand", "data": {
+        "device_id": "robot-01", "command_type": "navigate", "precondition": precondition,
+        "payload": {"latitude": 34.06, "longitude": -118.25}
+    }});
+
+    // Unmet: saved as skipped, with the reason, and never sent
+    ui.send(&navigate("battery > 30 AND status == online"));
+    let status = ui.recv_type("command:status");
+    assert_eq!(status["data"]["status"], "skipped", "{}", status);
+    assert_eq!(status["data"]["reason"], "battery is 22, needs > 30");
+    let sent = ui.recv_type("command:sent");
+    assert_eq!(sent["data"]["status"], "skipped", "{}", sent);
+    assert_eq!(sent["data"]["dispatched"], false);
+    assert_eq!(sent["data"]["reason"], "battery is 22, needs > 30");
+    let skipped = sent["data"]["commandId"].as_str().unwrap().to_string();
+    assert_eq!(stored_status(&server, &skipped), "skipped");
+
+    // Met: sent as usual
+    ui.send(&navigate("battery > 20 AND status == online"));
+    let sent = ui.recv_type("command:sent");
+    assert_eq!(sent["data"]["status"], "sent", "{}", sent);
+    let command = device.recv_type("command");
+    assert_eq!(command["data"]["commandId"], sent["data"]["commandId"], "{}", command);
+
+    // Nonsense is refused outright
+    ui.send(&navigate("fuel > 30"));
+    let rejected = ui.recv_type("command:rejected");
+    assert!(rejected["data"]["error"].as_str().unwrap().contains("unknown field"), "{}", rejected);
+}
+
+#[test]
+fn a_retry_with_the_same_idempotency_key_dispatches_once() {
+    let server = TestServer::start("cmd-idempotent");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+    let send = |ui: &mut Ws, key: &str| {
+        ui.send(&json!({"type": "sendCommand", "data": {
+            "device_id": "robot-01", "command_type": "move", "payload": {"x": 1}, "idempotency_key": key
+        }}));
+        ui.recv_type("command:sent")
+    };
+
+    let first = send(&mut ui, "move-7f3a");
+    assert_eq!(first["data"]["status"], "sent", "{}", first);
+    assert!(first["data"].get("duplicate").is_none());
+    let command_id = first["data"]["commandId"].as_str().unwrap();
+    assert_eq!(device.recv_type("command")["data"]["commandId"], command_id);
+    report(&mut device, "command:ack", command_id, None);
+    await_status(&mut ui, command_id, "delivered");
+
+    // The retry is answered with the original, as it stands now, and the robot moves once
+    let retry = send(&mut ui, "move-7f3a");
+    assert_eq!(retry["data"]["commandId"], command_id, "{}", retry);
+    assert_eq!((&retry["data"]["status"], &retry["data"]["duplicate"], &retry["data"]["dispatched"]), (&json!("delivered"), &json!(true), &json!(false)));
+    assert!(device.collect_type("command", std::time::Duration::from_millis(300)).is_empty());
+
+    // Another key is another command
+    let other = send(&mut ui, "move-8b21");
+    assert_ne!(other["data"]["commandId"], command_id);
+    assert_eq!(device.recv_type("command")["data"]["commandId"], other["data"]["commandId"]);
+}
+
+/// A one-shot HTTP receiver: the request line and body of the first
+/// request it gets, which it answers 200.
+fn mock_receiver() -> (u16, std::sync::mpsc::Receiver<(String, Value)>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+        let _ = tx.send((request_line.trim().to_string(), serde_json::from_slice(&body).unwrap()));
+    });
+    (port, rx)
+}
+
+#[test]
+fn a_callback_url_is_posted_the_outcome() {
+    // The receiver is on loopback, which callbacks only reach when allowed
+    let webhook_allow = vec![Cidr::parse("127.0.0.1").unwrap()];
+    let server = TestServer::start_with("cmd-callback", Config { webhook_allow, ..Config::default() });
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+    let (port, received) = mock_receiver();
+
+    ui.send(&json!({"type": "sendCommand", "data": {
+        "device_id": "robot-01", "command_type": "ring", "payload": {},
+        "callback_url": format!("http://127.0.0.1:{}/done?src=test", port)
+    }}));
+    let command_id = ui.recv_type("command:sent")["data"]["commandId"].as_str().unwrap().to_string();
+    device.recv_type("command");
+
+    // Delivered isn't the end of it; completed is
+    report(&mut device, "command:ack", &command_id, None);
+    await_status(&mut ui, &command_id, "delivered");
+    device.send(&json!({"type": "command:complete", "data": {"commandId": command_id, "result": {"rang": 3}}}));
+
+    let (request_line, body) = received.recv_timeout(TIMEOUT).expect("callback POST");
+    assert_eq!(request_line, "POST /done?src=test HTTP/1.1");
+    assert_eq!(
+        (&body["command_id"], &body["device_id"], &body["command_type"], &body["status"], &body["result"]),
+        (&json!(command_id), &json!("robot-01"), &json!("ring"), &json!("completed"), &json!({"rang": 3})),
+        "{}", body
+    );
+
+    ui.send(&json!({"type": "sendCommand", "data": {
+        "device_id": "robot-01", "command_type": "ring", "payload": {}, "callback_url": "ftp://example.com/done"
+    }}));
+    let rejected = ui.recv_type("command:rejected");
+    assert!(rejected["data"]["error"].as_str().unwrap().starts_with("callback_url"), "{}", rejected);
+}
+
+// ============================================================================
+// CANCELLING
+// ============================================================================
+
+// Cancelling a command: POST /api/commands/{id}/cancel and the UI's
+// `cancelCommand` mark it cancelled, and a device already carrying it
+// out gets a `command:cancel` and stops.
+
+const VIEWER: &str = "viewer-secret";
+
+fn cancel_config() -> Config {
+    Config { viewer_token: Some(VIEWER.to_string()), ..admin_config() }
+}
+
+/// Run the simulated device, as the simulator binary does, until `done`
+/// says so or the test times out.
+fn run_until(client: &mut DeviceClient, state: &mut DeviceState, done: impl Fn(&DeviceState) -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !done(state) {
+        assert!(Instant::now() < deadline, "timed out");
+        client.poll(|command| {
+            if command.cancel {
+                sim::cancel(state, &command.id);
+                return Vec::new();
+            }
+            sim::respond(state, &command.data)
+        }).unwrap();
+        state.update();
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn cancelling_a_navigate_under_way_stops_the_device() {
+    let server = TestServer::start_with("command-cancel", cancel_config());
+    let token = server.pair("robot-01", "robot");
+    let mut client = DeviceClient::new("127.0.0.1", server.port, "robot-01", "robot", "robot-01").with_token(&token);
+    let mut state = DeviceState::new();
+    state.quiet = true;
+    let mut ui = server.ui(Some(ADMIN));
+    client.connect(state.lat, state.lon).unwrap();
+    ui.recv_type("device:online");
+
+    ui.send(&json!({"type": "sendCommand", "data": {
+        "device_id": "robot-01", "command_type": "navigate", "payload": {"latitude": 35.0, "longitude": -119.0}
+    }}));
+    let command_id = ui.recv_type("command:sent")["data"]["commandId"].as_str().unwrap().to_string();
+    run_until(&mut client, &mut state, |s| s.status == "moving");
+    await_status(&mut ui, &command_id, "delivered");
+
+    let path = format!("/api/commands/{}/cancel", command_id);
+    // With a viewer token set, any other token is a viewer's
+    let (status, _) = server.http("POST", &path, None, Some("operator-token"));
+    assert_eq!(status, 403);
+    let (status, reply) = server.http("POST", &path, None, Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply["notified"], true, "{}", reply);
+    await_status(&mut ui, &command_id, "cancelled");
+
+    run_until(&mut client, &mut state, |s| s.status == "idle");
+    assert_eq!((state.target, state.speed), (None, 0.0));
+
+    // It's over now, and a finished command can't be cancelled
+    let (status, reply) = server.http("POST", &path, None, Some(ADMIN));
+    assert_eq!(status, 409, "{}", reply);
+    let (status, _) = server.http("POST", "/api/commands/nope/cancel", None, Some(ADMIN));
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn a_queued_command_cancelled_is_never_delivered() {
+    let server = TestServer::start_with("command-cancel-queued", cancel_config());
+    let token = server.pair("robot-01", "robot");
+    let mut ui = server.ui(Some(ADMIN));
+
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "ring", "payload": {}}}));
+    let sent = ui.recv_type("command:sent");
+    assert_eq!(sent["data"]["status"], "queued", "{}", sent);
+    let command_id = sent["data"]["commandId"].clone();
+
+    ui.send(&json!({"type": "cancelCommand", "data": {"commandId": command_id}}));
+    ui.recv_matching("command:status", |m| m["data"]["commandId"] == command_id && m["data"]["status"] == "cancelled");
+    ui.send(&json!({"type": "cancelCommand", "data": {"commandId": command_id}}));
+    let refused = ui.recv_type("error");
+    assert_eq!(refused["data"]["code"], "cancel_refused", "{}", refused);
+
+    let mut device = server.device("robot-01", "robot", &token);
+    assert!(device.collect_type("command", Duration::from_millis(500)).is_empty());
+
+    // A viewer may not cancel
+    let mut viewer = server.ui(Some(VIEWER));
+    viewer.send(&json!({"type": "cancelCommand", "data": {"commandId": command_id}}));
+    assert_eq!(viewer.recv_type("error")["data"]["code"], "read_only");
+}
+
+// ============================================================================
+// PAYLOAD CAP
+// ============================================================================
+
+// GLOBALRTS_MAX_COMMAND_PAYLOAD_BYTES: a command whose payload is over
+// the cap is rejected with reason `payload_too_large` and never saved.
+
+#[test]
+fn an_oversized_payload_is_rejected_and_a_normal_one_passes() {
+    let server = TestServer::start_with("payload-limit", Config { max_command_payload: 1024, ..admin_config() });
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(Some(ADMIN));
+    let big = json!({"text": "x".repeat(2000)});
+
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "display", "payload": big}}));
+    let rejected = ui.recv_type("command:rejected");
+    assert_eq!(rejected["data"]["reason"], "payload_too_large", "{}", rejected);
+    assert!(rejected["data"]["error"].as_str().unwrap().contains("1024"), "{}", rejected);
+
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "display", "payload": {"text": "hi"}}}));
+    let sent = ui.recv_type("command:sent");
+    assert_eq!(sent["data"]["status"], "sent", "{}", sent);
+    let command = device.recv_type("command");
+    assert_eq!(command["data"]["payload"]["text"], "hi", "oversized one never reached the device");
+
+    // The group endpoint refuses it whole
+    let group = json!({"device_ids": ["robot-01"], "command_type": "display", "payload": big});
+    let (status, reply) = server.http("POST", "/api/commands", Some(&group), Some(ADMIN));
+    assert_eq!(status, 413, "{}", reply);
+    assert_eq!(reply["reason"], "payload_too_large");
+}
+
+// ============================================================================
+// STATUS STREAM
+// ============================================================================
+
+// `GET /api/devices/{id}/commands/stream`: one device's command statuses
+// as server-sent events, as they change.
+
+/// An open event stream, past its response head.
+struct EventStream {
+    reader: BufReader<TcpStream>,
+}
+
+impl EventStream {
+    fn open(server: &TestServer, device_id: &str) -> Self {
+        let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        write!(stream, "GET /api/devices/{}/commands/stream HTTP/1.1\r\nHost: localhost\r\n\r\n", device_id).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert!(reader.read_line(&mut head).unwrap() > 0, "{}", head);
+        }
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(head.contains("Content-Type: text/event-stream\r\n"), "{}", head);
+        Self { reader }
+    }
+
+    /// The next event's data, skipping comments.
+    fn next(&mut self) -> Value {
+        let mut event = String::new();
+        let mut data = None;
+        loop {
+            let mut line = String::new();
+            assert!(self.reader.read_line(&mut line).unwrap() > 0, "stream ended");
+            match line.trim_end_matches('\n') {
+                "" if data.is_some() => break,
+                "" => {}
+                line if line.starts_with(':') => {}
+                line => match line.split_once(": ") {
+                    Some(("event", name)) => event = name.to_string(),
+                    Some(("data", json)) => data = Some(serde_json::from_str(json).unwrap()),
+                    _ => panic!("unexpected line {:?}", line),
+                },
+            }
+        }
+        assert_eq!(event, "command:status");
+        data.unwrap()
+    }
+}
+
+fn send_ring(ui: &mut Ws, device_id: &str) -> String {
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": device_id, "command_type": "ring", "payload": {}}}));
+    ui.recv_type("command:sent")["data"]["commandId"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn the_stream_carries_only_its_devices_commands() {
+    let server = TestServer::start("command-stream");
+    let token = server.pair("robot-01", "robot");
+    let mut robot = server.device("robot-01", "robot", &token);
+    let other_token = server.pair("robot-02", "robot");
+    let _other = server.device("robot-02", "robot", &other_token);
+    let mut ui = server.ui(None);
+    let mut events = EventStream::open(&server, "robot-01");
+
+    // robot-02's command changes status first, and isn't in the stream
+    send_ring(&mut ui, "robot-02");
+    let command_id = send_ring(&mut ui, "robot-01");
+    let command = robot.recv_type("command");
+    assert_eq!(command["data"]["commandId"], command_id.as_str(), "{}", command);
+    robot.send(&json!({"type": "command:ack", "data": {"commandId": command_id, "status": "received"}}));
+    robot.send(&json!({"type": "command:complete", "data": {"commandId": command_id}}));
+
+    for status in ["queued", "sent", "delivered", "completed"] {
+        let event = events.next();
+        assert_eq!(event["commandId"], command_id.as_str(), "{}", event);
+        assert_eq!(event["deviceId"], "robot-01");
+        assert_eq!(event["status"], status);
+    }
+
+    let (status, _, _) = server.http_raw("GET", "/api/devices/robot-99/commands/stream", None, None);
+    assert_eq!(status, 404);
+}
+
+// ============================================================================
+// GROUP COMMANDS
+// ============================================================================
+
+// `POST /api/commands` sends one command to several devices and reports
+// each device's result on its own.
+
+#[test]
+fn group_command_reports_mixed_results() {
+    let server = TestServer::start_with("group-mixed", admin_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    let body = json!({"device_ids": ["robot-01", "ghost-99"], "command_type": "ring", "payload": {}});
+    let (status, reply) = server.http("POST", "/api/commands", Some(&body), Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!((reply["ok"].as_u64(), reply["failed"].as_u64()), (Some(1), Some(1)));
+
+    let sent = &reply["results"][0];
+    assert_eq!((&sent["id"], &sent["status"], &sent["command_status"]), (&json!("robot-01"), &json!("ok"), &json!("sent")));
+    assert_eq!(reply["results"][1], json!({"id": "ghost-99", "status": "error", "error": "unknown device"}));
+
+    let command = device.recv_type("command");
+    assert_eq!(command["data"]["commandId"], sent["command_id"]);
+}
+
+#[test]
+fn a_bad_command_refuses_the_whole_group() {
+    let server = TestServer::start_with("group-refused", admin_config());
+    server.pair("robot-01", "robot");
+
+    let body = json!({"device_ids": ["robot-01"], "command_type": "ring", "precondition": "fuel > 3"});
+    let (status, reply) = server.http("POST", "/api/commands", Some(&body), Some(ADMIN));
+    assert_eq!(status, 400, "{}", reply);
+    let (status, _) = server.http("POST", "/api/commands", Some(&json!({"device_ids": [], "command_type": "ring"})), Some(ADMIN));
+    assert_eq!(status, 400);
+    let (status, _) = server.http("POST", "/api/commands", Some(&json!({"device_ids": ["robot-01"], "command_type": "ring"})), None);
+    assert_eq!(status, 401);
+}
+
+// ============================================================================
+// LEASES
+// ============================================================================
+
+// Command leases: an operator takes a device with
+// `POST /api/devices/{id}/lease`, and until it's released or runs out
+// only they may send it commands.
+
+/// Send a command; the reply is `command:sent` or `command:rejected`.
+fn try_command(ui: &mut Ws, device_id: &str) -> Value {
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": device_id, "command_type": "ring", "payload": {}}}));
+    loop {
+        let message = ui.recv_within(TIMEOUT).expect("a reply to sendCommand");
+        if message["type"] == "command:sent" || message["type"] == "command:rejected" {
+            return message;
+        }
+    }
+}
+
+/// The identity `token` has, as /api/whoami tells it.
+fn identity(server: &TestServer, token: &str) -> String {
+    server.http("GET", "/api/whoami", None, Some(token)).1["identity"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn only_the_holder_commands_a_leased_device() {
+    let server = TestServer::start_with("leases", admin_config());
+    server.pair("robot-01", "robot");
+    let mut alice = server.ui(Some("alice-token"));
+    let mut bob = server.ui(Some("bob-token"));
+    let alice_id = identity(&server, "alice-token");
+
+    let (status, lease) = server.http("POST", "/api/devices/robot-01/lease", Some(&json!({"ttl_secs": 60})), Some("alice-token"));
+    assert_eq!(status, 200, "{}", lease);
+    assert_eq!(lease["holder"], alice_id.as_str());
+    assert_eq!(lease["expires_at"].as_i64().unwrap() - lease["acquired_at"].as_i64().unwrap(), 60);
+    let changed = bob.recv_type("lease:changed");
+    assert_eq!(changed["data"], json!({"deviceId": "robot-01", "holder": alice_id, "expiresAt": lease["expires_at"]}));
+
+    // Bob is turned away; Alice isn't
+    let rejected = try_command(&mut bob, "robot-01");
+    assert_eq!(rejected["type"], "command:rejected", "{}", rejected);
+    assert_eq!(rejected["data"]["error"], "leased");
+    assert_eq!(try_command(&mut alice, "robot-01")["type"], "command:sent");
+
+    // Nor can Bob take the lease or end it
+    let (status, reply) = server.http("POST", "/api/devices/robot-01/lease", None, Some("bob-token"));
+    assert_eq!(status, 409, "{}", reply);
+    assert_eq!(reply["error"], "leased");
+    assert_eq!(reply["holder"], alice_id.as_str());
+    assert_eq!(server.http("DELETE", "/api/devices/robot-01/lease", None, Some("bob-token")).0, 409);
+    let (_, leases) = server.http("GET", "/api/leases", None, None);
+    assert_eq!(leases["leases"].as_array().unwrap().len(), 1, "{}", leases);
+
+    // Released, anyone may command it again
+    let (status, reply) = server.http("DELETE", "/api/devices/robot-01/lease", None, Some("alice-token"));
+    assert_eq!(status, 200, "{}", reply);
+    let changed = bob.recv_matching("lease:changed", |m| m["data"]["holder"].is_null());
+    assert_eq!(changed["data"]["deviceId"], "robot-01");
+    assert_eq!(try_command(&mut bob, "robot-01")["type"], "command:sent");
+    assert_eq!(server.http("DELETE", "/api/devices/robot-01/lease", None, Some("alice-token")).0, 404);
+
+    // The admin token ends anyone's lease
+    assert_eq!(server.http("POST", "/api/devices/robot-01/lease", None, Some("bob-token")).0, 200);
+    assert_eq!(server.http("DELETE", "/api/devices/robot-01/lease", None, Some(ADMIN)).0, 200);
+    assert_eq!(try_command(&mut alice, "robot-01")["type"], "command:sent");
+
+    for (path, body) in [
+        ("/api/devices/robot-01/lease", json!({"ttl_secs": 0})),
+        ("/api/devices/robot-01/lease", json!({"ttl_secs": 3601})),
+        ("/api/devices/robot-01/lease", json!({"ttl_secs": "long"})),
+    ] {
+        let (status, reply) = server.http("POST", path, Some(&body), Some("alice-token"));
+        assert_eq!(status, 400, "{}: {}", body, reply);
+    }
+    assert_eq!(server.http("POST", "/api/devices/robot-99/lease", None, Some("alice-token")).0, 404);
+
+    // Operators without a token can't be told apart, so can't hold one
+    assert_eq!(server.http("POST", "/api/devices/robot-01/lease", None, None).0, 401);
+    assert_eq!(server.http("DELETE", "/api/devices/robot-01/lease", None, None).0, 401);
+}
+
+#[test]
+fn a_lease_runs_out_on_its_own() {
+    let server = TestServer::start_with("lease-expiry", admin_config());
+    server.pair("robot-01", "robot");
+    let mut bob = server.ui(Some("bob-token"));
+
+    let (status, reply) = server.http("POST", "/api/devices/robot-01/lease", Some(&json!({"ttl_secs": 2})), Some("alice-token"));
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(try_command(&mut bob, "robot-01")["type"], "command:rejected");
+
+    // Housekeeping ends it and tells the UIs
+    bob.recv_matching("lease:changed", |m| m["data"]["holder"].is_null());
+    assert_eq!(try_command(&mut bob, "robot-01")["type"], "command:sent");
+    let (_, leases) = server.http("GET", "/api/leases", None, None);
+    assert_eq!(leases["leases"], json!([]));
+}
+
+// ============================================================================
+// MAINTENANCE
+// ============================================================================
+
+// Maintenance: `POST /api/maintenance` pauses command dispatch, fleet-wide
+// or per device. Commands are saved `held` and go out in order on release;
+// telemetry carries on as usual.
+
+fn maintenance(server: &TestServer, body: Value) -> Value {
+    let (status, reply) = server.http("POST", "/api/maintenance", Some(&body), Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    reply
+}
+
+fn command_sent(ui: &mut Ws, device_id: &str, command_type: &str) -> Value {
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": device_id, "command_type": command_type, "payload": {}}}));
+    ui.recv_type("command:sent")["data"].clone()
+}
+
+fn commands(device: &mut Ws) -> Vec<String> {
+    device.collect_type("command", Duration::from_millis(300)).iter()
+        .map(|c| c["data"]["type"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn fleet_maintenance_holds_commands_until_released() {
+    let server = TestServer::start_with("maintenance", admin_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    assert_eq!(server.http("POST", "/api/maintenance", Some(&json!({"active": true})), None).0, 401);
+    let state = maintenance(&server, json!({"active": true}));
+    assert_eq!(state["global"], true);
+    assert_eq!(ui.recv_type("maintenance:state")["data"], json!({"global": true, "devices": []}));
+
+    for command_type in ["ring", "dock"] {
+        let sent = command_sent(&mut ui, "robot-01", command_type);
+        assert_eq!(sent["status"], "held", "{}", sent);
+        assert_eq!(sent["dispatched"], false);
+    }
+    assert!(commands(&mut device).is_empty());
+
+    // Telemetry is untouched
+    device.send(&json!({"type": "telemetry", "data": {"latitude": 34.05, "longitude": -118.24, "battery": 70, "ack": true}}));
+    device.recv_type("telemetry:ack");
+
+    // A UI connecting now learns of the pause
+    let mut late = server.ui(None);
+    assert_eq!(late.recv_type("maintenance:state")["data"]["global"], true);
+
+    let released = maintenance(&server, json!({"active": false}));
+    assert_eq!(released["global"], false);
+    assert_eq!(released["released"], 2);
+    assert_eq!(commands(&mut device), ["ring", "dock"]);
+    let sent = ui.recv_matching("command:status", |m| m["data"]["status"] == "sent");
+    assert_eq!(sent["data"]["deviceId"], "robot-01");
+}
+
+#[test]
+fn device_maintenance_holds_only_that_device() {
+    let server = TestServer::start_with("maintenance-device", admin_config());
+    let robot_token = server.pair("robot-01", "robot");
+    let drone_token = server.pair("drone-01", "drone");
+    let mut robot = server.device("robot-01", "robot", &robot_token);
+    let mut drone = server.device("drone-01", "drone", &drone_token);
+    let mut ui = server.ui(None);
+
+    assert_eq!(server.http("POST", "/api/maintenance", Some(&json!({"active": true, "device_id": "ghost"})), Some(ADMIN)).0, 404);
+    maintenance(&server, json!({"active": true, "device_id": "robot-01"}));
+    maintenance(&server, json!({"active": true}));
+    let (_, state) = server.http("GET", "/api/maintenance", None, None);
+    assert_eq!(state, json!({"global": true, "devices": ["robot-01"]}));
+
+    assert_eq!(command_sent(&mut ui, "robot-01", "ring")["status"], "held");
+    assert_eq!(command_sent(&mut ui, "drone-01", "land")["status"], "held");
+
+    // The fleet resumes; robot-01 is still paused on its own
+    assert_eq!(maintenance(&server, json!({"active": false}))["released"], 1);
+    assert_eq!(commands(&mut drone), ["land"]);
+    assert!(commands(&mut robot).is_empty());
+    assert_eq!(command_sent(&mut ui, "drone-01", "hover")["status"], "sent");
+    assert_eq!(command_sent(&mut ui, "robot-01", "dock")["status"], "held");
+
+    assert_eq!(maintenance(&server, json!({"active": false, "device_id": "robot-01"}))["released"], 2);
+    assert_eq!(commands(&mut robot), ["ring", "dock"]);
+}
+
+// ============================================================================
+// POWER COMMANDS
+// ============================================================================
+
+// `reboot` and `shutdown`: refused without `"confirm": true`, sent over
+// WebSocket only by the admin, and written to the audit log.
+
+fn reboot(payload: serde_json::Value) -> serde_json::Value {
+    json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "reboot", "payload": payload}})
+}
+
+#[test]
+fn a_reboot_needs_confirming_and_is_audited() {
+    let server = TestServer::start_with("power-reboot", admin_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(Some(ADMIN));
+
+    ui.send(&reboot(json!({})));
+    let rejected = ui.recv_type("command:rejected");
+    assert!(rejected["data"]["error"].as_str().unwrap().contains(r#""confirm": true"#), "{}", rejected);
+
+    ui.send(&reboot(json!({"confirm": true})));
+    let sent = ui.recv_type("command:sent");
+    assert_eq!(sent["data"]["status"], "sent", "{}", sent);
+    let command_id = sent["data"]["commandId"].as_str().unwrap();
+    let command = device.recv_type("command");
+    assert_eq!(command["data"]["type"], "reboot", "only the confirmed one went out");
+
+    let (status, audit) = server.http("GET", "/api/audit?action=command.reboot", None, Some(ADMIN));
+    assert_eq!(status, 200, "{}", audit);
+    let entries = audit["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 1, "{}", audit);
+    assert_eq!(entries[0]["actor"], "admin");
+    assert_eq!(entries[0]["target"], "robot-01");
+    assert_eq!(entries[0]["detail"], format!("{} sent", command_id));
+}
+
+#[test]
+fn only_the_admin_may_send_power_commands() {
+    let server = TestServer::start_with("power-admin", admin_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    let mut operator = server.ui(None);
+    operator.send(&reboot(json!({"confirm": true})));
+    let rejected = operator.recv_type("command:rejected");
+    assert_eq!(rejected["data"]["error"], "reboot needs the admin token", "{}", rejected);
+    assert!(device.collect_type("command", std::time::Duration::from_millis(300)).is_empty());
+
+    // The group endpoint is the admin's already, and checks the payload the same way
+    let shutdown = |confirm: bool| json!({"device_ids": ["robot-01"], "command_type": "shutdown", "payload": {"confirm": confirm}});
+    let (status, reply) = server.http("POST", "/api/commands", Some(&shutdown(false)), Some(ADMIN));
+    assert_eq!(status, 400, "{}", reply);
+    let (status, reply) = server.http("POST", "/api/commands", Some(&shutdown(true)), Some(ADMIN));
+    assert_eq!((status, reply["ok"].as_u64()), (200, Some(1)), "{}", reply);
+    assert_eq!(device.recv_type("command")["data"]["type"], "shutdown");
+    let (_, audit) = server.http("GET", "/api/audit?action=command.shutdown", None, Some(ADMIN));
+    assert_eq!(audit["entries"].as_array().unwrap().len(), 1, "{}", audit);
+    let (_, audit) = server.http("GET", "/api/audit?action=command.reboot", None, Some(ADMIN));
+    assert!(audit["entries"].as_array().unwrap().is_empty(), "refused ones aren't audited: {}", audit);
+}
+
+// ============================================================================
+// QUEUE METRICS
+// ============================================================================
+
+// Commands piling up for unreachable devices: `GET /api/metrics` gauges,
+// `pending_commands` on each device and in `/api/stats`.
+
+fn metrics(server: &TestServer) -> String {
+    let (status, head, body) = server.http_raw("GET", "/api/metrics", None, None);
+    assert_eq!(status, 200);
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"), "{}", head);
+    String::from_utf8(body).unwrap()
+}
+
+fn pending(server: &TestServer, device_id: &str) -> Value {
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    devices["devices"].as_array().unwrap().iter()
+        .find(|d| d["id"] == device_id)
+        .map(|d| d["pending_commands"].clone())
+        .unwrap()
+}
+
+#[test]
+fn gauges_count_commands_queued_for_offline_devices() {
+    let server = TestServer::start("queue-metrics");
+    let token = server.pair("robot-01", "robot");
+    server.pair("robot-02", "robot");
+
+    let mut ui = server.ui(None);
+    for device_id in ["robot-01", "robot-01", "robot-01", "robot-02"] {
+        ui.send(&json!({"type": "sendCommand", "data": {"device_id": device_id, "command_type": "ring", "payload": {}}}));
+        let sent = ui.recv_type("command:sent");
+        assert_eq!(sent["data"]["status"], "queued", "{}", sent);
+    }
+
+    let text = metrics(&server);
+    assert!(text.contains("# TYPE globalrts_pending_commands gauge\n"), "{}", text);
+    assert!(text.contains("globalrts_pending_commands{device_id=\"robot-01\"} 3\n"), "{}", text);
+    assert!(text.contains("globalrts_pending_commands{device_id=\"robot-02\"} 1\n"), "{}", text);
+    assert!(text.contains("globalrts_fleet_pending_commands 4\n"), "{}", text);
+    assert_eq!(pending(&server, "robot-01"), 3);
+    assert_eq!(server.http("GET", "/api/stats", None, None).1["pending_commands"], 4);
+
+    // Delivered on reconnect, they stop counting
+    let mut device = server.device("robot-01", "robot", &token);
+    for _ in 0..3 {
+        device.recv_type("command");
+    }
+    let text = metrics(&server);
+    assert!(!text.contains("device_id=\"robot-01\""), "{}", text);
+    assert!(text.contains("globalrts_fleet_pending_commands 1\n"), "{}", text);
+    assert_eq!(pending(&server, "robot-01"), 0);
+}
+
+// ============================================================================
+// SERIAL COMMANDS
+// ============================================================================
+
+// GLOBALRTS_SERIAL_COMMANDS: a device that takes one command at a time
+// gets the next only once the one in flight completes or is cancelled.
+
+fn serial_config() -> Config {
+    Config { serial_commands: vec!["robot".to_string()], ..Config::default() }
+}
+
+/// Send `robot-01` a command; its id and the status it was given.
+fn send(ui: &mut Ws, command_type: &str) -> (String, String) {
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": command_type, "payload": {}}}));
+    let sent = ui.recv_type("command:sent");
+    (sent["data"]["commandId"].as_str().unwrap().to_string(), sent["data"]["status"].as_str().unwrap().to_string())
+}
+
+#[test]
+fn a_second_command_waits_for_the_first_to_complete() {
+    let server = TestServer::start_with("serial-commands", serial_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    let (first, status) = send(&mut ui, "ring");
+    assert_eq!(status, "sent");
+    assert_eq!(device.recv_type("command")["data"]["commandId"], first.as_str());
+    device.send(&json!({"type": "command:ack", "data": {"commandId": first, "status": "received"}}));
+
+    let (second, status) = send(&mut ui, "poll");
+    assert_eq!(status, "queued", "the first is still in flight");
+    let (third, _) = send(&mut ui, "stop");
+    assert!(device.collect_type("command", Duration::from_millis(300)).is_empty());
+
+    // The first completing lets the second out, and only the second
+    device.send(&json!({"type": "command:complete", "data": {"commandId": first, "status": "completed"}}));
+    let next = device.recv_type("command");
+    assert_eq!(next["data"]["commandId"], second.as_str(), "{}", next);
+    ui.recv_matching("command:status", |m| m["data"]["commandId"] == second.as_str() && m["data"]["status"] == "sent");
+    assert!(device.collect_type("command", Duration::from_millis(300)).is_empty());
+
+    // Cancelling the one in flight lets out the next as well
+    ui.send(&json!({"type": "cancelCommand", "data": {"commandId": second}}));
+    device.recv_type("command:cancel");
+    assert_eq!(device.recv_type("command")["data"]["commandId"], third.as_str());
+}
+
+#[test]
+fn other_devices_take_commands_as_fast_as_they_come() {
+    let server = TestServer::start_with("serial-commands-other", serial_config());
+    let token = server.pair("sensor-01", "sensor");
+    let mut device = server.device("sensor-01", "sensor", &token);
+    let mut ui = server.ui(None);
+
+    for _ in 0..2 {
+        ui.send(&json!({"type": "sendCommand", "data": {"device_id": "sensor-01", "command_type": "poll", "payload": {}}}));
+        assert_eq!(ui.recv_type("command:sent")["data"]["status"], "sent");
+    }
+    assert_eq!(device.collect_type("command", Duration::from_millis(300)).len(), 2);
+}
@@ -0,0 +1,398 @@
+//! Shared pieces for the integration tests: the `globalrts` binary run in a
+//! throwaway directory, plain HTTP calls, and a small WebSocket client that
+//! can send headers (tokens, subprotocols) on the upgrade.
+//!
+//! The server always listens on port 3000 and keeps its data in `data/`
+//! under its working directory, so a test binary runs one server at a
+//! time. Each server gets its whole configuration from a `Config`, written
+//! out as its GLOBALRTS_CONFIG file, so tests sharing a binary can each
+//! configure theirs and nothing leaks in from the environment.
+
+#![allow(dead_code)]
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use globalrts::Config;
+use serde_json::Value;
+
+/// How long a test waits for a reply before failing.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The admin token of servers started with `admin_config`.
+pub const ADMIN: &str = "admin-secret";
+
+/// The default configuration, with admin endpoints enabled for `ADMIN`.
+pub fn admin_config() -> Config {
+    Config { admin_token: Some(ADMIN.to_string()), ..Config::default() }
+}
+
+/// A fresh, empty directory under the system temp dir.
+pub fn temp_dir(label: &str) -> PathBuf {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "globalrts-test-{}-{}-{}",
+        label,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The server's fixed port.
+const PORT: u16 = 3000;
+
+/// The server binary in its own throwaway directory. Killed on drop.
+pub struct TestServer {
+    child: Option<Child>,
+    pub port: u16,
+    /// The server's `data/` (state.db and telemetry/).
+    pub data_dir: PathBuf,
+    /// The server's GLOBALRTS_CONFIG file. Rewrite it and `signal("HUP")`
+    /// to reload.
+    pub config_file: PathBuf,
+    dir: PathBuf,
+    /// Held while the server runs: one per test binary at a time.
+    _turn: MutexGuard<'static, ()>,
+}
+
+impl TestServer {
+    /// A server with the default configuration.
+    pub fn start(label: &str) -> Self {
+        Self::start_with(label, Config::default())
+    }
+
+    /// A server configured as `config`. Only its port is ignored.
+    pub fn start_with(label: &str, config: Config) -> Self {
+        static TURN: Mutex<()> = Mutex::new(());
+        // A test that failed while holding the turn has still let go of the port
+        let turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir(label);
+        let config_file = dir.join("globalrts.env");
+        std::fs::write(&config_file, config.to_settings()).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_globalrts"))
+            .current_dir(&dir)
+            .env("GLOBALRTS_CONFIG", &config_file)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("server starts");
+        let mut server = Self { child: Some(child), port: PORT, data_dir: dir.join("data"), config_file, dir, _turn: turn };
+
+        let deadline = Instant::now() + TIMEOUT;
+        while TcpStream::connect(("127.0.0.1", PORT)).is_err() {
+            let exited = server.child.as_mut().and_then(|c| c.try_wait().ok().flatten());
+            assert!(exited.is_none(), "server exited: {:?}", exited);
+            assert!(Instant::now() < deadline, "server never listened on {}", PORT);
+            thread::sleep(Duration::from_millis(20));
+        }
+        server
+    }
+
+    /// Send the server a signal, by name (`HUP`, `TERM`).
+    pub fn signal(&self, name: &str) {
+        let pid = self.child.as_ref().expect("server running").id();
+        let status = Command::new("kill").arg(format!("-{}", name)).arg(pid.to_string()).status().unwrap();
+        assert!(status.success(), "kill -{} {}", name, pid);
+    }
+
+    /// Stop the server now.
+    pub fn shutdown(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    /// An HTTP call. Returns the status and the body as JSON (a JSON
+    /// string when it isn't JSON).
+    pub fn http(&self, method: &str, path: &str, body: Option<&Value>, token: Option<&str>) -> (u16, Value) {
+        let (status, _, body) = self.http_raw(method, path, body.map(Value::to_string).as_deref(), token);
+        let json = serde_json::from_slice(&body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).to_string()));
+        (status, json)
+    }
+
+    /// An HTTP call with a raw body. Returns status, headers and body bytes.
+    pub fn http_raw(&self, method: &str, path: &str, body: Option<&str>, token: Option<&str>) -> (u16, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n", method, path);
+        if let Some(token) = token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        let body = body.unwrap_or("");
+        if !body.is_empty() {
+            request.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").expect("complete response");
+        let head = String::from_utf8_lossy(&response[..split]).to_string();
+        let status = head.split_whitespace().nth(1).and_then(|s| s.parse().ok()).unwrap_or(0);
+        (status, head, response[split + 4..].to_vec())
+    }
+
+    /// Pair a device through the code flow. Returns its token.
+    pub fn pair(&self, device_id: &str, device_type: &str) -> String {
+        self.pair_with(serde_json::json!({"device_id": device_id, "name": device_id, "device_type": device_type}))
+    }
+
+    /// `pair` for a device that will sign its messages.
+    pub fn pair_signed(&self, device_id: &str, device_type: &str) -> String {
+        self.pair_with(serde_json::json!({"device_id": device_id, "name": device_id, "device_type": device_type, "signed": true}))
+    }
+
+    fn pair_with(&self, request: Value) -> String {
+        let device_id = request["device_id"].as_str().unwrap().to_string();
+        let (status, reply) = self.http("POST", "/api/pair/request", Some(&request), None);
+        assert_eq!(status, 200, "{}", reply);
+        let (_, pending) = self.http("GET", "/api/pair/requests", None, None);
+        let code = pending["requests"].as_array().unwrap().iter()
+            .find(|r| r["device_id"] == device_id.as_str())
+            .and_then(|r| r["code"].as_str())
+            .expect("pending request")
+            .to_string();
+        let confirm = serde_json::json!({"device_id": device_id, "code": code});
+        let (status, reply) = self.http("POST", "/api/pair/confirm", Some(&confirm), None);
+        assert_eq!(status, 200, "{}", reply);
+        reply["token"].as_str().unwrap().to_string()
+    }
+
+    /// Open a WebSocket, with extra header lines (each ending in `\r\n`).
+    pub fn ws(&self, path: &str, headers: &str) -> Ws {
+        Ws::connect(self.port, path, headers).expect("WebSocket upgrade")
+    }
+
+    /// A UI connection, with `token` if given, that has asked for the
+    /// device list (which is what makes it a UI).
+    pub fn ui(&self, token: Option<&str>) -> Ws {
+        let headers = token.map(|t| format!("Authorization: Bearer {}\r\n", t)).unwrap_or_default();
+        let mut ws = self.ws("/", &headers);
+        ws.send(&serde_json::json!({"type": "getDevices", "data": {}}));
+        ws.recv_type("devices:list");
+        ws
+    }
+
+    /// A device connection that has registered with `token`.
+    pub fn device(&self, device_id: &str, device_type: &str, token: &str) -> Ws {
+        let mut ws = self.ws("/", "");
+        ws.send(&serde_json::json!({"type": "register", "data": {
+            "device_id": device_id, "device_type": device_type, "name": device_id,
+            "token": token, "latitude": 34.05, "longitude": -118.24
+        }}));
+        let registered = ws.recv_type("registered");
+        assert_eq!(registered["data"]["device"]["id"], device_id, "{}", registered);
+        ws
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The client side of a WebSocket: masked frames out, whole frames in.
+pub struct Ws {
+    stream: TcpStream,
+    /// The upgrade response headers.
+    pub handshake: String,
+}
+
+impl Ws {
+    /// Upgrade `path`, sending `headers` too. Err with the response when
+    /// the server doesn't switch protocols.
+    pub fn connect(port: u16, path: &str, headers: &str) -> Result<Self, String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+            path, port, headers
+        );
+        stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+        // Byte by byte, so no frame after the headers is swallowed
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut byte) {
+                Ok(1) => head.push(byte[0]),
+                _ => break,
+            }
+        }
+        let handshake = String::from_utf8_lossy(&head).to_string();
+        if !handshake.starts_with("HTTP/1.1 101") {
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest);
+            return Err(format!("{}{}", handshake, String::from_utf8_lossy(&rest)));
+        }
+        Ok(Self { stream, handshake })
+    }
+
+    /// Send a JSON message as a text frame.
+    pub fn send(&mut self, message: &Value) {
+        self.send_frame(0x1, message.to_string().as_bytes());
+    }
+
+    /// Send a text frame as-is.
+    pub fn send_text(&mut self, text: &str) {
+        self.send_frame(0x1, text.as_bytes());
+    }
+
+    /// Send a binary frame.
+    pub fn send_binary(&mut self, data: &[u8]) {
+        self.send_frame(0x2, data);
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        self.stream.write_all(&frame).unwrap();
+    }
+
+    /// The next message, or None once the connection is closed or `wait` passes.
+    pub fn recv_within(&mut self, wait: Duration) -> Option<Value> {
+        match self.next_frame(wait) {
+            Frame::Message(message) => Some(message),
+            Frame::Closed(_) | Frame::Timeout => None,
+        }
+    }
+
+    fn next_frame(&mut self, wait: Duration) -> Frame {
+        let deadline = Instant::now() + wait;
+        loop {
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return Frame::Timeout;
+            };
+            let _ = self.stream.set_read_timeout(Some(left.max(Duration::from_millis(1))));
+            let mut header = [0u8; 2];
+            match self.stream.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Frame::Timeout,
+                Err(_) => return Frame::Closed(None),
+            }
+            let _ = self.stream.set_read_timeout(Some(TIMEOUT));
+            let Some(payload) = self.read_payload(header[1]) else {
+                return Frame::Closed(None);
+            };
+            match header[0] & 0x0F {
+                0x1 => match serde_json::from_slice(&payload) {
+                    Ok(message) => return Frame::Message(message),
+                    Err(_) => continue,
+                },
+                0x8 => return Frame::Closed(payload.get(..2).map(|c| u16::from_be_bytes([c[0], c[1]]))),
+                _ => continue,
+            }
+        }
+    }
+
+    fn read_payload(&mut self, len_byte: u8) -> Option<Vec<u8>> {
+        let len = match len_byte & 0x7F {
+            126 => {
+                let mut ext = [0u8; 2];
+                self.stream.read_exact(&mut ext).ok()?;
+                u16::from_be_bytes(ext) as usize
+            }
+            127 => {
+                let mut ext = [0u8; 8];
+                self.stream.read_exact(&mut ext).ok()?;
+                u64::from_be_bytes(ext) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0u8; len];
+        self.stream.read_exact(&mut payload).ok()?;
+        Some(payload)
+    }
+
+    /// The next message of `msg_type`, skipping others. Panics after `TIMEOUT`.
+    pub fn recv_type(&mut self, msg_type: &str) -> Value {
+        self.recv_matching(msg_type, |_| true)
+    }
+
+    /// The next message of `msg_type` that `matches`, skipping others.
+    pub fn recv_matching(&mut self, msg_type: &str, matches: impl Fn(&Value) -> bool) -> Value {
+        let deadline = Instant::now() + TIMEOUT;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.recv_within(left) {
+                Some(message) if message["type"] == msg_type && matches(&message) => return message,
+                Some(_) => {}
+                None => break,
+            }
+        }
+        panic!("no {} message", msg_type);
+    }
+
+    /// Messages of `msg_type` arriving within `wait`.
+    pub fn collect_type(&mut self, msg_type: &str, wait: Duration) -> Vec<Value> {
+        let deadline = Instant::now() + wait;
+        let mut found = Vec::new();
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.recv_within(left) {
+                Some(message) if message["type"] == msg_type => found.push(message),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        found
+    }
+
+    /// Whether the server closes the connection within `wait`. Messages
+    /// before the close are skipped.
+    pub fn is_closed(&mut self, wait: Duration) -> bool {
+        self.closed_within(wait).is_some()
+    }
+
+    /// The close code the server sent, if it closes within `wait`. A close
+    /// without a code (or a dropped socket) is `Some(None)`.
+    pub fn close_code(&mut self, wait: Duration) -> Option<Option<u16>> {
+        self.closed_within(wait)
+    }
+
+    fn closed_within(&mut self, wait: Duration) -> Option<Option<u16>> {
+        let deadline = Instant::now() + wait;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.next_frame(left) {
+                Frame::Message(_) => {}
+                Frame::Closed(code) => return Some(code),
+                Frame::Timeout => return None,
+            }
+        }
+        None
+    }
+}
+
+enum Frame {
+    Message(Value),
+    /// With the close frame's status code, if it had one.
+    Closed(Option<u16>),
+    Timeout,
+}
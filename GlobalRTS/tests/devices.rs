@@ -0,0 +1,708 @@
+//! What UIs see of the fleet: device lists and their filters, stats,
+//! appearance, and how updates reach them.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::{admin_config, TestServer, Ws, ADMIN, TIMEOUT};
+use globalrts::Config;
+use serde_json::{json, Value};
+
+// ============================================================================
+// APPEARANCE
+// ============================================================================
+
+// Device color and icon: derived until chosen, chosen with
+// `PATCH /api/devices/{id}/appearance`, and the same everywhere a device
+// is listed.
+
+#[test]
+fn appearance_defaults_then_sticks_once_chosen() {
+    let server = TestServer::start("appearance");
+    let token = server.pair("robot-01", "robot");
+
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    let default_color = devices["devices"][0]["color"].as_str().unwrap().to_string();
+    assert_eq!(default_color.len(), 7, "{}", devices);
+    assert_eq!(devices["devices"][0]["icon"], "🤖");
+
+    let mut ui = server.ui(None);
+    ui.send(&json!({"type": "getDevices", "data": {"deltas": true}}));
+    ui.recv_type("devices:list");
+
+    let path = "/api/devices/robot-01/appearance";
+    let (status, reply) = server.http("PATCH", path, Some(&json!({"color": "#ff8800", "icon": "🚜"})), None);
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply, json!({"device_id": "robot-01", "color": "#ff8800", "icon": "🚜"}));
+    let changed = ui.recv_type("devices:changed");
+    assert_eq!(changed["data"][0]["color"], "#ff8800", "{}", changed);
+
+    // Registering again keeps the choice
+    server.device("robot-01", "robot", &token);
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["color"], "#ff8800", "{}", devices);
+    assert_eq!(devices["devices"][0]["icon"], "🚜");
+
+    // Only the color goes back to its default
+    let (_, reply) = server.http("PATCH", path, Some(&json!({"color": null})), None);
+    assert_eq!(reply["color"], default_color.as_str());
+    assert_eq!(reply["icon"], "🚜");
+}
+
+#[test]
+fn bad_appearance_is_refused() {
+    let server = TestServer::start("appearance-refused");
+    server.pair("robot-01", "robot");
+    let path = "/api/devices/robot-01/appearance";
+
+    assert_eq!(server.http("PATCH", path, Some(&json!({"color": "orange"})), None).0, 400);
+    assert_eq!(server.http("PATCH", path, Some(&json!({"icon": ""})), None).0, 400);
+    assert_eq!(server.http("PATCH", path, Some(&json!({"color": "#ff8800", "icon": "x".repeat(100)})), None).0, 400);
+    assert_eq!(server.http("PATCH", "/api/devices/robot-99/appearance", Some(&json!({"color": "#ff8800"})), None).0, 404);
+
+    // Nothing half-applied
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_ne!(devices["devices"][0]["color"], "#ff8800", "{}", devices);
+}
+
+// ============================================================================
+// CONNECTED DEVICES
+// ============================================================================
+
+// `GET /api/devices/connected`: devices with a live connection, not
+// every device that's registered.
+
+fn connected_ids(server: &TestServer) -> Vec<String> {
+    let (status, reply) = server.http("GET", "/api/devices/connected", None, None);
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply["count"].as_u64().unwrap() as usize, reply["devices"].as_array().unwrap().len());
+    reply["devices"].as_array().unwrap().iter().map(|d| d["id"].as_str().unwrap().to_string()).collect()
+}
+
+#[test]
+fn only_devices_with_a_live_connection_are_listed() {
+    let server = TestServer::start("connected");
+    let token = server.pair("robot-01", "robot");
+    server.pair("robot-02", "robot");
+    assert!(connected_ids(&server).is_empty());
+
+    let device = server.device("robot-01", "robot", &token);
+    assert_eq!(connected_ids(&server), ["robot-01"]);
+    let (_, reply) = server.http("GET", "/api/devices/connected", None, None);
+    let robot: &Value = &reply["devices"][0];
+    assert_eq!(robot["device_type"], "robot");
+    assert_eq!(robot["status"], "online");
+    assert!(robot["connected_secs"].is_u64(), "{}", robot);
+
+    // Both are registered all along
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"].as_array().unwrap().len(), 2);
+
+    drop(device);
+    let deadline = Instant::now() + TIMEOUT;
+    while !connected_ids(&server).is_empty() {
+        assert!(Instant::now() < deadline, "robot-01 still listed after disconnecting");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// ============================================================================
+// DEVICE INFO
+// ============================================================================
+
+// A registered device can describe itself anew (capabilities, firmware,
+// sensors) with `device:update_info`, without reconnecting.
+
+#[test]
+fn a_firmware_update_reaches_uis_and_the_registry() {
+    let server = TestServer::start("device-info");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.ws("/", "");
+    device.send(&json!({"type": "register", "data": {
+        "device_id": "robot-01", "device_type": "robot", "name": "robot-01", "token": token,
+        "latitude": 34.05, "longitude": -118.24, "capabilities": ["navigate", "camera"]
+    }}));
+    assert_eq!(device.recv_type("registered")["data"]["device"]["capabilities"], json!(["navigate", "camera"]));
+    let mut ui = server.ui(None);
+
+    device.send(&json!({"type": "device:update_info", "data": {"firmware_version": "2.4.1", "sensors": ["temperature", "lidar"]}}));
+    let changed = ui.recv_type("device:info_changed");
+    assert_eq!(changed["data"]["id"], "robot-01");
+    assert_eq!(changed["data"]["firmware_version"], "2.4.1");
+    assert_eq!(changed["data"]["capabilities"], json!(["navigate", "camera"]), "left out, so kept");
+
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    let listed = &devices["devices"][0];
+    assert_eq!((&listed["firmware_version"], &listed["sensors"]), (&json!("2.4.1"), &json!(["temperature", "lidar"])));
+
+    // Only about itself, and within limits
+    device.send(&json!({"type": "device:update_info", "data": {"device_id": "robot-02", "firmware_version": "6.6.6"}}));
+    assert_eq!(device.recv_type("error")["data"]["code"], "invalid_info");
+    device.send(&json!({"type": "device:update_info", "data": {"firmware_version": ""}}));
+    assert_eq!(device.recv_type("error")["data"]["code"], "invalid_info");
+    assert!(ui.collect_type("device:info_changed", Duration::from_millis(300)).is_empty());
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["firmware_version"], "2.4.1");
+
+    // A UI connection isn't a device and can't speak for one
+    ui.send(&json!({"type": "device:update_info", "data": {"device_id": "robot-01", "firmware_version": "0.0.1"}}));
+    assert!(ui.collect_type("device:info_changed", Duration::from_millis(300)).is_empty());
+}
+
+// ============================================================================
+// ETAGS
+// ============================================================================
+
+// `/api/devices` and `/api/stats` carry an ETag, and answer a poller
+// whose copy is still current with 304 and no body.
+
+/// GET `path` with extra header lines: the status, head and body.
+fn get(server: &TestServer, path: &str, headers: &str) -> (u16, String, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n{}\r\n", path, headers);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    let response = String::from_utf8_lossy(&response).to_string();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).and_then(|s| s.parse().ok()).unwrap_or(0);
+    (status, head.to_string(), body.to_string())
+}
+
+fn etag(head: &str) -> String {
+    head.lines().find_map(|line| line.strip_prefix("ETag: ")).expect(head).to_string()
+}
+
+#[test]
+fn an_unchanged_device_list_is_not_sent_again() {
+    let server = TestServer::start("etag-devices");
+    server.pair("robot-01", "robot");
+
+    let (status, head, _) = get(&server, "/api/devices", "");
+    assert_eq!(status, 200);
+    let tag = etag(&head);
+    let (status, head, body) = get(&server, "/api/devices", &format!("If-None-Match: {}\r\n", tag));
+    assert_eq!(status, 304, "{}", head);
+    assert_eq!(etag(&head), tag);
+    assert!(body.is_empty(), "{}", body);
+
+    // A new device is a new list
+    server.pair("robot-02", "robot");
+    let (status, head, body) = get(&server, "/api/devices", &format!("If-None-Match: {}\r\n", tag));
+    assert_eq!(status, 200);
+    assert_ne!(etag(&head), tag);
+    assert!(body.contains("robot-02"), "{}", body);
+
+    // Weak and listed tags match too; another doesn't
+    let tag = etag(&head);
+    assert_eq!(get(&server, "/api/devices", &format!("If-None-Match: \"0\", W/{}\r\n", tag)).0, 304);
+    assert_eq!(get(&server, "/api/devices", "If-None-Match: \"0\"\r\n").0, 200);
+}
+
+#[test]
+fn unchanged_stats_are_not_sent_again() {
+    let server = TestServer::start("etag-stats");
+    server.pair("robot-01", "robot");
+
+    let (_, head, _) = get(&server, "/api/stats", "");
+    let tag = etag(&head);
+    assert_eq!(get(&server, "/api/stats", &format!("If-None-Match: {}\r\n", tag)).0, 304);
+}
+
+// ============================================================================
+// DEVICES NEAR A POINT
+// ============================================================================
+
+// `GET /api/devices/near`: the devices within a radius of a point,
+// nearest first, with their distance.
+
+#[test]
+fn devices_near_a_point_are_listed_nearest_first() {
+    let server = TestServer::start("near");
+    let la = server.pair("robot-01", "robot");
+    let sd = server.pair("robot-02", "robot");
+    let _la = server.device("robot-01", "robot", &la);
+    let mut san_diego = server.ws("/", "");
+    san_diego.send(&json!({"type": "register", "data": {
+        "device_id": "robot-02", "device_type": "robot", "name": "robot-02",
+        "token": sd, "latitude": 32.7157, "longitude": -117.1611
+    }}));
+    san_diego.recv_type("registered");
+
+    let (status, near) = server.http("GET", "/api/devices/near?lat=34.06&lon=-118.24&radius=5000", None, None);
+    assert_eq!(status, 200, "{}", near);
+    assert_eq!(near["count"], 1, "{}", near);
+    assert_eq!(near["devices"][0]["id"], "robot-01");
+    assert!(near["devices"][0]["distance_m"].as_f64().unwrap() < 5000.0, "{}", near);
+
+    let (_, near) = server.http("GET", "/api/devices/near?lat=34.06&lon=-118.24&radius=200000", None, None);
+    let ids: Vec<_> = near["devices"].as_array().unwrap().iter().map(|d| d["id"].clone()).collect();
+    assert_eq!(ids, [json!("robot-01"), json!("robot-02")], "{}", near);
+
+    for query in ["lat=34&lon=-118", "lat=91&lon=0&radius=10", "lat=34&lon=-118&radius=-1", "lat=x&lon=0&radius=1"] {
+        let (status, _) = server.http("GET", &format!("/api/devices/near?{}", query), None, None);
+        assert_eq!(status, 400, "{}", query);
+    }
+}
+
+// ============================================================================
+// SENSOR FILTER
+// ============================================================================
+
+// `GET /api/devices?sensor=&op=&value=` picks devices out by their latest
+// sensor reading, which is kept on the device row as telemetry arrives.
+
+fn ids(reply: &Value) -> Vec<&str> {
+    let mut ids: Vec<&str> = reply["devices"].as_array().unwrap().iter().map(|d| d["id"].as_str().unwrap()).collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn devices_filter_on_their_latest_sensor_reading() {
+    let server = TestServer::start("sensor-filter");
+    let readings = [
+        ("hot-01", json!({"temperature": 95.5, "humidity": 20})),
+        ("warm-01", json!({"temperature": 80})),
+        ("cold-01", json!({"temperature": -4})),
+        ("dry-01", json!({"humidity": 10})),
+        ("odd-01", json!({"temperature": "n/a"})),
+    ];
+    let mut devices = Vec::new();
+    for (id, sensors) in &readings {
+        let token = server.pair(id, "sensor");
+        let mut device = server.device(id, "sensor", &token);
+        device.send(&json!({"type": "telemetry", "data": {"latitude": 0.0, "longitude": 0.0, "sensors": sensors, "ack": true}}));
+        device.recv_type("telemetry:ack");
+        devices.push(device);
+    }
+    // A later reading without sensors keeps the last ones
+    devices[0].send(&json!({"type": "telemetry", "data": {"latitude": 1.0, "longitude": 1.0, "ack": true}}));
+    devices[0].recv_type("telemetry:ack");
+
+    let filter = |query: &str| {
+        let (status, reply) = server.http("GET", &format!("/api/devices?{}", query), None, None);
+        assert_eq!(status, 200, "{}: {}", query, reply);
+        reply
+    };
+    assert_eq!(ids(&filter("sensor=temperature&op=gt&value=80")), ["hot-01"]);
+    assert_eq!(ids(&filter("sensor=temperature&op=lt&value=80")), ["cold-01"]);
+    assert_eq!(ids(&filter("sensor=temperature&op=eq&value=80")), ["warm-01"]);
+    assert_eq!(ids(&filter("sensor=temperature&op=gt&value=-100")), ["cold-01", "hot-01", "warm-01"]);
+    assert_eq!(ids(&filter("sensor=humidity&op=lt&value=15")), ["dry-01"]);
+    assert!(ids(&filter("sensor=pressure&op=gt&value=0")).is_empty());
+    assert_eq!(ids(&server.http("GET", "/api/devices", None, None).1).len(), 5);
+
+    for bad in ["sensor=temperature&op=ge&value=80", "sensor=temperature&op=gt&value=hot", "sensor=temperature&op=gt", "sensor=a.b&op=gt&value=1"] {
+        let (status, reply) = server.http("GET", &format!("/api/devices?{}", bad), None, None);
+        assert_eq!(status, 400, "{}: {}", bad, reply);
+    }
+}
+
+// ============================================================================
+// FLEET STATS
+// ============================================================================
+
+// `GET /api/stats`: the fleet's headline numbers in one call.
+
+#[test]
+fn stats_count_the_fleet() {
+    let server = TestServer::start("stats");
+    let token = server.pair("robot-01", "robot");
+    server.pair("robot-02", "robot");
+    server.pair("drone-01", "drone");
+    let (status, _) = server.http("POST", "/api/pair/request", Some(&json!({"device_id": "sensor-01", "name": "Sensor", "device_type": "iot"})), None);
+    assert_eq!(status, 200);
+
+    let mut device = server.device("robot-01", "robot", &token);
+    for battery in [90, 89, 88] {
+        device.send(&json!({"type": "telemetry", "data": {"latitude": 34.05, "longitude": -118.24, "battery": battery, "ack": true}}));
+        device.recv_type("telemetry:ack");
+    }
+
+    let mut ui = server.ui(None);
+    for device_id in ["robot-01", "robot-02", "robot-02"] {
+        ui.send(&json!({"type": "sendCommand", "data": {"device_id": device_id, "command_type": "ring", "payload": {}}}));
+        ui.recv_type("command:sent");
+    }
+
+    let (status, stats) = server.http("GET", "/api/stats", None, None);
+    assert_eq!(status, 200, "{}", stats);
+    assert_eq!(stats["devices"], json!({
+        "total": 3, "online": 1, "offline": 2, "sleeping": 0, "stale": 0,
+        "by_type": {"drone": 1, "robot": 2},
+    }));
+    assert_eq!(stats["pairing_requests"], 1);
+    assert_eq!(stats["commands_today"], json!({"queued": 2, "sent": 1}));
+    assert_eq!(stats["telemetry_today"], 3);
+}
+
+// ============================================================================
+// HEATMAP
+// ============================================================================
+
+// `GET /api/heatmap`: every device's positions over a range, counted per
+// lat/lon cell for the UI's heatmap layer.
+
+/// Cells as (lat, lon) in ten-thousandths of a degree, with their weight.
+fn cells(reply: &Value) -> Vec<(i64, i64, u64)> {
+    reply["cells"].as_array().unwrap().iter()
+        .map(|c| ((c[0].as_f64().unwrap() * 1e4).round() as i64, (c[1].as_f64().unwrap() * 1e4).round() as i64, c[2].as_f64().unwrap() as u64))
+        .collect()
+}
+
+#[test]
+fn heatmap_counts_the_fleets_visits_per_cell() {
+    let server = TestServer::start("heatmap");
+    // robot-01 waits in one cell, then heads north through the next;
+    // robot-02 passes through robot-01's waiting cell once
+    let tracks = [
+        ("robot-01", vec![(10.0005, 20.0005), (10.0005, 20.0005), (10.0005, 20.0005), (10.0015, 20.0005)]),
+        ("robot-02", vec![(10.0005, 20.0005), (10.0035, 20.0045)]),
+    ];
+    for (id, track) in &tracks {
+        let token = server.pair(id, "robot");
+        let mut device = server.device(id, "robot", &token);
+        for (lat, lon) in track {
+            device.send(&json!({"type": "telemetry", "data": {"latitude": lat, "longitude": lon, "ack": true}}));
+            device.recv_type("telemetry:ack");
+        }
+    }
+
+    let (status, reply) = server.http("GET", "/api/heatmap?cell=0.001", None, None);
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply["records"], 6);
+    assert_eq!(reply["cell"], 0.001);
+    assert_eq!(cells(&reply), [(100005, 200005, 4), (100015, 200005, 1), (100035, 200045, 1)]);
+
+    // A coarser cell folds them together
+    let (status, reply) = server.http("GET", "/api/heatmap?cell=0.01", None, None);
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(cells(&reply), [(100050, 200050, 6)]);
+
+    // Nothing before the range
+    let (status, reply) = server.http("GET", "/api/heatmap?end=1", None, None);
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply["records"], 0);
+    assert!(cells(&reply).is_empty());
+
+    for bad in ["cell=0", "cell=-1", "cell=0.00001", "cell=45", "cell=NaN", "cell=fine"] {
+        let (status, reply) = server.http("GET", &format!("/api/heatmap?{}", bad), None, None);
+        assert_eq!(status, 400, "{}: {}", bad, reply);
+    }
+}
+
+// ============================================================================
+// SLEEP
+// ============================================================================
+
+// Device sleep: a battery device announces a low-power sleep with a wake
+// time, and until then it's neither offline nor stale and its commands
+// wait for it.
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+/// The device as `GET /api/devices` lists it.
+fn listed(server: &TestServer, device_id: &str) -> Value {
+    let (_, reply) = server.http("GET", "/api/devices", None, None);
+    reply["devices"].as_array().unwrap().iter().find(|d| d["id"] == device_id).cloned().unwrap()
+}
+
+/// Send a command from `ui`, returning its `command:sent` reply.
+fn send_command(ui: &mut Ws, device_id: &str) -> Value {
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": device_id, "command_type": "ring", "payload": {}}}));
+    ui.recv_type("command:sent")
+}
+
+#[test]
+fn a_sleeping_device_queues_commands_until_its_heartbeat() {
+    let server = TestServer::start("sleep-connected");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    let wake_at = now() + 600;
+    device.send(&json!({"type": "sleep", "data": {"wake_at": wake_at}}));
+    let sleeping = ui.recv_type("device:sleeping");
+    assert_eq!(sleeping["data"], json!({"deviceId": "robot-01", "wakeAt": wake_at}));
+    assert_eq!(listed(&server, "robot-01")["status"], "sleeping");
+    assert_eq!(listed(&server, "robot-01")["wake_at"], wake_at);
+
+    // Still connected, but nothing goes out while it sleeps
+    let sent = send_command(&mut ui, "robot-01");
+    assert_eq!(sent["data"]["status"], "queued", "{}", sent);
+    assert!(device.collect_type("command", Duration::from_millis(300)).is_empty());
+
+    // A heartbeat wakes it, and the queue follows
+    device.send(&json!({"type": "ping", "data": {"t": 1}}));
+    let command = device.recv_type("command");
+    assert_eq!(command["data"]["commandId"], sent["data"]["commandId"]);
+    assert_eq!(ui.recv_type("device:online")["data"]["id"], "robot-01");
+    let device_info = listed(&server, "robot-01");
+    assert_eq!((device_info["status"].as_str(), device_info["wake_at"].as_i64()), (Some("online"), None));
+}
+
+#[test]
+fn a_device_that_hangs_up_to_sleep_is_not_offline_or_stale() {
+    let server = TestServer::start("sleep-hung-up");
+    let token = server.pair("robot-01", "robot");
+    let mut ui = server.ui(None);
+    let mut device = server.device("robot-01", "robot", &token);
+
+    device.send(&json!({"type": "sleep", "data": {"wake_at": now() + 600}}));
+    ui.recv_type("device:sleeping");
+    drop(device);
+    assert!(ui.collect_type("device:offline", Duration::from_millis(300)).is_empty());
+    assert_eq!(listed(&server, "robot-01")["status"], "sleeping");
+    let (_, stats) = server.http("GET", "/api/stats", None, None);
+    assert_eq!((stats["devices"]["sleeping"].as_i64(), stats["devices"]["offline"].as_i64(), stats["devices"]["stale"].as_i64()),
+        (Some(1), Some(0), Some(0)), "{}", stats);
+
+    let sent = send_command(&mut ui, "robot-01");
+    assert_eq!(sent["data"]["status"], "queued", "{}", sent);
+
+    // Registering again brings it back online, and the command with it
+    let mut device = server.device("robot-01", "robot", &token);
+    let command = device.recv_type("command");
+    assert_eq!(command["data"]["commandId"], sent["data"]["commandId"]);
+    assert_eq!(listed(&server, "robot-01")["status"], "online");
+}
+
+#[test]
+fn a_sleep_must_end_in_the_future() {
+    let server = TestServer::start("sleep-invalid");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    for wake_at in [now() - 1, now() + 30 * 86400] {
+        device.send(&json!({"type": "sleep", "data": {"wake_at": wake_at}}));
+        assert_eq!(device.recv_type("error")["data"]["code"], "invalid_sleep");
+    }
+    assert_eq!(listed(&server, "robot-01")["status"], "online");
+}
+
+// ============================================================================
+// DEVICE LIST DELTAS
+// ============================================================================
+
+// UIs that ask for deltas (`getDevices` with `"deltas": true`) keep their
+// device list from devices:added / devices:removed / devices:changed after
+// the first snapshot. Other UIs keep the per-event messages.
+
+#[test]
+fn pairing_and_revoking_produce_deltas() {
+    let server = TestServer::start("deltas");
+    let mut ui = server.ws("/", "");
+    ui.send(&json!({"type": "getDevices", "data": {"deltas": true}}));
+    assert_eq!(ui.recv_type("devices:list")["data"], json!([]));
+    let mut legacy = server.ui(None);
+
+    let token = server.pair("robot-01", "robot");
+    let added = ui.recv_type("devices:added");
+    assert_eq!(added["data"].as_array().unwrap().len(), 1, "{}", added);
+    assert_eq!(added["data"][0]["id"], "robot-01");
+    assert_eq!(added["data"][0]["status"], "offline");
+
+    let device = server.device("robot-01", "robot", &token);
+    let changed = ui.recv_type("devices:changed");
+    assert_eq!(changed["data"][0]["id"], "robot-01");
+    assert_eq!(changed["data"][0]["status"], "online");
+    drop(device);
+    let changed = ui.recv_type("devices:changed");
+    assert_eq!(changed["data"], json!([{"id": "robot-01", "status": "offline"}]));
+
+    let (status, _) = server.http("DELETE", "/api/devices/robot-01", None, None);
+    assert_eq!(status, 200);
+    assert_eq!(ui.recv_type("devices:removed")["data"], json!(["robot-01"]));
+
+    // Per-event UIs see what they always did, and no deltas
+    let mut seen = Vec::new();
+    while let Some(message) = legacy.recv_within(Duration::from_millis(300)) {
+        seen.push(message["type"].as_str().unwrap().to_string());
+    }
+    assert!(seen.contains(&"device:online".to_string()), "{:?}", seen);
+    assert!(seen.contains(&"device:revoked".to_string()), "{:?}", seen);
+    assert!(!seen.iter().any(|t| t.starts_with("devices:")), "{:?}", seen);
+}
+
+// ============================================================================
+// COALESCED UPDATES
+// ============================================================================
+
+// Coalesced device updates: UIs get the latest position per device in one
+// timed `devices:update` batch.
+
+fn telemetry(device: &mut Ws, latitude: f64) {
+    device.send(&json!({"type": "telemetry", "data": {"latitude": latitude, "longitude": -118.24, "battery": 90.0}}));
+}
+
+#[test]
+fn rapid_updates_arrive_as_one_batch() {
+    let server = TestServer::start_with("coalesce", Config { update_interval_ms: 500, ..Config::default() });
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    // Wait out one flush, so the next three land well inside one interval
+    telemetry(&mut device, 34.00);
+    ui.recv_type("devices:update");
+    for latitude in [34.01, 34.02, 34.03] {
+        telemetry(&mut device, latitude);
+    }
+
+    let mut batches = Vec::new();
+    while let Some(message) = ui.recv_within(Duration::from_millis(1500)) {
+        assert_ne!(message["type"], "device:update", "updates are batched, not forwarded");
+        if message["type"] == "devices:update" {
+            batches.push(message);
+        }
+    }
+    assert_eq!(batches.len(), 1, "{:?}", batches);
+    let updates = batches[0]["data"].as_array().unwrap();
+    assert_eq!(updates.len(), 1, "{:?}", updates);
+    assert_eq!(updates[0]["id"], "robot-01");
+    assert_eq!(updates[0]["latitude"], 34.03);
+}
+
+// ============================================================================
+// INTERPOLATION
+// ============================================================================
+
+// GLOBALRTS_INTERPOLATE_HZ: between two real samples, UIs get projected
+// positions along the last segment, flagged `interpolated`.
+
+#[test]
+fn positions_between_samples_are_interpolated() {
+    let server = TestServer::start_with("interpolation", Config { interpolate_hz: 10, ..Config::default() });
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    // A single sample is no segment: nothing is projected
+    telemetry(&mut device, 34.00);
+    let first = ui.recv_type("device:update");
+    assert!(first["data"].get("interpolated").is_none(), "{}", first);
+    assert!(ui.collect_type("device:update", Duration::from_millis(400)).is_empty());
+
+    thread::sleep(Duration::from_millis(600));
+    telemetry(&mut device, 34.01);
+    let second = ui.recv_matching("device:update", |m| m["data"]["latitude"] == 34.01);
+    assert!(second["data"].get("interpolated").is_none(), "{}", second);
+
+    let projected = ui.collect_type("device:update", Duration::from_millis(1200));
+    assert!(projected.len() >= 3, "{:?}", projected);
+    let mut last = 34.01;
+    for update in &projected {
+        assert_eq!(update["data"]["interpolated"], true, "{}", update);
+        // On along the segment, no further than one more of it
+        let latitude = update["data"]["latitude"].as_f64().unwrap();
+        assert!(latitude >= last && latitude <= 34.02 + 1e-9, "{:?}", projected);
+        last = latitude;
+    }
+}
+
+// ============================================================================
+// DEVICE TYPE CHECK
+// ============================================================================
+
+// GLOBALRTS_TYPE_CHECK_SAMPLES: a device of an airborne type that keeps
+// reporting no altitude gets a `device:type_warning` to UIs.
+
+/// Report `altitude`, and wait until it's been stored.
+fn report_altitude(device: &mut Ws, altitude: f64) {
+    device.send(&json!({"type": "telemetry", "data": {
+        "latitude": 34.05, "longitude": -118.24, "altitude": altitude, "battery": 90.0, "ack": true
+    }}));
+    device.recv_type("telemetry:ack");
+}
+
+#[test]
+fn a_drone_that_never_leaves_the_ground_is_flagged() {
+    let server = TestServer::start_with("type-check", Config { type_check_samples: 5, ..Config::default() });
+    let drone_token = server.pair("drone-01", "drone");
+    let robot_token = server.pair("robot-01", "robot");
+    let mut drone = server.device("drone-01", "drone", &drone_token);
+    let mut robot = server.device("robot-01", "robot", &robot_token);
+    let mut ui = server.ui(None);
+
+    // Taking off before the threshold starts the count over
+    for altitude in [0.0, 0.0, 0.0, 0.0, 40.0, 0.0, 0.0, 0.0, 0.0] {
+        report_altitude(&mut drone, altitude);
+    }
+    assert!(ui.collect_type("device:type_warning", Duration::from_millis(300)).is_empty());
+
+    report_altitude(&mut drone, 0.2);
+    let warning = ui.recv_type("device:type_warning");
+    assert_eq!(warning["data"]["deviceId"], "drone-01", "{}", warning);
+    assert_eq!(warning["data"]["deviceType"], "drone");
+    assert_eq!(warning["data"]["samples"], 5);
+
+    // Once per run, and a ground type on the ground is as it should be
+    for _ in 0..6 {
+        report_altitude(&mut drone, 0.0);
+        report_altitude(&mut robot, 0.0);
+    }
+    assert!(ui.collect_type("device:type_warning", Duration::from_millis(300)).is_empty());
+}
+
+// ============================================================================
+// CAPABILITIES BY TYPE
+// ============================================================================
+
+// GLOBALRTS_TYPE_CAPABILITIES: a device that declares no capabilities
+// gets its type's, and takes only the commands they name.
+
+#[test]
+fn a_drone_takes_navigate_by_default_but_a_sensor_does_not() {
+    let type_capabilities = BTreeMap::from([
+        ("drone".to_string(), vec!["navigate".to_string(), "takeoff".to_string(), "land".to_string()]),
+        ("sensor".to_string(), vec!["poll".to_string()]),
+    ]);
+    let server = TestServer::start_with("type-capabilities", Config { type_capabilities, ..admin_config() });
+    let drone_token = server.pair("drone-01", "drone");
+    let sensor_token = server.pair("sensor-01", "sensor");
+    let robot_token = server.pair("robot-01", "robot");
+    let mut drone = server.device("drone-01", "drone", &drone_token);
+    let _sensor = server.device("sensor-01", "sensor", &sensor_token);
+    let _robot = server.device("robot-01", "robot", &robot_token);
+
+    let (_, devices) = server.http("GET", "/api/devices", None, Some(ADMIN));
+    let capabilities = |id: &str| devices["devices"].as_array().unwrap().iter()
+        .find(|d| d["id"] == id)
+        .map(|d| d["capabilities"].clone())
+        .unwrap();
+    assert_eq!(capabilities("drone-01"), json!(["navigate", "takeoff", "land"]));
+    assert_eq!(capabilities("sensor-01"), json!(["poll"]));
+    assert_eq!(capabilities("robot-01"), json!([]));
+
+    let navigate = json!({"latitude": 34.05, "longitude": -118.24});
+    let mut ui = server.ui(Some(ADMIN));
+    ui.send(&json!({"type": "sendCommand", "data": {
+        "device_id": "drone-01", "command_type": "navigate", "payload": navigate
+    }}));
+    let sent = ui.recv_type("command:sent");
+    assert_eq!(sent["data"]["status"], "sent", "{}", sent);
+    drone.recv_type("command");
+
+    ui.send(&json!({"type": "sendCommand", "data": {
+        "device_id": "sensor-01", "command_type": "navigate", "payload": navigate
+    }}));
+    let rejected = ui.recv_type("command:rejected");
+    assert_eq!(rejected["data"]["deviceId"], "sensor-01", "{}", rejected);
+    assert!(rejected["data"]["error"].as_str().unwrap().contains("doesn't take navigate"), "{}", rejected);
+
+    // In a group each device answers for itself; a type not configured takes anything
+    let group = json!({"device_ids": ["drone-01", "sensor-01", "robot-01"], "command_type": "navigate", "payload": navigate});
+    let (status, results) = server.http("POST", "/api/commands", Some(&group), Some(ADMIN));
+    assert_eq!(status, 200, "{}", results);
+    let statuses: Vec<_> = results["results"].as_array().unwrap().iter().map(|r| r["status"].clone()).collect();
+    assert_eq!(statuses, [json!("ok"), json!("error"), json!("ok")], "{}", results);
+}
@@ -0,0 +1,69 @@
+//! The server embedded through the library API: run in-process on a port
+//! of its own choosing, then shut down.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use common::{temp_dir, Ws};
+use globalrts::{Config, Server};
+use serde_json::json;
+
+#[test]
+fn embedded_server_starts_and_shuts_down() {
+    let data_dir = temp_dir("library").join("data");
+    let handle = Server::run(Config { port: 0, data_dir: data_dir.to_string_lossy().into_owned(), ..Config::default() }).unwrap();
+    let port = handle.local_addr().port();
+    assert_ne!(port, 0);
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    stream.write_all(b"GET /api/version HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let mut ui = Ws::connect(port, "/", "").unwrap();
+    ui.send(&json!({"type": "getDevices", "data": {}}));
+    ui.recv_type("devices:list");
+
+    handle.shutdown().unwrap();
+    assert_eq!(ui.close_code(Duration::from_secs(2)), Some(Some(1001)));
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    assert!(data_dir.join("state.db").exists());
+}
+
+#[test]
+fn two_embedded_servers_keep_separate_state() {
+    let start = |label: &str| {
+        let data_dir = temp_dir(label).join("data");
+        Server::run(Config { port: 0, data_dir: data_dir.to_string_lossy().into_owned(), ..Config::default() }).unwrap()
+    };
+    let (first, second) = (start("library-first"), start("library-second"));
+
+    let pair = |port: u16| {
+        let body = r#"{"device_id": "robot-01", "name": "robot-01", "device_type": "robot"}"#;
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+        write!(stream, "POST /api/pair/request HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    };
+    let pending = |port: u16| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+        stream.write_all(b"GET /api/pair/requests HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response.contains("robot-01")
+    };
+    pair(first.local_addr().port());
+    assert!(pending(first.local_addr().port()));
+    assert!(!pending(second.local_addr().port()));
+
+    first.shutdown().unwrap();
+    second.shutdown().unwrap();
+}
@@ -0,0 +1,439 @@
+//! Getting devices into the fleet: pairing by code, auto-approval, bulk
+//! import and registry backups, and the checks a registration passes.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+use common::{admin_config, TestServer, Ws, ADMIN};
+use globalrts::policy::RegistrationPolicy;
+use globalrts::Config;
+use serde_json::{json, Value};
+
+// ============================================================================
+// PAIRING CODES
+// ============================================================================
+
+// Pairing requests carry `seconds_remaining` from the server's clock, so a
+// UI can count a code down without trusting its own clock.
+
+#[test]
+fn seconds_remaining_counts_down() {
+    let server = TestServer::start("pairing-countdown");
+    let (status, _) = server.http("POST", "/api/pair/request", Some(&json!({"device_id": "robot-01", "name": "Robot", "device_type": "robot"})), None);
+    assert_eq!(status, 200);
+
+    let remaining = || {
+        let (_, reply) = server.http("GET", "/api/pair/requests", None, None);
+        reply["requests"][0]["seconds_remaining"].as_i64().unwrap_or_else(|| panic!("{}", reply))
+    };
+    let first = remaining();
+    assert!(first > 290 && first <= 300, "{}", first);
+    thread::sleep(Duration::from_millis(1100));
+    let second = remaining();
+    assert!(second < first, "{} then {}", first, second);
+
+    thread::sleep(Duration::from_millis(1100));
+    let mut ui = server.ui(None);
+    let broadcast = ui.recv_matching("pairing:requests", |m| m["data"]["requests"][0]["seconds_remaining"].as_i64().is_some_and(|s| s < second));
+    assert_eq!(broadcast["data"]["requests"][0]["device_id"], "robot-01");
+}
+
+#[test]
+fn requests_are_broadcast_as_they_change() {
+    let server = TestServer::start("pairing-events");
+    let mut ui = server.ui(None);
+    ui.recv_type("pairing:requests");
+
+    let request = json!({"device_id": "robot-01", "name": "Robot", "device_type": "robot"});
+    assert_eq!(server.http("POST", "/api/pair/request", Some(&request), None).0, 200);
+    let started = std::time::Instant::now();
+    let broadcast = ui.recv_type("pairing:requests");
+    // Well inside the reconcile interval: it came from the request itself
+    assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+    assert_eq!(broadcast["data"]["requests"][0]["device_id"], "robot-01");
+
+    assert_eq!(server.http("DELETE", "/api/pair/robot-01", None, None).0, 200);
+    assert_eq!(ui.recv_type("pairing:requests")["data"]["requests"], json!([]));
+}
+
+// ============================================================================
+// PAIRING COOLDOWN
+// ============================================================================
+
+// With GLOBALRTS_PAIR_COOLDOWN_SECS set, a device can't ask for a fresh
+// pairing code while its last one is new. It gets a 429 whose Retry-After
+// says how long is left.
+
+fn pairing_cooldown_config() -> Config {
+    Config { pair_cooldown_secs: 30, ..Config::default() }
+}
+
+#[test]
+fn asking_again_too_soon_says_when_to_retry() {
+    let server = TestServer::start_with("pair-cooldown", pairing_cooldown_config());
+    let request = json!({"device_id": "robot-01", "name": "Robot", "device_type": "robot"}).to_string();
+    let (status, _, _) = server.http_raw("POST", "/api/pair/request", Some(&request), None);
+    assert_eq!(status, 200);
+
+    let (status, head, body) = server.http_raw("POST", "/api/pair/request", Some(&request), None);
+    assert_eq!(status, 429, "{}", head);
+    assert!(head.starts_with("HTTP/1.1 429 Too Many Requests"), "{}", head);
+    let retry_after: u64 = head.lines()
+        .find_map(|line| line.strip_prefix("Retry-After: "))
+        .unwrap_or_else(|| panic!("no Retry-After in {}", head))
+        .parse()
+        .unwrap();
+    assert!((29..=30).contains(&retry_after), "{}", retry_after);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["retry_after"], retry_after);
+
+    // Other devices aren't held up, and the pending code still works
+    let other = json!({"device_id": "robot-02", "name": "Robot", "device_type": "robot"}).to_string();
+    assert_eq!(server.http_raw("POST", "/api/pair/request", Some(&other), None).0, 200);
+    let (_, pending) = server.http("GET", "/api/pair/requests", None, None);
+    assert_eq!(pending["requests"].as_array().unwrap().len(), 2);
+}
+
+// ============================================================================
+// AUTO-APPROVAL
+// ============================================================================
+
+// Pairing auto-approval: device ids matching GLOBALRTS_PAIR_AUTO_APPROVE
+// get a token straight from `/api/pair/request`, and it's in the audit log.
+
+fn auto_approval_config() -> Config {
+    Config { pair_auto_approve: vec!["factory-*".to_string(), "bench-07".to_string()], ..admin_config() }
+}
+
+fn request(server: &TestServer, device_id: &str) -> Value {
+    let body = json!({"device_id": device_id, "name": device_id, "device_type": "robot"});
+    let (status, reply) = server.http("POST", "/api/pair/request", Some(&body), None);
+    assert_eq!(status, 200, "{}", reply);
+    reply
+}
+
+fn pending(server: &TestServer) -> Vec<String> {
+    let (_, reply) = server.http("GET", "/api/pair/requests", None, None);
+    let mut ids: Vec<String> = reply["requests"].as_array().unwrap().iter().map(|r| r["device_id"].as_str().unwrap().to_string()).collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn matching_ids_pair_without_a_code() {
+    let server = TestServer::start_with("auto-approve", auto_approval_config());
+
+    let reply = request(&server, "factory-001");
+    assert_eq!(reply["status"], "paired", "{}", reply);
+    assert_eq!(reply["auto_approved"], true);
+    let token = reply["token"].as_str().unwrap();
+    server.device("factory-001", "robot", token);
+
+    let exact = request(&server, "bench-07");
+    assert_eq!(exact["status"], "paired", "{}", exact);
+    assert!(pending(&server).is_empty());
+
+    let (status, audit) = server.http("GET", "/api/audit?action=pair.auto_approve", None, Some(ADMIN));
+    assert_eq!(status, 200, "{}", audit);
+    let entries = audit["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 2, "{}", audit);
+    assert_eq!(entries[0]["target"], "factory-001");
+    assert_eq!(entries[0]["actor"], "server");
+    assert_eq!(entries[0]["detail"], "matched factory-*");
+    assert_eq!(entries[1]["target"], "bench-07");
+    assert_eq!(server.http("GET", "/api/audit", None, None).0, 401);
+}
+
+#[test]
+fn other_ids_and_repairs_go_through_the_code() {
+    let server = TestServer::start_with("auto-approve-manual", auto_approval_config());
+
+    for device_id in ["robot-01", "bench-070", "my-factory-1"] {
+        let reply = request(&server, device_id);
+        assert_eq!(reply["status"], "pending", "{}: {}", device_id, reply);
+        assert!(reply.get("token").is_none(), "{}", reply);
+    }
+    assert_eq!(pending(&server), ["bench-070", "my-factory-1", "robot-01"]);
+
+    // A device already paired gets a new token only from an operator
+    assert_eq!(request(&server, "factory-001")["status"], "paired");
+    assert_eq!(request(&server, "factory-001")["status"], "pending");
+    assert!(pending(&server).contains(&"factory-001".to_string()));
+
+    let (_, audit) = server.http("GET", "/api/audit", None, Some(ADMIN));
+    assert_eq!(audit["entries"].as_array().unwrap().len(), 1, "{}", audit);
+}
+
+// ============================================================================
+// BULK IMPORT
+// ============================================================================
+
+// Bulk provisioning: `POST /api/devices/import` puts devices straight into
+// the registry with tokens, all or nothing, behind the admin token.
+
+#[test]
+fn imported_devices_connect_with_their_tokens() {
+    let server = TestServer::start_with("import", admin_config());
+    let preissued = "a1".repeat(20);
+    let batch = json!([
+        {"id": "robot-01", "name": "Robot One", "type": "robot", "token": preissued},
+        {"id": "drone-01", "type": "drone"},
+        {"id": "sensor-01", "name": "Sensor", "type": "sensor"},
+    ]);
+
+    let (status, reply) = server.http("POST", "/api/devices/import", Some(&batch), Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!((reply["ok"].as_u64(), reply["failed"].as_u64()), (Some(3), Some(0)));
+    assert_eq!(reply["results"][0], json!({"id": "robot-01", "status": "ok", "token": preissued}));
+    let generated = reply["results"][1]["token"].as_str().unwrap().to_string();
+    assert_eq!(generated.len(), 64);
+
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"].as_array().unwrap().len(), 3, "{}", devices);
+
+    // No pairing code needed: the pre-issued token is enough
+    server.device("robot-01", "robot", &preissued);
+    server.device("drone-01", "drone", &generated);
+}
+
+#[test]
+fn one_bad_row_imports_nothing() {
+    let server = TestServer::start_with("import-refused", admin_config());
+    let batch = json!([
+        {"id": "robot-01", "type": "robot"},
+        {"id": "robot-02", "type": "robot", "token": "too-short"},
+        {"id": "robot-01", "type": "robot"},
+    ]);
+
+    let (status, reply) = server.http("POST", "/api/devices/import", Some(&batch), Some(ADMIN));
+    assert_eq!(status, 400, "{}", reply);
+    assert_eq!(reply["error"], "2 of 3 rows refused; nothing imported");
+    assert_eq!((reply["ok"].as_u64(), reply["failed"].as_u64()), (Some(0), Some(3)));
+    let statuses: Vec<&str> = reply["results"].as_array().unwrap().iter()
+        .map(|r| r["status"].as_str().unwrap())
+        .collect();
+    assert_eq!(statuses, ["error", "error", "error"]);
+    // The first row was fine: only the others kept it out
+    assert_eq!(reply["results"][0]["error"], "not imported: another row was refused");
+    assert_eq!(reply["results"][2]["error"], "duplicate id in import");
+    assert!(reply["results"][0].get("token").is_none());
+
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"], json!([]));
+}
+
+#[test]
+fn import_needs_the_admin_token() {
+    let server = TestServer::start_with("import-auth", admin_config());
+    let batch = json!([{"id": "robot-01", "type": "robot"}]);
+
+    assert_eq!(server.http("POST", "/api/devices/import", Some(&batch), None).0, 401);
+    assert_eq!(server.http("POST", "/api/devices/import", Some(&batch), Some("guess")).0, 401);
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"], json!([]));
+}
+
+// ============================================================================
+// REGISTRY BACKUP
+// ============================================================================
+
+// Registry backup: `GET /api/devices/export` writes out every device with
+// its token, and `POST /api/devices/restore` puts them back on another
+// server, all or nothing.
+
+#[test]
+fn an_export_restores_onto_a_fresh_server() {
+    let old = TestServer::start_with("export-old", admin_config());
+    let token = old.pair("robot-01", "robot");
+    old.pair("drone-01", "drone");
+    let (status, _) = old.http("PATCH", "/api/devices/robot-01/appearance", Some(&json!({"color": "#ff8800", "icon": "R"})), None);
+    assert_eq!(status, 200);
+    assert_eq!(old.http("GET", "/api/devices/export", None, None).0, 401);
+
+    let (status, export) = old.http("GET", "/api/devices/export", None, Some(ADMIN));
+    assert_eq!(status, 200, "{}", export);
+    assert_eq!(export["version"], 1);
+    let devices = export["devices"].as_array().unwrap();
+    assert_eq!(devices.len(), 2, "{}", export);
+    assert_eq!(devices[1]["token"], token.as_str());
+    assert_eq!(devices[1]["color"], "#ff8800");
+    // Test servers take turns on one port
+    drop(old);
+
+    let new = TestServer::start_with("export-new", admin_config());
+    let (status, reply) = new.http("POST", "/api/devices/restore", Some(&export), Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply["results"][0], json!({"id": "drone-01", "status": "ok", "outcome": "restored"}));
+    let (_, again) = new.http("GET", "/api/devices/export", None, Some(ADMIN));
+    assert_eq!(again["devices"], export["devices"]);
+
+    // The old token still works, with no pairing on the new server
+    new.device("robot-01", "robot", &token);
+
+    // Restoring again skips what's there, unless told to replace it
+    let (_, reply) = new.http("POST", "/api/devices/restore", Some(&export), Some(ADMIN));
+    assert_eq!(reply["results"][1]["outcome"], "skipped", "{}", reply);
+    let (_, reply) = new.http("POST", "/api/devices/restore?conflict=replace", Some(&export), Some(ADMIN));
+    assert_eq!(reply["results"][1]["outcome"], "replaced", "{}", reply);
+}
+
+#[test]
+fn a_bad_export_restores_nothing() {
+    let server = TestServer::start_with("restore-bad", admin_config());
+    let token = server.pair("robot-01", "robot");
+    let device = |id: &str, token: &str| json!({"id": id, "name": id, "device_type": "robot", "status": "offline", "token": token});
+
+    // robot-02 claims robot-01's token: robot-03 goes back out with it
+    let export = json!({"version": 1, "devices": [device("robot-03", &"b2".repeat(32)), device("robot-02", &token)]});
+    let (status, reply) = server.http("POST", "/api/devices/restore", Some(&export), Some(ADMIN));
+    assert_eq!(status, 400, "{}", reply);
+    assert_eq!(reply["results"][0]["error"], "not restored: another row was refused");
+    assert_eq!(reply["results"][1]["error"], "token already in use");
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"].as_array().unwrap().len(), 1, "{}", devices);
+
+    for (path, body) in [
+        ("/api/devices/restore", json!({"version": 2, "devices": []})),
+        ("/api/devices/restore", json!([])),
+        ("/api/devices/restore?conflict=overwrite", json!({"version": 1, "devices": []})),
+    ] {
+        assert_eq!(server.http("POST", path, Some(&body), Some(ADMIN)).0, 400, "{} {}", path, body);
+    }
+}
+
+// ============================================================================
+// REGISTRY AS CSV
+// ============================================================================
+
+// `GET /api/devices.csv`: the registry as a spreadsheet download, with
+// fields holding commas or quotes quoted.
+
+#[test]
+fn a_name_with_a_comma_is_quoted() {
+    let server = TestServer::start("devices-csv");
+    let token = server.pair("robot-01", "robot");
+    let mut ws = server.ws("/", "");
+    ws.send(&json!({"type": "register", "data": {
+        "device_id": "robot-01", "device_type": "robot", "name": "Robot, \"Alpha\"",
+        "token": token, "latitude": 34.05, "longitude": -118.24
+    }}));
+    ws.recv_type("registered");
+
+    let (status, head, body) = server.http_raw("GET", "/api/devices.csv", None, None);
+    assert_eq!(status, 200, "{}", head);
+    assert!(head.contains("Content-Type: text/csv"), "{}", head);
+    assert!(head.contains("Content-Disposition: attachment; filename=\"devices.csv\""), "{}", head);
+
+    let csv = String::from_utf8(body).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "id,name,device_type,status,latitude,longitude,battery,last_seen,tags");
+    assert!(lines[1].starts_with("robot-01,\"Robot, \"\"Alpha\"\"\",robot,online,34.05,-118.24,"), "{}", csv);
+    assert_eq!(lines.len(), 2, "{}", csv);
+}
+
+// ============================================================================
+// RE-REGISTRATION
+// ============================================================================
+
+// Re-registration: a device that connects again while its old connection
+// lingers keeps one live connection and stays online.
+
+#[test]
+fn reregistering_replaces_the_lingering_connection() {
+    let server = TestServer::start("reregister");
+    let token = server.pair("robot-01", "robot");
+    let mut old = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+
+    // The old socket is still open when the device registers again
+    let mut new = server.device("robot-01", "robot", &token);
+    assert!(old.is_closed(common::TIMEOUT), "old connection is dropped");
+    ui.recv_type("device:online");
+    let offline = ui.collect_type("device:offline", Duration::from_millis(500));
+    assert!(offline.is_empty(), "{:?}", offline);
+
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["status"], "online", "{}", devices);
+
+    // Commands reach the one live connection
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "ring", "payload": {}}}));
+    assert_eq!(ui.recv_type("command:sent")["data"]["status"], "sent");
+    new.recv_type("command");
+}
+
+// ============================================================================
+// REGISTRATION POLICY
+// ============================================================================
+
+// The registration policy: devices of types not allowed, or with names
+// breaking the pattern, are refused at `register`; the rest are tagged
+// by type.
+
+fn registration_policy_config() -> Config {
+    let tags = BTreeMap::from([("drone".to_string(), vec!["aerial".to_string(), "fleet-a".to_string()])]);
+    let types = vec!["drone".to_string(), "robot".to_string()];
+    Config {
+        registration: RegistrationPolicy::new(types, Some("^[a-z]+-\\d{2}$"), tags).unwrap(),
+        ..Config::default()
+    }
+}
+
+/// Register as `name`; the first answer.
+fn register(server: &TestServer, device_id: &str, device_type: &str, name: &str, token: &str) -> (Ws, Value) {
+    let mut ws = server.ws("/", "");
+    ws.send(&json!({"type": "register", "data": {
+        "device_id": device_id, "device_type": device_type, "name": name,
+        "token": token, "latitude": 34.05, "longitude": -118.24
+    }}));
+    let reply = ws.recv_within(common::TIMEOUT).expect("an answer");
+    (ws, reply)
+}
+
+fn device(server: &TestServer, device_id: &str) -> Value {
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    devices["devices"].as_array().unwrap().iter().find(|d| d["id"] == device_id).cloned().unwrap()
+}
+
+#[test]
+fn a_type_not_allowed_is_refused() {
+    let server = TestServer::start_with("policy-type", registration_policy_config());
+    let token = server.pair("sensor-01", "sensor");
+
+    let (_, reply) = register(&server, "sensor-01", "sensor", "sensor-01", &token);
+    assert_eq!(reply["type"], "error", "{}", reply);
+    assert_eq!(reply["data"]["code"], "registration_refused");
+    assert!(reply["data"]["message"].as_str().unwrap().contains("sensor"), "{}", reply);
+    assert_ne!(device(&server, "sensor-01")["status"], "online");
+}
+
+#[test]
+fn a_name_breaking_the_pattern_is_refused() {
+    let server = TestServer::start_with("policy-name", registration_policy_config());
+    let token = server.pair("robot-01", "robot");
+
+    let (_, reply) = register(&server, "robot-01", "robot", "Robot Alpha", &token);
+    assert_eq!(reply["data"]["code"], "registration_refused", "{}", reply);
+    assert!(reply["data"]["message"].as_str().unwrap().contains("doesn't match"), "{}", reply);
+
+    let (_, reply) = register(&server, "robot-01", "robot", "robot-01", &token);
+    assert_eq!(reply["type"], "registered", "{}", reply);
+    assert_eq!(device(&server, "robot-01")["tags"], json!([]));
+}
+
+#[test]
+fn devices_are_tagged_by_type() {
+    let server = TestServer::start_with("policy-tags", registration_policy_config());
+    let token = server.pair("drone-01", "drone");
+
+    let (ws, reply) = register(&server, "drone-01", "drone", "drone-01", &token);
+    assert_eq!(reply["type"], "registered", "{}", reply);
+    assert_eq!(reply["data"]["device"]["tags"], json!(["aerial", "fleet-a"]));
+    drop(ws);
+
+    // Registering again doesn't tag it twice
+    register(&server, "drone-01", "drone", "drone-01", &token);
+    assert_eq!(device(&server, "drone-01")["tags"], json!(["aerial", "fleet-a"]));
+}
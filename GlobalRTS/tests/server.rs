@@ -0,0 +1,508 @@
+//! The server as a whole: static files, its own endpoints, limits on HTTP
+//! bodies, logging, configuration reloads, demo mode, listening addresses,
+//! and the device client library against it.
+
+mod common;
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::{admin_config, temp_dir, TestServer, ADMIN, TIMEOUT};
+use globalrts::client::{DeviceClient, Pairing};
+use globalrts::protocol::TelemetryMessage;
+use globalrts::{Config, Server};
+use serde_json::{json, Value};
+
+// ============================================================================
+// STATIC FILES
+// ============================================================================
+
+// Static files: the bundled favicon, plain-text misses, CORS, and gzip sidecars.
+
+/// The header's value in a response head, if present.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// GET `path` with extra request headers; the response head and body.
+fn get(port: u16, path: &str, headers: &str) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\n{}Connection: close\r\n\r\n", path, headers).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").expect("response head");
+    (String::from_utf8_lossy(&response[..split]).into_owned(), response[split + 4..].to_vec())
+}
+
+#[test]
+fn favicon_is_served_from_the_binary() {
+    // The test server runs without a public/ directory
+    let server = TestServer::start("favicon");
+    let (status, head, body) = server.http_raw("GET", "/favicon.ico", None, None);
+    assert_eq!(status, 200, "{}", head);
+    assert_eq!(header(&head, "content-type"), Some("image/x-icon"));
+    assert_eq!(&body[..4], &[0, 0, 1, 0], "ICO header");
+}
+
+#[test]
+fn missing_assets_get_a_small_plain_text_404() {
+    let server = TestServer::start("static-404");
+    let (status, head, body) = server.http_raw("GET", "/missing.png", None, None);
+    assert_eq!(status, 404, "{}", head);
+    assert_eq!(header(&head, "content-type"), Some("text/plain; charset=utf-8"));
+    assert_eq!(body, b"Not Found");
+}
+
+#[test]
+fn static_paths_answer_preflights_like_the_api() {
+    let server = TestServer::start("static-preflight");
+    // What a browser sends before fetching an asset with a custom header
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.write_all(b"OPTIONS /assets/map.png HTTP/1.1\r\nHost: 127.0.0.1\r\nOrigin: https://embedder.example\r\n\
+        Access-Control-Request-Method: GET\r\nAccess-Control-Request-Headers: x-trace-id\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    assert_eq!(header(&response, "access-control-allow-origin"), Some("*"));
+    assert_eq!(header(&response, "access-control-allow-methods"), Some("GET, OPTIONS"));
+    assert_eq!(header(&response, "access-control-allow-headers"), Some("x-trace-id"));
+
+    let (status, head, _) = server.http_raw("OPTIONS", "/api/devices", None, None);
+    assert_eq!(status, 204, "{}", head);
+    assert_eq!(header(&head, "access-control-allow-methods"), Some("GET, POST, PUT, PATCH, DELETE, OPTIONS"));
+    assert_eq!(header(&head, "access-control-allow-headers"), Some("Content-Type, Authorization, X-Request-Id"));
+
+    // And the responses themselves may be read cross-origin, misses included
+    let (status, head, _) = server.http_raw("GET", "/favicon.ico", None, None);
+    assert_eq!((status, header(&head, "access-control-allow-origin")), (200, Some("*")));
+    let (status, head, _) = server.http_raw("GET", "/missing.png", None, None);
+    assert_eq!((status, header(&head, "access-control-allow-origin")), (404, Some("*")));
+}
+
+#[test]
+fn a_gzip_sidecar_is_served_to_clients_that_take_gzip() {
+    let server = TestServer::start("static-gzip");
+    let public = server.data_dir.parent().unwrap().join("public");
+    fs::create_dir_all(&public).unwrap();
+    fs::write(public.join("app.js"), "console.log('plain');").unwrap();
+    // Anything will do: the server sends the sidecar as it is
+    let compressed = b"\x1f\x8b\x08\x00compressed".to_vec();
+    fs::write(public.join("app.js.gz"), &compressed).unwrap();
+
+    let (head, body) = get(server.port, "/app.js", "Accept-Encoding: br, gzip\r\n");
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(body, compressed);
+    assert_eq!(header(&head, "content-encoding"), Some("gzip"));
+    assert_eq!(header(&head, "content-type"), Some("application/javascript"));
+    assert_eq!(header(&head, "vary"), Some("Accept-Encoding"));
+
+    // Without gzip, or refusing it, the plain file
+    for headers in ["", "Accept-Encoding: gzip;q=0, identity\r\n"] {
+        let (head, body) = get(server.port, "/app.js", headers);
+        assert_eq!(body, b"console.log('plain');", "{}", head);
+        assert_eq!(header(&head, "content-encoding"), None);
+        assert_eq!(header(&head, "vary"), Some("Accept-Encoding"));
+    }
+
+    // A sidecar older than its file is stale and ignored
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    File::options().write(true).open(public.join("app.js.gz")).unwrap().set_modified(an_hour_ago).unwrap();
+    let (head, body) = get(server.port, "/app.js", "Accept-Encoding: gzip\r\n");
+    assert_eq!(body, b"console.log('plain');", "{}", head);
+    assert_eq!((header(&head, "content-encoding"), header(&head, "vary")), (None, None));
+}
+
+// ============================================================================
+// VERSION
+// ============================================================================
+
+// `GET /api/version` reports what was built.
+
+#[test]
+fn version_endpoint_reports_the_compiled_crate_version() {
+    let server = TestServer::start("version");
+    let (status, info) = server.http("GET", "/api/version", None, None);
+    assert_eq!(status, 200, "{}", info);
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(info["protocol_version"], 1);
+    assert!(info["build_time"].as_i64().unwrap() > 0, "{}", info);
+    assert!(info["git_commit"].is_string() || info["git_commit"].is_null(), "{}", info);
+}
+
+// ============================================================================
+// SERVER TIME
+// ============================================================================
+
+// The server's clock: `GET /api/time`, and `server_t` on `registered`,
+// for clients correcting their timestamps for skew.
+
+fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+#[test]
+fn the_time_endpoint_answers_anyone_with_the_current_time() {
+    let server = TestServer::start("server-time");
+
+    let before = now_millis();
+    let (status, time) = server.http("GET", "/api/time", None, None);
+    assert_eq!(status, 200, "{}", time);
+    let millis = time["millis"].as_i64().unwrap();
+    assert!(millis >= before - 1000 && millis <= now_millis() + 1000, "{}", time);
+    assert_eq!(time["unix"].as_i64().unwrap(), millis / 1000, "{}", time);
+}
+
+#[test]
+fn registering_tells_the_device_the_server_time() {
+    let server = TestServer::start("server-time-registered");
+    let token = server.pair("robot-01", "robot");
+
+    let mut ws = server.ws("/", "");
+    let before = now_millis();
+    ws.send(&json!({"type": "register", "data": {
+        "device_id": "robot-01", "device_type": "robot", "name": "robot-01", "token": token,
+        "latitude": 34.05, "longitude": -118.24
+    }}));
+    let registered = ws.recv_type("registered");
+    let server_t = registered["data"]["server_t"].as_i64().unwrap();
+    assert!(server_t >= before - 1000 && server_t <= now_millis() + 1000, "{}", registered);
+}
+
+// ============================================================================
+// REQUEST IDS
+// ============================================================================
+
+// Every HTTP response carries an `X-Request-Id`: a fresh one, or the
+// client's own if it sent a usable one.
+
+/// `path` with extra header lines; the raw response head.
+fn head_of(server: &TestServer, path: &str, headers: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n{}\r\n", path, headers);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    let response = String::from_utf8_lossy(&response).to_string();
+    response.split("\r\n\r\n").next().unwrap().to_string()
+}
+
+fn request_id(head: &str) -> Option<&str> {
+    head.lines().find_map(|line| line.strip_prefix("X-Request-Id: "))
+}
+
+#[test]
+fn responses_carry_a_request_id_and_keep_the_clients() {
+    let server = TestServer::start("request-id");
+
+    let first = head_of(&server, "/api/version", "");
+    let second = head_of(&server, "/api/version", "");
+    let id = request_id(&first).expect(&first);
+    assert_eq!(id.len(), 16, "{}", id);
+    assert!(id.chars().all(|c| c.is_ascii_hexdigit()), "{}", id);
+    assert_ne!(request_id(&second), Some(id));
+    assert!(first.contains("Access-Control-Expose-Headers: X-Request-Id"), "{}", first);
+
+    // Errors and static misses too
+    assert!(request_id(&head_of(&server, "/api/devices/ghost-99/nowhere", "")).is_some());
+    assert!(request_id(&head_of(&server, "/no-such-page.html", "")).is_some());
+
+    let kept = head_of(&server, "/api/version", "X-Request-Id: lb-7f3a.retry:2\r\n");
+    assert_eq!(request_id(&kept), Some("lb-7f3a.retry:2"), "{}", kept);
+    let replaced = head_of(&server, "/api/version", "X-Request-Id: not a usable id\r\n");
+    assert_eq!(request_id(&replaced).map(str::len), Some(16), "{}", replaced);
+}
+
+// ============================================================================
+// BODY CAP
+// ============================================================================
+
+// HTTP body cap: a declared Content-Length over GLOBALRTS_HTTP_MAX_BODY_BYTES
+// is refused with 413 before the body is read. Imports have their own cap.
+
+fn body_cap_config() -> Config {
+    Config { max_body: 4096, ..admin_config() }
+}
+
+#[test]
+fn oversized_bodies_are_refused_before_they_are_sent() {
+    let server = TestServer::start_with("body-limit", body_cap_config());
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    // Headers only: a server waiting for the body would time out instead
+    write!(stream, "POST /api/pair/request HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 1000000000\r\n\r\n").unwrap();
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(&head, b"HTTP/1.1 413");
+
+    let (status, reply) = server.http("POST", "/api/pair/request", Some(&json!({"device_id": "robot-01", "name": "Robot", "device_type": "robot"})), None);
+    assert_eq!(status, 200, "{}", reply);
+}
+
+#[test]
+fn imports_may_exceed_the_general_cap() {
+    let server = TestServer::start_with("body-limit-import", body_cap_config());
+    let batch: Vec<_> = (0..100).map(|i| json!({"id": format!("robot-{:03}", i), "name": format!("Robot {}", i), "type": "robot"})).collect();
+    assert!(json!(batch).to_string().len() > 4096);
+
+    let (status, reply) = server.http("POST", "/api/devices/import", Some(&json!(batch)), Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply["ok"], 100);
+}
+
+// ============================================================================
+// LOG FILE
+// ============================================================================
+
+// GLOBALRTS_LOG_FILE: the log goes to a file, rotated by size, with only
+// the newest few kept.
+
+#[test]
+fn the_log_goes_to_a_rotated_file() {
+    let server = TestServer::start_with("log-file", Config {
+        log_file: Some("logs/server.log".to_string()),
+        log_max_bytes: 256,
+        log_keep: 1,
+        ..Config::default()
+    });
+    for n in 0..10 {
+        server.pair(&format!("robot-{:02}", n), "robot");
+    }
+
+    let logs = server.data_dir.parent().unwrap().join("logs");
+    let read = |name: &str| std::fs::read_to_string(logs.join(name)).unwrap_or_default();
+    let (current, rotated) = (read("server.log"), read("server.log.1"));
+    assert!(current.contains("Device paired: robot-09"), "{}", current);
+    assert!(rotated.contains("Device paired: robot-0"), "{}", rotated);
+    assert!(current.len() <= 256, "{}", current.len());
+    assert!(!logs.join("server.log.2").exists());
+}
+
+// ============================================================================
+// RELOAD
+// ============================================================================
+
+// SIGHUP re-reads the configuration, GLOBALRTS_CONFIG's file included, and
+// applies what can change live without dropping anyone.
+
+#[test]
+fn a_reload_changes_a_live_limit_and_keeps_connections() {
+    let server = TestServer::start_with("reload", Config {
+        max_body: 64,
+        log_file: Some("logs/server.log".to_string()),
+        ..Config::default()
+    });
+    let file = &server.config_file;
+    let request = json!({"device_id": "robot-with-a-long-enough-id", "name": "A robot with a long enough name", "device_type": "robot"});
+    let (status, _) = server.http("POST", "/api/pair/request", Some(&request), None);
+    assert_eq!(status, 413);
+    let mut ui = server.ui(None);
+
+    std::fs::write(file, "# Larger bodies now\nGLOBALRTS_HTTP_MAX_BODY_BYTES=\"4096\"\n").unwrap();
+    server.signal("HUP");
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let (status, reply) = server.http("POST", "/api/pair/request", Some(&request), None);
+        if status == 200 {
+            break;
+        }
+        assert_eq!(status, 413, "{}", reply);
+        assert!(Instant::now() < deadline, "the new limit never applied");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // The UI connected before the reload is still served
+    ui.send(&json!({"type": "getDevices", "data": {}}));
+    ui.recv_type("devices:list");
+
+    // A file that doesn't parse is logged, to the log file, and the running settings kept
+    std::fs::write(file, "GLOBALRTS_HTTP_MAX_BODY_BYTES 64\n").unwrap();
+    server.signal("HUP");
+    let log = server.data_dir.parent().unwrap().join("logs/server.log");
+    let deadline = Instant::now() + TIMEOUT;
+    while !std::fs::read_to_string(&log).unwrap_or_default().contains("Reload failed") {
+        assert!(Instant::now() < deadline, "the failed reload was never logged");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let (status, reply) = server.http("POST", "/api/pair/request", Some(&request), None);
+    assert_eq!(status, 200, "{}", reply);
+}
+
+// ============================================================================
+// CONFIGURATION FILE
+// ============================================================================
+
+// Settings from GLOBALRTS_CONFIG's file count as much as the environment,
+// the admin and viewer tokens included.
+
+#[test]
+fn tokens_set_only_in_the_file_are_honored() {
+    let server = TestServer::start_with("config-file", Config {
+        admin_token: Some("file-admin".to_string()),
+        viewer_token: Some("file-viewer".to_string()),
+        ..Config::default()
+    });
+    server.pair("robot-01", "robot");
+
+    let (status, audit) = server.http("GET", "/api/audit", None, Some("file-admin"));
+    assert_eq!(status, 200, "{}", audit);
+    let (_, whoami) = server.http("GET", "/api/whoami", None, Some("file-viewer"));
+    assert_eq!(whoami["role"], "viewer");
+    let (status, refused) = server.http("DELETE", "/api/devices/robot-01", None, Some("file-viewer"));
+    assert_eq!(status, 403, "{}", refused);
+}
+
+// ============================================================================
+// DEMO MODE
+// ============================================================================
+
+// GLOBALRTS_DEMO_DEVICES: simulated devices run inside the server, come
+// online and report without a simulator process each.
+
+#[test]
+fn demo_mode_brings_the_configured_number_of_devices_online() {
+    let server = TestServer::start_with("demo", Config { demo_devices: 3, ..Config::default() });
+
+    let deadline = Instant::now() + TIMEOUT;
+    let online = loop {
+        let (status, reply) = server.http("GET", "/api/devices", None, None);
+        assert_eq!(status, 200, "{}", reply);
+        let online: Vec<Value> = reply["devices"].as_array().unwrap().iter()
+            .filter(|d| d["status"] == "online")
+            .cloned()
+            .collect();
+        if online.len() == 3 {
+            break online;
+        }
+        assert!(Instant::now() < deadline, "{}", reply);
+        thread::sleep(Duration::from_millis(50));
+    };
+    let ids: Vec<&str> = online.iter().map(|d| d["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, ["demo-01", "demo-02", "demo-03"]);
+    let types: Vec<&str> = online.iter().map(|d| d["device_type"].as_str().unwrap()).collect();
+    assert_eq!(types, ["robot", "phone", "drone"]);
+
+    // They keep reporting, as a real device would
+    let mut ui = server.ui(None);
+    let update = ui.recv_matching("device:update", |m| m["data"]["id"] == "demo-02");
+    assert!(update["data"]["battery"].as_f64().unwrap() > 0.0, "{}", update);
+}
+
+// ============================================================================
+// IPV6
+// ============================================================================
+
+// Listening on IPv6, alone and alongside IPv4 on the same port.
+
+/// GET /api/version at `addr`; the raw response.
+fn get_version(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(b"GET /api/version HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    response
+}
+
+#[test]
+fn serves_ipv6_loopback_alone_and_beside_ipv4() {
+    let data_dir = temp_dir("ipv6").join("data").to_string_lossy().into_owned();
+    let v6: IpAddr = Ipv6Addr::LOCALHOST.into();
+    let v4: IpAddr = Ipv4Addr::LOCALHOST.into();
+
+    let handle = Server::run(Config { port: 0, bind: vec![v6], data_dir: data_dir.clone(), ..Config::default() }).unwrap();
+    let addr = handle.local_addr();
+    assert_eq!(addr.ip(), v6);
+    assert!(get_version(addr).starts_with("HTTP/1.1 200"));
+    assert!(TcpStream::connect((v4, addr.port())).is_err());
+    handle.shutdown().unwrap();
+
+    // Both families on one picked port
+    let handle = Server::run(Config { port: 0, bind: vec![v4, v6], data_dir, ..Config::default() }).unwrap();
+    let port = handle.local_addr().port();
+    assert_eq!(handle.local_addrs().len(), 2);
+    for ip in [v4, v6] {
+        assert!(get_version(SocketAddr::new(ip, port)).starts_with("HTTP/1.1 200"), "{}", ip);
+    }
+    handle.shutdown().unwrap();
+    assert!(TcpStream::connect((v6, port)).is_err());
+}
+
+// ============================================================================
+// CLIENT LIBRARY
+// ============================================================================
+
+// `globalrts::client`: a device built on `DeviceClient` pairs, keeps its
+// token, registers, reports telemetry, answers commands and reconnects.
+
+fn telemetry(latitude: f64) -> TelemetryMessage {
+    TelemetryMessage {
+        latitude, longitude: -118.24, altitude: 0.0, heading: 0.0, speed: 0.0,
+        battery: 80.0, sensors: serde_json::Value::Null, ack: false,
+    }
+}
+
+/// Poll `client` until `done` says so, or the test times out.
+fn poll_until(client: &mut DeviceClient, mut done: impl FnMut(&mut DeviceClient) -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !done(client) {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn a_device_pairs_connects_reports_and_takes_commands() {
+    let server = TestServer::start("client");
+    let token_file = server.data_dir.join("robot-01.token");
+    let mut client = DeviceClient::new("127.0.0.1", server.port, "robot-01", "robot", "Robot Alpha")
+        .with_token_file(&token_file);
+    assert!(!client.is_paired());
+
+    // Pair with the code the operator reads off the UI
+    assert_eq!(client.request_pairing().unwrap(), Pairing::Pending);
+    let (_, pending) = server.http("GET", "/api/pair/requests", None, None);
+    let code = pending["requests"][0]["code"].as_str().unwrap().to_string();
+    assert!(client.confirm_pairing("000000").is_err());
+    client.confirm_pairing(&code).unwrap();
+    assert_eq!(std::fs::read_to_string(&token_file).unwrap(), client.token().unwrap());
+
+    let mut ui = server.ui(None);
+    client.connect(34.05, -118.24).unwrap();
+    ui.recv_type("device:online");
+    client.send_telemetry(&telemetry(34.06)).unwrap();
+    let update = ui.recv_type("device:update");
+    assert_eq!(update["data"]["latitude"], 34.06, "{}", update);
+
+    ui.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "ring", "payload": {}}}));
+    let mut received = Vec::new();
+    poll_until(&mut client, |client| {
+        client.poll(|command| {
+            received.push(command.command_type.clone());
+            vec![command.ack(), command.complete("completed")]
+        }).unwrap();
+        !received.is_empty()
+    });
+    assert_eq!(received, ["ring"]);
+    let done = ui.recv_matching("command:status", |m| m["data"]["status"] == "completed");
+    assert_eq!(done["data"]["deviceId"], "robot-01");
+
+    // Another run finds the token kept; its connection displaces this one,
+    // which notices and comes back
+    let mut again = DeviceClient::new("127.0.0.1", server.port, "robot-01", "robot", "Robot Alpha")
+        .with_token_file(&token_file);
+    assert!(again.is_paired());
+    again.connect(34.06, -118.24).unwrap();
+    poll_until(&mut client, |client| client.poll(|_| Vec::new()).is_err());
+    assert!(!client.is_connected());
+    poll_until(&mut client, |client| client.poll(|_| Vec::new()).is_ok());
+    assert!(client.is_connected());
+    client.send_telemetry(&telemetry(34.07)).unwrap();
+    ui.recv_matching("device:update", |m| m["data"]["latitude"] == 34.07);
+}
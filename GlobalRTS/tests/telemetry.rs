@@ -0,0 +1,687 @@
+//! Telemetry: how it is taken in, stored and read back, and the alerts
+//! devices raise alongside it.
+
+mod common;
+
+use std::path::Path;
+use std::time::Duration;
+
+use common::{admin_config, TestServer, Ws, ADMIN, TIMEOUT};
+use globalrts::telemetry::OutOfOrder;
+use globalrts::Config;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+
+// ============================================================================
+// ACKNOWLEDGEMENT AND DOWNLOADS
+// ============================================================================
+
+// Telemetry acknowledgement (`ack: true` gets exactly one `telemetry:ack`
+// once the record is stored), the per-device stats endpoint and reading the
+// last N records.
+
+#[test]
+fn acked_telemetry_gets_exactly_one_ack() {
+    let server = TestServer::start("telemetry-ack");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    device.send(&json!({"type": "telemetry", "data": {"latitude": 34.05, "longitude": -118.24, "ack": true}}));
+    let acks = device.collect_type("telemetry:ack", Duration::from_millis(500));
+    assert_eq!(acks.len(), 1, "{:?}", acks);
+    let timestamp = acks[0]["data"]["timestamp"].as_i64().unwrap();
+
+    // The acked record is on disk, not just in the writer's buffer
+    let stored = day_file(&server.data_dir.join("telemetry"), "robot-01.jsonl");
+    assert!(stored.contains(&format!("\"timestamp\":{}", timestamp)), "{}", stored);
+
+    device.send(&json!({"type": "telemetry", "data": {"latitude": 34.06, "longitude": -118.24}}));
+    let acks = device.collect_type("telemetry:ack", Duration::from_millis(500));
+    assert!(acks.is_empty(), "{:?}", acks);
+}
+
+#[test]
+fn stats_summarize_stored_telemetry() {
+    let server = TestServer::start("telemetry-stats");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    for (latitude, battery) in [(34.0, 90.0), (34.1, 80.0)] {
+        device.send(&json!({"type": "telemetry", "data": {
+            "latitude": latitude, "longitude": -118.0, "battery": battery, "ack": true
+        }}));
+        device.recv_type("telemetry:ack");
+    }
+
+    let (status, stats) = server.http("GET", "/api/devices/robot-01/stats", None, None);
+    assert_eq!(status, 200, "{}", stats);
+    assert_eq!(stats["device_id"], "robot-01");
+    assert_eq!(stats["count"], 2);
+    assert_eq!(stats["battery"]["avg"], 85.0);
+    let distance = stats["distance_m"].as_f64().unwrap();
+    assert!((distance - 11_119.0).abs() < 10.0, "{}", distance);
+
+    let (_, empty) = server.http("GET", "/api/devices/robot-02/stats", None, None);
+    assert_eq!(empty["count"], 0);
+    assert!(empty["speed"].is_null(), "{}", empty);
+}
+
+#[test]
+fn recent_returns_the_last_n_records_in_order() {
+    let server = TestServer::start("telemetry-recent");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    for battery in 80..90 {
+        device.send(&json!({"type": "telemetry", "data": {"latitude": 34.0, "longitude": -118.0, "battery": battery, "ack": true}}));
+        device.recv_type("telemetry:ack");
+    }
+
+    let (status, recent) = server.http("GET", "/api/telemetry/robot-01/recent?n=3", None, None);
+    assert_eq!(status, 200, "{}", recent);
+    assert_eq!(recent["count"], 3);
+    let batteries: Vec<f64> = recent["records"].as_array().unwrap().iter().map(|r| r["battery"].as_f64().unwrap()).collect();
+    assert_eq!(batteries, [87.0, 88.0, 89.0]);
+
+    let (_, all) = server.http("GET", "/api/telemetry/robot-01/recent?n=500", None, None);
+    assert_eq!(all["count"], 10);
+    assert_eq!(all["records"][0]["battery"], 80.0);
+
+    assert_eq!(server.http("GET", "/api/telemetry/robot-01/recent?n=lots", None, None).0, 400);
+    let (_, none) = server.http("GET", "/api/telemetry/robot-02/recent", None, None);
+    assert_eq!(none["records"], json!([]));
+}
+
+#[test]
+fn fields_project_records_down_to_the_named_keys() {
+    let server = TestServer::start("telemetry-fields");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    device.send(&json!({"type": "telemetry", "data": {"latitude": 34.0, "longitude": -118.0, "battery": 80, "ack": true}}));
+    device.recv_type("telemetry:ack");
+
+    let (status, recent) = server.http("GET", "/api/telemetry/robot-01/recent?fields=latitude,timestamp", None, None);
+    assert_eq!(status, 200, "{}", recent);
+    let record = recent["records"][0].as_object().unwrap();
+    let mut keys: Vec<&str> = record.keys().map(String::as_str).collect();
+    keys.sort();
+    assert_eq!(keys, ["latitude", "timestamp"]);
+    assert_eq!(record["latitude"], 34.0);
+
+    let (status, error) = server.http("GET", "/api/telemetry/robot-01/recent?fields=latitude,altitud", None, None);
+    assert_eq!(status, 400);
+    assert!(error["error"].as_str().unwrap().contains("altitud"), "{}", error);
+    assert_eq!(server.http("GET", "/api/telemetry/robot-01.ndjson.gz?fields=bogus", None, None).0, 400);
+}
+
+/// The contents of `name` somewhere under the YYYY/MM/DD tree at `dir`.
+fn day_file(dir: &Path, name: &str) -> String {
+    for entry in std::fs::read_dir(dir).unwrap().flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            let found = day_file(&path, name);
+            if !found.is_empty() {
+                return found;
+            }
+        } else if path.file_name().is_some_and(|n| n == name) {
+            return std::fs::read_to_string(path).unwrap();
+        }
+    }
+    String::new()
+}
+
+// ============================================================================
+// DAY FILES
+// ============================================================================
+
+// `GET /api/telemetry/{id}/files` lists a device's day files with their
+// sizes and line counts: gzipped and plain, sharded and from before
+// sharding was on.
+
+#[test]
+fn files_across_two_days_are_listed_with_sizes() {
+    let server = TestServer::start_with("telemetry-files", Config { telemetry_gzip: true, telemetry_shard: true, ..Config::default() });
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    for battery in [90, 89, 88] {
+        device.send(&json!({"type": "telemetry", "data": {"latitude": 34.0, "longitude": -118.0, "battery": battery, "ack": true}}));
+        device.recv_type("telemetry:ack");
+    }
+
+    // An older day, written plain before sharding was turned on
+    let old_day = server.data_dir.join("telemetry/2024/01/15");
+    std::fs::create_dir_all(&old_day).unwrap();
+    let old = "{\"device_id\":\"robot-01\",\"timestamp\":1705312800,\"latitude\":34.0,\"longitude\":-118.0}\n".repeat(2);
+    std::fs::write(old_day.join("robot-01.jsonl"), &old).unwrap();
+
+    let (status, listing) = server.http("GET", "/api/telemetry/robot-01/files", None, None);
+    assert_eq!(status, 200, "{}", listing);
+    let files = listing["files"].as_array().unwrap();
+    assert_eq!(files.len(), 2, "{}", listing);
+
+    assert_eq!(files[0]["date"], "2024-01-15");
+    assert_eq!(files[0]["path"], "2024/01/15/robot-01.jsonl");
+    assert_eq!(files[0]["bytes"], old.len());
+    assert_eq!(files[0]["lines"], 2);
+    assert_eq!(files[0]["gzip"], false);
+
+    let today = files[1]["path"].as_str().unwrap();
+    assert!(today.ends_with("/robot-01.jsonl.gz"), "{}", today);
+    assert_eq!(today.split('/').count(), 5, "not sharded: {}", today);
+    assert!(files[1]["bytes"].as_u64().unwrap() > 0, "{}", listing);
+    assert_eq!(files[1]["lines"], 3);
+    assert_eq!(files[1]["gzip"], true);
+
+    assert_eq!(listing["total_lines"], 5);
+    assert_eq!(listing["total_bytes"], old.len() as u64 + files[1]["bytes"].as_u64().unwrap());
+
+    let (_, none) = server.http("GET", "/api/telemetry/robot-02/files", None, None);
+    assert_eq!(none["files"], json!([]));
+    assert_eq!(server.http("GET", "/api/telemetry/..%2Fx/files", None, None).0, 400);
+}
+
+// ============================================================================
+// OUT-OF-ORDER RECORDS
+// ============================================================================
+
+// GLOBALRTS_TELEMETRY_OUT_OF_ORDER=drop: a record older than the last one
+// stored for its device is dropped, so its files stay in time order.
+
+fn upload_at(server: &TestServer, token: &str, timestamps: &[i64]) -> Value {
+    let records: Vec<Value> = timestamps.iter()
+        .map(|t| json!({"timestamp": t, "latitude": 34.0, "longitude": -118.0, "battery": 80}))
+        .collect();
+    let (status, reply) = server.http("POST", "/api/telemetry/robot-01", Some(&json!({"records": records})), Some(token));
+    assert_eq!(status, 200, "{}", reply);
+    reply
+}
+
+#[test]
+fn an_out_of_order_sample_is_dropped_and_an_in_order_one_accepted() {
+    let server = TestServer::start_with("telemetry-order", Config { telemetry_out_of_order: OutOfOrder::Drop, ..Config::default() });
+    let token = server.pair("robot-01", "robot");
+
+    let reply = upload_at(&server, &token, &[1_700_000_100]);
+    assert_eq!((reply["stored"].clone(), reply["dropped"].clone()), (json!(1), json!(0)));
+
+    // Behind the last stored one, even within the same upload
+    let reply = upload_at(&server, &token, &[1_700_000_050, 1_700_000_200, 1_700_000_150]);
+    assert_eq!((reply["stored"].clone(), reply["dropped"].clone()), (json!(1), json!(2)), "{}", reply);
+
+    // Level with it is in order
+    let reply = upload_at(&server, &token, &[1_700_000_200, 1_700_000_300]);
+    assert_eq!(reply["stored"], 2, "{}", reply);
+
+    let (_, recent) = server.http("GET", "/api/telemetry/robot-01/recent?n=10", None, None);
+    let timestamps: Vec<i64> = recent["records"].as_array().unwrap().iter().map(|r| r["timestamp"].as_i64().unwrap()).collect();
+    assert_eq!(timestamps, [1_700_000_100, 1_700_000_200, 1_700_000_200, 1_700_000_300]);
+}
+
+// ============================================================================
+// MULTI-DEVICE QUERIES
+// ============================================================================
+
+// `GET /api/telemetry?device_ids=...`: several devices' records in one
+// request, each device's kept apart.
+
+fn upload(server: &TestServer, device_id: &str, token: &str, records: Value) {
+    let (status, reply) = server.http("POST", &format!("/api/telemetry/{}", device_id), Some(&json!({"records": records})), Some(token));
+    assert_eq!(status, 200, "{}", reply);
+}
+
+fn timestamps(records: &Value) -> Vec<i64> {
+    records.as_array().unwrap().iter().map(|r| r["timestamp"].as_i64().unwrap()).collect()
+}
+
+#[test]
+fn each_device_gets_its_own_records() {
+    let server = TestServer::start("telemetry-query");
+    // Recent enough to be filed today, where the range reads them from
+    let t = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64 - 60;
+    let robot = server.pair("robot-01", "robot");
+    let drone = server.pair("drone-01", "drone");
+    upload(&server, "robot-01", &robot, json!([
+        {"timestamp": t, "latitude": 34.0, "longitude": -118.0, "battery": 90},
+        {"timestamp": t + 10, "latitude": 34.1, "longitude": -118.1, "battery": 89},
+        {"timestamp": t + 20, "latitude": 34.2, "longitude": -118.2, "battery": 88}
+    ]));
+    upload(&server, "drone-01", &drone, json!([
+        {"timestamp": t + 5, "latitude": 51.5, "longitude": -0.1, "battery": 70}
+    ]));
+
+    let (status, reply) = server.http("GET", &format!("/api/telemetry?device_ids=robot-01,drone-01,ghost-99&start={}&end={}", t, t + 15), None, None);
+    assert_eq!(status, 200, "{}", reply);
+    let devices = &reply["devices"];
+    assert_eq!(timestamps(&devices["robot-01"]), [t, t + 10]);
+    assert_eq!(timestamps(&devices["drone-01"]), [t + 5]);
+    assert_eq!(devices["drone-01"][0]["device_id"], "drone-01");
+    assert_eq!(devices["ghost-99"], json!([]));
+
+    // limit keeps each device's latest; fields projects each record
+    let (_, reply) = server.http("GET", "/api/telemetry?device_ids=robot-01,drone-01&limit=1&fields=timestamp,battery", None, None);
+    assert_eq!(reply["devices"]["robot-01"], json!([{"timestamp": t + 20, "battery": 88.0}]));
+    assert_eq!(reply["devices"]["drone-01"], json!([{"timestamp": t + 5, "battery": 70.0}]));
+
+    let too_many: Vec<String> = (0..101).map(|n| format!("robot-{}", n)).collect();
+    assert_eq!(server.http("GET", &format!("/api/telemetry?device_ids={}", too_many.join(",")), None, None).0, 400);
+    assert_eq!(server.http("GET", "/api/telemetry", None, None).0, 400);
+    assert_eq!(server.http("GET", "/api/telemetry?device_ids=robot-01&fields=colour", None, None).0, 400);
+}
+
+// ============================================================================
+// MINIMUM DISTANCE
+// ============================================================================
+
+// GLOBALRTS_TELEMETRY_MIN_DISTANCE_M: a parked device's GPS jitter isn't
+// written every second, while moves and battery or sensor changes are.
+
+fn minimum_distance_config() -> Config {
+    Config { telemetry_min_distance_m: 5.0, ..Config::default() }
+}
+
+#[test]
+fn a_stationary_device_writes_far_fewer_records() {
+    let server = TestServer::start_with("min-distance", minimum_distance_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut send = |latitude: f64, battery: f64, sensors: Value| {
+        device.send(&json!({"type": "telemetry", "data": {
+            "latitude": latitude, "longitude": -118.24, "battery": battery, "sensors": sensors, "ack": true
+        }}));
+        device.recv_type("telemetry:ack");
+    };
+
+    // Parked: a metre or so of jitter (0.00001° of latitude is about 1.1 m)
+    for i in 0..30 {
+        send(34.05 + (i % 3) as f64 * 0.00001, 90.0, json!({"temp": 21}));
+    }
+    // Each of these says something new
+    send(34.05, 89.0, json!({"temp": 21}));
+    send(34.05, 89.0, json!({"temp": 22}));
+    send(34.0501, 89.0, json!({"temp": 22}));
+
+    let (status, stats) = server.http("GET", "/api/devices/robot-01/stats", None, None);
+    assert_eq!(status, 200, "{}", stats);
+    assert_eq!(stats["count"], 4, "{}", stats);
+
+    // The registry still follows every report
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["latitude"], 34.0501);
+    assert_eq!(devices["devices"][0]["status"], "online");
+}
+
+// ============================================================================
+// RETENTION
+// ============================================================================
+
+// `PATCH /api/devices/{id}/retention` gives one device its own telemetry
+// retention, overriding GLOBALRTS_TELEMETRY_RETENTION_DAYS.
+
+fn retention_config() -> Config {
+    Config { telemetry_retention_days: 90, ..admin_config() }
+}
+
+#[test]
+fn an_admin_sets_and_clears_a_device_retention() {
+    let server = TestServer::start_with("retention", retention_config());
+    server.pair("safety-01", "robot");
+    let path = "/api/devices/safety-01/retention";
+
+    let (status, reply) = server.http("PATCH", path, Some(&json!({"retention_days": 365})), Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply, json!({"device_id": "safety-01", "retention_days": 365}));
+    let (status, reply) = server.http("PATCH", path, Some(&json!({"retention_days": null})), Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply["retention_days"], Value::Null);
+
+    assert_eq!(server.http("PATCH", path, Some(&json!({"retention_days": 365})), None).0, 401);
+    for bad in [json!({}), json!({"retention_days": -1}), json!({"retention_days": "long"}), json!({"retention_days": 100_000})] {
+        let (status, reply) = server.http("PATCH", path, Some(&bad), Some(ADMIN));
+        assert_eq!(status, 400, "{}: {}", bad, reply);
+    }
+    let ghost = server.http("PATCH", "/api/devices/ghost-99/retention", Some(&json!({"retention_days": 30})), Some(ADMIN));
+    assert_eq!(ghost.0, 404);
+}
+
+// ============================================================================
+// REPLAY
+// ============================================================================
+
+// Replay: `POST /api/telemetry/{id}/replay` plays a device's recorded
+// telemetry back to UIs as `replay:update`, leaving its live state alone.
+
+#[test]
+fn replay_sends_the_recorded_track_in_order() {
+    let server = TestServer::start_with("replay", admin_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    for (latitude, battery) in [(34.0, 90), (34.1, 80), (34.2, 70)] {
+        device.send(&json!({"type": "telemetry", "data": {"latitude": latitude, "longitude": -118.24, "battery": battery, "ack": true}}));
+        device.recv_type("telemetry:ack");
+    }
+    let mut ui = server.ui(None);
+
+    let (status, reply) = server.http("POST", "/api/telemetry/robot-01/replay", Some(&json!({"speed": 100})), Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    let replay_id = reply["replay_id"].as_str().unwrap().to_string();
+
+    let mut updates = Vec::new();
+    let end = loop {
+        let message = ui.recv_within(TIMEOUT).expect("replay ends");
+        match message["type"].as_str() {
+            Some("replay:update") => updates.push(message["data"].clone()),
+            Some("replay:end") => break message,
+            _ => {}
+        }
+    };
+    let track: Vec<(f64, f64)> = updates.iter()
+        .map(|u| (u["latitude"].as_f64().unwrap(), u["battery"].as_f64().unwrap()))
+        .collect();
+    assert_eq!(track, [(34.0, 90.0), (34.1, 80.0), (34.2, 70.0)]);
+    assert!(updates.iter().all(|u| u["replayId"] == replay_id && u["deviceId"] == "robot-01"));
+    assert_eq!(end["data"], json!({"replayId": replay_id, "deviceId": "robot-01", "records": 3, "cancelled": false}));
+
+    // Replayed positions never reach the live device table or its updates
+    assert!(ui.collect_type("device:update", Duration::from_millis(200)).is_empty());
+
+    // The live state still has the last real reading
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["battery"], 70.0, "{}", devices);
+    assert_eq!(server.http("DELETE", "/api/telemetry/robot-01/replay", None, Some(ADMIN)).0, 404);
+}
+
+// ============================================================================
+// BINARY TELEMETRY
+// ============================================================================
+
+// Binary telemetry: a device that negotiates the binary subprotocol may
+// send fixed-layout frames, which are stored just as JSON telemetry is.
+
+const SUBPROTOCOL: &str = "globalrts.binary-telemetry";
+
+/// A frame as the layout describes it: timestamp then six f64s, little-endian.
+fn frame(timestamp: i64, values: [f64; 6]) -> Vec<u8> {
+    let mut frame = timestamp.to_le_bytes().to_vec();
+    for value in values {
+        frame.extend_from_slice(&value.to_le_bytes());
+    }
+    frame
+}
+
+/// Register `device_id` on a connection offering the binary subprotocol.
+fn binary_device(server: &TestServer, device_id: &str, token: &str) -> Ws {
+    let mut ws = server.ws("/", &format!("Sec-WebSocket-Protocol: {}\r\n", SUBPROTOCOL));
+    assert!(ws.handshake.contains(&format!("Sec-WebSocket-Protocol: {}\r\n", SUBPROTOCOL)), "{}", ws.handshake);
+    ws.send(&json!({"type": "register", "data": {
+        "device_id": device_id, "device_type": "robot", "name": device_id,
+        "token": token, "latitude": 0.0, "longitude": 0.0
+    }}));
+    ws.recv_type("registered");
+    ws
+}
+
+/// The stored record without what differs between two sends of the same reading.
+fn reading(record: &Value) -> Value {
+    let mut record = record.clone();
+    record.as_object_mut().unwrap().remove("timestamp");
+    record
+}
+
+#[test]
+fn binary_frames_are_stored_like_json_telemetry() {
+    let server = TestServer::start("binary-telemetry");
+    let token = server.pair("robot-01", "robot");
+    let mut device = binary_device(&server, "robot-01", &token);
+    let mut ui = server.ui(None);
+
+    let values = [34.052235, -118.243683, 71.5, 270.25, 3.5, 64.0];
+    device.send(&json!({"type": "telemetry", "data": {
+        "latitude": values[0], "longitude": values[1], "altitude": values[2],
+        "heading": values[3], "speed": values[4], "battery": values[5], "ack": true
+    }}));
+    device.recv_type("telemetry:ack");
+    device.send_binary(&frame(0, values));
+    // Frames are handled in order: once this is acked the binary one is stored
+    device.send(&json!({"type": "telemetry", "data": {"latitude": 1.0, "longitude": 2.0, "ack": true}}));
+    device.recv_type("telemetry:ack");
+
+    let (status, recent) = server.http("GET", "/api/telemetry/robot-01/recent?n=3", None, None);
+    assert_eq!(status, 200, "{}", recent);
+    let records = recent["records"].as_array().unwrap();
+    assert_eq!(records.len(), 3, "{}", recent);
+    assert_eq!(reading(&records[1]), reading(&records[0]));
+    assert_eq!(records[1]["battery"], 64.0);
+
+    // UIs see binary telemetry move the device too
+    ui.recv_matching("device:update", |m| m["data"]["battery"] == 64.0 && m["data"]["heading"] == 270.25);
+
+    // A frame of the wrong size is refused, and the connection carries on
+    device.send_binary(&frame(0, values)[..40]);
+    let error = device.recv_type("error");
+    assert_eq!(error["data"]["code"], "invalid_telemetry", "{}", error);
+    device.send(&json!({"type": "ping", "data": {}}));
+    device.recv_type("pong");
+}
+
+#[test]
+fn without_the_subprotocol_binary_frames_are_ignored() {
+    let server = TestServer::start("binary-telemetry-off");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    assert!(!device.handshake.contains("Sec-WebSocket-Protocol"), "{}", device.handshake);
+
+    device.send_binary(&frame(0, [1.0, 2.0, 0.0, 0.0, 0.0, 50.0]));
+    device.send(&json!({"type": "telemetry", "data": {"latitude": 3.0, "longitude": 4.0, "ack": true}}));
+    device.recv_type("telemetry:ack");
+    assert!(device.collect_type("error", Duration::from_millis(200)).is_empty());
+
+    let (_, recent) = server.http("GET", "/api/telemetry/robot-01/recent?n=10", None, None);
+    assert_eq!(recent["count"], 1, "{}", recent);
+}
+
+// ============================================================================
+// SIGNING
+// ============================================================================
+
+// Signed devices: paired with `"signed": true`, their telemetry, command
+// reports and alerts must carry `sig = hex(HMAC-SHA1(token, type + "\n" + data))`
+// over the raw data text. Unsigned devices are unaffected.
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> String {
+    let mut block = [0u8; 64];
+    block[..key.len()].copy_from_slice(key);
+    let inner = Sha1::new().chain_update(block.map(|b| b ^ 0x36)).chain_update(message).finalize();
+    let outer = Sha1::new().chain_update(block.map(|b| b ^ 0x5c)).chain_update(inner).finalize();
+    outer.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A telemetry message whose data is `data`, signed over `signed_data`.
+fn telemetry(token: &str, signed_data: &str, data: &str) -> String {
+    let sig = hmac_sha1(token.as_bytes(), format!("telemetry\n{}", signed_data).as_bytes());
+    format!(r#"{{"type": "telemetry", "data": {}, "sig": "{}"}}"#, data, sig)
+}
+
+fn acks(device: &mut Ws) -> Vec<Value> {
+    device.collect_type("telemetry:ack", Duration::from_millis(500))
+}
+
+#[test]
+fn signed_telemetry_is_accepted() {
+    let server = TestServer::start("signing-valid");
+    let token = server.pair_signed("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    let data = r#"{"latitude": 34.05, "longitude": -118.24, "battery": 88, "ack": true}"#;
+    device.send_text(&telemetry(&token, data, data));
+    assert_eq!(acks(&mut device).len(), 1);
+
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["battery"], 88.0, "{}", devices);
+}
+
+#[test]
+fn signed_telemetry_in_a_batch_is_accepted() {
+    let server = TestServer::start("signing-batch");
+    let token = server.pair_signed("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    // Signed over the text as sent, spacing and all
+    let data = r#"{"latitude": 34.05, "longitude": -118.24, "battery": 77, "ack": true}"#;
+    device.send_text(&format!(r#"{{"type": "batch", "data": {{"requests": [{}]}}}}"#, telemetry(&token, data, data)));
+    let result = device.recv_type("batch:result");
+    let replies = &result["data"]["results"][0]["replies"];
+    assert_eq!(replies[0]["type"], "telemetry:ack", "{}", result);
+
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["battery"], 77.0, "{}", devices);
+}
+
+#[test]
+fn tampered_and_unsigned_telemetry_is_rejected() {
+    let server = TestServer::start("signing-tampered");
+    let token = server.pair_signed("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    let signed = r#"{"latitude": 34.05, "longitude": -118.24, "battery": 88, "ack": true}"#;
+    let tampered = r#"{"latitude": 34.05, "longitude": -118.24, "battery": 11, "ack": true}"#;
+    device.send_text(&telemetry(&token, signed, tampered));
+    let error = device.recv_type("error");
+    assert_eq!(error["data"]["code"], "bad_signature", "{}", error);
+
+    device.send(&json!({"type": "telemetry", "data": {"latitude": 34.05, "longitude": -118.24, "battery": 11, "ack": true}}));
+    assert_eq!(device.recv_type("error")["data"]["code"], "bad_signature");
+
+    assert!(acks(&mut device).is_empty());
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert_eq!(devices["devices"][0]["battery"], 100.0, "{}", devices);
+}
+
+#[test]
+fn an_unsigned_devices_token_cannot_register_as_a_signed_device() {
+    let server = TestServer::start("signing-spoof");
+    server.pair_signed("robot-01", "robot");
+    let other = server.pair("robot-02", "robot");
+
+    let mut spoof = server.ws("/", "");
+    spoof.send(&json!({"type": "register", "data": {
+        "device_id": "robot-01", "device_type": "robot", "name": "robot-01",
+        "token": other, "latitude": 34.05, "longitude": -118.24
+    }}));
+    let error = spoof.recv_type("error");
+    assert_eq!(error["data"]["code"], "invalid_token", "{}", error);
+
+    spoof.send(&json!({"type": "telemetry", "data": {"latitude": 34.05, "longitude": -118.24, "battery": 11, "ack": true}}));
+    assert!(acks(&mut spoof).is_empty());
+    let (_, devices) = server.http("GET", "/api/devices", None, None);
+    assert!(devices["devices"].as_array().unwrap().iter().all(|d| d["battery"] != 11.0), "{}", devices);
+}
+
+#[test]
+fn unsigned_devices_need_no_signature() {
+    let server = TestServer::start("signing-off");
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    device.send(&json!({"type": "telemetry", "data": {"latitude": 34.05, "longitude": -118.24, "ack": true}}));
+    assert_eq!(acks(&mut device).len(), 1);
+}
+
+// ============================================================================
+// ALERTS
+// ============================================================================
+
+// Device alerts: discrete events pushed to UIs as `alert:new` and kept for
+// `GET /api/alerts` until an operator acknowledges them.
+
+#[test]
+fn alerts_reach_uis_and_are_kept_per_device() {
+    let server = TestServer::start_with("alerts", admin_config());
+    let robot_token = server.pair("robot-01", "robot");
+    let drone_token = server.pair("drone-01", "drone");
+    let mut ui = server.ui(None);
+    let mut robot = server.device("robot-01", "robot", &robot_token);
+    let mut drone = server.device("drone-01", "drone", &drone_token);
+
+    robot.send(&json!({"type": "alert", "data": {"severity": "warning", "code": "obstacle_detected", "message": "Pallet in aisle 4"}}));
+    let alert = ui.recv_type("alert:new");
+    assert_eq!(alert["data"]["deviceId"], "robot-01", "{}", alert);
+    assert_eq!(alert["data"]["severity"], "warning");
+    assert_eq!(alert["data"]["code"], "obstacle_detected");
+    assert_eq!(alert["data"]["message"], "Pallet in aisle 4");
+    assert!(alert["data"]["id"].is_i64());
+
+    drone.send(&json!({"type": "alert", "data": {"severity": "critical", "code": "emergency_stop"}}));
+    ui.recv_type("alert:new");
+    robot.send(&json!({"type": "alert", "data": {"severity": "info", "code": "task_failed", "message": "Retrying"}}));
+    ui.recv_type("alert:new");
+
+    let (status, all) = server.http("GET", "/api/alerts", None, None);
+    assert_eq!(status, 200, "{}", all);
+    let codes: Vec<&str> = all["alerts"].as_array().unwrap().iter().map(|a| a["code"].as_str().unwrap()).collect();
+    assert_eq!(codes, ["obstacle_detected", "emergency_stop", "task_failed"]);
+
+    let (_, robot_alerts) = server.http("GET", "/api/alerts?device_id=robot-01", None, None);
+    let robot_alerts = robot_alerts["alerts"].as_array().unwrap();
+    assert_eq!(robot_alerts.len(), 2);
+    assert!(robot_alerts.iter().all(|a| a["device_id"] == "robot-01"));
+    assert_eq!(robot_alerts[1]["message"], "Retrying");
+    assert!(robot_alerts[0]["created_at"].as_i64().unwrap() > 0);
+
+    let (_, latest) = server.http("GET", "/api/alerts?limit=1", None, None);
+    assert_eq!(latest["alerts"][0]["code"], "task_failed", "{}", latest);
+
+    let (_, future) = server.http("GET", "/api/alerts?since=99999999999", None, None);
+    assert_eq!(future["alerts"], json!([]));
+}
+
+#[test]
+fn malformed_alerts_are_refused() {
+    let server = TestServer::start_with("alerts-invalid", admin_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+
+    device.send(&json!({"type": "alert", "data": {"severity": "apocalyptic", "code": "obstacle_detected"}}));
+    let error = device.recv_type("error");
+    assert_eq!(error["data"]["code"], "invalid_alert", "{}", error);
+
+    device.send(&json!({"type": "alert", "data": {"severity": "info", "code": ""}}));
+    assert_eq!(device.recv_type("error")["data"]["code"], "invalid_alert");
+
+    let (_, alerts) = server.http("GET", "/api/alerts", None, None);
+    assert_eq!(alerts["alerts"], json!([]));
+}
+
+#[test]
+fn acknowledged_alerts_leave_the_open_list() {
+    let server = TestServer::start_with("alerts-ack", admin_config());
+    let token = server.pair("robot-01", "robot");
+    let mut ui = server.ui(None);
+    let mut device = server.device("robot-01", "robot", &token);
+    for code in ["obstacle_detected", "task_failed"] {
+        device.send(&json!({"type": "alert", "data": {"severity": "warning", "code": code}}));
+    }
+    let id = ui.recv_type("alert:new")["data"]["id"].as_i64().unwrap();
+    ui.recv_type("alert:new");
+
+    let path = format!("/api/alerts/{}/ack", id);
+    assert_eq!(server.http("POST", &path, None, None).0, 401);
+
+    let (status, alert) = server.http("POST", &path, Some(&json!({"by": "dana"})), Some(ADMIN));
+    assert_eq!(status, 200, "{}", alert);
+    assert_eq!(alert["acknowledged_by"], "dana");
+    assert!(alert["acknowledged_at"].as_i64().unwrap() > 0);
+
+    let acknowledged = ui.recv_type("alert:acknowledged");
+    assert_eq!(acknowledged["data"]["id"], id, "{}", acknowledged);
+    assert_eq!(acknowledged["data"]["deviceId"], "robot-01");
+    assert_eq!(acknowledged["data"]["acknowledgedBy"], "dana");
+
+    assert_eq!(server.http("POST", &path, None, Some(ADMIN)).0, 409);
+    assert_eq!(server.http("POST", "/api/alerts/9999/ack", None, Some(ADMIN)).0, 404);
+
+    let (_, open) = server.http("GET", "/api/alerts?unacknowledged=true", None, None);
+    let codes: Vec<&str> = open["alerts"].as_array().unwrap().iter().map(|a| a["code"].as_str().unwrap()).collect();
+    assert_eq!(codes, ["task_failed"]);
+
+    let (_, all) = server.http("GET", "/api/alerts", None, None);
+    assert_eq!(all["alerts"][0]["acknowledged_by"], "dana", "{}", all);
+    assert_eq!(all["alerts"][1]["acknowledged_at"], json!(null));
+}
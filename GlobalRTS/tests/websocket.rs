@@ -0,0 +1,269 @@
+//! The WebSocket connection itself: caps on what a client sends, what
+//! happens to one that stops reading, and requests any client may make.
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::{admin_config, TestServer, ADMIN, TIMEOUT};
+use globalrts::Config;
+use serde_json::{json, Value};
+
+// ============================================================================
+// INGRESS CAPS
+// ============================================================================
+
+// WebSocket ingress caps: a client sending faster than the configured
+// bytes/sec (averaged over a few seconds) is closed with 1008, and one
+// sending a message over the size cap with 1009.
+
+/// 1000 bytes/sec over the server's 5-second window: 5000 bytes.
+const LIMIT: u64 = 1000;
+
+/// Largest message, in bytes.
+const MAX_MESSAGE: u64 = 2000;
+
+fn ingress_caps_config() -> Config {
+    Config {
+        ingress_limit: LIMIT,
+        max_message: MAX_MESSAGE,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn sustained_flood_is_closed_with_policy_violation() {
+    let server = TestServer::start_with("ingress", ingress_caps_config());
+    let mut ws = server.ws("/", "");
+
+    // 1000-byte payloads are 1008-byte frames (2 header, 2 length, 4 mask)
+    let junk = "x".repeat(1000);
+    for _ in 0..4 {
+        ws.send_text(&junk);
+    }
+    assert_eq!(ws.close_code(Duration::from_millis(300)), None, "4032 bytes is under the cap");
+
+    // The fifth takes the window to 5040
+    ws.send_text(&junk);
+    assert_eq!(ws.close_code(TIMEOUT), Some(Some(1008)));
+}
+
+#[test]
+fn oversized_message_is_closed_with_too_big() {
+    let server = TestServer::start_with("ingress-size", ingress_caps_config());
+    let mut ws = server.ws("/", "");
+
+    ws.send_text(&"x".repeat(2000));
+    assert_eq!(ws.close_code(Duration::from_millis(300)), None, "2000 bytes is at the cap");
+
+    ws.send_text(&"x".repeat(2001));
+    assert_eq!(ws.close_code(TIMEOUT), Some(Some(1009)));
+}
+
+// ============================================================================
+// SEND QUEUE
+// ============================================================================
+
+// Each WebSocket client has its own bounded send queue. A client that stops
+// reading fills only its own queue and is then closed with 1008; everyone
+// else keeps getting broadcasts.
+
+fn send_queue_config() -> Config {
+    Config {
+        send_queue: 262144,
+        ingress_limit: 0,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn stalled_reader_is_dropped_without_holding_up_others() {
+    let server = TestServer::start_with("backpressure", send_queue_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(None);
+    // Never read from again
+    let mut stalled = server.ui(None);
+
+    // Every completion is broadcast to both UIs: 40 x 200 KB is far more
+    // than the stalled UI's queue and socket buffers hold
+    let result = "x".repeat(200_000);
+    let started = Instant::now();
+    for _ in 0..40 {
+        ui.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "ring", "payload": {}}}));
+        let command = device.recv_type("command");
+        let command_id = command["data"]["commandId"].clone();
+        device.send(&json!({"type": "command:complete", "data": {"commandId": command_id, "status": "completed", "result": result}}));
+        let complete = ui.recv_type("command:complete");
+        assert_eq!(complete["data"]["commandId"], command_id);
+    }
+    assert!(started.elapsed() < Duration::from_secs(10), "broadcasts stalled: {:?}", started.elapsed());
+
+    assert_eq!(stalled.close_code(TIMEOUT), Some(Some(1008)));
+}
+
+// ============================================================================
+// WRITE TIMEOUT
+// ============================================================================
+
+// GLOBALRTS_WS_WRITE_TIMEOUT_SECS: a client that takes nothing written to
+// it for that long is taken for dead, shut and removed, even while its
+// send queue still has room.
+
+#[test]
+fn a_reader_that_never_drains_is_disconnected() {
+    let config = Config { ws_write_timeout_secs: 1, send_queue: 0, ingress_limit: 0, ..admin_config() };
+    let server = TestServer::start_with("write-timeout", config);
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let mut ui = server.ui(Some(ADMIN));
+    // Never read from until the end
+    let mut stalled = server.ui(Some(ADMIN));
+
+    // 40 x 200 KB is more than the stalled UI's socket buffers hold, and
+    // with no queue cap only the timeout can drop it
+    let result = "x".repeat(200_000);
+    for _ in 0..40 {
+        ui.send(&json!({"type": "sendCommand", "data": {"device_id": "robot-01", "command_type": "ring", "payload": {}}}));
+        let command = device.recv_type("command");
+        let command_id = command["data"]["commandId"].clone();
+        device.send(&json!({"type": "command:complete", "data": {"commandId": command_id, "status": "completed", "result": result}}));
+        ui.recv_type("command:complete");
+    }
+
+    let connections = || server.http("GET", "/api/connections", None, Some(ADMIN)).1["count"].clone();
+    let deadline = Instant::now() + TIMEOUT;
+    while connections() != 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(connections(), 2);
+
+    // What did arrive is followed by the end of the stream, not a close frame
+    assert_eq!(stalled.close_code(TIMEOUT), Some(None));
+}
+
+// ============================================================================
+// CONNECTION LIST
+// ============================================================================
+
+// `GET /api/connections` lists live WebSocket connections with the frames
+// and bytes that have crossed each, for debugging protocol trouble.
+
+#[test]
+fn stats_count_the_frames_each_way() {
+    let server = TestServer::start_with("connections", admin_config());
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    let _ui = server.ui(None);
+
+    for battery in [90, 89, 88] {
+        device.send(&json!({"type": "telemetry", "data": {"latitude": 34.0, "longitude": -118.0, "battery": battery, "ack": true}}));
+        device.recv_type("telemetry:ack");
+    }
+    device.send_binary(b"\x00\x01");
+    device.send(&json!({"type": "ping", "data": {}}));
+    device.recv_type("pong");
+
+    let (status, reply) = server.http("GET", "/api/connections", None, Some(ADMIN));
+    assert_eq!(status, 200, "{}", reply);
+    assert_eq!(reply["count"], 2);
+    let find = |kind: &str| -> Value {
+        reply["connections"].as_array().unwrap().iter().find(|c| c["client_type"] == kind).cloned().unwrap()
+    };
+
+    let robot = find("device");
+    assert_eq!(robot["device_id"], "robot-01");
+    assert_eq!(robot["ip"], "127.0.0.1");
+    let stats = &robot["stats"];
+    // register, three telemetry, ping; and the binary frame, which is ignored
+    assert_eq!(stats["frames_in"], json!({"text": 5, "binary": 1, "close": 0, "ping": 0, "pong": 0, "other": 0}), "{}", robot);
+    // registered, three acks, pong
+    assert_eq!(stats["frames_out"]["text"], 5, "{}", robot);
+    assert!(stats["bytes_in"].as_u64().unwrap() > 100, "{}", robot);
+    assert!(stats["bytes_out"].as_u64().unwrap() > 100, "{}", robot);
+    assert_eq!(stats["protocol_errors"], 0);
+
+    let ui = find("ui");
+    assert_eq!(ui["device_id"], Value::Null);
+    assert_eq!(ui["stats"]["frames_in"]["text"], 1, "getDevices: {}", ui);
+
+    assert_eq!(server.http("GET", "/api/connections", None, None).0, 401);
+}
+
+// ============================================================================
+// PING
+// ============================================================================
+
+// Application-level `ping`, answered with `pong` for any client.
+
+fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+#[test]
+fn ping_echoes_the_client_timestamp_with_the_servers() {
+    let server = TestServer::start("ping");
+
+    // Before identifying as anything
+    let mut ws = server.ws("/", "");
+    let before = now_millis();
+    ws.send(&json!({"type": "ping", "data": {"t": 12345}}));
+    let pong = ws.recv_type("pong");
+    assert_eq!(pong["data"]["t"], 12345, "{}", pong);
+    let server_t = pong["data"]["server_t"].as_i64().unwrap();
+    assert!(server_t >= before - 1000 && server_t <= now_millis() + 1000, "{}", pong);
+
+    // And as a UI and a device
+    let mut ui = server.ui(None);
+    ui.send(&json!({"type": "ping", "data": {"t": 1.5}}));
+    assert_eq!(ui.recv_type("pong")["data"]["t"], 1.5);
+
+    let token = server.pair("robot-01", "robot");
+    let mut device = server.device("robot-01", "robot", &token);
+    device.send(&json!({"type": "ping", "data": {"t": "abc"}}));
+    assert_eq!(device.recv_type("pong")["data"]["t"], "abc");
+}
+
+// ============================================================================
+// BATCHES
+// ============================================================================
+
+// A `batch` over the WebSocket: several requests in one message, their
+// answers in one `batch:result`.
+
+#[test]
+fn a_batch_answers_every_request_in_one_message() {
+    let server = TestServer::start("batch");
+    server.pair("robot-01", "robot");
+    server.pair("drone-01", "drone");
+
+    let mut ui = server.ws("/", "");
+    ui.send(&json!({"type": "batch", "data": {"id": "connect-1", "requests": [
+        {"type": "getDevices", "data": {}},
+        {"type": "getStats", "data": {}},
+        {"type": "batch", "data": {"requests": []}}
+    ]}}));
+    let result = ui.recv_type("batch:result");
+    assert_eq!(result["data"]["id"], "connect-1");
+    let results = result["data"]["results"].as_array().unwrap();
+    assert_eq!(results.len(), 3, "{}", result);
+
+    assert_eq!(results[0]["type"], "getDevices");
+    let devices = &results[0]["replies"][0];
+    assert_eq!(devices["type"], "devices:list", "{}", result);
+    assert_eq!(devices["data"].as_array().unwrap().len(), 2);
+
+    assert_eq!(results[1]["type"], "getStats");
+    let stats = &results[1]["replies"][0];
+    assert_eq!(stats["type"], "stats", "{}", result);
+    assert_eq!(stats["data"]["devices"]["total"], 2);
+
+    assert_eq!(results[2]["replies"][0]["data"]["code"], "invalid_batch");
+
+    // Nothing was sent separately: the next answer is to the next message
+    ui.send(&json!({"type": "getStats", "data": {}}));
+    let next = ui.recv_within(TIMEOUT).expect("an answer");
+    assert_eq!(next["type"], "stats", "{}", next);
+    assert_eq!(next["data"]["devices"]["total"], 2);
+}